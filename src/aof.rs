@@ -0,0 +1,394 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use crate::rdb::{self, RdbError, Snapshot};
+use crate::value::Value;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AofError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Rdb(#[from] RdbError),
+    #[error("bad command at offset {0}")]
+    Corrupt(usize),
+}
+
+/// The append-only file: every write, in the order writes took effect,
+/// logged as a RESP command so that replaying the file rebuilds the dataset.
+///
+/// Commands are buffered as they are fed and written out before their
+/// clients get a reply. A rewrite replaces the file with a snapshot of the
+/// dataset followed by the commands fed since the snapshot was taken.
+pub struct AppendOnlyFile {
+    /// Whether commands are fed, which they are from the snapshot of the
+    /// rewrite that creates the file on.
+    enabled: AtomicBool,
+    /// Whether a rewrite is running, which only one may be at a time.
+    rewriting: AtomicBool,
+    /// Whether the last attempt to write out commands failed, which keeps
+    /// writes refused until one succeeds.
+    failed: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The file commands are appended to, which is missing while the
+    /// rewrite that creates it runs.
+    file: Option<File>,
+    /// Commands not written out yet.
+    buffer: Vec<u8>,
+    /// Commands fed since the running rewrite took its snapshot.
+    rewrite: Option<Vec<u8>>,
+    /// How long the file is up to the last command written out whole.
+    len: u64,
+    /// Why the last attempt to write out commands failed.
+    error: Option<String>,
+}
+
+impl AppendOnlyFile {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            rewriting: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Starts appending to the file at `path`, which has to exist.
+    pub fn open(&self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        let len = file.metadata()?.len();
+        let mut state = self.state.lock().unwrap();
+        state.file = Some(file);
+        state.len = len;
+        state.error = None;
+        self.failed.store(false, Ordering::Release);
+        self.enabled.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stops logging, dropping the commands not written out yet. A running
+    /// rewrite still replaces the file, but the server does not log to it.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.enabled.store(false, Ordering::Release);
+        state.file = None;
+        state.buffer.clear();
+        state.rewrite = None;
+        state.error = None;
+        self.failed.store(false, Ordering::Release);
+    }
+
+    /// Why commands could not be written out, if the last attempt failed.
+    pub fn write_error(&self) -> Option<String> {
+        if !self.failed.load(Ordering::Acquire) {
+            return None;
+        }
+        self.state.lock().unwrap().error.clone()
+    }
+
+    /// Logs a command, if logging is on.
+    pub fn feed(&self, command: &[Bytes]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let start = state.buffer.len();
+        encode(command, &mut state.buffer);
+        let State {
+            buffer, rewrite, ..
+        } = &mut *state;
+        if let Some(rewrite) = rewrite {
+            rewrite.extend_from_slice(&buffer[start..]);
+        }
+    }
+
+    /// Writes out the buffered commands, and flushes the file to disk if
+    /// `fsync` is set.
+    ///
+    /// As in Redis, a failure leaves the log failed until a later attempt
+    /// succeeds. A write cut short is cut off the file again, so that the
+    /// commands are written whole by the next attempt rather than twice; if
+    /// even that fails, only the part that did not make it is kept.
+    pub fn write(&self, fsync: bool) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            file,
+            buffer,
+            len,
+            error,
+            ..
+        } = &mut *state;
+        let Some(file) = file else {
+            // The rewrite that creates the file has the commands.
+            buffer.clear();
+            return Ok(());
+        };
+        let mut written = 0;
+        let mut result = Ok(());
+        while written < buffer.len() {
+            match file.write(&buffer[written..]) {
+                Ok(0) => {
+                    result = Err(io::Error::from(io::ErrorKind::WriteZero));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        match result {
+            Ok(()) => {
+                *len += written as u64;
+                buffer.clear();
+            }
+            Err(_) if written == 0 || file.set_len(*len).is_ok() => {}
+            Err(_) => {
+                *len += written as u64;
+                buffer.drain(..written);
+            }
+        }
+        if result.is_ok() && fsync {
+            result = file.sync_data();
+        }
+        match &result {
+            Ok(()) => *error = None,
+            Err(err) => *error = Some(err.to_string()),
+        }
+        self.failed.store(result.is_err(), Ordering::Release);
+        result
+    }
+
+    /// Claims the right to rewrite the file, returning false if a rewrite
+    /// is already running.
+    pub fn claim_rewrite(&self) -> bool {
+        self.rewriting
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Starts keeping the commands fed from now on for the rewritten file,
+    /// and turns logging on if `enable` is set. The caller takes the
+    /// snapshot before any other write can happen.
+    pub fn begin_rewrite(&self, enable: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.rewrite = Some(vec![]);
+        if enable {
+            self.enabled.store(true, Ordering::Release);
+        }
+    }
+
+    /// Replaces the file at `path` with `base`, the snapshot taken when the
+    /// rewrite began, followed by the commands fed since. Only appending
+    /// those commands holds up feeding; `base` is written before.
+    pub fn finish_rewrite(&self, path: &Path, base: &[u8]) -> io::Result<()> {
+        let temp = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let mut file = File::create(&temp)?;
+        file.write_all(base)?;
+        file.sync_data()?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(rewrite) = state.rewrite.take() {
+            file.write_all(&rewrite)?;
+        }
+        file.sync_data()?;
+        fs::rename(temp, path)?;
+        if self.is_enabled() {
+            // Whatever was still buffered is in the new file already.
+            state.buffer.clear();
+            state.len = file.metadata()?.len();
+            state.file = Some(file);
+            state.error = None;
+            self.failed.store(false, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Releases the claim taken with [`AppendOnlyFile::claim_rewrite`].
+    pub fn end_rewrite(&self) {
+        self.state.lock().unwrap().rewrite = None;
+        self.rewriting.store(false, Ordering::Release);
+    }
+}
+
+impl Default for AppendOnlyFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What an append-only file holds.
+pub struct Contents {
+    /// The snapshot a rewrite starts the file with, if it was rewritten.
+    pub base: Option<Snapshot>,
+    /// Where the commands start, past the snapshot.
+    pub start: usize,
+    /// The logged commands, each with the offset just past it.
+    pub commands: Vec<(Vec<Bytes>, usize)>,
+    /// The length of the file, which is past the last command if the server
+    /// went down halfway through logging it.
+    pub len: usize,
+}
+
+/// Reads the append-only file at `path`, or returns `None` if there is none.
+/// An incomplete last command is left out, but anything else that is not a
+/// command is an error.
+pub fn load(path: &Path) -> Result<Option<Contents>, AofError> {
+    let input = match fs::read(path) {
+        Ok(input) => input,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let (base, mut pos) = if input.starts_with(b"REDIS") {
+        let (base, len) = rdb::decode_prefix(&input)?;
+        (Some(base), len)
+    } else {
+        (None, 0)
+    };
+    let start = pos;
+    let mut commands = vec![];
+    while pos < input.len() {
+        match decode(&input[pos..]) {
+            Ok(Some((command, len))) => {
+                pos += len;
+                commands.push((command, pos));
+            }
+            Ok(None) => break,
+            Err(()) => return Err(AofError::Corrupt(pos)),
+        }
+    }
+    Ok(Some(Contents {
+        base,
+        start,
+        commands,
+        len: input.len(),
+    }))
+}
+
+/// Writes the file at `path` anew with `base` as its snapshot.
+pub fn create(path: &Path, base: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(base)?;
+    file.sync_all()
+}
+
+/// Cuts the file at `path` down to `len` bytes.
+pub fn truncate(path: &Path, len: usize) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len as u64)?;
+    file.sync_all()
+}
+
+fn encode(command: &[Bytes], buffer: &mut Vec<u8>) {
+    let command = command.iter().cloned().map(Value::BulkString).collect();
+    Value::Array(command)
+        .encode(buffer)
+        .expect("Error while logging a command");
+}
+
+/// Reads one command, an array of bulk strings, from the start of `input`,
+/// returning it with its length, or `None` if `input` ends before it does.
+fn decode(input: &[u8]) -> Result<Option<(Vec<Bytes>, usize)>, ()> {
+    let mut pos = 0;
+    let Some(count) = header(input, &mut pos, b'*')? else {
+        return Ok(None);
+    };
+    let mut command = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let Some(len) = header(input, &mut pos, b'$')? else {
+            return Ok(None);
+        };
+        let Some(arg) = input.get(pos..pos + len + 2) else {
+            return Ok(None);
+        };
+        if !arg.ends_with(b"\r\n") {
+            return Err(());
+        }
+        command.push(Bytes::copy_from_slice(&arg[..len]));
+        pos += len + 2;
+    }
+    Ok(Some((command, pos)))
+}
+
+/// Reads a `<kind><number>\r\n` line at `pos`, moving past it.
+fn header(input: &[u8], pos: &mut usize, kind: u8) -> Result<Option<usize>, ()> {
+    let rest = &input[*pos..];
+    let Some(end) = rest.iter().position(|&byte| byte == b'\n') else {
+        // A line can only be this long if it is not a header.
+        return if rest.len() > 32 { Err(()) } else { Ok(None) };
+    };
+    let line = rest[..end].strip_suffix(b"\r").ok_or(())?;
+    let number = line.strip_prefix(&[kind]).ok_or(())?;
+    let number = std::str::from_utf8(number)
+        .ok()
+        .and_then(|n| n.parse().ok());
+    *pos += end + 1;
+    number.map(Some).ok_or(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<Bytes> {
+        args.iter()
+            .map(|arg| Bytes::from(arg.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn commands_round_trip_and_a_torn_one_is_left_out() {
+        let set = command(&["SET", "key", "line\r\nbreak"]);
+        let mut input = vec![];
+        encode(&set, &mut input);
+        let len = input.len();
+        encode(&command(&["DEL", "key"]), &mut input);
+        assert_eq!(decode(&input), Ok(Some((set, len))));
+        for end in len..input.len() {
+            assert_eq!(decode(&input[len..end]), Ok(None), "{end}");
+        }
+        assert_eq!(decode(b"+OK\r\n"), Err(()));
+        assert_eq!(decode(b"*1\r\n$3\r\nabcd\r\n"), Err(()));
+    }
+
+    #[test]
+    fn failed_writes_are_retried_whole_and_refuse_nothing_once_they_succeed() {
+        let dir = std::env::temp_dir().join(format!("mini-redis-{}-aof-write", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        create(&path, b"").unwrap();
+        let aof = AppendOnlyFile::new();
+        aof.open(&path).unwrap();
+        aof.feed(&command(&["SET", "a", "1"]));
+        aof.write(false).unwrap();
+
+        // A handle that cannot be written through stands in for a full disk.
+        let writable = aof.state.lock().unwrap().file.replace(File::open(&path).unwrap());
+        aof.feed(&command(&["SET", "b", "2"]));
+        assert!(aof.write(false).is_err());
+        assert!(aof.write_error().is_some());
+
+        aof.state.lock().unwrap().file = writable;
+        aof.write(false).unwrap();
+        assert_eq!(aof.write_error(), None);
+        let mut expected = vec![];
+        encode(&command(&["SET", "a", "1"]), &mut expected);
+        encode(&command(&["SET", "b", "2"]), &mut expected);
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
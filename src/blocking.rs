@@ -0,0 +1,160 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::operation::ListEnd;
+use crate::stream::StreamId;
+use crate::value::{Protocol, Value};
+
+/// What a blocked client wants to do once one of its keys has an element or entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockedOperation {
+    /// `BLPOP`/`BRPOP`: pop from the given end.
+    Pop(ListEnd),
+    /// `BLMOVE`: pop from one end and push to the destination list.
+    Move(ListEnd, Bytes, ListEnd),
+    /// `XREAD`: read up to `count` entries after the given ID of whichever
+    /// stream gets new entries first.
+    Read {
+        after: Vec<(Bytes, StreamId)>,
+        count: Option<usize>,
+        protocol: Protocol,
+    },
+    /// `XREADGROUP`: deliver up to `count` entries the group has not seen
+    /// yet from whichever stream gets some first.
+    ReadGroup {
+        group: Bytes,
+        consumer: Bytes,
+        count: Option<usize>,
+        noack: bool,
+        protocol: Protocol,
+    },
+}
+
+/// A client parked on one or more list or stream keys.
+pub struct Waiter {
+    pub keys: Vec<Bytes>,
+    pub operation: BlockedOperation,
+    /// Receives the reply once the client has been served.
+    pub reply: oneshot::Sender<Value>,
+}
+
+/// Keeps track of clients blocked on list and stream keys, so the write path
+/// can hand newly pushed elements and added entries to them.
+///
+/// Every key has a FIFO queue of waiters: the client that blocked first is
+/// served first. A client blocked on several keys sits in each of their
+/// queues and leaves all of them once it is served or times out.
+#[derive(Default)]
+pub struct BlockingRegistry {
+    inner: Mutex<Waiters>,
+}
+
+#[derive(Default)]
+pub struct Waiters {
+    next_id: u64,
+    queues: HashMap<Bytes, VecDeque<u64>>,
+    waiters: HashMap<u64, Waiter>,
+}
+
+impl BlockingRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Locks the registry. Checking a key for elements and registering a
+    /// waiter on it must happen under the same lock, or a push in between
+    /// would go unnoticed.
+    pub fn lock(&self) -> MutexGuard<'_, Waiters> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl Waiters {
+    /// Queues `waiter` behind the clients already blocked on its keys and
+    /// returns an id to remove it with.
+    pub fn register(&mut self, waiter: Waiter) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        for key in &waiter.keys {
+            self.queues.entry(key.clone()).or_default().push_back(id);
+        }
+        self.waiters.insert(id, waiter);
+        id
+    }
+
+    /// Removes a waiter from the queues of all its keys.
+    pub fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|queued| *queued != id);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+
+    /// The ids of the clients blocked on `key`, longest waiting first.
+    pub fn queued(&self, key: &Bytes) -> Vec<u64> {
+        self.queues
+            .get(key)
+            .map_or(vec![], |queue| queue.iter().copied().collect())
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Waiter> {
+        self.waiters.get(&id)
+    }
+
+    pub fn is_blocked_on(&self, key: &Bytes) -> bool {
+        self.queues.contains_key(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiter(keys: &[&'static str]) -> (Waiter, oneshot::Receiver<Value>) {
+        let (reply, receiver) = oneshot::channel();
+        let waiter = Waiter {
+            keys: keys
+                .iter()
+                .map(|key| Bytes::from_static(key.as_bytes()))
+                .collect(),
+            operation: BlockedOperation::Pop(ListEnd::Left),
+            reply,
+        };
+        (waiter, receiver)
+    }
+
+    #[test]
+    fn waiters_are_served_in_fifo_order() {
+        let registry = BlockingRegistry::new();
+        let mut waiters = registry.lock();
+        let key = Bytes::from_static(b"a");
+        let first = waiters.register(waiter(&["a"]).0);
+        let second = waiters.register(waiter(&["b", "a"]).0);
+        assert_eq!(waiters.queued(&key), vec![first, second]);
+        waiters.remove(first);
+        assert_eq!(waiters.queued(&key), vec![second]);
+        assert_eq!(waiters.queued(&Bytes::from_static(b"b")), vec![second]);
+        assert!(waiters.get(first).is_none());
+    }
+
+    #[test]
+    fn removing_a_waiter_clears_all_its_keys() {
+        let registry = BlockingRegistry::new();
+        let mut waiters = registry.lock();
+        let id = waiters.register(waiter(&["a", "b"]).0);
+        assert!(waiters.is_blocked_on(&Bytes::from_static(b"b")));
+        assert!(waiters.remove(id).is_some());
+        assert!(waiters.remove(id).is_none());
+        assert!(!waiters.is_blocked_on(&Bytes::from_static(b"a")));
+        assert!(!waiters.is_blocked_on(&Bytes::from_static(b"b")));
+    }
+}
@@ -0,0 +1,636 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use crate::crc16;
+use crate::dataframe::unix_time_millis;
+use crate::replication::random_id;
+use crate::value::{bulk, Value};
+
+/// How many slots keys are spread over.
+pub const SLOTS: u16 = 16384;
+
+/// The index of this node among the nodes it knows of.
+const MYSELF: usize = 0;
+
+/// The slot `key` belongs to. Only the part of the key within the first
+/// braces, if not empty, is hashed, so that keys sharing a `{tag}` share
+/// a slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&byte| byte == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        match rest.iter().position(|&byte| byte == b'}') {
+            Some(0) | None => None,
+            Some(close) => Some(&rest[..close]),
+        }
+    });
+    crc16::checksum(tag.unwrap_or(key)) % SLOTS
+}
+
+/// What this node knows of the cluster it is part of, when cluster mode is
+/// enabled.
+///
+/// Every node serves the slots it claims, unless another node claims them
+/// with a higher config epoch, which wins. Nodes keep each other up to date
+/// by regularly sending what they claim and which nodes they know of, so
+/// that meeting one node of a cluster is enough to learn of the others.
+pub struct Cluster {
+    enabled: AtomicBool,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The nodes this one knows of, itself first. Nodes are never
+    /// forgotten, so that their index stays the same.
+    nodes: Vec<Node>,
+    /// The index in `nodes` of the node serving each slot.
+    slots: Vec<Option<usize>>,
+    /// Slots this node serves that are moving to another, with the index
+    /// of the other.
+    migrating: HashMap<u16, usize>,
+    /// Slots moving to this node, with the index of the node serving them.
+    importing: HashMap<u16, usize>,
+    /// Addresses met that did not answer yet.
+    meeting: Vec<(String, u16)>,
+    /// The highest config epoch seen.
+    current_epoch: u64,
+}
+
+struct Node {
+    id: String,
+    host: String,
+    port: u16,
+    /// Settles which of two nodes claiming a slot serves it.
+    epoch: u64,
+    /// Whether the last message exchanged with the node went through.
+    connected: bool,
+    /// When the last message from the node came, in Unix milliseconds.
+    seen: i64,
+}
+
+/// Who serves a slot.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Owner {
+    Myself,
+    Node(String, u16),
+    Unassigned,
+}
+
+/// A change to a slot made with `CLUSTER SETSLOT`, naming nodes by ID.
+#[derive(Debug)]
+pub enum SetSlot {
+    /// Start moving the slot here from the node serving it.
+    Importing(String),
+    /// Start moving the slot from here to the node.
+    Migrating(String),
+    /// Stop moving the slot.
+    Stable,
+    /// Hand the slot to the node, once its keys moved there.
+    Node(String),
+}
+
+impl Cluster {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            state: Mutex::new(State {
+                nodes: vec![Node {
+                    id: random_id(),
+                    host: String::from("127.0.0.1"),
+                    port: 0,
+                    epoch: 0,
+                    connected: true,
+                    seen: 0,
+                }],
+                slots: vec![None; SLOTS as usize],
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+                meeting: vec![],
+                current_epoch: 0,
+            }),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Release);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Sets the address other nodes and clients reach this node at.
+    pub fn set_address(&self, host: String, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.nodes[MYSELF].host = host;
+        state.nodes[MYSELF].port = port;
+    }
+
+    pub fn set_host(&self, host: String) {
+        self.state.lock().unwrap().nodes[MYSELF].host = host;
+    }
+
+    pub fn myid(&self) -> String {
+        self.state.lock().unwrap().nodes[MYSELF].id.clone()
+    }
+
+    pub fn owner(&self, slot: u16) -> Owner {
+        let state = self.state.lock().unwrap();
+        match state.slots[slot as usize] {
+            Some(MYSELF) => Owner::Myself,
+            Some(node) => Owner::Node(state.nodes[node].host.clone(), state.nodes[node].port),
+            None => Owner::Unassigned,
+        }
+    }
+
+    /// The address of the node the slot is moving to, if it is moving from
+    /// this node.
+    pub fn migrating(&self, slot: u16) -> Option<(String, u16)> {
+        let state = self.state.lock().unwrap();
+        let node = &state.nodes[*state.migrating.get(&slot)?];
+        Some((node.host.clone(), node.port))
+    }
+
+    /// Whether the slot is moving to this node.
+    pub fn importing(&self, slot: u16) -> bool {
+        self.state.lock().unwrap().importing.contains_key(&slot)
+    }
+
+    /// Moves a slot along, as `CLUSTER SETSLOT` does.
+    pub fn set_slot(&self, slot: u16, change: SetSlot) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let index = slot as usize;
+        match change {
+            SetSlot::Importing(id) => {
+                if state.slots[index] == Some(MYSELF) {
+                    return Err(format!("ERR I'm already the owner of hash slot {slot}"));
+                }
+                let node = state.node(&id)?;
+                state.importing.insert(slot, node);
+            }
+            SetSlot::Migrating(id) => {
+                if state.slots[index] != Some(MYSELF) {
+                    return Err(format!("ERR I'm not the owner of hash slot {slot}"));
+                }
+                let node = state.node(&id)?;
+                state.migrating.insert(slot, node);
+            }
+            SetSlot::Stable => {
+                state.migrating.remove(&slot);
+                state.importing.remove(&slot);
+            }
+            SetSlot::Node(id) => {
+                let node = state.node(&id)?;
+                // The other nodes only take the slot from the node serving
+                // it for a config epoch higher than that node's.
+                if node == MYSELF && state.slots[index].is_some_and(|owner| owner != MYSELF) {
+                    state.current_epoch += 1;
+                    state.nodes[MYSELF].epoch = state.current_epoch;
+                }
+                state.slots[index] = Some(node);
+                state.migrating.remove(&slot);
+                state.importing.remove(&slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes this node serve `slots`, none of which may be served already.
+    pub fn add_slots(&self, slots: &[u16]) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        check_unique(slots)?;
+        if let Some(slot) = slots
+            .iter()
+            .find(|&&slot| state.slots[slot as usize].is_some())
+        {
+            return Err(format!("ERR Slot {slot} is already busy"));
+        }
+        for &slot in slots {
+            state.slots[slot as usize] = Some(MYSELF);
+        }
+        Ok(())
+    }
+
+    /// Forgets who serves `slots`, all of which must be served.
+    pub fn del_slots(&self, slots: &[u16]) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        check_unique(slots)?;
+        if let Some(slot) = slots
+            .iter()
+            .find(|&&slot| state.slots[slot as usize].is_none())
+        {
+            return Err(format!("ERR Slot {slot} is already unassigned"));
+        }
+        for &slot in slots {
+            state.slots[slot as usize] = None;
+            state.migrating.remove(&slot);
+            state.importing.remove(&slot);
+        }
+        Ok(())
+    }
+
+    /// Starts getting in touch with the node at `host` and `port`.
+    pub fn meet(&self, host: String, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        if !state.knows(&host, port) {
+            state.meeting.push((host, port));
+        }
+    }
+
+    /// Whether this node is still to hear back from the node at `host` and
+    /// `port` it was asked to meet.
+    pub fn is_meeting(&self, host: &str, port: u16) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .meeting
+            .iter()
+            .any(|(h, p)| (h.as_str(), *p) == (host, port))
+    }
+
+    /// The addresses of the other nodes, known or being met.
+    pub fn peers(&self) -> Vec<(String, u16)> {
+        let state = self.state.lock().unwrap();
+        let nodes = state.nodes[MYSELF + 1..]
+            .iter()
+            .map(|node| (node.host.clone(), node.port));
+        nodes.chain(state.meeting.iter().cloned()).collect()
+    }
+
+    /// Notes whether the node at `host` and `port` answered the last message
+    /// sent to it, which ends meeting it if it did.
+    pub fn set_link(&self, host: &str, port: u16, connected: bool) {
+        let mut state = self.state.lock().unwrap();
+        if connected {
+            state
+                .meeting
+                .retain(|(h, p)| (h.as_str(), *p) != (host, port));
+        }
+        let node = state.nodes[MYSELF + 1..]
+            .iter_mut()
+            .find(|node| node.host == host && node.port == port);
+        if let Some(node) = node {
+            node.connected = connected;
+        }
+    }
+
+    /// What this node tells the others: its ID, address and config epoch,
+    /// the slots it serves, and the addresses of the nodes it knows of.
+    pub fn message(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let myself = &state.nodes[MYSELF];
+        let slots: Vec<String> = state
+            .ranges()
+            .into_iter()
+            .filter(|&(_, _, node)| node == MYSELF)
+            .map(|(start, end, _)| format!("{start}-{end}"))
+            .collect();
+        let mut message = vec![
+            myself.id.clone(),
+            myself.host.clone(),
+            myself.port.to_string(),
+            myself.epoch.to_string(),
+            slots.join(","),
+        ];
+        for node in &state.nodes[MYSELF + 1..] {
+            message.push(format!("{}:{}", node.host, node.port));
+        }
+        message
+    }
+
+    /// Takes in what another node told this one, as `message` built it.
+    /// Unless the node is meeting this one, it has to be one this node
+    /// already knows of.
+    pub fn receive(&self, message: &[Bytes], meet: bool) -> Result<(), String> {
+        let [id, host, port, epoch, slots, addresses @ ..] = message else {
+            return Err(invalid());
+        };
+        let (id, host) = (text(id)?.to_string(), text(host)?.to_string());
+        let port: u16 = text(port)?.parse().map_err(|_| invalid())?;
+        let epoch: u64 = text(epoch)?.parse().map_err(|_| invalid())?;
+        let mut claimed = vec![false; SLOTS as usize];
+        for range in text(slots)?.split(',').filter(|range| !range.is_empty()) {
+            let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+            let start: u16 = start.parse().map_err(|_| invalid())?;
+            let end: u16 = end.parse().map_err(|_| invalid())?;
+            if start > end || end >= SLOTS {
+                return Err(invalid());
+            }
+            claimed[start as usize..=end as usize].fill(true);
+        }
+        let mut others = vec![];
+        for address in addresses {
+            let (host, port) = text(address)?.rsplit_once(':').ok_or_else(invalid)?;
+            let port: u16 = port.parse().map_err(|_| invalid())?;
+            others.push((host.to_string(), port));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if id == state.nodes[MYSELF].id {
+            return Ok(());
+        }
+        // A node that restarted comes back with another ID at the same address.
+        let known = state.nodes[MYSELF + 1..]
+            .iter()
+            .position(|node| node.id == id || (node.host == host && node.port == port));
+        let sender = match known {
+            Some(index) => index + 1,
+            None if !meet => return Err(String::from("ERR Gossip from an unknown node")),
+            None => {
+                state.nodes.push(Node {
+                    id: id.clone(),
+                    host: host.clone(),
+                    port,
+                    epoch,
+                    connected: true,
+                    seen: 0,
+                });
+                state.nodes.len() - 1
+            }
+        };
+        let node = &mut state.nodes[sender];
+        node.id = id;
+        node.host = host.clone();
+        node.port = port;
+        node.epoch = epoch;
+        node.connected = true;
+        node.seen = unix_time_millis();
+        state.current_epoch = state.current_epoch.max(epoch);
+        state.meeting.retain(|(h, p)| (h, *p) != (&host, port));
+        for (slot, claimed) in claimed.into_iter().enumerate() {
+            match state.slots[slot] {
+                Some(owner) if owner == sender && !claimed => state.slots[slot] = None,
+                Some(owner) if claimed && state.beats(sender, owner) => {
+                    state.slots[slot] = Some(sender);
+                    if owner == MYSELF {
+                        state.migrating.remove(&(slot as u16));
+                    }
+                }
+                None if claimed => state.slots[slot] = Some(sender),
+                _ => {}
+            }
+        }
+        for (host, port) in others {
+            if !state.knows(&host, port) {
+                state.meeting.push((host, port));
+            }
+        }
+        Ok(())
+    }
+
+    /// `CLUSTER INFO`: whether every slot is served, and by how many nodes.
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let assigned = state.slots.iter().flatten().count();
+        let mut serving: Vec<usize> = state.slots.iter().flatten().copied().collect();
+        serving.sort_unstable();
+        serving.dedup();
+        let cluster_state = match assigned == SLOTS as usize {
+            true => "ok",
+            false => "fail",
+        };
+        format!(
+            "cluster_enabled:1\r\n\
+             cluster_state:{cluster_state}\r\n\
+             cluster_slots_assigned:{assigned}\r\n\
+             cluster_slots_ok:{assigned}\r\n\
+             cluster_slots_pfail:0\r\n\
+             cluster_slots_fail:0\r\n\
+             cluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\n\
+             cluster_current_epoch:{}\r\n\
+             cluster_my_epoch:{}\r\n",
+            state.nodes.len(),
+            serving.len(),
+            state.current_epoch,
+            state.nodes[MYSELF].epoch,
+        )
+    }
+
+    /// `CLUSTER SLOTS`: each range of slots served by the same node, with
+    /// the node's address and ID.
+    pub fn slots(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let ranges = state.ranges().into_iter().map(|(start, end, node)| {
+            let node = &state.nodes[node];
+            Value::Array(vec![
+                Value::Integer(start as i64),
+                Value::Integer(end as i64),
+                Value::Array(vec![
+                    Value::BulkString(Bytes::from(node.host.clone())),
+                    Value::Integer(node.port as i64),
+                    Value::BulkString(Bytes::from(node.id.clone())),
+                    Value::Map(vec![]),
+                ]),
+            ])
+        });
+        Value::Array(ranges.collect())
+    }
+
+    /// `CLUSTER SHARDS`: the slots each node serves, with the node. `offset`
+    /// is this node's replication offset; those of the others are unknown.
+    pub fn shards(&self, offset: u64) -> Value {
+        let state = self.state.lock().unwrap();
+        let ranges = state.ranges();
+        let shards = state.nodes.iter().enumerate().map(|(index, node)| {
+            let slots = ranges
+                .iter()
+                .filter(|&&(_, _, owner)| owner == index)
+                .flat_map(|&(start, end, _)| {
+                    [Value::Integer(start as i64), Value::Integer(end as i64)]
+                });
+            let health = match node.connected {
+                true => "online",
+                false => "fail",
+            };
+            let offset = match index {
+                MYSELF => offset,
+                _ => 0,
+            };
+            let node = Value::Map(vec![
+                (bulk("id"), bulk(&node.id)),
+                (bulk("port"), Value::Integer(node.port as i64)),
+                (bulk("ip"), bulk(&node.host)),
+                (bulk("endpoint"), bulk(&node.host)),
+                (bulk("role"), bulk("master")),
+                (bulk("replication-offset"), Value::Integer(offset as i64)),
+                (bulk("health"), bulk(health)),
+            ]);
+            Value::Map(vec![
+                (bulk("slots"), Value::Array(slots.collect())),
+                (bulk("nodes"), Value::Array(vec![node])),
+            ])
+        });
+        Value::Array(shards.collect())
+    }
+
+    /// `CLUSTER NODES`: a line per node, in the format of Redis's
+    /// `nodes.conf`. Nodes talk over the port clients use, so that is the
+    /// bus port listed too.
+    pub fn nodes(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let ranges = state.ranges();
+        let mut lines = String::new();
+        for (index, node) in state.nodes.iter().enumerate() {
+            let flags = match index {
+                MYSELF => "myself,master",
+                _ => "master",
+            };
+            let link = match node.connected {
+                true => "connected",
+                false => "disconnected",
+            };
+            lines.push_str(&format!(
+                "{} {}:{}@{} {flags} - 0 {} {} {link}",
+                node.id, node.host, node.port, node.port, node.seen, node.epoch
+            ));
+            for &(start, end, _) in ranges.iter().filter(|&&(_, _, owner)| owner == index) {
+                match start == end {
+                    true => lines.push_str(&format!(" {start}")),
+                    false => lines.push_str(&format!(" {start}-{end}")),
+                }
+            }
+            if index == MYSELF {
+                let mut moving: Vec<String> = state
+                    .migrating
+                    .iter()
+                    .map(|(slot, &node)| format!(" [{slot}->-{}]", state.nodes[node].id))
+                    .chain(
+                        state
+                            .importing
+                            .iter()
+                            .map(|(slot, &node)| format!(" [{slot}-<-{}]", state.nodes[node].id)),
+                    )
+                    .collect();
+                moving.sort();
+                lines.extend(moving);
+            }
+            lines.push('\n');
+        }
+        lines
+    }
+}
+
+impl Default for Cluster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    /// Each range of consecutive slots served by the same node, with the
+    /// index of the node.
+    fn ranges(&self) -> Vec<(u16, u16, usize)> {
+        let mut ranges: Vec<(u16, u16, usize)> = vec![];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let Some(owner) = *owner else { continue };
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, end, node)) if *node == owner && *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot, owner)),
+            }
+        }
+        ranges
+    }
+
+    /// The index of the node with the given ID.
+    fn node(&self, id: &str) -> Result<usize, String> {
+        self.nodes
+            .iter()
+            .position(|node| node.id == id)
+            .ok_or_else(|| format!("ERR I don't know about node {id}"))
+    }
+
+    fn knows(&self, host: &str, port: u16) -> bool {
+        let known = |(h, p): (&str, u16)| h == host && p == port;
+        self.nodes.iter().any(|node| known((&node.host, node.port)))
+            || self.meeting.iter().any(|(h, p)| known((h, *p)))
+    }
+
+    /// Whether node `a` takes over a slot node `b` claims too: the higher
+    /// config epoch wins, and the lower ID if they are the same.
+    fn beats(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (&self.nodes[a], &self.nodes[b]);
+        (a.epoch, &b.id) > (b.epoch, &a.id)
+    }
+}
+
+fn check_unique(slots: &[u16]) -> Result<(), String> {
+    let mut seen = vec![false; SLOTS as usize];
+    for &slot in slots {
+        if std::mem::replace(&mut seen[slot as usize], true) {
+            return Err(format!("ERR Slot {slot} specified multiple times"));
+        }
+    }
+    Ok(())
+}
+
+fn text(arg: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(arg).map_err(|_| invalid())
+}
+
+fn invalid() -> String {
+    String::from("ERR Invalid cluster message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(cluster: &Cluster) -> Vec<Bytes> {
+        cluster.message().into_iter().map(Bytes::from).collect()
+    }
+
+    #[test]
+    fn hash_tags_pick_the_part_of_the_key_hashed() {
+        assert_eq!(key_slot(b"123456789"), 0x31c3 % SLOTS);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+        // Empty or unclosed braces hash the whole key.
+        assert_eq!(
+            key_slot(b"foo{}{bar}"),
+            crc16::checksum(b"foo{}{bar}") % SLOTS
+        );
+        assert_eq!(key_slot(b"foo{bar"), crc16::checksum(b"foo{bar") % SLOTS);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    #[test]
+    fn higher_epochs_take_over_slots() {
+        let a = Cluster::new();
+        let b = Cluster::new();
+        a.set_address(String::from("127.0.0.1"), 7000);
+        b.set_address(String::from("127.0.0.1"), 7001);
+        a.add_slots(&[0, 1, 2, 5]).unwrap();
+        assert_eq!(
+            a.add_slots(&[2]),
+            Err(String::from("ERR Slot 2 is already busy"))
+        );
+        b.add_slots(&[3]).unwrap();
+        // Only a node meeting this one may gossip without being known.
+        assert_eq!(
+            b.receive(&message(&a), false),
+            Err(String::from("ERR Gossip from an unknown node"))
+        );
+        assert_eq!(b.owner(1), Owner::Unassigned);
+        b.receive(&message(&a), true).unwrap();
+        assert_eq!(b.owner(1), Owner::Node(String::from("127.0.0.1"), 7000));
+        assert_eq!(b.owner(3), Owner::Myself);
+        assert_eq!(b.owner(4), Owner::Unassigned);
+        assert!(b.nodes().contains(" 0-2 5\n"));
+
+        a.del_slots(&[5]).unwrap();
+        b.receive(&message(&a), false).unwrap();
+        assert_eq!(b.owner(5), Owner::Unassigned);
+
+        // Claiming the same slot, the higher epoch wins.
+        b.add_slots(&[5]).unwrap();
+        let mut message = message(&a);
+        message[3] = Bytes::from("1");
+        message[4] = Bytes::from("0-2,5-5");
+        b.receive(&message, false).unwrap();
+        assert_eq!(b.owner(5), Owner::Node(String::from("127.0.0.1"), 7000));
+    }
+}
@@ -0,0 +1,491 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use crate::frame::DEFAULT_MAX_BULK_LEN;
+use crate::glob;
+
+/// The parameters `CONFIG GET` and `CONFIG SET` know about.
+const PARAMETERS: &[&str] = &[
+    "notify-keyspace-events",
+    "dir",
+    "dbfilename",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "repl-backlog-size",
+    "proto-max-bulk-len",
+];
+
+/// Runtime settings, changed with `CONFIG SET`.
+pub struct Config {
+    keyspace_events: AtomicU32,
+    /// The directory snapshots and the append-only file are written to.
+    dir: RwLock<PathBuf>,
+    /// The name of the snapshot file within `dir`.
+    dbfilename: RwLock<String>,
+    /// Whether write commands are logged to the append-only file.
+    appendonly: AtomicBool,
+    /// The name of the append-only file within `dir`.
+    appendfilename: RwLock<String>,
+    appendfsync: AtomicU8,
+    /// How many bytes of the replication stream are kept for replicas that
+    /// reconnect.
+    repl_backlog_size: AtomicUsize,
+    /// The longest string a client may send or a command may build.
+    proto_max_bulk_len: AtomicUsize,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            keyspace_events: AtomicU32::default(),
+            dir: RwLock::new(PathBuf::from(".")),
+            dbfilename: RwLock::new(String::from("dump.rdb")),
+            appendonly: AtomicBool::new(false),
+            appendfilename: RwLock::new(String::from("appendonly.aof")),
+            appendfsync: AtomicU8::new(AppendFsync::EverySec as u8),
+            repl_backlog_size: AtomicUsize::new(1024 * 1024),
+            proto_max_bulk_len: AtomicUsize::new(DEFAULT_MAX_BULK_LEN),
+        }
+    }
+
+    /// Where the snapshot is written to and loaded from.
+    pub fn snapshot_path(&self) -> PathBuf {
+        let dir = self.dir.read().unwrap();
+        dir.join(&*self.dbfilename.read().unwrap())
+    }
+
+    /// Where write commands are logged to and replayed from.
+    pub fn appendonly_path(&self) -> PathBuf {
+        let dir = self.dir.read().unwrap();
+        dir.join(&*self.appendfilename.read().unwrap())
+    }
+
+    pub fn appendonly(&self) -> bool {
+        self.appendonly.load(Ordering::Relaxed)
+    }
+
+    pub fn appendfsync(&self) -> AppendFsync {
+        AppendFsync::ALL[self.appendfsync.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn repl_backlog_size(&self) -> usize {
+        self.repl_backlog_size.load(Ordering::Relaxed)
+    }
+
+    pub fn proto_max_bulk_len(&self) -> usize {
+        self.proto_max_bulk_len.load(Ordering::Relaxed)
+    }
+
+    /// The classes of keyspace events that are published.
+    pub fn keyspace_events(&self) -> KeyspaceEvents {
+        KeyspaceEvents(self.keyspace_events.load(Ordering::Relaxed))
+    }
+
+    /// The parameters whose name matches the glob `pattern`, with their values.
+    pub fn get(&self, pattern: &[u8]) -> Vec<(&'static str, String)> {
+        PARAMETERS
+            .iter()
+            .filter(|name| glob::matches(&pattern.to_ascii_lowercase(), name.as_bytes()))
+            .map(|&name| (name, self.value(name)))
+            .collect()
+    }
+
+    /// Changes all of the given parameters, or none of them if any name or
+    /// value is invalid.
+    pub fn set(&self, pairs: &[(&[u8], &[u8])]) -> Result<(), String> {
+        let mut changes = vec![];
+        for &(name, value) in pairs {
+            let name = name.to_ascii_lowercase();
+            match &name[..] {
+                b"notify-keyspace-events" => match KeyspaceEvents::parse(value) {
+                    Some(events) => changes.push(Change::KeyspaceEvents(events)),
+                    None => {
+                        return Err(invalid_argument(
+                            "notify-keyspace-events",
+                            "Invalid event class character. Use 'Ag$lshzxeKEtmdn'.",
+                        ))
+                    }
+                },
+                b"dir" => {
+                    let dir = Path::new(std::str::from_utf8(value).unwrap_or_default());
+                    if !dir.is_dir() {
+                        return Err(invalid_argument("dir", "No such file or directory"));
+                    }
+                    changes.push(Change::Dir(dir.to_path_buf()));
+                }
+                b"dbfilename" => match std::str::from_utf8(value) {
+                    Ok(name) if !name.is_empty() && !name.contains('/') => {
+                        changes.push(Change::DbFilename(name.to_string()))
+                    }
+                    _ => {
+                        return Err(invalid_argument(
+                            "dbfilename",
+                            "dbfilename can't be a path, just a filename",
+                        ))
+                    }
+                },
+                b"appendonly" => match parse_bool(value) {
+                    Some(on) => changes.push(Change::AppendOnly(on)),
+                    None => {
+                        return Err(invalid_argument(
+                            "appendonly",
+                            "argument must be 'yes' or 'no'",
+                        ))
+                    }
+                },
+                b"appendfilename" => match std::str::from_utf8(value) {
+                    Ok(name) if !name.is_empty() && !name.contains('/') => {
+                        changes.push(Change::AppendFilename(name.to_string()))
+                    }
+                    _ => {
+                        return Err(invalid_argument(
+                            "appendfilename",
+                            "appendfilename can't be a path, just a filename",
+                        ))
+                    }
+                },
+                b"appendfsync" => match AppendFsync::parse(value) {
+                    Some(fsync) => changes.push(Change::AppendFsync(fsync)),
+                    None => {
+                        return Err(invalid_argument(
+                            "appendfsync",
+                            "argument(s) must be one of the following: always, everysec, no",
+                        ))
+                    }
+                },
+                b"repl-backlog-size" => match parse_memory(value) {
+                    Some(size) => changes.push(Change::ReplBacklogSize(size)),
+                    None => {
+                        return Err(invalid_argument(
+                            "repl-backlog-size",
+                            "argument must be a memory value",
+                        ))
+                    }
+                },
+                b"proto-max-bulk-len" => match parse_memory(value) {
+                    Some(len) => changes.push(Change::ProtoMaxBulkLen(len)),
+                    None => {
+                        return Err(invalid_argument(
+                            "proto-max-bulk-len",
+                            "argument must be a memory value",
+                        ))
+                    }
+                },
+                _ => {
+                    return Err(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        String::from_utf8_lossy(&name)
+                    ))
+                }
+            }
+        }
+        for change in changes {
+            match change {
+                Change::KeyspaceEvents(events) => {
+                    self.keyspace_events.store(events.0, Ordering::Relaxed)
+                }
+                Change::Dir(dir) => *self.dir.write().unwrap() = dir,
+                Change::DbFilename(name) => *self.dbfilename.write().unwrap() = name,
+                Change::AppendOnly(on) => self.appendonly.store(on, Ordering::Relaxed),
+                Change::AppendFilename(name) => *self.appendfilename.write().unwrap() = name,
+                Change::AppendFsync(fsync) => {
+                    self.appendfsync.store(fsync as u8, Ordering::Relaxed)
+                }
+                Change::ReplBacklogSize(size) => {
+                    self.repl_backlog_size.store(size, Ordering::Relaxed)
+                }
+                Change::ProtoMaxBulkLen(len) => {
+                    self.proto_max_bulk_len.store(len, Ordering::Relaxed)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, name: &str) -> String {
+        match name {
+            "notify-keyspace-events" => self.keyspace_events().to_string(),
+            "dir" => self.dir.read().unwrap().display().to_string(),
+            "dbfilename" => self.dbfilename.read().unwrap().clone(),
+            "appendonly" => String::from(if self.appendonly() { "yes" } else { "no" }),
+            "appendfilename" => self.appendfilename.read().unwrap().clone(),
+            "appendfsync" => self.appendfsync().to_string(),
+            "repl-backlog-size" => self.repl_backlog_size().to_string(),
+            "proto-max-bulk-len" => self.proto_max_bulk_len().to_string(),
+            _ => unreachable!("unknown parameter {name}"),
+        }
+    }
+}
+
+/// A validated `CONFIG SET` change, applied once every pair is known to be valid.
+enum Change {
+    KeyspaceEvents(KeyspaceEvents),
+    Dir(PathBuf),
+    DbFilename(String),
+    AppendOnly(bool),
+    AppendFilename(String),
+    AppendFsync(AppendFsync),
+    ReplBacklogSize(usize),
+    ProtoMaxBulkLen(usize),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn invalid_argument(name: &str, reason: &str) -> String {
+    format!("ERR CONFIG SET failed (possibly related to argument '{name}') - {reason}")
+}
+
+fn parse_bool(value: &[u8]) -> Option<bool> {
+    match &value.to_ascii_lowercase()[..] {
+        b"yes" => Some(true),
+        b"no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a number of bytes, optionally in units of `k`, `kb`, `m`, `mb`,
+/// `g` or `gb`, where the ones with a `b` are powers of 1024.
+fn parse_memory(value: &[u8]) -> Option<usize> {
+    let value = std::str::from_utf8(value).ok()?.to_ascii_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &value[digits.len()..] {
+        "" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+/// How often the append-only file is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write, before its client gets the reply.
+    Always,
+    /// Once a second, so a crash loses at most a second of writes.
+    EverySec,
+    /// Whenever the operating system sees fit.
+    No,
+}
+
+impl AppendFsync {
+    const ALL: [Self; 3] = [Self::Always, Self::EverySec, Self::No];
+
+    fn parse(value: &[u8]) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|fsync| value.eq_ignore_ascii_case(fsync.to_string().as_bytes()))
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        })
+    }
+}
+
+/// A set of keyspace event classes, written as the characters of Redis's
+/// `notify-keyspace-events` setting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeyspaceEvents(u32);
+
+impl KeyspaceEvents {
+    /// Publish to `__keyspace@0__:<key>`.
+    pub const KEYSPACE: Self = Self(1 << 0);
+    /// Publish to `__keyevent@0__:<event>`.
+    pub const KEYEVENT: Self = Self(1 << 1);
+    /// Commands that work on any type, such as `DEL` and `EXPIRE`.
+    pub const GENERIC: Self = Self(1 << 2);
+    pub const STRING: Self = Self(1 << 3);
+    pub const LIST: Self = Self(1 << 4);
+    pub const SET: Self = Self(1 << 5);
+    pub const HASH: Self = Self(1 << 6);
+    pub const ZSET: Self = Self(1 << 7);
+    /// Keys removed because their deadline passed.
+    pub const EXPIRED: Self = Self(1 << 8);
+    pub const EVICTED: Self = Self(1 << 9);
+    pub const STREAM: Self = Self(1 << 10);
+    pub const KEY_MISS: Self = Self(1 << 11);
+    pub const MODULE: Self = Self(1 << 12);
+    pub const NEW: Self = Self(1 << 13);
+
+    /// What `A` stands for: every class except key misses and new keys.
+    const ALL: Self = Self(
+        Self::GENERIC.0
+            | Self::STRING.0
+            | Self::LIST.0
+            | Self::SET.0
+            | Self::HASH.0
+            | Self::ZSET.0
+            | Self::EXPIRED.0
+            | Self::EVICTED.0
+            | Self::STREAM.0
+            | Self::MODULE.0,
+    );
+
+    /// The classes and their characters, in the order Redis prints them.
+    const CLASSES: [(char, Self); 10] = [
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('d', Self::MODULE),
+    ];
+
+    pub fn parse(flags: &[u8]) -> Option<Self> {
+        let mut events = Self::default();
+        for flag in flags {
+            let class = match flag {
+                b'A' => Self::ALL,
+                b'K' => Self::KEYSPACE,
+                b'E' => Self::KEYEVENT,
+                b'm' => Self::KEY_MISS,
+                b'n' => Self::NEW,
+                flag => Self::CLASSES
+                    .iter()
+                    .find(|(c, _)| *c as u8 == *flag)
+                    .map(|(_, class)| *class)?,
+            };
+            events.0 |= class.0;
+        }
+        Some(events)
+    }
+
+    /// Whether any of the classes in `other` are included.
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 & Self::ALL.0 == Self::ALL.0 {
+            f.write_str("A")?;
+        } else {
+            for (c, class) in Self::CLASSES {
+                if self.intersects(class) {
+                    write!(f, "{c}")?;
+                }
+            }
+        }
+        for (c, class) in [
+            ('K', Self::KEYSPACE),
+            ('E', Self::KEYEVENT),
+            ('m', Self::KEY_MISS),
+            ('n', Self::NEW),
+        ] {
+            if self.intersects(class) {
+                write!(f, "{c}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyspace_events_round_trip() {
+        let events = KeyspaceEvents::parse(b"Ex$g").unwrap();
+        assert_eq!(events.to_string(), "g$xE");
+        assert!(events.intersects(KeyspaceEvents::EXPIRED));
+        assert!(!events.intersects(KeyspaceEvents::KEYSPACE));
+        assert_eq!(KeyspaceEvents::parse(b"gKA").unwrap().to_string(), "AK");
+        assert_eq!(KeyspaceEvents::parse(b"").unwrap().to_string(), "");
+        assert_eq!(KeyspaceEvents::parse(b"Kq"), None);
+    }
+
+    #[test]
+    fn set_applies_all_pairs_or_none() {
+        let config = Config::new();
+        let err = config.set(&[
+            (b"notify-keyspace-events", b"KEA"),
+            (b"no-such-parameter", b"1"),
+        ]);
+        assert!(err.is_err());
+        assert_eq!(config.keyspace_events(), KeyspaceEvents::default());
+        config.set(&[(b"NOTIFY-keyspace-events", b"Kx")]).unwrap();
+        assert_eq!(
+            config.get(b"notify-*"),
+            vec![("notify-keyspace-events", String::from("xK"))]
+        );
+        assert!(config.get(b"maxmemory").is_empty());
+    }
+
+    #[test]
+    fn snapshot_path_joins_dir_and_dbfilename() {
+        let config = Config::new();
+        assert_eq!(config.snapshot_path(), Path::new("./dump.rdb"));
+        assert!(config.set(&[(b"dbfilename", b"dir/backup.rdb")]).is_err());
+        assert!(config.set(&[(b"dir", b"/no/such/directory")]).is_err());
+        config
+            .set(&[(b"dir", b"/tmp"), (b"dbfilename", b"backup.rdb")])
+            .unwrap();
+        assert_eq!(config.snapshot_path(), Path::new("/tmp/backup.rdb"));
+        assert_eq!(config.get(b"dir"), vec![("dir", String::from("/tmp"))]);
+    }
+
+    #[test]
+    fn appendonly_parameters() {
+        let config = Config::new();
+        assert!(!config.appendonly());
+        assert_eq!(config.appendfsync(), AppendFsync::EverySec);
+        config
+            .set(&[(b"appendonly", b"YES"), (b"appendfsync", b"always")])
+            .unwrap();
+        assert!(config.appendonly());
+        assert_eq!(config.appendfsync(), AppendFsync::Always);
+        assert!(config.set(&[(b"appendonly", b"maybe")]).is_err());
+        assert!(config.set(&[(b"appendfsync", b"hourly")]).is_err());
+        assert_eq!(
+            config.get(b"append*"),
+            vec![
+                ("appendonly", String::from("yes")),
+                ("appendfilename", String::from("appendonly.aof")),
+                ("appendfsync", String::from("always")),
+            ]
+        );
+        assert_eq!(config.appendonly_path(), Path::new("./appendonly.aof"));
+    }
+
+    #[test]
+    fn memory_values() {
+        let config = Config::new();
+        assert_eq!(config.repl_backlog_size(), 1024 * 1024);
+        config.set(&[(b"repl-backlog-size", b"64KB")]).unwrap();
+        assert_eq!(config.repl_backlog_size(), 64 * 1024);
+        config.set(&[(b"repl-backlog-size", b"2m")]).unwrap();
+        assert_eq!(
+            config.get(b"repl-*"),
+            vec![("repl-backlog-size", String::from("2000000"))]
+        );
+        for value in [&b"lots"[..], b"1tb", b"-1", b"kb"] {
+            assert!(config.set(&[(b"repl-backlog-size", value)]).is_err());
+        }
+        assert_eq!(config.proto_max_bulk_len(), 512 * 1024 * 1024);
+        config.set(&[(b"proto-max-bulk-len", b"1mb")]).unwrap();
+        assert_eq!(config.proto_max_bulk_len(), 1024 * 1024);
+    }
+}
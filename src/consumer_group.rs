@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use bytes::Bytes;
+
+use crate::stream::StreamId;
+
+/// An entry delivered to a consumer that has not acknowledged it yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingEntry {
+    pub consumer: Bytes,
+    /// When the entry was last delivered, in Unix milliseconds.
+    pub delivered_at: i64,
+    pub deliveries: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Consumer {
+    /// When the consumer last tried to read or claim entries, in Unix milliseconds.
+    pub seen_at: i64,
+    /// When the consumer last got any entries, if it ever did.
+    pub active_at: Option<i64>,
+    pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    /// The IDs of the entries pending for this consumer.
+    pub fn pending(&self) -> &BTreeSet<StreamId> {
+        &self.pending
+    }
+}
+
+/// A group of consumers sharing the entries of a stream: each entry is
+/// delivered to one of them, and stays pending for that consumer until it
+/// acknowledges it or another consumer claims it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsumerGroup {
+    /// The greatest ID delivered to the group so far.
+    pub last_delivered: StreamId,
+    /// How many entries of the stream the group has read, if known.
+    pub entries_read: Option<u64>,
+    /// The pending entries of all consumers, by ID.
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<Bytes, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId, entries_read: Option<u64>) -> Self {
+        Self {
+            last_delivered,
+            entries_read,
+            ..Self::default()
+        }
+    }
+
+    pub fn pending(&self) -> &BTreeMap<StreamId, PendingEntry> {
+        &self.pending
+    }
+
+    pub fn consumers(&self) -> &BTreeMap<Bytes, Consumer> {
+        &self.consumers
+    }
+
+    pub fn consumer_mut(&mut self, name: &[u8]) -> Option<&mut Consumer> {
+        self.consumers.get_mut(name)
+    }
+
+    /// Records that `name` tried to read or claim entries at `now`, creating
+    /// the consumer if needed. Returns whether it was created.
+    pub fn touch_consumer(&mut self, name: &Bytes, now: i64) -> bool {
+        let created = !self.consumers.contains_key(name);
+        self.consumers.entry(name.clone()).or_default().seen_at = now;
+        created
+    }
+
+    /// Creates a consumer, returning whether it did not exist yet.
+    pub fn create_consumer(&mut self, name: &Bytes, now: i64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.touch_consumer(name, now)
+    }
+
+    /// Deletes a consumer along with its pending entries, returning how many
+    /// entries were pending for it.
+    pub fn delete_consumer(&mut self, name: &[u8]) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    /// Makes `id` pending for `consumer`, taking it over from the consumer it
+    /// was pending for, if any.
+    pub fn assign(&mut self, id: StreamId, consumer: &Bytes, delivered_at: i64, deliveries: u64) {
+        let entry = PendingEntry {
+            consumer: consumer.clone(),
+            delivered_at,
+            deliveries,
+        };
+        if let Some(previous) = self.pending.insert(id, entry) {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        let owner = self.consumers.entry(consumer.clone()).or_default();
+        owner.pending.insert(id);
+    }
+
+    /// Records another delivery of a pending entry at `now`.
+    pub fn redeliver(&mut self, id: StreamId, now: i64) {
+        if let Some(entry) = self.pending.get_mut(&id) {
+            entry.delivered_at = now;
+            entry.deliveries += 1;
+        }
+    }
+
+    /// Acknowledges an entry, returning whether it was pending.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(owner) = self.consumers.get_mut(&entry.consumer) {
+            owner.pending.remove(&id);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_entries_follow_their_owner() {
+        let (alice, bob) = (Bytes::from_static(b"alice"), Bytes::from_static(b"bob"));
+        let mut group = ConsumerGroup::new(StreamId::MIN, Some(0));
+        assert!(group.touch_consumer(&alice, 1));
+        assert!(!group.create_consumer(&alice, 2));
+        group.assign(StreamId::new(1, 0), &alice, 10, 1);
+        group.assign(StreamId::new(2, 0), &alice, 10, 1);
+        group.assign(StreamId::new(2, 0), &bob, 20, 2);
+        assert_eq!(group.consumers()[&alice].pending().len(), 1);
+        assert_eq!(group.pending()[&StreamId::new(2, 0)].delivered_at, 20);
+        assert_eq!(group.pending()[&StreamId::new(2, 0)].consumer, bob);
+
+        group.redeliver(StreamId::new(1, 0), 30);
+        assert_eq!(group.pending()[&StreamId::new(1, 0)].deliveries, 2);
+        assert!(group.ack(StreamId::new(1, 0)));
+        assert!(!group.ack(StreamId::new(1, 0)));
+        assert!(group.consumers()[&alice].pending().is_empty());
+
+        assert_eq!(group.delete_consumer(b"bob"), Some(1));
+        assert_eq!(group.delete_consumer(b"bob"), None);
+        assert!(group.pending().is_empty());
+    }
+}
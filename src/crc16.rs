@@ -0,0 +1,42 @@
+/// The CCITT polynomial, in the unreflected CRC-16 variant (XMODEM) Redis
+/// maps keys to cluster slots with.
+const POLYNOMIAL: u16 = 0x1021;
+
+const TABLE: [u16; 256] = table();
+
+const fn table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = (byte as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ POLYNOMIAL,
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// The checksum of `bytes`, which is 0 for no bytes.
+pub fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, byte| {
+        TABLE[((crc >> 8) ^ *byte as u16) as usize] ^ (crc << 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_xmodem_check_value() {
+        assert_eq!(checksum(b"123456789"), 0x31c3);
+        assert_eq!(checksum(b""), 0);
+    }
+}
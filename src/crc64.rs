@@ -0,0 +1,49 @@
+/// The reflected form of the Jones polynomial `0xad93d23594c935a9`, the
+/// CRC-64 variant Redis checksums RDB files with.
+const POLYNOMIAL: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const TABLE: [u64; 256] = table();
+
+const fn table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ POLYNOMIAL,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// Continues the checksum `crc` of some bytes over `bytes` that follow them.
+/// The checksum of no bytes is 0.
+pub fn update(crc: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(crc, |crc, byte| {
+        TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+pub fn checksum(bytes: &[u8]) -> u64 {
+    update(0, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_redis_check_value() {
+        assert_eq!(checksum(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+        let crc = update(checksum(b"1234"), b"56789");
+        assert_eq!(crc, checksum(b"123456789"));
+        assert_eq!(checksum(b""), 0);
+    }
+}
@@ -1,20 +1,22 @@
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Default)]
 pub enum DataFrame<T> {
+    #[default]
     Empty,
     Plain(T),
     Expiring {
         data: T,
-        expiration: Duration,
-        timestamp: Instant,
+        /// Unix time in milliseconds at which the value expires.
+        deadline: i64,
     },
 }
 
-impl<T> Default for DataFrame<T> {
-    fn default() -> Self {
-        DataFrame::Empty
-    }
+/// The current Unix time in milliseconds, the clock deadlines are kept in.
+pub fn unix_time_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as i64)
 }
 
 impl<T> DataFrame<T> {
@@ -23,29 +25,57 @@ impl<T> DataFrame<T> {
     }
 
     pub fn with_expiration(data: T, expiration: Duration) -> Self {
-        Self::Expiring {
-            data,
-            expiration,
-            timestamp: Instant::now(),
-        }
+        let expiration = i64::try_from(expiration.as_millis()).unwrap_or(i64::MAX);
+        Self::with_deadline(data, unix_time_millis().saturating_add(expiration))
+    }
+
+    pub fn with_deadline(data: T, deadline: i64) -> Self {
+        Self::Expiring { data, deadline }
     }
 }
 
 impl<T> DataFrame<T> {
-    pub fn has_expired(&self) -> bool {
-        if let Self::Expiring {
-            data: _,
-            expiration,
-            timestamp,
-        } = &self
-        {
-            if &timestamp.elapsed() >= expiration {
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+    pub fn data(&self) -> Option<&T> {
+        match self {
+            Self::Empty => None,
+            Self::Plain(data) | Self::Expiring { data, .. } => Some(data),
         }
     }
+
+    pub fn data_mut(&mut self) -> Option<&mut T> {
+        match self {
+            Self::Empty => None,
+            Self::Plain(data) | Self::Expiring { data, .. } => Some(data),
+        }
+    }
+
+    pub fn into_data(self) -> Option<T> {
+        match self {
+            Self::Empty => None,
+            Self::Plain(data) | Self::Expiring { data, .. } => Some(data),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<i64> {
+        match self {
+            Self::Expiring { deadline, .. } => Some(*deadline),
+            _ => None,
+        }
+    }
+
+    /// Sets or, given `None`, removes the deadline, keeping the data.
+    pub fn set_deadline(&mut self, deadline: Option<i64>) {
+        *self = match (std::mem::take(self), deadline) {
+            (Self::Empty, _) => Self::Empty,
+            (Self::Plain(data) | Self::Expiring { data, .. }, Some(deadline)) => {
+                Self::Expiring { data, deadline }
+            }
+            (Self::Plain(data) | Self::Expiring { data, .. }, None) => Self::Plain(data),
+        };
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.deadline()
+            .is_some_and(|deadline| deadline <= unix_time_millis())
+    }
 }
@@ -0,0 +1,125 @@
+/// Adds `a` and `b` and formats the sum the way Redis replies to
+/// `INCRBYFLOAT` and `HINCRBYFLOAT`: rounded to 17 digits after the point,
+/// without trailing zeros.
+///
+/// Redis adds in long double precision, so `0.1` and `0.2` sum to `0.3`
+/// rather than to the double nearest it, `0.30000000000000004`. To match,
+/// the operands are taken as the shortest decimals that parse back to them,
+/// which is how they were written for up to 15 significant digits, and
+/// added exactly. Only sums of operands too far apart in magnitude for that
+/// fall back to the sum of the doubles.
+pub fn add(a: f64, b: f64) -> String {
+    Decimal::shortest(a)
+        .checked_add(Decimal::shortest(b))
+        .unwrap_or_else(|| Decimal::shortest(a + b))
+        .to_fixed(17)
+}
+
+/// The number `mantissa * 10^exponent`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Decimal {
+    mantissa: i128,
+    exponent: i32,
+}
+
+impl Decimal {
+    /// The shortest decimal that parses back to the finite `float`.
+    fn shortest(float: f64) -> Self {
+        // `{:e}` writes the shortest round-tripping digits, like `-1.25e-3`.
+        let text = format!("{float:e}");
+        let (digits, exponent) = text.split_once('e').unwrap();
+        let fraction = digits.split_once('.').map_or(0, |(_, fraction)| fraction.len());
+        Self {
+            mantissa: digits.replace('.', "").parse().unwrap(),
+            exponent: exponent.parse::<i32>().unwrap() - fraction as i32,
+        }
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        let (high, low) = if self.exponent >= other.exponent {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let scale = 10i128.checked_pow((high.exponent - low.exponent) as u32)?;
+        let mantissa = high.mantissa.checked_mul(scale)?.checked_add(low.mantissa)?;
+        Some(Self {
+            mantissa,
+            exponent: low.exponent,
+        })
+    }
+
+    /// Formats the number like `printf("%.*f")` with `places` digits after
+    /// the point, rounding half to even, then drops trailing zeros and the
+    /// point if nothing is left after it.
+    fn to_fixed(self, places: i32) -> String {
+        let mut magnitude = self.mantissa.unsigned_abs();
+        let mut exponent = self.exponent;
+        if exponent < -places {
+            // Dropping more digits than a u128 holds leaves less than half
+            // of the last place kept.
+            magnitude = match 10u128.checked_pow((-places - exponent) as u32) {
+                None => 0,
+                Some(scale) => {
+                    let (kept, dropped) = (magnitude / scale, magnitude % scale);
+                    let half = scale / 2;
+                    kept + u128::from(dropped > half || dropped == half && kept % 2 == 1)
+                }
+            };
+            exponent = -places;
+        }
+        while exponent < 0 && magnitude != 0 {
+            let (rest, last) = (magnitude / 10, magnitude % 10);
+            if last != 0 {
+                break;
+            }
+            magnitude = rest;
+            exponent += 1;
+        }
+        if magnitude == 0 {
+            return String::from("0");
+        }
+        let digits = magnitude.to_string();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        if exponent >= 0 {
+            return format!("{sign}{digits}{}", "0".repeat(exponent as usize));
+        }
+        let point = digits.len() as i32 + exponent;
+        if point > 0 {
+            let (whole, fraction) = digits.split_at(point as usize);
+            format!("{sign}{whole}.{fraction}")
+        } else {
+            format!("{sign}0.{}{digits}", "0".repeat(-point as usize))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_decimals_without_binary_noise() {
+        assert_eq!(add(0.2, 0.1), "0.3");
+        assert_eq!(add(10.5, 0.1), "10.6");
+        assert_eq!(add(5.0e3, 2.0e2), "5200");
+        assert_eq!(add(1.1, -1.1), "0");
+        assert_eq!(add(-0.5, -0.25), "-0.75");
+        assert_eq!(add(0.30000000000000004, 0.0), "0.30000000000000004");
+    }
+
+    #[test]
+    fn rounds_to_seventeen_places() {
+        assert_eq!(add(1e-17, 0.0), "0.00000000000000001");
+        assert_eq!(add(1e-18, 0.0), "0");
+        assert_eq!(add(5e-18, 0.0), "0");
+        assert_eq!(add(1.5e-17, 0.0), "0.00000000000000002");
+        assert_eq!(add(-1e-20, 0.0), "0");
+    }
+
+    #[test]
+    fn writes_large_sums_without_an_exponent() {
+        assert_eq!(add(1e17, 0.0), "100000000000000000");
+        assert_eq!(add(1e300, 1e-300), format!("1{}", "0".repeat(300)));
+    }
+}
@@ -0,0 +1,404 @@
+use std::io::{self, Cursor};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::parse::RedisParser;
+use crate::value::Value;
+
+const INITIAL_CAPACITY: usize = 4096;
+/// The longest bulk string a frame may hold by default, as Redis's default
+/// `proto-max-bulk-len`.
+pub const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// The most bytes a single frame may take, as Redis's default
+/// `client-query-buffer-limit`.
+const MAX_FRAME_LEN: usize = 1024 * 1024 * 1024;
+/// The longest type and length line a frame may start an element with.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Per-connection buffer that collects bytes across reads and splits them
+/// into complete RESP frames.
+///
+/// A frame that arrives in pieces stays in the buffer until the rest of it
+/// is read, and several pipelined frames that arrive in one read are
+/// returned one by one, in order. A frame is only parsed once it is known
+/// to be complete, and what was checked of a partial frame is not gone over
+/// again as the rest of it arrives.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+    max_bulk_len: usize,
+    /// How far into the buffer the frame being read is known to be complete.
+    scanned: usize,
+    /// How many more elements each aggregate the frame being read is nested
+    /// in has, outermost first. Empty between frames.
+    pending: Vec<usize>,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_bulk_len(DEFAULT_MAX_BULK_LEN)
+    }
+
+    /// A decoder that rejects bulk strings longer than `max_bulk_len`.
+    pub fn with_max_bulk_len(max_bulk_len: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(INITIAL_CAPACITY),
+            max_bulk_len,
+            scanned: 0,
+            pending: vec![],
+        }
+    }
+
+    /// The buffer that reads from the connection should append to.
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Removes the next complete frame from the buffer.
+    ///
+    /// Returns `Ok(None)` if the buffer only holds part of a frame, and an
+    /// error if the buffered bytes are not valid RESP or the frame is too
+    /// big.
+    pub fn decode<P>(&mut self, parser: &P) -> Result<Option<Value>, io::Error>
+    where
+        P: for<'a> RedisParser<Cursor<&'a [u8]>>,
+    {
+        let frame = self.decode_frame(parser)?;
+        Ok(frame.map(|(value, _)| value))
+    }
+
+    /// Like `decode`, but also returns the bytes the frame was read from.
+    pub fn decode_frame<P>(&mut self, parser: &P) -> Result<Option<(Value, Bytes)>, io::Error>
+    where
+        P: for<'a> RedisParser<Cursor<&'a [u8]>>,
+    {
+        let Some(len) = self.scan()? else {
+            return Ok(None);
+        };
+        let mut cursor = Cursor::new(&self.buf[..len]);
+        match parser.parse(&mut cursor) {
+            Ok(value) => {
+                let consumed = cursor.position() as usize;
+                Ok(Some((value, self.buf.split_to(consumed).freeze())))
+            }
+            Err(_) => Err(invalid_data("invalid frame")),
+        }
+    }
+
+    /// Checks whether the buffer starts with a complete frame, carrying on
+    /// from where the last call stopped, and returns its length if so.
+    ///
+    /// Only the type and length of each element are looked at, which is
+    /// enough to tell where the frame ends; the parser checks the rest.
+    fn scan(&mut self) -> Result<Option<usize>, io::Error> {
+        if self.pending.is_empty() {
+            self.scanned = 0;
+            self.pending.push(1);
+        }
+        while let Some(&left) = self.pending.last() {
+            if left == 0 {
+                self.pending.pop();
+                continue;
+            }
+            let rest = &self.buf[self.scanned..];
+            let Some(newline) = rest
+                .iter()
+                .take(MAX_LINE_LEN)
+                .position(|&byte| byte == b'\n')
+            else {
+                if rest.len() >= MAX_LINE_LEN {
+                    return Err(invalid_data("too big line"));
+                }
+                return Ok(None);
+            };
+            let line = &rest[..newline];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let Some((&kind, arg)) = line.split_first() else {
+                return Err(invalid_data("empty line"));
+            };
+            let mut end = self.scanned + newline + 1;
+            let mut nested = 0;
+            match kind {
+                b'$' | b'=' => {
+                    let len = parse_len(arg).ok_or_else(|| invalid_data("invalid bulk length"))?;
+                    if let Ok(len) = usize::try_from(len) {
+                        if len > self.max_bulk_len {
+                            return Err(invalid_data("invalid bulk length"));
+                        }
+                        end += len + 2;
+                    }
+                }
+                b'*' | b'~' | b'>' | b'%' | b'|' => {
+                    let len =
+                        parse_len(arg).ok_or_else(|| invalid_data("invalid multibulk length"))?;
+                    nested = usize::try_from(len).unwrap_or(0);
+                    if matches!(kind, b'%' | b'|') {
+                        nested *= 2;
+                    }
+                }
+                b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => {}
+                _ => {
+                    return Err(invalid_data(&format!(
+                        "unexpected '{}'",
+                        kind.escape_ascii()
+                    )))
+                }
+            }
+            if end > MAX_FRAME_LEN {
+                return Err(invalid_data("frame too big"));
+            }
+            if self.buf.len() < end {
+                return Ok(None);
+            }
+            self.scanned = end;
+            // Attributes come on top of the element they describe.
+            if kind != b'|' {
+                *self.pending.last_mut().unwrap() -= 1;
+            }
+            if nested > 0 {
+                self.pending.push(nested);
+            }
+        }
+        Ok(Some(self.scanned))
+    }
+
+    /// Removes a `$<length>\r\n` payload that, unlike a bulk string, does
+    /// not end in `\r\n`, the way a primary sends its snapshot.
+    pub fn decode_payload(&mut self) -> Result<Option<Bytes>, io::Error> {
+        let Some(end) = self.buf.iter().position(|&byte| byte == b'\n') else {
+            return Ok(None);
+        };
+        let len = self.buf[..end]
+            .strip_prefix(b"$")
+            .and_then(|line| line.strip_suffix(b"\r"))
+            .and_then(|len| std::str::from_utf8(len).ok())
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| invalid_data("invalid payload"))?;
+        if self.buf.len() < end + 1 + len {
+            self.buf.reserve(end + 1 + len - self.buf.len());
+            return Ok(None);
+        }
+        self.buf.advance(end + 1);
+        self.pending.clear();
+        Ok(Some(self.buf.split_to(len).freeze()))
+    }
+}
+
+/// An aggregate's number of elements or a bulk string's length, where -1
+/// stands for null.
+fn parse_len(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg)
+        .ok()?
+        .parse::<i64>()
+        .ok()
+        .filter(|&len| len >= -1 && len <= i32::MAX as i64)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::parse::RespParser;
+    use crate::value::Value::*;
+
+    fn command(args: &[&str]) -> Value {
+        Array(
+            args.iter()
+                .map(|arg| BulkString(Bytes::from(arg.to_string())))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn decode_empty_buffer() {
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.decode(&RespParser::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_byte_at_a_time() {
+        let parser = RespParser::new();
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let mut decoder = FrameDecoder::new();
+        for &byte in &input[..input.len() - 1] {
+            decoder.extend_from_slice(&[byte]);
+            assert_eq!(decoder.decode(&parser).unwrap(), None);
+        }
+        decoder.extend_from_slice(&input[input.len() - 1..]);
+        assert_eq!(
+            decoder.decode(&parser).unwrap(),
+            Some(command(&["SET", "key", "value"]))
+        );
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn decode_pipelined_commands() {
+        let parser = RespParser::new();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend_from_slice(
+            b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+        );
+        assert_eq!(decoder.decode(&parser).unwrap(), Some(command(&["PING"])));
+        assert_eq!(
+            decoder.decode(&parser).unwrap(),
+            Some(command(&["ECHO", "hi"]))
+        );
+        assert_eq!(
+            decoder.decode(&parser).unwrap(),
+            Some(command(&["GET", "k"]))
+        );
+        assert_eq!(decoder.decode(&parser).unwrap(), None);
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn decode_keeps_partial_trailing_frame() {
+        let parser = RespParser::new();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend_from_slice(b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$5\r\nhel");
+        assert_eq!(decoder.decode(&parser).unwrap(), Some(command(&["PING"])));
+        assert_eq!(decoder.decode(&parser).unwrap(), None);
+        decoder.extend_from_slice(b"lo\r\n");
+        assert_eq!(
+            decoder.decode(&parser).unwrap(),
+            Some(command(&["ECHO", "hello"]))
+        );
+    }
+
+    #[test]
+    fn decode_large_bulk_string() {
+        let parser = RespParser::new();
+        let payload = "x".repeat(10_000);
+        let input = format!("*2\r\n$4\r\nECHO\r\n${}\r\n{}\r\n", payload.len(), payload);
+        let mut decoder = FrameDecoder::new();
+        for chunk in input.as_bytes().chunks(512) {
+            assert_eq!(decoder.decode(&parser).unwrap(), None);
+            decoder.extend_from_slice(chunk);
+        }
+        assert_eq!(
+            decoder.decode(&parser).unwrap(),
+            Some(command(&["ECHO", &payload]))
+        );
+    }
+
+    /// Counts how many times frames are parsed.
+    struct CountingParser(AtomicUsize);
+
+    impl<'a> RedisParser<Cursor<&'a [u8]>> for CountingParser {
+        fn parse(&self, input: &mut Cursor<&'a [u8]>) -> Result<Value, io::Error> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            RespParser::new().parse(input)
+        }
+    }
+
+    #[test]
+    fn decode_parses_a_frame_once_it_is_complete() {
+        let parser = CountingParser(AtomicUsize::new(0));
+        let payload = "x".repeat(100_000);
+        let input = format!("*2\r\n$4\r\nECHO\r\n${}\r\n{}\r\n", payload.len(), payload);
+        let mut decoder = FrameDecoder::new();
+        for chunk in input.as_bytes().chunks(100) {
+            assert_eq!(decoder.decode(&parser).unwrap(), None);
+            decoder.extend_from_slice(chunk);
+        }
+        assert_eq!(
+            decoder.decode(&parser).unwrap(),
+            Some(command(&["ECHO", &payload]))
+        );
+        assert_eq!(parser.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn decode_nested_frames() {
+        let parser = RespParser::new();
+        let mut decoder = FrameDecoder::new();
+        let input = b"*3\r\n%1\r\n+a\r\n*-1\r\n|1\r\n+ttl\r\n:1\r\n$-1\r\n*0\r\n";
+        for &byte in &input[..input.len() - 4] {
+            decoder.extend_from_slice(&[byte]);
+            assert_eq!(decoder.decode(&parser).unwrap(), None);
+        }
+        decoder.extend_from_slice(&input[input.len() - 4..]);
+        assert_eq!(
+            decoder.decode(&parser).unwrap(),
+            Some(Array(vec![
+                Map(vec![(SimpleString(String::from("a")), NullArray)]),
+                Attribute(
+                    vec![(SimpleString(String::from("ttl")), Integer(1))],
+                    Box::new(NullBulkString)
+                ),
+                Array(vec![]),
+            ]))
+        );
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_bulk_strings_over_the_limit() {
+        let parser = RespParser::new();
+        let mut decoder = FrameDecoder::with_max_bulk_len(5);
+        decoder.extend_from_slice(b"*1\r\n$5\r\nhello\r\n");
+        assert_eq!(decoder.decode(&parser).unwrap(), Some(command(&["hello"])));
+        decoder.extend_from_slice(b"*1\r\n$6\r\n");
+        let err = decoder.decode(&parser).unwrap_err();
+        assert_eq!(err.to_string(), "invalid bulk length");
+        let mut decoder = FrameDecoder::new();
+        decoder.extend_from_slice(b"*-2\r\n");
+        let err = decoder.decode(&parser).unwrap_err();
+        assert_eq!(err.to_string(), "invalid multibulk length");
+    }
+
+    #[test]
+    fn decode_invalid_frame() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend_from_slice(b"!bogus\r\n");
+        assert!(decoder.decode(&RespParser::new()).is_err());
+    }
+
+    #[test]
+    fn decode_frames_with_their_bytes_and_payloads() {
+        let parser = RespParser::new();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend_from_slice(b"+FULLRESYNC\r\n$5\r\nREDIS*1\r\n$4\r\nPING\r\n");
+        assert_eq!(
+            decoder.decode_frame(&parser).unwrap(),
+            Some((
+                SimpleString(String::from("FULLRESYNC")),
+                Bytes::from("+FULLRESYNC\r\n")
+            ))
+        );
+        assert_eq!(
+            decoder.decode_payload().unwrap(),
+            Some(Bytes::from("REDIS"))
+        );
+        assert_eq!(
+            decoder.decode_frame(&parser).unwrap(),
+            Some((command(&["PING"]), Bytes::from("*1\r\n$4\r\nPING\r\n")))
+        );
+        decoder.extend_from_slice(b"$3\r\nab");
+        assert_eq!(decoder.decode_payload().unwrap(), None);
+        decoder.extend_from_slice(b"c");
+        assert_eq!(decoder.decode_payload().unwrap(), Some(Bytes::from("abc")));
+        decoder.extend_from_slice(b"+OK\r\n");
+        assert!(decoder.decode_payload().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+/// Matches `string` against a Redis glob-style `pattern`.
+///
+/// Supports `*` (any sequence), `?` (any byte), character classes like
+/// `[abc]`, `[a-z]` and `[^x]`, and `\` to escape the next byte. As in
+/// Redis, an unterminated class runs to the end of the pattern.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match: the
+    // pattern right after the star, and the next byte the star could absorb.
+    let mut backtrack = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, s + 1));
+            continue;
+        }
+        match match_one(pattern, p, string[s]) {
+            Some((true, next)) => {
+                p = next;
+                s += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_s)) => {
+                    p = star_p;
+                    s = star_s;
+                    backtrack = Some((star_p, star_s + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|&b| b == b'*')
+}
+
+/// Matches `byte` against the pattern element at `p`, returning whether it
+/// matched and where the next element starts, or `None` at the pattern's end.
+fn match_one(pattern: &[u8], p: usize, byte: u8) -> Option<(bool, usize)> {
+    match *pattern.get(p)? {
+        b'?' => Some((true, p + 1)),
+        b'[' => Some(match_class(pattern, p + 1, byte)),
+        b'\\' if p + 1 < pattern.len() => Some((pattern[p + 1] == byte, p + 2)),
+        literal => Some((literal == byte, p + 1)),
+    }
+}
+
+/// Matches `byte` against the class whose body starts at `p`, just after `[`.
+fn match_class(pattern: &[u8], mut p: usize, byte: u8) -> (bool, usize) {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == byte;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = (
+                pattern[p].min(pattern[p + 2]),
+                pattern[p].max(pattern[p + 2]),
+            );
+            matched |= (low..=high).contains(&byte);
+            p += 3;
+        } else {
+            matched |= pattern[p] == byte;
+            p += 1;
+        }
+    }
+    // Skip the closing bracket, if there is one.
+    (matched != negate, (p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, string: &str) -> bool {
+        matches(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(check("*", ""));
+        assert!(check("*", "anything"));
+        assert!(check("h?llo", "hello"));
+        assert!(!check("h?llo", "hllo"));
+        assert!(check("h*llo", "hllo"));
+        assert!(check("h*llo", "heeeello"));
+        assert!(check("*llo*", "hello world"));
+        assert!(check("a*b*c", "aXbYbZc"));
+        assert!(!check("a*b*c", "aXbYbZ"));
+        assert!(check("**a", "ba"));
+        assert!(!check("hello", "hello!"));
+        assert!(!check("", "a"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(check("h[ae]llo", "hello"));
+        assert!(check("h[ae]llo", "hallo"));
+        assert!(!check("h[ae]llo", "hillo"));
+        assert!(check("h[^e]llo", "hallo"));
+        assert!(!check("h[^e]llo", "hello"));
+        assert!(check("h[a-b]llo", "hbllo"));
+        assert!(check("h[b-a]llo", "hallo"));
+        assert!(!check("h[a-b]llo", "hcllo"));
+        assert!(check("[a-]", "-"));
+        assert!(check("[\\]]", "]"));
+        assert!(check("a[bc", "ab"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(check("h\\*llo", "h*llo"));
+        assert!(!check("h\\*llo", "hello"));
+        assert!(check("\\?", "?"));
+        assert!(!check("\\?", "a"));
+        assert!(check("a\\", "a\\"));
+    }
+}
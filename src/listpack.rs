@@ -0,0 +1,214 @@
+use bytes::Bytes;
+
+/// The byte that ends every listpack.
+const END: u8 = 0xff;
+/// The total size and the element count that start every listpack.
+const HEADER_SIZE: usize = 6;
+
+/// An element of a listpack, Redis's compact encoding of a list of small
+/// strings and integers, which RDB files store several value types in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    Int(i64),
+    String(Bytes),
+}
+
+impl Element {
+    /// The element as a string, with integers written in decimal.
+    pub fn into_bytes(self) -> Bytes {
+        match self {
+            Self::Int(int) => Bytes::from(int.to_string()),
+            Self::String(string) => string,
+        }
+    }
+
+    /// The element as an integer, if it is one or is a string spelling one.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(int) => Some(*int),
+            Self::String(string) => std::str::from_utf8(string).ok()?.parse().ok(),
+        }
+    }
+}
+
+/// Packs `elements` into a listpack, each in the smallest encoding that
+/// fits it.
+pub fn encode(elements: &[Element]) -> Vec<u8> {
+    let mut listpack = vec![0; HEADER_SIZE];
+    let mut entry = vec![];
+    for element in elements {
+        entry.clear();
+        match element {
+            Element::Int(int) => encode_int(*int, &mut entry),
+            Element::String(string) => {
+                let len = string.len();
+                match len {
+                    0..=63 => entry.push(0x80 | len as u8),
+                    64..=4095 => entry.extend([0xe0 | (len >> 8) as u8, len as u8]),
+                    _ => {
+                        entry.push(0xf0);
+                        entry.extend((len as u32).to_le_bytes());
+                    }
+                }
+                entry.extend_from_slice(string);
+            }
+        }
+        listpack.extend_from_slice(&entry);
+        encode_backlen(entry.len(), &mut listpack);
+    }
+    listpack.push(END);
+    let size = listpack.len() as u32;
+    // Listpacks of more than 65534 elements leave their count to be counted.
+    let count = u16::try_from(elements.len()).unwrap_or(u16::MAX);
+    listpack[..4].copy_from_slice(&size.to_le_bytes());
+    listpack[4..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+    listpack
+}
+
+fn encode_int(int: i64, entry: &mut Vec<u8>) {
+    match int {
+        0..=127 => entry.push(int as u8),
+        -4096..=4095 => {
+            let int = int as u16 & 0x1fff;
+            entry.extend([0xc0 | (int >> 8) as u8, int as u8]);
+        }
+        _ if i16::try_from(int).is_ok() => {
+            entry.push(0xf1);
+            entry.extend((int as i16).to_le_bytes());
+        }
+        -0x80_0000..=0x7f_ffff => {
+            entry.push(0xf2);
+            entry.extend(&(int as i32).to_le_bytes()[..3]);
+        }
+        _ if i32::try_from(int).is_ok() => {
+            entry.push(0xf3);
+            entry.extend((int as i32).to_le_bytes());
+        }
+        _ => {
+            entry.push(0xf4);
+            entry.extend(int.to_le_bytes());
+        }
+    }
+}
+
+/// Appends the length of an entry written backwards, seven bits per byte,
+/// which lets a listpack be walked from its end.
+fn encode_backlen(len: usize, listpack: &mut Vec<u8>) {
+    let size = backlen_size(len);
+    for byte in (0..size).rev() {
+        let bits = (len >> (7 * byte)) as u8 & 0x7f;
+        // Every byte but the last one read going backwards flags that more follow.
+        listpack.push(if byte + 1 < size { bits | 0x80 } else { bits });
+    }
+}
+
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Unpacks the elements of a listpack, or returns `None` if it is malformed.
+pub fn decode(listpack: &[u8]) -> Option<Vec<Element>> {
+    let size = u32::from_le_bytes(listpack.get(..4)?.try_into().ok()?) as usize;
+    if size != listpack.len() || listpack.last() != Some(&END) {
+        return None;
+    }
+    let mut elements = vec![];
+    let mut rest = &listpack[HEADER_SIZE..];
+    loop {
+        let (&encoding, data) = rest.split_first()?;
+        let (element, len) = match encoding {
+            END => return Some(elements),
+            0x00..=0x7f => (Element::Int(encoding as i64), 1),
+            0x80..=0xbf => string(data, (encoding & 0x3f) as usize, 1)?,
+            0xc0..=0xdf => {
+                let int = ((encoding as i64 & 0x1f) << 8) | *data.first()? as i64;
+                // Sign-extend the 13 bits.
+                (Element::Int((int << 51) >> 51), 2)
+            }
+            0xe0..=0xef => {
+                let len = ((encoding as usize & 0x0f) << 8) | *data.first()? as usize;
+                string(&data[1..], len, 2)?
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+                string(&data[4..], len, 5)?
+            }
+            0xf1..=0xf4 => {
+                let width = match encoding {
+                    0xf1 => 2,
+                    0xf2 => 3,
+                    0xf3 => 4,
+                    _ => 8,
+                };
+                let mut bytes = [0; 8];
+                bytes[8 - width..].copy_from_slice(data.get(..width)?);
+                // The bytes are little-endian, so shifting them to the top
+                // and back sign-extends them.
+                let int = i64::from_le_bytes(bytes) >> (8 * (8 - width));
+                (Element::Int(int), 1 + width)
+            }
+            _ => return None,
+        };
+        elements.push(element);
+        rest = rest.get(len + backlen_size(len)..)?;
+    }
+}
+
+/// A string of `len` bytes at the start of `data`, and the size of its
+/// entry given that the encoding took `header` bytes.
+fn string(data: &[u8], len: usize, header: usize) -> Option<(Element, usize)> {
+    let string = Bytes::copy_from_slice(data.get(..len)?);
+    Some((Element::String(string), header + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elements_round_trip_in_every_encoding() {
+        let mut elements: Vec<Element> = [
+            0,
+            127,
+            128,
+            -1,
+            -4096,
+            4095,
+            4096,
+            i16::MIN as i64,
+            -0x80_0000,
+            0x7f_ffff,
+            i32::MAX as i64,
+            i32::MIN as i64 - 1,
+            i64::MIN,
+            i64::MAX,
+        ]
+        .into_iter()
+        .map(Element::Int)
+        .collect();
+        for len in [0, 63, 64, 4095, 4096, 20000] {
+            elements.push(Element::String(Bytes::from(vec![b'x'; len])));
+        }
+        let listpack = encode(&elements);
+        assert_eq!(decode(&listpack), Some(elements));
+        assert_eq!(decode(&listpack[..listpack.len() - 1]), None);
+    }
+
+    #[test]
+    fn lengths_can_be_read_backwards() {
+        let mut buf = vec![];
+        encode_backlen(127, &mut buf);
+        assert_eq!(buf, [127]);
+        buf.clear();
+        encode_backlen(500, &mut buf);
+        // 500 is 3 * 128 + 116: read from the end, 116 comes first with its
+        // continuation bit set.
+        assert_eq!(buf, [3, 116 | 0x80]);
+    }
+}
@@ -1,15 +1,132 @@
+pub mod aof;
+pub mod blocking;
+pub mod cluster;
+pub mod config;
+pub mod consumer_group;
+pub mod crc16;
+pub mod crc64;
+pub mod data;
 pub mod dataframe;
+pub mod decimal;
+pub mod frame;
+pub mod glob;
+pub mod listpack;
 pub mod operation;
 pub mod parse;
+pub mod persistence;
+pub mod pubsub;
+pub mod rdb;
+pub mod replication;
+pub mod sentinel;
 pub mod server;
+pub mod sorted_set;
 pub mod store;
+pub mod stream;
+pub mod transaction;
 pub mod value;
 
 use server::Server;
 
 const REDIS_PORT: &str = "6379";
+const SENTINEL_PORT: &str = "26379";
 
+/// Starts a server configured by `--<parameter> <value>` arguments, which
+/// take any parameter `CONFIG SET` does, `--port`, `--replicaof
+/// "<host> <port>"`, `--cluster-enabled yes`, and `--sentinel yes` with
+/// `--sentinel-monitor "<name> <host> <port> <quorum>"` for each primary
+/// to monitor.
 #[tokio::main]
 async fn main() {
-    Server::new(REDIS_PORT).listen().await;
+    let mut port = None;
+    let mut parameters = vec![];
+    let mut primary = None;
+    let mut cluster = false;
+    let mut sentinel = false;
+    let mut monitored = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let (name, value) = match (arg.strip_prefix("--"), args.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => {
+                println!("Usage: mini-redis [--<parameter> <value> ...]");
+                return;
+            }
+        };
+        match name {
+            "port" => port = Some(value),
+            "replicaof" => {
+                let target = value.split_once(' ').and_then(|(host, port)| {
+                    port.trim().parse::<u16>().ok().map(|port| (host.to_string(), port))
+                });
+                match target {
+                    Some(target) => primary = Some(target),
+                    None => {
+                        println!("Invalid --replicaof, expected \"<host> <port>\"");
+                        return;
+                    }
+                }
+            }
+            "cluster-enabled" => match &value[..] {
+                "yes" => cluster = true,
+                "no" => cluster = false,
+                _ => {
+                    println!("Invalid --cluster-enabled, expected yes or no");
+                    return;
+                }
+            },
+            "sentinel" => match &value[..] {
+                "yes" => sentinel = true,
+                "no" => sentinel = false,
+                _ => {
+                    println!("Invalid --sentinel, expected yes or no");
+                    return;
+                }
+            },
+            "sentinel-monitor" => {
+                let fields: Vec<&str> = value.split_whitespace().collect();
+                let master = match &fields[..] {
+                    [name, host, port, quorum] => port
+                        .parse::<u16>()
+                        .ok()
+                        .zip(quorum.parse::<usize>().ok())
+                        .map(|(port, quorum)| {
+                            (name.to_string(), host.to_string(), port, quorum)
+                        }),
+                    _ => None,
+                };
+                match master {
+                    Some(master) => monitored.push(master),
+                    None => {
+                        println!(
+                            "Invalid --sentinel-monitor, expected \"<name> <host> <port> <quorum>\""
+                        );
+                        return;
+                    }
+                }
+            }
+            _ => parameters.push((name.to_string(), value)),
+        }
+    }
+    let default_port = if sentinel { SENTINEL_PORT } else { REDIS_PORT };
+    let server = Server::new(port.unwrap_or_else(|| String::from(default_port)));
+    if let Err(err) = server.configure(&parameters) {
+        println!("{}", err);
+        return;
+    }
+    if cluster {
+        server.enable_cluster();
+    }
+    if sentinel {
+        server.enable_sentinel();
+        for (name, host, port, quorum) in monitored {
+            if let Err(err) = server.sentinel_monitor(name, host, port, quorum) {
+                println!("{}", err);
+                return;
+            }
+        }
+    }
+    if let Some((host, port)) = primary {
+        server.replicaof(host, port);
+    }
+    server.listen().await;
 }
@@ -1,19 +1,418 @@
+mod cluster;
+mod config;
+mod consumer_group;
+mod expire;
+mod hash;
+mod keyspace;
+mod list;
+mod migrate;
+mod persistence;
+mod pubsub;
+mod replication;
+mod sentinel;
+mod set;
+mod stream;
+mod string;
+mod transaction;
+mod zset;
+
 use std::time::Duration;
 
+use bytes::Bytes;
+
+use zset::RangeKind;
+
+use crate::cluster::SetSlot;
+use crate::dataframe::unix_time_millis;
+use crate::sorted_set::{LexBound, ScoreBound};
+use crate::stream::{NewId, StreamId, Trim};
+use crate::value::Protocol;
 use crate::value::Value;
 
 #[derive(Debug)]
 pub enum Operation {
     Ping,
-    Echo(String),
-    Get(String),
-    Set(String, String, SetOptions),
+    Echo(Bytes),
+    Get(Bytes),
+    Set(Bytes, Bytes, SetOptions),
+    Hello(Option<Protocol>),
+    Push(Bytes, Vec<Bytes>, ListEnd),
+    PushX(Bytes, Vec<Bytes>, ListEnd),
+    Pop(Bytes, Option<usize>, ListEnd),
+    LLen(Bytes),
+    LRange(Bytes, i64, i64),
+    LIndex(Bytes, i64),
+    LSet(Bytes, i64, Bytes),
+    LTrim(Bytes, i64, i64),
+    LRem(Bytes, i64, Bytes),
+    LInsert(Bytes, InsertPosition, Bytes, Bytes),
+    LMove(Bytes, Bytes, ListEnd, ListEnd),
+    /// Blocking pop from the first non-empty key. No timeout means wait forever.
+    BPop(Vec<Bytes>, ListEnd, Option<Duration>),
+    BLMove(Bytes, Bytes, ListEnd, ListEnd, Option<Duration>),
+    HSet(Bytes, Vec<(Bytes, Bytes)>),
+    HMSet(Bytes, Vec<(Bytes, Bytes)>),
+    HSetNx(Bytes, Bytes, Bytes),
+    HGet(Bytes, Bytes),
+    HMGet(Bytes, Vec<Bytes>),
+    HDel(Bytes, Vec<Bytes>),
+    HGetAll(Bytes),
+    HIncrBy(Bytes, Bytes, i64),
+    HIncrByFloat(Bytes, Bytes, f64),
+    HExists(Bytes, Bytes),
+    HKeys(Bytes),
+    HVals(Bytes),
+    HLen(Bytes),
+    HStrLen(Bytes, Bytes),
+    SAdd(Bytes, Vec<Bytes>),
+    SRem(Bytes, Vec<Bytes>),
+    SMembers(Bytes),
+    SIsMember(Bytes, Bytes),
+    SMIsMember(Bytes, Vec<Bytes>),
+    SCard(Bytes),
+    /// `SINTER`, `SUNION` or `SDIFF` over the given keys.
+    SCombine(Vec<Bytes>, SetOperator),
+    /// Like `SCombine`, but stores the result under the first key.
+    SCombineStore(Bytes, Vec<Bytes>, SetOperator),
+    SMove(Bytes, Bytes, Bytes),
+    /// A negative count allows the same member to be picked more than once.
+    SRandMember(Bytes, Option<i64>),
+    SPop(Bytes, Option<usize>),
+    /// Score and member pairs to add.
+    ZAdd(Bytes, ZAddOptions, Vec<(f64, Bytes)>),
+    ZRange(Bytes, Box<ZRangeOptions>),
+    /// The member's rank, counted from the top if the first flag is set,
+    /// and whether to reply with its score too.
+    ZRank(Bytes, Bytes, bool, bool),
+    ZIncrBy(Bytes, f64, Bytes),
+    ZRem(Bytes, Vec<Bytes>),
+    ZCard(Bytes),
+    ZScore(Bytes, Bytes),
+    /// `ZPOPMIN`, or `ZPOPMAX` if the flag is set.
+    ZPop(Bytes, Option<usize>, bool),
+    Del(Vec<Bytes>),
+    Exists(Vec<Bytes>),
+    Type(Bytes),
+    Rename(Bytes, Bytes),
+    RenameNx(Bytes, Bytes),
+    Keys(Bytes),
+    Expire(Bytes, Expiry, ExpireConditions),
+    Ttl(Bytes, TimeUnit),
+    ExpireTime(Bytes, TimeUnit),
+    Persist(Bytes),
+    /// `INCR`, `DECR`, `INCRBY` and `DECRBY`, with decrements negated.
+    IncrBy(Bytes, i64),
+    IncrByFloat(Bytes, f64),
+    Append(Bytes, Bytes),
+    StrLen(Bytes),
+    GetRange(Bytes, i64, i64),
+    SetRange(Bytes, usize, Bytes),
+    GetDel(Bytes),
+    GetEx(Bytes, Option<GetExOption>),
+    MGet(Vec<Bytes>),
+    /// Key and value pairs to write at once.
+    MSet(Vec<(Bytes, Bytes)>),
+    MSetNx(Vec<(Bytes, Bytes)>),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Bytes>),
+    Unwatch,
+    Subscribe(Vec<Bytes>),
+    /// Channels to leave; all of them if empty.
+    Unsubscribe(Vec<Bytes>),
+    PSubscribe(Vec<Bytes>),
+    /// Patterns to leave; all of them if empty.
+    PUnsubscribe(Vec<Bytes>),
+    Publish(Bytes, Bytes),
+    /// Active channels, optionally only those matching a glob pattern.
+    PubSubChannels(Option<Bytes>),
+    PubSubNumSub(Vec<Bytes>),
+    PubSubNumPat,
+    /// Glob patterns of the parameters to read.
+    ConfigGet(Vec<Bytes>),
+    /// Parameter and value pairs to change at once.
+    ConfigSet(Vec<(Bytes, Bytes)>),
+    /// Field and value pairs of the new entry.
+    XAdd(Bytes, Box<XAddOptions>, NewId, Vec<(Bytes, Bytes)>),
+    /// The entries between two IDs, both included and lowest first, at most
+    /// the given count of them, and replied highest first if the flag is set.
+    XRange(Bytes, StreamId, StreamId, Option<usize>, bool),
+    XLen(Bytes),
+    XDel(Bytes, Vec<StreamId>),
+    XTrim(Bytes, Trim),
+    XRead(Vec<(Bytes, ReadFrom)>, XReadOptions),
+    /// Key and group, where the group starts reading, how many entries it has
+    /// read if known, and whether to create the stream (MKSTREAM).
+    XGroupCreate(Bytes, Bytes, ReadFrom, Option<u64>, bool),
+    XGroupSetId(Bytes, Bytes, ReadFrom, Option<u64>),
+    XGroupDestroy(Bytes, Bytes),
+    /// Key, group and consumer.
+    XGroupCreateConsumer(Bytes, Bytes, Bytes),
+    XGroupDelConsumer(Bytes, Bytes, Bytes),
+    /// Group and consumer, then what to read from each stream: `>` for
+    /// entries not delivered to the group yet, and otherwise the consumer's
+    /// pending entries after the given ID.
+    XReadGroup(Bytes, Bytes, Vec<(Bytes, ReadFrom)>, Box<XReadOptions>),
+    XAck(Bytes, Bytes, Vec<StreamId>),
+    /// A summary of the group's pending entries, or those in a range.
+    XPending(Bytes, Bytes, Option<Box<PendingRange>>),
+    /// Key, group and the consumer to hand the entries to.
+    XClaim(Bytes, Bytes, Bytes, Box<ClaimOptions>),
+    XAutoClaim(Bytes, Bytes, Bytes, Box<AutoClaimOptions>),
+    XInfoStream(Bytes),
+    XInfoGroups(Bytes),
+    XInfoConsumers(Bytes, Bytes),
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// The primary to follow, or `None` to stop following one.
+    ReplicaOf(Option<(String, u16)>),
+    PSync(Bytes, i64),
+    ReplConf(Vec<(Bytes, Bytes)>),
+    Role,
+    /// How many replicas to wait for, and for how long, or forever if `None`.
+    Wait(usize, Option<Duration>),
+    ClusterInfo,
+    ClusterMyId,
+    ClusterNodes,
+    ClusterSlots,
+    ClusterShards,
+    ClusterKeySlot(Bytes),
+    /// The address of a node to get in touch with.
+    ClusterMeet(String, u16),
+    ClusterAddSlots(Vec<u16>),
+    ClusterDelSlots(Vec<u16>),
+    /// What another node tells this one of the cluster, and whether the
+    /// node is meeting this one, which it may do without being known.
+    ClusterGossip(bool, Vec<Bytes>),
+    ClusterSetSlot(u16, SetSlot),
+    ClusterCountKeysInSlot(u16),
+    /// At most the given count of keys in the slot.
+    ClusterGetKeysInSlot(u16, usize),
+    /// Lets the next command use a slot this node is importing.
+    Asking,
+    Dump(Bytes),
+    Restore(Bytes, Box<RestoreOptions>),
+    Migrate(Box<MigrateOptions>),
+    SentinelMyId,
+    SentinelMasters,
+    SentinelMaster(String),
+    SentinelReplicas(String),
+    SentinelSentinels(String),
+    SentinelGetMasterAddr(String),
+    /// Name, address and quorum of a primary to monitor.
+    SentinelMonitor(String, String, u16, usize),
+    /// Option and value pairs to change for a monitored primary.
+    SentinelSet(String, Vec<(Bytes, Bytes)>),
+    /// Whether the primary at an address is down, asked by another sentinel,
+    /// which may want a vote in an epoch as well, unless it gives `*`.
+    SentinelIsMasterDownByAddr(String, u16, u64, String),
     Invalid(String),
 }
 
-#[derive(Debug)]
+/// The options of `SET`.
+#[derive(Debug, Default)]
 pub struct SetOptions {
-    pub expiration: Option<Duration>,
+    pub expiry: Option<Expiry>,
+    /// Keep the key's current deadline instead of clearing it.
+    pub keep_ttl: bool,
+    pub condition: Option<SetCondition>,
+    /// Reply with the previous value instead of OK.
+    pub get: bool,
+}
+
+/// Whether `SET` only writes keys that do not exist yet (NX) or only ones
+/// that do (XX).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    Nx,
+    Xx,
+}
+
+/// How `GETEX` changes the deadline of the key it reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GetExOption {
+    Expire(Expiry),
+    Persist,
+}
+
+/// When a key given to an `EXPIRE`-family command expires, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// Relative to the time the command runs.
+    In(i64),
+    /// As a Unix time.
+    At(i64),
+}
+
+/// The NX, XX, GT and LT flags of the `EXPIRE`-family commands.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExpireConditions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+}
+
+/// The unit a command takes or replies with times in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Seconds,
+    Milliseconds,
+}
+
+/// The options of `XADD`.
+#[derive(Debug, Default)]
+pub struct XAddOptions {
+    /// Do not create the stream if the key does not exist.
+    pub nomkstream: bool,
+    pub trim: Option<Trim>,
+}
+
+/// Where `XREAD` and `XREADGROUP` start reading a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFrom {
+    /// The entries with a greater ID.
+    After(StreamId),
+    /// `$` for `XREAD`: the entries added from now on. `>` for `XREADGROUP`:
+    /// the entries not delivered to the group yet.
+    New,
+}
+
+/// The options of `XREAD` and `XREADGROUP`.
+#[derive(Debug, Default)]
+pub struct XReadOptions {
+    /// At most how many entries to read from each stream.
+    pub count: Option<usize>,
+    /// Wait for entries if there are none yet.
+    pub block: bool,
+    /// How long to wait, or forever if `None`.
+    pub timeout: Option<Duration>,
+    /// `XREADGROUP` only: do not keep the entries pending.
+    pub noack: bool,
+}
+
+/// The range form of `XPENDING`.
+#[derive(Debug)]
+pub struct PendingRange {
+    /// Only entries not delivered for at least this many milliseconds.
+    pub min_idle: Option<i64>,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<Bytes>,
+}
+
+/// The arguments of `XCLAIM` after the consumer.
+#[derive(Debug, Default)]
+pub struct ClaimOptions {
+    /// Only claim entries not delivered for at least this many milliseconds.
+    pub min_idle: i64,
+    pub ids: Vec<StreamId>,
+    /// Set the delivery time this many milliseconds back.
+    pub idle: Option<i64>,
+    /// Set the delivery time to this Unix time in milliseconds.
+    pub time: Option<i64>,
+    /// Set the delivery count instead of adding one.
+    pub retry_count: Option<u64>,
+    /// Create pending entries for IDs no consumer has pending.
+    pub force: bool,
+    /// Reply with IDs only, and do not count this as a delivery.
+    pub justid: bool,
+    /// Move the group's last delivered ID forward to this one.
+    pub last_id: Option<StreamId>,
+}
+
+/// The arguments of `XAUTOCLAIM` after the consumer.
+#[derive(Debug)]
+pub struct AutoClaimOptions {
+    pub min_idle: i64,
+    /// Where to start scanning the pending entries.
+    pub start: StreamId,
+    pub count: usize,
+    pub justid: bool,
+}
+
+/// The arguments of `RESTORE` after the key.
+#[derive(Debug)]
+pub struct RestoreOptions {
+    /// In milliseconds, relative to when the command runs unless `absttl`
+    /// is set, and 0 for no deadline.
+    pub ttl: i64,
+    pub payload: Bytes,
+    /// Overwrite the key if it exists.
+    pub replace: bool,
+    pub absttl: bool,
+    /// Sent as `RESTORE-ASKING`, into a slot being imported.
+    pub asking: bool,
+}
+
+/// The arguments of `MIGRATE`.
+#[derive(Debug)]
+pub struct MigrateOptions {
+    /// The address of the target instance.
+    pub host: String,
+    pub port: u16,
+    pub keys: Vec<Bytes>,
+    /// How long the target may take to answer.
+    pub timeout: Duration,
+    /// Keep the keys here as well.
+    pub copy: bool,
+    /// Overwrite the keys on the target.
+    pub replace: bool,
+}
+
+/// The flags of `ZADD`, named after the Redis options.
+#[derive(Debug, Default)]
+pub struct ZAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+#[derive(Debug)]
+pub struct ZRangeOptions {
+    pub by: RangeBy,
+    pub rev: bool,
+    /// Offset and count; a negative count means all remaining members.
+    pub limit: Option<(i64, i64)>,
+    pub with_scores: bool,
+}
+
+/// The range of members a range command selects. Score and lex bounds are
+/// always stored lowest first, even for reversed ranges.
+#[derive(Debug)]
+pub enum RangeBy {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+/// The end of a list a command pushes to or pops from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// Where `LINSERT` places the new element relative to the pivot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertPosition {
+    Before,
+    After,
+}
+
+/// How the multi-key set commands combine their sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOperator {
+    Inter,
+    Union,
+    Diff,
 }
 
 pub trait OperationDeducer: Send {
@@ -24,6 +423,12 @@ pub struct StandardOperationDeducer;
 
 unsafe impl Send for StandardOperationDeducer {}
 
+impl Default for StandardOperationDeducer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StandardOperationDeducer {
     pub fn new() -> Self {
         Self {}
@@ -32,65 +437,320 @@ impl StandardOperationDeducer {
 
 impl OperationDeducer for StandardOperationDeducer {
     fn deduce_operation(&self, input: &Value) -> Operation {
-        if let Value::Array(tokens) = input {
-            let op = if let Some(Value::BulkString(s)) = tokens.get(0) {
-                s
-            } else {
-                return Operation::Invalid(String::from("Error: Invalid or corrupt input"));
-            }
-            .to_lowercase();
-            match &op[..] {
-                "ping" => Operation::Ping,
-                "echo" => self.deduce_echo(tokens),
-                "get" => self.deduce_get(tokens),
-                "set" => self.deduce_set(tokens),
-                _ => Operation::Invalid(format!("Error: Unkown operation {op}")),
-            }
-        } else {
-            return Operation::Invalid(String::from("Error: Invalid or corrupt input"));
+        let args = match input {
+            Value::Array(tokens) => tokens
+                .iter()
+                .map(|token| match token {
+                    Value::BulkString(arg) => Some(arg.clone()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<Bytes>>>(),
+            _ => None,
+        };
+        let (op, args) = match args.as_deref() {
+            Some([op, args @ ..]) => (String::from_utf8_lossy(op).to_lowercase(), args),
+            _ => return Operation::Invalid(String::from("Error: Invalid or corrupt input")),
+        };
+        match &op[..] {
+            "ping" => Operation::Ping,
+            "echo" => self.deduce_echo(args),
+            "get" => self.deduce_get(args),
+            "set" => self.deduce_set(args),
+            "hello" => self.deduce_hello(args),
+            "lpush" => self.deduce_push(&op, args, ListEnd::Left),
+            "rpush" => self.deduce_push(&op, args, ListEnd::Right),
+            "lpushx" => self.deduce_pushx(&op, args, ListEnd::Left),
+            "rpushx" => self.deduce_pushx(&op, args, ListEnd::Right),
+            "lpop" => self.deduce_pop(&op, args, ListEnd::Left),
+            "rpop" => self.deduce_pop(&op, args, ListEnd::Right),
+            "llen" => self.deduce_llen(&op, args),
+            "lrange" => self.deduce_lrange(&op, args),
+            "lindex" => self.deduce_lindex(&op, args),
+            "lset" => self.deduce_lset(&op, args),
+            "ltrim" => self.deduce_ltrim(&op, args),
+            "lrem" => self.deduce_lrem(&op, args),
+            "linsert" => self.deduce_linsert(&op, args),
+            "lmove" => self.deduce_lmove(&op, args),
+            "rpoplpush" => self.deduce_rpoplpush(&op, args),
+            "blpop" => self.deduce_bpop(&op, args, ListEnd::Left),
+            "brpop" => self.deduce_bpop(&op, args, ListEnd::Right),
+            "blmove" => self.deduce_blmove(&op, args),
+            "brpoplpush" => self.deduce_brpoplpush(&op, args),
+            "hset" => self.deduce_hset(&op, args),
+            "hmset" => self.deduce_hmset(&op, args),
+            "hsetnx" => self.deduce_hsetnx(&op, args),
+            "hget" => self.deduce_hget(&op, args),
+            "hmget" => self.deduce_hmget(&op, args),
+            "hdel" => self.deduce_hdel(&op, args),
+            "hgetall" => self.deduce_hgetall(&op, args),
+            "hincrby" => self.deduce_hincrby(&op, args),
+            "hincrbyfloat" => self.deduce_hincrbyfloat(&op, args),
+            "hexists" => self.deduce_hexists(&op, args),
+            "hkeys" => self.deduce_hkeys(&op, args),
+            "hvals" => self.deduce_hvals(&op, args),
+            "hlen" => self.deduce_hlen(&op, args),
+            "hstrlen" => self.deduce_hstrlen(&op, args),
+            "sadd" => self.deduce_sadd(&op, args),
+            "srem" => self.deduce_srem(&op, args),
+            "smembers" => self.deduce_smembers(&op, args),
+            "sismember" => self.deduce_sismember(&op, args),
+            "smismember" => self.deduce_smismember(&op, args),
+            "scard" => self.deduce_scard(&op, args),
+            "sinter" => self.deduce_scombine(&op, args, SetOperator::Inter),
+            "sunion" => self.deduce_scombine(&op, args, SetOperator::Union),
+            "sdiff" => self.deduce_scombine(&op, args, SetOperator::Diff),
+            "sinterstore" => self.deduce_scombinestore(&op, args, SetOperator::Inter),
+            "sunionstore" => self.deduce_scombinestore(&op, args, SetOperator::Union),
+            "sdiffstore" => self.deduce_scombinestore(&op, args, SetOperator::Diff),
+            "smove" => self.deduce_smove(&op, args),
+            "srandmember" => self.deduce_srandmember(&op, args),
+            "spop" => self.deduce_spop(&op, args),
+            "zadd" => self.deduce_zadd(&op, args),
+            "zrange" => self.deduce_zrange(&op, args),
+            "zrevrange" => self.deduce_zrangeby(&op, args, RangeKind::Rank, true),
+            "zrangebyscore" => self.deduce_zrangeby(&op, args, RangeKind::Score, false),
+            "zrevrangebyscore" => self.deduce_zrangeby(&op, args, RangeKind::Score, true),
+            "zrangebylex" => self.deduce_zrangeby(&op, args, RangeKind::Lex, false),
+            "zrevrangebylex" => self.deduce_zrangeby(&op, args, RangeKind::Lex, true),
+            "zrank" => self.deduce_zrank(&op, args, false),
+            "zrevrank" => self.deduce_zrank(&op, args, true),
+            "zincrby" => self.deduce_zincrby(&op, args),
+            "zrem" => self.deduce_zrem(&op, args),
+            "zcard" => self.deduce_zcard(&op, args),
+            "zscore" => self.deduce_zscore(&op, args),
+            "zpopmin" => self.deduce_zpop(&op, args, false),
+            "zpopmax" => self.deduce_zpop(&op, args, true),
+            "del" => self.deduce_del(&op, args),
+            "exists" => self.deduce_exists(&op, args),
+            "type" => self.deduce_type(&op, args),
+            "rename" => self.deduce_rename(&op, args),
+            "renamenx" => self.deduce_renamenx(&op, args),
+            "keys" => self.deduce_keys(&op, args),
+            "expire" => self.deduce_expire(&op, args, TimeUnit::Seconds, false),
+            "pexpire" => self.deduce_expire(&op, args, TimeUnit::Milliseconds, false),
+            "expireat" => self.deduce_expire(&op, args, TimeUnit::Seconds, true),
+            "pexpireat" => self.deduce_expire(&op, args, TimeUnit::Milliseconds, true),
+            "ttl" => self.deduce_ttl(&op, args, TimeUnit::Seconds),
+            "pttl" => self.deduce_ttl(&op, args, TimeUnit::Milliseconds),
+            "expiretime" => self.deduce_expiretime(&op, args, TimeUnit::Seconds),
+            "pexpiretime" => self.deduce_expiretime(&op, args, TimeUnit::Milliseconds),
+            "persist" => self.deduce_persist(&op, args),
+            "incr" => self.deduce_incr(&op, args, 1),
+            "decr" => self.deduce_incr(&op, args, -1),
+            "incrby" => self.deduce_incrby(&op, args, false),
+            "decrby" => self.deduce_incrby(&op, args, true),
+            "incrbyfloat" => self.deduce_incrbyfloat(&op, args),
+            "append" => self.deduce_append(&op, args),
+            "strlen" => self.deduce_strlen(&op, args),
+            "getrange" => self.deduce_getrange(&op, args),
+            "setrange" => self.deduce_setrange(&op, args),
+            "getdel" => self.deduce_getdel(&op, args),
+            "getex" => self.deduce_getex(&op, args),
+            "mget" => self.deduce_mget(&op, args),
+            "mset" => self.deduce_mset(&op, args, false),
+            "msetnx" => self.deduce_mset(&op, args, true),
+            "multi" | "exec" | "discard" | "unwatch" => self.deduce_transaction(&op, args),
+            "watch" => self.deduce_watch(&op, args),
+            "subscribe" | "psubscribe" => self.deduce_subscribe(&op, args),
+            "unsubscribe" | "punsubscribe" => self.deduce_unsubscribe(&op, args),
+            "publish" => self.deduce_publish(&op, args),
+            "pubsub" => self.deduce_pubsub(&op, args),
+            "config" => self.deduce_config(&op, args),
+            "xadd" => self.deduce_xadd(&op, args),
+            "xrange" => self.deduce_xrange(&op, args, false),
+            "xrevrange" => self.deduce_xrange(&op, args, true),
+            "xlen" => self.deduce_xlen(&op, args),
+            "xdel" => self.deduce_xdel(&op, args),
+            "xtrim" => self.deduce_xtrim(&op, args),
+            "xread" => self.deduce_xread(&op, args),
+            "xgroup" => self.deduce_xgroup(&op, args),
+            "xreadgroup" => self.deduce_xreadgroup(&op, args),
+            "xack" => self.deduce_xack(&op, args),
+            "xpending" => self.deduce_xpending(&op, args),
+            "xclaim" => self.deduce_xclaim(&op, args),
+            "xautoclaim" => self.deduce_xautoclaim(&op, args),
+            "xinfo" => self.deduce_xinfo(&op, args),
+            "save" | "lastsave" | "bgrewriteaof" => self.deduce_persistence(&op, args),
+            "bgsave" => self.deduce_bgsave(args),
+            "replicaof" | "slaveof" => self.deduce_replicaof(&op, args),
+            "psync" => self.deduce_psync(&op, args),
+            "replconf" => self.deduce_replconf(&op, args),
+            "role" => self.deduce_role(&op, args),
+            "wait" => self.deduce_wait(&op, args),
+            "cluster" => self.deduce_cluster(&op, args),
+            "asking" => self.deduce_asking(&op, args),
+            "dump" => self.deduce_dump(&op, args),
+            "restore" | "restore-asking" => self.deduce_restore(&op, args),
+            "migrate" => self.deduce_migrate(&op, args),
+            "sentinel" => self.deduce_sentinel(&op, args),
+            _ => Operation::Invalid(format!("Error: Unkown operation {op}")),
         }
     }
 }
 
 impl StandardOperationDeducer {
-    fn deduce_echo(&self, tokens: &[Value]) -> Operation {
-        if let Some(Value::BulkString(s)) = tokens.get(1) {
+    fn deduce_echo(&self, args: &[Bytes]) -> Operation {
+        if let Some(s) = args.first() {
             Operation::Echo(s.clone())
         } else {
             Operation::Invalid(String::from("Error: Invalid or corrupt input"))
         }
     }
 
-    fn deduce_get(&self, tokens: &[Value]) -> Operation {
-        match tokens {
-            [Value::BulkString(_), Value::BulkString(key)] => Operation::Get(key.clone()),
+    fn deduce_get(&self, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::Get(key.clone()),
             _ => Operation::Invalid(String::from("Invalid syntax for GET operation")),
         }
     }
 
-    fn deduce_set(&self, tokens: &[Value]) -> Operation {
-        match tokens {
-            [Value::BulkString(_), Value::BulkString(key), Value::BulkString(val)] => {
-                Operation::Set(key.clone(), val.clone(), SetOptions { expiration: None })
+    fn deduce_set(&self, args: &[Bytes]) -> Operation {
+        let (key, val, args) = match args {
+            [key, val, args @ ..] => (key, val, args),
+            _ => return wrong_arity("set"),
+        };
+        let mut options = SetOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let arg = arg.to_ascii_lowercase();
+            let unit = match &arg[..] {
+                b"nx" | b"xx" => {
+                    let condition = match &arg[..] {
+                        b"nx" => SetCondition::Nx,
+                        _ => SetCondition::Xx,
+                    };
+                    if options
+                        .condition
+                        .is_some_and(|current| current != condition)
+                    {
+                        return syntax_error();
+                    }
+                    options.condition = Some(condition);
+                    continue;
+                }
+                b"get" => {
+                    options.get = true;
+                    continue;
+                }
+                b"keepttl" if options.expiry.is_none() => {
+                    options.keep_ttl = true;
+                    continue;
+                }
+                b"ex" | b"exat" => TimeUnit::Seconds,
+                b"px" | b"pxat" => TimeUnit::Milliseconds,
+                _ => return syntax_error(),
+            };
+            if options.expiry.is_some() || options.keep_ttl {
+                return syntax_error();
             }
-            [Value::BulkString(_), Value::BulkString(key), Value::BulkString(val), Value::BulkString(ex_op), Value::BulkString(duration)]
-                if (ex_op.eq_ignore_ascii_case("ex") || ex_op.eq_ignore_ascii_case("px"))
-                    && duration.parse::<u64>().is_ok() =>
-            {
-                let expiration = if ex_op.eq_ignore_ascii_case("ex") {
-                    Duration::from_secs(duration.parse().unwrap())
-                } else {
-                    Duration::from_millis(duration.parse().unwrap())
-                };
-                Operation::Set(
-                    key.clone(),
-                    val.clone(),
-                    SetOptions {
-                        expiration: Some(expiration),
-                    },
-                )
+            let time = match args.next() {
+                Some(time) => time,
+                None => return syntax_error(),
+            };
+            match parse_expiry_option("set", time, unit, arg.ends_with(b"at")) {
+                Ok(expiry) => options.expiry = Some(expiry),
+                Err(err) => return err,
+            }
+        }
+        Operation::Set(key.clone(), val.clone(), options)
+    }
+
+    fn deduce_hello(&self, args: &[Bytes]) -> Operation {
+        let protocol = match args.first() {
+            None => return Operation::Hello(None),
+            Some(version) => match parse_u64(version) {
+                Some(2) => Protocol::Resp2,
+                Some(3) => Protocol::Resp3,
+                Some(_) => {
+                    return Operation::Invalid(String::from("NOPROTO unsupported protocol version"))
+                }
+                None => {
+                    return Operation::Invalid(String::from(
+                        "ERR Protocol version is not an integer or out of range",
+                    ))
+                }
+            },
+        };
+        // AUTH and SETNAME are accepted for compatibility with clients that always send them;
+        // there are no users to authenticate and connection names are not tracked.
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let arity = if option.eq_ignore_ascii_case(b"auth") {
+                2
+            } else if option.eq_ignore_ascii_case(b"setname") {
+                1
+            } else {
+                0
+            };
+            if arity == 0 || options.by_ref().take(arity).count() < arity {
+                let option = String::from_utf8_lossy(option);
+                return Operation::Invalid(format!("ERR Syntax error in HELLO option '{option}'"));
             }
-            _ => Operation::Invalid(String::from("Invalid syntax for SET operation")),
         }
+        Operation::Hello(Some(protocol))
     }
 }
+
+fn wrong_arity(command: &str) -> Operation {
+    Operation::Invalid(format!(
+        "ERR wrong number of arguments for '{command}' command"
+    ))
+}
+
+fn not_an_integer() -> Operation {
+    Operation::Invalid(String::from("ERR value is not an integer or out of range"))
+}
+
+fn syntax_error() -> Operation {
+    Operation::Invalid(String::from("ERR syntax error"))
+}
+
+fn not_a_float() -> Operation {
+    Operation::Invalid(String::from("ERR value is not a valid float"))
+}
+
+/// Parses the time given to the EX, PX, EXAT or PXAT option of `command`,
+/// which has to be positive.
+fn parse_expiry_option(
+    command: &str,
+    time: &[u8],
+    unit: TimeUnit,
+    absolute: bool,
+) -> Result<Expiry, Operation> {
+    let time = match parse_i64(time) {
+        Some(time) if time > 0 => time,
+        Some(_) => return Err(invalid_expire_time(command)),
+        None => return Err(not_an_integer()),
+    };
+    let millis = match unit {
+        TimeUnit::Seconds => time.checked_mul(1000),
+        TimeUnit::Milliseconds => Some(time),
+    };
+    match (millis, absolute) {
+        (Some(millis), true) => Ok(Expiry::At(millis)),
+        (Some(millis), false) if unix_time_millis().checked_add(millis).is_some() => {
+            Ok(Expiry::In(millis))
+        }
+        _ => Err(invalid_expire_time(command)),
+    }
+}
+
+fn invalid_expire_time(command: &str) -> Operation {
+    Operation::Invalid(format!("ERR invalid expire time in '{command}' command"))
+}
+
+fn parse_u64(bytes: &[u8]) -> Option<u64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn parse_i64(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn parse_f64(bytes: &[u8]) -> Option<f64> {
+    std::str::from_utf8(bytes)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|float: &f64| !float.is_nan())
+}
@@ -0,0 +1,133 @@
+use bytes::Bytes;
+
+use super::{parse_i64, wrong_arity, Operation, StandardOperationDeducer};
+use crate::cluster::{SetSlot, SLOTS};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_cluster(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (subcommand, args) = match args {
+            [subcommand, args @ ..] => (subcommand.to_ascii_lowercase(), args),
+            _ => return wrong_arity(op),
+        };
+        match (&subcommand[..], args) {
+            (b"info", []) => Operation::ClusterInfo,
+            (b"myid", []) => Operation::ClusterMyId,
+            (b"nodes", []) => Operation::ClusterNodes,
+            (b"slots", []) => Operation::ClusterSlots,
+            (b"shards", []) => Operation::ClusterShards,
+            (b"keyslot", [key]) => Operation::ClusterKeySlot(key.clone()),
+            (b"meet", [host, port]) => {
+                let host = String::from_utf8_lossy(host).into_owned();
+                let port = std::str::from_utf8(port)
+                    .ok()
+                    .and_then(|port| port.parse::<u16>().ok());
+                match port {
+                    Some(port) => Operation::ClusterMeet(host, port),
+                    None => Operation::Invalid(format!(
+                        "ERR Invalid node address specified: {host}:{}",
+                        String::from_utf8_lossy(&args[1])
+                    )),
+                }
+            }
+            (b"addslots" | b"delslots", [_, ..]) => {
+                let Some(slots) = args.iter().map(|slot| parse_slot(slot)).collect() else {
+                    return invalid_slot();
+                };
+                match &subcommand[..] {
+                    b"addslots" => Operation::ClusterAddSlots(slots),
+                    _ => Operation::ClusterDelSlots(slots),
+                }
+            }
+            (b"addslotsrange" | b"delslotsrange", [_, ..]) if args.len() % 2 == 0 => {
+                let mut slots = vec![];
+                for range in args.chunks(2) {
+                    let (Some(start), Some(end)) = (parse_slot(&range[0]), parse_slot(&range[1]))
+                    else {
+                        return invalid_slot();
+                    };
+                    if start > end {
+                        return Operation::Invalid(format!(
+                            "ERR start slot number {start} is greater than end slot number {end}"
+                        ));
+                    }
+                    slots.extend(start..=end);
+                }
+                match &subcommand[..] {
+                    b"addslotsrange" => Operation::ClusterAddSlots(slots),
+                    _ => Operation::ClusterDelSlots(slots),
+                }
+            }
+            (b"gossip", [kind, message @ ..]) => match &kind.to_ascii_lowercase()[..] {
+                b"meet" => Operation::ClusterGossip(true, message.to_vec()),
+                b"ping" => Operation::ClusterGossip(false, message.to_vec()),
+                _ => Operation::Invalid(String::from("ERR Invalid cluster message")),
+            },
+            (b"setslot", [slot, change, args @ ..]) => {
+                let Some(slot) = parse_slot(slot) else {
+                    return invalid_slot();
+                };
+                let node = || String::from_utf8_lossy(&args[0]).into_owned();
+                let change = match (&change.to_ascii_lowercase()[..], args.len()) {
+                    (b"importing", 1) => SetSlot::Importing(node()),
+                    (b"migrating", 1) => SetSlot::Migrating(node()),
+                    (b"node", 1) => SetSlot::Node(node()),
+                    (b"stable", 0) => SetSlot::Stable,
+                    _ => {
+                        return Operation::Invalid(String::from(
+                            "ERR Invalid CLUSTER SETSLOT action or number of arguments. \
+                             Try CLUSTER HELP",
+                        ))
+                    }
+                };
+                Operation::ClusterSetSlot(slot, change)
+            }
+            (b"countkeysinslot", [slot]) => match parse_slot(slot) {
+                Some(slot) => Operation::ClusterCountKeysInSlot(slot),
+                None => invalid_slot(),
+            },
+            (b"getkeysinslot", [slot, count]) => {
+                let Some(slot) = parse_slot(slot) else {
+                    return invalid_slot();
+                };
+                match parse_i64(count) {
+                    Some(count) if count >= 0 => {
+                        Operation::ClusterGetKeysInSlot(slot, count as usize)
+                    }
+                    _ => Operation::Invalid(String::from("ERR Invalid number of keys")),
+                }
+            }
+            (
+                b"info" | b"myid" | b"nodes" | b"slots" | b"shards" | b"keyslot" | b"meet"
+                | b"addslots" | b"delslots" | b"addslotsrange" | b"delslotsrange" | b"setslot"
+                | b"countkeysinslot" | b"getkeysinslot" | b"gossip",
+                _,
+            ) => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                wrong_arity(&format!("cluster|{subcommand}"))
+            }
+            _ => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                Operation::Invalid(format!(
+                    "ERR unknown subcommand '{subcommand}'. Try CLUSTER HELP."
+                ))
+            }
+        }
+    }
+
+    pub(super) fn deduce_asking(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [] => Operation::Asking,
+            _ => wrong_arity(op),
+        }
+    }
+}
+
+fn parse_slot(slot: &[u8]) -> Option<u16> {
+    parse_i64(slot)
+        .filter(|slot| (0..SLOTS as i64).contains(slot))
+        .map(|slot| slot as u16)
+}
+
+fn invalid_slot() -> Operation {
+    Operation::Invalid(String::from("ERR Invalid or out of range slot"))
+}
@@ -0,0 +1,32 @@
+use bytes::Bytes;
+
+use super::{wrong_arity, Operation, StandardOperationDeducer};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_config(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (subcommand, args) = match args {
+            [subcommand, args @ ..] => (subcommand.to_ascii_lowercase(), args),
+            _ => return wrong_arity(op),
+        };
+        match &subcommand[..] {
+            b"get" if !args.is_empty() => Operation::ConfigGet(args.to_vec()),
+            b"set" if !args.is_empty() && args.len() % 2 == 0 => {
+                let pairs = args
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Operation::ConfigSet(pairs)
+            }
+            b"get" | b"set" => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                wrong_arity(&format!("config|{subcommand}"))
+            }
+            _ => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                Operation::Invalid(format!(
+                    "ERR unknown subcommand '{subcommand}'. Try CONFIG HELP."
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use bytes::Bytes;
+
+use super::stream::{invalid_id, parse_bound, parse_id, parse_reads};
+use super::{
+    not_an_integer, parse_i64, syntax_error, wrong_arity, AutoClaimOptions, ClaimOptions,
+    Operation, PendingRange, ReadFrom, StandardOperationDeducer,
+};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_xgroup(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (subcommand, args) = match args {
+            [subcommand, args @ ..] => (subcommand.to_ascii_lowercase(), args),
+            _ => return wrong_arity(op),
+        };
+        match (&subcommand[..], args) {
+            (b"create", [key, group, id, options @ ..]) => {
+                let mut mkstream = false;
+                let mut entries_read = None;
+                let mut options = options;
+                while let [option, rest @ ..] = options {
+                    options = match (&option.to_ascii_lowercase()[..], rest) {
+                        (b"mkstream", rest) => {
+                            mkstream = true;
+                            rest
+                        }
+                        (b"entriesread", [value, rest @ ..]) => {
+                            entries_read = match parse_entries_read(value) {
+                                Ok(read) => read,
+                                Err(err) => return err,
+                            };
+                            rest
+                        }
+                        _ => return syntax_error(),
+                    };
+                }
+                match parse_group_start(id) {
+                    Some(from) => Operation::XGroupCreate(
+                        key.clone(),
+                        group.clone(),
+                        from,
+                        entries_read,
+                        mkstream,
+                    ),
+                    None => invalid_id(),
+                }
+            }
+            (b"setid", [key, group, id, options @ ..]) => {
+                let entries_read = match options {
+                    [] => None,
+                    [option, value] if option.eq_ignore_ascii_case(b"entriesread") => {
+                        match parse_entries_read(value) {
+                            Ok(read) => read,
+                            Err(err) => return err,
+                        }
+                    }
+                    _ => return syntax_error(),
+                };
+                match parse_group_start(id) {
+                    Some(from) => {
+                        Operation::XGroupSetId(key.clone(), group.clone(), from, entries_read)
+                    }
+                    None => invalid_id(),
+                }
+            }
+            (b"destroy", [key, group]) => Operation::XGroupDestroy(key.clone(), group.clone()),
+            (b"createconsumer", [key, group, consumer]) => {
+                Operation::XGroupCreateConsumer(key.clone(), group.clone(), consumer.clone())
+            }
+            (b"delconsumer", [key, group, consumer]) => {
+                Operation::XGroupDelConsumer(key.clone(), group.clone(), consumer.clone())
+            }
+            (b"create" | b"setid" | b"destroy" | b"createconsumer" | b"delconsumer", _) => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                wrong_arity(&format!("xgroup|{subcommand}"))
+            }
+            _ => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                Operation::Invalid(format!(
+                    "ERR unknown subcommand '{subcommand}'. Try XGROUP HELP."
+                ))
+            }
+        }
+    }
+
+    pub(super) fn deduce_xreadgroup(&self, op: &str, args: &[Bytes]) -> Operation {
+        if args.len() < 6 {
+            return wrong_arity(op);
+        }
+        match parse_reads(op, args, true) {
+            Ok((Some((group, consumer)), options, reads)) => {
+                Operation::XReadGroup(group, consumer, reads, Box::new(options))
+            }
+            Ok((None, ..)) => unreachable!("XREADGROUP parsed without a group"),
+            Err(err) => err,
+        }
+    }
+
+    pub(super) fn deduce_xack(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, group, ids) = match args {
+            [key, group, ids @ ..] if !ids.is_empty() => (key, group, ids),
+            _ => return wrong_arity(op),
+        };
+        match ids.iter().map(|id| parse_id(id)).collect::<Option<_>>() {
+            Some(ids) => Operation::XAck(key.clone(), group.clone(), ids),
+            None => invalid_id(),
+        }
+    }
+
+    pub(super) fn deduce_xpending(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, group, args) = match args {
+            [key, group, args @ ..] => (key, group, args),
+            _ => return wrong_arity(op),
+        };
+        if args.is_empty() {
+            return Operation::XPending(key.clone(), group.clone(), None);
+        }
+        let (min_idle, args) = match args {
+            [option, idle, args @ ..] if option.eq_ignore_ascii_case(b"idle") => {
+                match parse_i64(idle) {
+                    Some(idle) => (Some(idle), args),
+                    None => return not_an_integer(),
+                }
+            }
+            args => (None, args),
+        };
+        let (start, end, count, consumer) = match args {
+            [start, end, count] => (start, end, count, None),
+            [start, end, count, consumer] => (start, end, count, Some(consumer.clone())),
+            _ => return syntax_error(),
+        };
+        let (start, end) = match (parse_bound(start, true), parse_bound(end, false)) {
+            (Ok(start), Ok(end)) => (start, end),
+            (Err(err), _) | (_, Err(err)) => return err,
+        };
+        let count = match parse_i64(count) {
+            Some(count) => count.max(0) as usize,
+            None => return not_an_integer(),
+        };
+        let range = PendingRange {
+            min_idle,
+            start,
+            end,
+            count,
+            consumer,
+        };
+        Operation::XPending(key.clone(), group.clone(), Some(Box::new(range)))
+    }
+
+    pub(super) fn deduce_xclaim(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, group, consumer, min_idle, args) = match args {
+            [key, group, consumer, min_idle, args @ ..] if !args.is_empty() => {
+                (key, group, consumer, min_idle, args)
+            }
+            _ => return wrong_arity(op),
+        };
+        let mut options = ClaimOptions {
+            min_idle: match parse_i64(min_idle) {
+                Some(min_idle) => min_idle.max(0),
+                None => return invalid_claim_argument("min-idle-time", "XCLAIM"),
+            },
+            ..ClaimOptions::default()
+        };
+        // The IDs run up to the first argument that is not one.
+        let ids = args.iter().take_while(|id| parse_id(id).is_some()).count();
+        if ids == 0 {
+            return invalid_id();
+        }
+        options.ids = args[..ids].iter().filter_map(|id| parse_id(id)).collect();
+        let mut args = &args[ids..];
+        while let [option, rest @ ..] = args {
+            let name = option.to_ascii_lowercase();
+            args = match (&name[..], rest) {
+                (b"force", rest) => {
+                    options.force = true;
+                    rest
+                }
+                (b"justid", rest) => {
+                    options.justid = true;
+                    rest
+                }
+                (b"idle" | b"time" | b"retrycount", [value, rest @ ..]) => {
+                    let Some(value) = parse_i64(value) else {
+                        let option = String::from_utf8_lossy(&name).to_uppercase();
+                        return invalid_claim_argument(&format!("{option} option"), "XCLAIM");
+                    };
+                    match &name[..] {
+                        b"idle" => options.idle = Some(value),
+                        b"time" => options.time = Some(value),
+                        _ => options.retry_count = Some(value.max(0) as u64),
+                    }
+                    rest
+                }
+                (b"lastid", [id, rest @ ..]) => {
+                    options.last_id = match parse_id(id) {
+                        Some(id) => Some(id),
+                        None => return invalid_id(),
+                    };
+                    rest
+                }
+                _ => {
+                    return Operation::Invalid(format!(
+                        "ERR Unrecognized XCLAIM option '{}'",
+                        String::from_utf8_lossy(option)
+                    ))
+                }
+            };
+        }
+        Operation::XClaim(
+            key.clone(),
+            group.clone(),
+            consumer.clone(),
+            Box::new(options),
+        )
+    }
+
+    pub(super) fn deduce_xautoclaim(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, group, consumer, min_idle, start, args) = match args {
+            [key, group, consumer, min_idle, start, args @ ..] => {
+                (key, group, consumer, min_idle, start, args)
+            }
+            _ => return wrong_arity(op),
+        };
+        let min_idle = match parse_i64(min_idle) {
+            Some(min_idle) => min_idle.max(0),
+            None => return invalid_claim_argument("min-idle-time", "XAUTOCLAIM"),
+        };
+        let start = match parse_bound(start, true) {
+            Ok(start) => start,
+            Err(err) => return err,
+        };
+        let mut options = AutoClaimOptions {
+            min_idle,
+            start,
+            count: 100,
+            justid: false,
+        };
+        let mut args = args;
+        while let [option, rest @ ..] = args {
+            args = match (&option.to_ascii_lowercase()[..], rest) {
+                (b"count", [count, rest @ ..]) => {
+                    options.count = match parse_i64(count) {
+                        Some(count) if count > 0 && count <= i64::MAX / 10 => count as usize,
+                        Some(_) => {
+                            return Operation::Invalid(String::from("ERR COUNT must be > 0"))
+                        }
+                        None => return not_an_integer(),
+                    };
+                    rest
+                }
+                (b"justid", rest) => {
+                    options.justid = true;
+                    rest
+                }
+                _ => return syntax_error(),
+            };
+        }
+        Operation::XAutoClaim(
+            key.clone(),
+            group.clone(),
+            consumer.clone(),
+            Box::new(options),
+        )
+    }
+
+    pub(super) fn deduce_xinfo(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (subcommand, args) = match args {
+            [subcommand, args @ ..] => (subcommand.to_ascii_lowercase(), args),
+            _ => return wrong_arity(op),
+        };
+        match (&subcommand[..], args) {
+            (b"stream", [key]) => Operation::XInfoStream(key.clone()),
+            (b"stream", [_, full, ..]) if full.eq_ignore_ascii_case(b"full") => {
+                Operation::Invalid(String::from("ERR XINFO STREAM FULL is not supported"))
+            }
+            (b"stream", [_, ..]) => syntax_error(),
+            (b"groups", [key]) => Operation::XInfoGroups(key.clone()),
+            (b"consumers", [key, group]) => Operation::XInfoConsumers(key.clone(), group.clone()),
+            (b"stream" | b"groups" | b"consumers", _) => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                wrong_arity(&format!("xinfo|{subcommand}"))
+            }
+            _ => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                Operation::Invalid(format!(
+                    "ERR unknown subcommand '{subcommand}'. Try XINFO HELP."
+                ))
+            }
+        }
+    }
+}
+
+/// Parses the ID a group starts reading after, where `$` stands for the last
+/// entry of the stream.
+fn parse_group_start(id: &[u8]) -> Option<ReadFrom> {
+    match id {
+        b"$" => Some(ReadFrom::New),
+        id => parse_id(id).map(ReadFrom::After),
+    }
+}
+
+/// Parses the ENTRIESREAD option, where -1 means the count is unknown.
+fn parse_entries_read(value: &[u8]) -> Result<Option<u64>, Operation> {
+    match parse_i64(value) {
+        Some(-1) => Ok(None),
+        Some(read) if read >= 0 => Ok(Some(read as u64)),
+        Some(_) => Err(Operation::Invalid(String::from(
+            "ERR value for ENTRIESREAD must be positive or -1",
+        ))),
+        None => Err(not_an_integer()),
+    }
+}
+
+fn invalid_claim_argument(argument: &str, command: &str) -> Operation {
+    Operation::Invalid(format!("ERR Invalid {argument} argument for {command}"))
+}
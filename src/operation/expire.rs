@@ -0,0 +1,85 @@
+use bytes::Bytes;
+
+use super::{
+    invalid_expire_time, not_an_integer, parse_i64, wrong_arity, ExpireConditions, Expiry,
+    Operation, StandardOperationDeducer, TimeUnit,
+};
+use crate::dataframe::unix_time_millis;
+
+impl StandardOperationDeducer {
+    /// `EXPIRE` and `PEXPIRE`, or `EXPIREAT` and `PEXPIREAT` if `absolute`.
+    pub(super) fn deduce_expire(
+        &self,
+        op: &str,
+        args: &[Bytes],
+        unit: TimeUnit,
+        absolute: bool,
+    ) -> Operation {
+        let (key, time, options) = match args {
+            [key, time, options @ ..] => (key, time, options),
+            _ => return wrong_arity(op),
+        };
+        let time = match parse_i64(time) {
+            Some(time) => time,
+            None => return not_an_integer(),
+        };
+        let millis = match unit {
+            TimeUnit::Seconds => time.checked_mul(1000),
+            TimeUnit::Milliseconds => Some(time),
+        };
+        let expiry = match (millis, absolute) {
+            (Some(millis), true) => Expiry::At(millis),
+            // Relative times must not overflow once the current time is added.
+            (Some(millis), false) if unix_time_millis().checked_add(millis).is_some() => {
+                Expiry::In(millis)
+            }
+            _ => return invalid_expire_time(op),
+        };
+        let mut conditions = ExpireConditions::default();
+        for option in options {
+            let flag = match &option.to_ascii_lowercase()[..] {
+                b"nx" => &mut conditions.nx,
+                b"xx" => &mut conditions.xx,
+                b"gt" => &mut conditions.gt,
+                b"lt" => &mut conditions.lt,
+                _ => {
+                    let option = String::from_utf8_lossy(option);
+                    return Operation::Invalid(format!("ERR Unsupported option {option}"));
+                }
+            };
+            *flag = true;
+        }
+        if conditions.nx && (conditions.xx || conditions.gt || conditions.lt) {
+            return Operation::Invalid(String::from(
+                "ERR NX and XX, GT or LT options at the same time are not compatible",
+            ));
+        }
+        if conditions.gt && conditions.lt {
+            return Operation::Invalid(String::from(
+                "ERR GT and LT options at the same time are not compatible",
+            ));
+        }
+        Operation::Expire(key.clone(), expiry, conditions)
+    }
+
+    pub(super) fn deduce_ttl(&self, op: &str, args: &[Bytes], unit: TimeUnit) -> Operation {
+        match args {
+            [key] => Operation::Ttl(key.clone(), unit),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_expiretime(&self, op: &str, args: &[Bytes], unit: TimeUnit) -> Operation {
+        match args {
+            [key] => Operation::ExpireTime(key.clone(), unit),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_persist(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::Persist(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+}
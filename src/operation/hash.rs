@@ -0,0 +1,130 @@
+use bytes::Bytes;
+
+use super::{
+    not_a_float, not_an_integer, parse_f64, parse_i64, wrong_arity, Operation,
+    StandardOperationDeducer,
+};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_hset(&self, op: &str, args: &[Bytes]) -> Operation {
+        match field_values(args) {
+            Some((key, pairs)) => Operation::HSet(key, pairs),
+            None => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hmset(&self, op: &str, args: &[Bytes]) -> Operation {
+        match field_values(args) {
+            Some((key, pairs)) => Operation::HMSet(key, pairs),
+            None => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hsetnx(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, field, value] => Operation::HSetNx(key.clone(), field.clone(), value.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hget(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, field] => Operation::HGet(key.clone(), field.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hmget(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, fields @ ..] if !fields.is_empty() => {
+                Operation::HMGet(key.clone(), fields.to_vec())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hdel(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, fields @ ..] if !fields.is_empty() => {
+                Operation::HDel(key.clone(), fields.to_vec())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hgetall(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::HGetAll(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hincrby(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, field, increment] => match parse_i64(increment) {
+                Some(increment) => Operation::HIncrBy(key.clone(), field.clone(), increment),
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hincrbyfloat(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, field, increment] => match parse_f64(increment) {
+                Some(increment) => Operation::HIncrByFloat(key.clone(), field.clone(), increment),
+                None => not_a_float(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hexists(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, field] => Operation::HExists(key.clone(), field.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hkeys(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::HKeys(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hvals(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::HVals(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hlen(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::HLen(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_hstrlen(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, field] => Operation::HStrLen(key.clone(), field.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+}
+
+/// Splits `key field value [field value ...]` arguments.
+fn field_values(args: &[Bytes]) -> Option<(Bytes, Vec<(Bytes, Bytes)>)> {
+    match args {
+        [key, pairs @ ..] if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            Some((key.clone(), pairs))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,47 @@
+use bytes::Bytes;
+
+use super::{wrong_arity, Operation, StandardOperationDeducer};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_del(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [] => wrong_arity(op),
+            keys => Operation::Del(keys.to_vec()),
+        }
+    }
+
+    pub(super) fn deduce_exists(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [] => wrong_arity(op),
+            keys => Operation::Exists(keys.to_vec()),
+        }
+    }
+
+    pub(super) fn deduce_type(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::Type(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_rename(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, new_key] => Operation::Rename(key.clone(), new_key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_renamenx(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, new_key] => Operation::RenameNx(key.clone(), new_key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_keys(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [pattern] => Operation::Keys(pattern.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+}
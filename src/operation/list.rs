@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::{
+    not_an_integer, parse_i64, syntax_error, wrong_arity, InsertPosition, ListEnd, Operation,
+    StandardOperationDeducer,
+};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_push(&self, op: &str, args: &[Bytes], end: ListEnd) -> Operation {
+        match args {
+            [key, values @ ..] if !values.is_empty() => {
+                Operation::Push(key.clone(), values.to_vec(), end)
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_pushx(&self, op: &str, args: &[Bytes], end: ListEnd) -> Operation {
+        match args {
+            [key, values @ ..] if !values.is_empty() => {
+                Operation::PushX(key.clone(), values.to_vec(), end)
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_pop(&self, op: &str, args: &[Bytes], end: ListEnd) -> Operation {
+        match args {
+            [key] => Operation::Pop(key.clone(), None, end),
+            [key, count] => match parse_i64(count) {
+                Some(count) if count >= 0 => Operation::Pop(key.clone(), Some(count as usize), end),
+                Some(_) => {
+                    Operation::Invalid(String::from("ERR value is out of range, must be positive"))
+                }
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_llen(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::LLen(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_lrange(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, start, stop] => match (parse_i64(start), parse_i64(stop)) {
+                (Some(start), Some(stop)) => Operation::LRange(key.clone(), start, stop),
+                _ => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_lindex(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, index] => match parse_i64(index) {
+                Some(index) => Operation::LIndex(key.clone(), index),
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_lset(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, index, value] => match parse_i64(index) {
+                Some(index) => Operation::LSet(key.clone(), index, value.clone()),
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_ltrim(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, start, stop] => match (parse_i64(start), parse_i64(stop)) {
+                (Some(start), Some(stop)) => Operation::LTrim(key.clone(), start, stop),
+                _ => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_lrem(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, count, value] => match parse_i64(count) {
+                Some(count) => Operation::LRem(key.clone(), count, value.clone()),
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_linsert(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, position, pivot, value] => {
+                let position = if position.eq_ignore_ascii_case(b"before") {
+                    InsertPosition::Before
+                } else if position.eq_ignore_ascii_case(b"after") {
+                    InsertPosition::After
+                } else {
+                    return syntax_error();
+                };
+                Operation::LInsert(key.clone(), position, pivot.clone(), value.clone())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_lmove(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [source, destination, from, to] => match (parse_end(from), parse_end(to)) {
+                (Some(from), Some(to)) => {
+                    Operation::LMove(source.clone(), destination.clone(), from, to)
+                }
+                _ => syntax_error(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_rpoplpush(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [source, destination] => Operation::LMove(
+                source.clone(),
+                destination.clone(),
+                ListEnd::Right,
+                ListEnd::Left,
+            ),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_bpop(&self, op: &str, args: &[Bytes], end: ListEnd) -> Operation {
+        match args {
+            [keys @ .., timeout] if !keys.is_empty() => match parse_timeout(timeout) {
+                Ok(timeout) => Operation::BPop(keys.to_vec(), end, timeout),
+                Err(err) => err,
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_blmove(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [source, destination, from, to, timeout] => {
+                let (from, to) = match (parse_end(from), parse_end(to)) {
+                    (Some(from), Some(to)) => (from, to),
+                    _ => return syntax_error(),
+                };
+                match parse_timeout(timeout) {
+                    Ok(timeout) => {
+                        Operation::BLMove(source.clone(), destination.clone(), from, to, timeout)
+                    }
+                    Err(err) => err,
+                }
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_brpoplpush(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [source, destination, timeout] => match parse_timeout(timeout) {
+                Ok(timeout) => Operation::BLMove(
+                    source.clone(),
+                    destination.clone(),
+                    ListEnd::Right,
+                    ListEnd::Left,
+                    timeout,
+                ),
+                Err(err) => err,
+            },
+            _ => wrong_arity(op),
+        }
+    }
+}
+
+fn parse_end(end: &[u8]) -> Option<ListEnd> {
+    if end.eq_ignore_ascii_case(b"left") {
+        Some(ListEnd::Left)
+    } else if end.eq_ignore_ascii_case(b"right") {
+        Some(ListEnd::Right)
+    } else {
+        None
+    }
+}
+
+/// Parses a blocking timeout given in (fractional) seconds, where zero means
+/// blocking forever.
+fn parse_timeout(timeout: &[u8]) -> Result<Option<Duration>, Operation> {
+    let timeout = std::str::from_utf8(timeout)
+        .ok()
+        .and_then(|timeout| timeout.parse::<f64>().ok())
+        .filter(|timeout| timeout.is_finite());
+    match timeout {
+        None => Err(Operation::Invalid(String::from(
+            "ERR timeout is not a float or out of range",
+        ))),
+        Some(timeout) if timeout < 0.0 => {
+            Err(Operation::Invalid(String::from("ERR timeout is negative")))
+        }
+        Some(0.0) => Ok(None),
+        Some(timeout) => match Duration::try_from_secs_f64(timeout) {
+            Ok(timeout) => Ok(Some(timeout)),
+            Err(_) => Err(Operation::Invalid(String::from("ERR timeout is out of range"))),
+        },
+    }
+}
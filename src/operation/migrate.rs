@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::{
+    not_an_integer, parse_i64, parse_u64, syntax_error, wrong_arity, MigrateOptions, Operation,
+    RestoreOptions, StandardOperationDeducer,
+};
+
+/// How long `MIGRATE` waits on the target when given no positive timeout.
+const DEFAULT_MIGRATE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_dump(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::Dump(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    /// `RESTORE`, or `RESTORE-ASKING`, which nodes send each other to move
+    /// keys into a slot still being imported.
+    pub(super) fn deduce_restore(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, ttl, payload, args) = match args {
+            [key, ttl, payload, args @ ..] => (key, ttl, payload, args),
+            _ => return wrong_arity(op),
+        };
+        let ttl = match parse_i64(ttl) {
+            Some(ttl) if ttl >= 0 => ttl,
+            Some(_) => {
+                return Operation::Invalid(String::from("ERR Invalid TTL value, must be >= 0"))
+            }
+            None => return not_an_integer(),
+        };
+        let mut options = RestoreOptions {
+            ttl,
+            payload: payload.clone(),
+            replace: false,
+            absttl: false,
+            asking: op == "restore-asking",
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match &arg.to_ascii_lowercase()[..] {
+                b"replace" => options.replace = true,
+                b"absttl" => options.absttl = true,
+                // Keys carry no idle time or access frequency to restore.
+                b"idletime" | b"freq" => match args.next().and_then(|arg| parse_i64(arg)) {
+                    Some(value) if value >= 0 => {}
+                    _ => return syntax_error(),
+                },
+                _ => return syntax_error(),
+            }
+        }
+        Operation::Restore(key.clone(), Box::new(options))
+    }
+
+    pub(super) fn deduce_migrate(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (host, port, key, db, timeout, args) = match args {
+            [host, port, key, db, timeout, args @ ..] => (host, port, key, db, timeout, args),
+            _ => return wrong_arity(op),
+        };
+        let (Some(port), Some(db), Some(timeout)) = (
+            parse_u64(port).and_then(|port| u16::try_from(port).ok()),
+            parse_i64(db),
+            parse_i64(timeout),
+        ) else {
+            return not_an_integer();
+        };
+        // There is a single database.
+        if db != 0 {
+            return Operation::Invalid(String::from("ERR DB index is out of range"));
+        }
+        let timeout = match timeout {
+            timeout if timeout > 0 => Duration::from_millis(timeout as u64),
+            _ => DEFAULT_MIGRATE_TIMEOUT,
+        };
+        let mut options = MigrateOptions {
+            host: String::from_utf8_lossy(host).into_owned(),
+            port,
+            keys: vec![key.clone()],
+            timeout,
+            copy: false,
+            replace: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match &arg.to_ascii_lowercase()[..] {
+                b"copy" => options.copy = true,
+                b"replace" => options.replace = true,
+                // The keys to move follow, in place of the single key, which
+                // must then be empty.
+                b"keys" if key.is_empty() => {
+                    options.keys = args.cloned().collect();
+                    break;
+                }
+                b"keys" => {
+                    return Operation::Invalid(String::from(
+                        "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                    ))
+                }
+                _ => return syntax_error(),
+            }
+        }
+        Operation::Migrate(Box::new(options))
+    }
+}
@@ -0,0 +1,27 @@
+use bytes::Bytes;
+
+use super::{syntax_error, wrong_arity, Operation, StandardOperationDeducer};
+
+impl StandardOperationDeducer {
+    /// `SAVE`, `LASTSAVE` and `BGREWRITEAOF`, none of which takes arguments.
+    pub(super) fn deduce_persistence(&self, op: &str, args: &[Bytes]) -> Operation {
+        if !args.is_empty() {
+            return wrong_arity(op);
+        }
+        match op {
+            "save" => Operation::Save,
+            "bgrewriteaof" => Operation::BgRewriteAof,
+            _ => Operation::LastSave,
+        }
+    }
+
+    /// `BGSAVE`, whose SCHEDULE option is accepted but changes nothing, as
+    /// a save never waits for a rewrite of the append-only file.
+    pub(super) fn deduce_bgsave(&self, args: &[Bytes]) -> Operation {
+        match args {
+            [] => Operation::BgSave,
+            [option] if option.eq_ignore_ascii_case(b"schedule") => Operation::BgSave,
+            _ => syntax_error(),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use bytes::Bytes;
+
+use super::{wrong_arity, Operation, StandardOperationDeducer};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_subscribe(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [] => wrong_arity(op),
+            channels if op == "subscribe" => Operation::Subscribe(channels.to_vec()),
+            patterns => Operation::PSubscribe(patterns.to_vec()),
+        }
+    }
+
+    /// `UNSUBSCRIBE` and `PUNSUBSCRIBE`, which leave everything when given
+    /// no arguments.
+    pub(super) fn deduce_unsubscribe(&self, op: &str, args: &[Bytes]) -> Operation {
+        if op == "unsubscribe" {
+            Operation::Unsubscribe(args.to_vec())
+        } else {
+            Operation::PUnsubscribe(args.to_vec())
+        }
+    }
+
+    pub(super) fn deduce_publish(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [channel, message] => Operation::Publish(channel.clone(), message.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_pubsub(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (subcommand, args) = match args {
+            [subcommand, args @ ..] => (subcommand.to_ascii_lowercase(), args),
+            _ => return wrong_arity(op),
+        };
+        match (&subcommand[..], args) {
+            (b"channels", []) => Operation::PubSubChannels(None),
+            (b"channels", [pattern]) => Operation::PubSubChannels(Some(pattern.clone())),
+            (b"numsub", channels) => Operation::PubSubNumSub(channels.to_vec()),
+            (b"numpat", []) => Operation::PubSubNumPat,
+            (b"channels" | b"numpat", _) => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                wrong_arity(&format!("pubsub|{subcommand}"))
+            }
+            _ => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                Operation::Invalid(format!(
+                    "ERR unknown subcommand '{subcommand}'. Try PUBSUB HELP."
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::{not_an_integer, parse_i64, wrong_arity, Operation, StandardOperationDeducer};
+
+impl StandardOperationDeducer {
+    /// `REPLICAOF host port`, or `REPLICAOF NO ONE` to stop following a
+    /// primary. `SLAVEOF` is the same command.
+    pub(super) fn deduce_replicaof(&self, op: &str, args: &[Bytes]) -> Operation {
+        let [host, port] = args else {
+            return wrong_arity(op);
+        };
+        if host.eq_ignore_ascii_case(b"no") && port.eq_ignore_ascii_case(b"one") {
+            return Operation::ReplicaOf(None);
+        }
+        let port = std::str::from_utf8(port)
+            .ok()
+            .and_then(|port| port.parse::<u16>().ok());
+        match port {
+            Some(port) => {
+                Operation::ReplicaOf(Some((String::from_utf8_lossy(host).into_owned(), port)))
+            }
+            None => Operation::Invalid(String::from("ERR Invalid master port")),
+        }
+    }
+
+    /// `PSYNC replid offset`, sent by a replica to continue the stream
+    /// `replid` from `offset`, or to sync from scratch.
+    pub(super) fn deduce_psync(&self, op: &str, args: &[Bytes]) -> Operation {
+        let [replid, offset] = args else {
+            return wrong_arity(op);
+        };
+        match parse_i64(offset) {
+            Some(offset) => Operation::PSync(replid.clone(), offset),
+            None => not_an_integer(),
+        }
+    }
+
+    /// `REPLCONF option value [option value ...]`, which replicas send their
+    /// primary while syncing.
+    pub(super) fn deduce_replconf(&self, op: &str, args: &[Bytes]) -> Operation {
+        if args.is_empty() || args.len() % 2 == 1 {
+            return wrong_arity(op);
+        }
+        let pairs = args
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        Operation::ReplConf(pairs)
+    }
+
+    pub(super) fn deduce_role(&self, op: &str, args: &[Bytes]) -> Operation {
+        if !args.is_empty() {
+            return wrong_arity(op);
+        }
+        Operation::Role
+    }
+
+    /// `WAIT numreplicas timeout`, with the timeout in milliseconds and 0
+    /// meaning forever.
+    pub(super) fn deduce_wait(&self, op: &str, args: &[Bytes]) -> Operation {
+        let [numreplicas, timeout] = args else {
+            return wrong_arity(op);
+        };
+        let (Some(numreplicas), Some(timeout)) = (parse_i64(numreplicas), parse_i64(timeout))
+        else {
+            return not_an_integer();
+        };
+        if timeout < 0 {
+            return Operation::Invalid(String::from("ERR timeout is negative"));
+        }
+        let timeout = (timeout > 0).then(|| Duration::from_millis(timeout as u64));
+        Operation::Wait(numreplicas.max(0) as usize, timeout)
+    }
+}
@@ -0,0 +1,73 @@
+use bytes::Bytes;
+
+use super::{parse_u64, wrong_arity, Operation, StandardOperationDeducer};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_sentinel(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (subcommand, args) = match args {
+            [subcommand, args @ ..] => (subcommand.to_ascii_lowercase(), args),
+            _ => return wrong_arity(op),
+        };
+        let text = |arg: &Bytes| String::from_utf8_lossy(arg).into_owned();
+        match (&subcommand[..], args) {
+            (b"myid", []) => Operation::SentinelMyId,
+            (b"masters", []) => Operation::SentinelMasters,
+            (b"master", [name]) => Operation::SentinelMaster(text(name)),
+            (b"replicas" | b"slaves", [name]) => Operation::SentinelReplicas(text(name)),
+            (b"sentinels", [name]) => Operation::SentinelSentinels(text(name)),
+            (b"get-master-addr-by-name", [name]) => Operation::SentinelGetMasterAddr(text(name)),
+            (b"monitor", [name, host, port, quorum]) => {
+                let Some(port) = parse_u64(port).and_then(|port| u16::try_from(port).ok()) else {
+                    return Operation::Invalid(format!(
+                        "ERR Invalid port '{}'",
+                        String::from_utf8_lossy(port)
+                    ));
+                };
+                let Some(quorum) = parse_u64(quorum) else {
+                    return Operation::Invalid(String::from("ERR Invalid quorum"));
+                };
+                Operation::SentinelMonitor(text(name), text(host), port, quorum as usize)
+            }
+            (b"set", [name, options @ ..]) if !options.is_empty() && options.len() % 2 == 0 => {
+                let options = options
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Operation::SentinelSet(text(name), options)
+            }
+            (b"is-master-down-by-addr", [host, port, epoch, candidate]) => {
+                let (Some(port), Some(epoch)) = (
+                    parse_u64(port).and_then(|port| u16::try_from(port).ok()),
+                    parse_u64(epoch),
+                ) else {
+                    return Operation::Invalid(String::from(
+                        "ERR value is not an integer or out of range",
+                    ));
+                };
+                Operation::SentinelIsMasterDownByAddr(text(host), port, epoch, text(candidate))
+            }
+            (
+                b"myid"
+                | b"masters"
+                | b"master"
+                | b"replicas"
+                | b"slaves"
+                | b"sentinels"
+                | b"get-master-addr-by-name"
+                | b"monitor"
+                | b"set"
+                | b"is-master-down-by-addr",
+                _,
+            ) => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                wrong_arity(&format!("sentinel|{subcommand}"))
+            }
+            _ => {
+                let subcommand = String::from_utf8_lossy(&subcommand);
+                Operation::Invalid(format!(
+                    "ERR unknown subcommand '{subcommand}'. Try SENTINEL HELP."
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use bytes::Bytes;
+
+use super::{
+    not_an_integer, parse_i64, wrong_arity, Operation, SetOperator, StandardOperationDeducer,
+};
+
+/// The most members `SRANDMEMBER` repeats for a negative count.
+const MAX_REPEATED_MEMBERS: i64 = 1 << 20;
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_sadd(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, members @ ..] if !members.is_empty() => {
+                Operation::SAdd(key.clone(), members.to_vec())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_srem(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, members @ ..] if !members.is_empty() => {
+                Operation::SRem(key.clone(), members.to_vec())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_smembers(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::SMembers(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_sismember(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, member] => Operation::SIsMember(key.clone(), member.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_smismember(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, members @ ..] if !members.is_empty() => {
+                Operation::SMIsMember(key.clone(), members.to_vec())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_scard(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::SCard(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_scombine(
+        &self,
+        op: &str,
+        args: &[Bytes],
+        operator: SetOperator,
+    ) -> Operation {
+        match args {
+            [] => wrong_arity(op),
+            keys => Operation::SCombine(keys.to_vec(), operator),
+        }
+    }
+
+    pub(super) fn deduce_scombinestore(
+        &self,
+        op: &str,
+        args: &[Bytes],
+        operator: SetOperator,
+    ) -> Operation {
+        match args {
+            [destination, keys @ ..] if !keys.is_empty() => {
+                Operation::SCombineStore(destination.clone(), keys.to_vec(), operator)
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_smove(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [source, destination, member] => {
+                Operation::SMove(source.clone(), destination.clone(), member.clone())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_srandmember(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::SRandMember(key.clone(), None),
+            [key, count] => match parse_i64(count) {
+                // Redis rejects counts this large. A negative count asks for
+                // that many repeated members in one reply, which is built in
+                // memory here, so it is held to a far lower bound.
+                Some(count)
+                    if count.unsigned_abs() > (i64::MAX / 2) as u64
+                        || count < -MAX_REPEATED_MEMBERS =>
+                {
+                    Operation::Invalid(String::from("ERR value is out of range"))
+                }
+                Some(count) => Operation::SRandMember(key.clone(), Some(count)),
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_spop(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::SPop(key.clone(), None),
+            [key, count] => match parse_i64(count) {
+                Some(count) if count >= 0 => Operation::SPop(key.clone(), Some(count as usize)),
+                Some(_) => {
+                    Operation::Invalid(String::from("ERR value is out of range, must be positive"))
+                }
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::{
+    not_an_integer, parse_i64, syntax_error, wrong_arity, Operation, ReadFrom,
+    StandardOperationDeducer, XAddOptions, XReadOptions,
+};
+use crate::stream::{NewId, StreamId, Trim, TrimThreshold};
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_xadd(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, args) = match args {
+            [key, args @ ..] => (key, args),
+            _ => return wrong_arity(op),
+        };
+        let (options, args) = match parse_trim_options(args, true) {
+            Ok(parsed) => parsed,
+            Err(err) => return err,
+        };
+        let (id, fields) = match args {
+            [id, fields @ ..] if !fields.is_empty() && fields.len() % 2 == 0 => (id, fields),
+            _ => return wrong_arity(op),
+        };
+        let id = match parse_new_id(id) {
+            Some(id) => id,
+            None => return invalid_id(),
+        };
+        let pairs = fields
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        Operation::XAdd(key.clone(), Box::new(options), id, pairs)
+    }
+
+    /// `XRANGE`, or `XREVRANGE` if `rev` is set, which takes the end first.
+    pub(super) fn deduce_xrange(&self, op: &str, args: &[Bytes], rev: bool) -> Operation {
+        let (key, first, second, options) = match args {
+            [key, first, second, options @ ..] => (key, first, second, options),
+            _ => return wrong_arity(op),
+        };
+        let (start, end) = if rev {
+            (second, first)
+        } else {
+            (first, second)
+        };
+        let (start, end) = match (parse_bound(start, true), parse_bound(end, false)) {
+            (Ok(start), Ok(end)) => (start, end),
+            (Err(err), _) | (_, Err(err)) => return err,
+        };
+        let count = match options {
+            [] => None,
+            [option, count] if option.eq_ignore_ascii_case(b"count") => match parse_i64(count) {
+                Some(count) => Some(count.max(0) as usize),
+                None => return not_an_integer(),
+            },
+            _ => return syntax_error(),
+        };
+        Operation::XRange(key.clone(), start, end, count, rev)
+    }
+
+    pub(super) fn deduce_xlen(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::XLen(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_xdel(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, ids) = match args {
+            [key, ids @ ..] if !ids.is_empty() => (key, ids),
+            _ => return wrong_arity(op),
+        };
+        let ids = ids.iter().map(|id| parse_id(id)).collect::<Option<_>>();
+        match ids {
+            Some(ids) => Operation::XDel(key.clone(), ids),
+            None => invalid_id(),
+        }
+    }
+
+    pub(super) fn deduce_xtrim(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, args) = match args {
+            [key, args @ ..] if args.len() >= 2 => (key, args),
+            _ => return wrong_arity(op),
+        };
+        match parse_trim_options(args, false) {
+            Ok((
+                XAddOptions {
+                    trim: Some(trim), ..
+                },
+                _,
+            )) => Operation::XTrim(key.clone(), trim),
+            Ok(_) => syntax_error(),
+            Err(err) => err,
+        }
+    }
+
+    pub(super) fn deduce_xread(&self, op: &str, args: &[Bytes]) -> Operation {
+        if args.len() < 3 {
+            return wrong_arity(op);
+        }
+        match parse_reads(op, args, false) {
+            Ok((_, options, reads)) => Operation::XRead(reads, options),
+            Err(err) => err,
+        }
+    }
+}
+
+/// The group and consumer names of `XREADGROUP`, the options, and what to
+/// read from each stream.
+type ParsedReads = (Option<(Bytes, Bytes)>, XReadOptions, Vec<(Bytes, ReadFrom)>);
+
+/// Parses the arguments of `XREAD`, or of `XREADGROUP` if `group` is set, in
+/// which case the group and consumer names come first in the result.
+pub(super) fn parse_reads(
+    op: &str,
+    mut args: &[Bytes],
+    group: bool,
+) -> Result<ParsedReads, Operation> {
+    let mut names = None;
+    let mut options = XReadOptions::default();
+    let streams = loop {
+        let (option, rest) = match args {
+            [option, rest @ ..] => (option.to_ascii_lowercase(), rest),
+            [] => return Err(syntax_error()),
+        };
+        args = match (&option[..], rest) {
+            (b"count", [count, rest @ ..]) => {
+                options.count = match parse_i64(count) {
+                    Some(count) if count > 0 => Some(count as usize),
+                    Some(_) => None,
+                    None => return Err(not_an_integer()),
+                };
+                rest
+            }
+            (b"block", [timeout, rest @ ..]) => {
+                options.block = true;
+                options.timeout = match parse_i64(timeout) {
+                    Some(0) => None,
+                    Some(millis) if millis > 0 => Some(Duration::from_millis(millis as u64)),
+                    Some(_) => {
+                        return Err(Operation::Invalid(String::from("ERR timeout is negative")))
+                    }
+                    None => {
+                        return Err(Operation::Invalid(String::from(
+                            "ERR timeout is not an integer or out of range",
+                        )))
+                    }
+                };
+                rest
+            }
+            (b"group", [name, consumer, rest @ ..]) if group => {
+                names = Some((name.clone(), consumer.clone()));
+                rest
+            }
+            (b"group", _) if !group => return Err(Operation::Invalid(String::from(
+                "ERR The GROUP option is only supported by XREADGROUP. You called XREAD instead.",
+            ))),
+            (b"noack", rest) if group => {
+                options.noack = true;
+                rest
+            }
+            (b"streams", streams) => break streams,
+            _ => return Err(syntax_error()),
+        };
+    };
+    let new = if group { ">" } else { "$" };
+    if streams.is_empty() || streams.len() % 2 == 1 {
+        return Err(Operation::Invalid(format!(
+            "ERR Unbalanced '{op}' list of streams: for each stream key an ID or '{new}' must be specified."
+        )));
+    }
+    if group && names.is_none() {
+        return Err(Operation::Invalid(String::from(
+            "ERR Missing GROUP option for XREADGROUP",
+        )));
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let mut reads = Vec::with_capacity(keys.len());
+    for (key, id) in keys.iter().zip(ids) {
+        let from = match (&id[..], group) {
+            (b"$", false) | (b">", true) => ReadFrom::New,
+            (b">", false) => {
+                return Err(Operation::Invalid(String::from(
+                    "ERR The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option.",
+                )))
+            }
+            (b"$", true) => {
+                return Err(Operation::Invalid(String::from(
+                    "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.",
+                )))
+            }
+            (id, _) => parse_id(id).map(ReadFrom::After).ok_or_else(invalid_id)?,
+        };
+        reads.push((key.clone(), from));
+    }
+    Ok((names, options, reads))
+}
+
+/// Parses the trimming options at the start of `args`, and for `XADD` also
+/// NOMKSTREAM, returning the arguments after them.
+fn parse_trim_options(
+    mut args: &[Bytes],
+    xadd: bool,
+) -> Result<(XAddOptions, &[Bytes]), Operation> {
+    let mut options = XAddOptions::default();
+    let mut threshold = None;
+    let mut approximate = false;
+    let mut limit = None;
+    while let [option, rest @ ..] = args {
+        let option = option.to_ascii_lowercase();
+        args = match (&option[..], rest) {
+            (b"nomkstream", _) if xadd => {
+                options.nomkstream = true;
+                rest
+            }
+            (b"maxlen" | b"minid", [exactness, value, rest @ ..])
+                if &exactness[..] == b"~" || &exactness[..] == b"=" =>
+            {
+                approximate = &exactness[..] == b"~";
+                threshold = Some(parse_threshold(&option, value)?);
+                rest
+            }
+            (b"maxlen" | b"minid", [value, rest @ ..]) => {
+                approximate = false;
+                threshold = Some(parse_threshold(&option, value)?);
+                rest
+            }
+            (b"limit", [count, rest @ ..]) => {
+                limit = match parse_i64(count) {
+                    Some(count) if count >= 0 => Some(count as usize),
+                    Some(_) => {
+                        return Err(Operation::Invalid(String::from(
+                            "ERR The LIMIT argument must be >= 0.",
+                        )))
+                    }
+                    None => return Err(not_an_integer()),
+                };
+                rest
+            }
+            (b"maxlen" | b"minid" | b"limit", _) => return Err(syntax_error()),
+            // The first argument that is not an option is the ID of the new entry.
+            _ if xadd => break,
+            _ => return Err(syntax_error()),
+        };
+    }
+    if limit.is_some() && !approximate {
+        let err = match threshold {
+            None => "ERR syntax error, LIMIT cannot be used without specifying a trimming strategy",
+            Some(_) => "ERR syntax error, LIMIT cannot be used without the special ~ option",
+        };
+        return Err(Operation::Invalid(String::from(err)));
+    }
+    options.trim = threshold.map(|threshold| Trim {
+        threshold,
+        approximate,
+        limit,
+    });
+    Ok((options, args))
+}
+
+fn parse_threshold(strategy: &[u8], value: &[u8]) -> Result<TrimThreshold, Operation> {
+    if strategy == b"minid" {
+        return parse_id(value)
+            .map(TrimThreshold::MinId)
+            .ok_or_else(invalid_id);
+    }
+    match parse_i64(value) {
+        Some(max) if max >= 0 => Ok(TrimThreshold::MaxLen(max as usize)),
+        Some(_) => Err(Operation::Invalid(String::from(
+            "ERR The MAXLEN argument must be >= 0.",
+        ))),
+        None => Err(not_an_integer()),
+    }
+}
+
+/// Parses the ID of a new entry: `*`, `<ms>-*` or an explicit ID.
+fn parse_new_id(id: &[u8]) -> Option<NewId> {
+    if id == b"*" {
+        return Some(NewId::Auto);
+    }
+    if let Some(ms) = id.strip_suffix(b"-*") {
+        return match StreamId::parse(ms)? {
+            (ms, None) => Some(NewId::AutoSeq(ms)),
+            (_, Some(_)) => None,
+        };
+    }
+    parse_id(id).map(NewId::Explicit)
+}
+
+/// Parses an ID, taking a missing sequence number as 0.
+pub(super) fn parse_id(id: &[u8]) -> Option<StreamId> {
+    let (ms, seq) = StreamId::parse(id)?;
+    Some(StreamId::new(ms, seq.unwrap_or(0)))
+}
+
+/// Parses the start or end of an ID range as an inclusive bound. `-` and `+`
+/// stand for the smallest and greatest IDs, a missing sequence number for
+/// the whole millisecond, and a leading `(` excludes the ID itself.
+pub(super) fn parse_bound(bound: &[u8], start: bool) -> Result<StreamId, Operation> {
+    let (exclusive, bound) = match bound.strip_prefix(b"(") {
+        Some(bound) => (true, bound),
+        None => (false, bound),
+    };
+    let id = match bound {
+        b"-" => StreamId::MIN,
+        b"+" => StreamId::MAX,
+        bound => match StreamId::parse(bound) {
+            Some((ms, Some(seq))) => StreamId::new(ms, seq),
+            Some((ms, None)) if start => StreamId::new(ms, 0),
+            Some((ms, None)) => StreamId::new(ms, u64::MAX),
+            None => return Err(invalid_id()),
+        },
+    };
+    match (exclusive, start) {
+        (false, _) => Ok(id),
+        (true, true) => id.next().ok_or_else(|| invalid_interval("start")),
+        (true, false) => id.prev().ok_or_else(|| invalid_interval("end")),
+    }
+}
+
+pub(super) fn invalid_id() -> Operation {
+    Operation::Invalid(String::from(
+        "ERR Invalid stream ID specified as stream command argument",
+    ))
+}
+
+fn invalid_interval(bound: &str) -> Operation {
+    Operation::Invalid(format!("ERR invalid {bound} ID for the interval"))
+}
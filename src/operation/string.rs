@@ -0,0 +1,134 @@
+use bytes::Bytes;
+
+use super::{
+    not_a_float, not_an_integer, parse_expiry_option, parse_f64, parse_i64, syntax_error,
+    wrong_arity, GetExOption, Operation, StandardOperationDeducer, TimeUnit,
+};
+
+impl StandardOperationDeducer {
+    /// `INCR` and `DECR`, which change the value by one in the given direction.
+    pub(super) fn deduce_incr(&self, op: &str, args: &[Bytes], increment: i64) -> Operation {
+        match args {
+            [key] => Operation::IncrBy(key.clone(), increment),
+            _ => wrong_arity(op),
+        }
+    }
+
+    /// `INCRBY`, or `DECRBY` if `negate` is set.
+    pub(super) fn deduce_incrby(&self, op: &str, args: &[Bytes], negate: bool) -> Operation {
+        let (key, increment) = match args {
+            [key, increment] => (key, increment),
+            _ => return wrong_arity(op),
+        };
+        match parse_i64(increment) {
+            Some(increment) if !negate => Operation::IncrBy(key.clone(), increment),
+            Some(decrement) => match decrement.checked_neg() {
+                Some(increment) => Operation::IncrBy(key.clone(), increment),
+                None => Operation::Invalid(String::from("ERR decrement would overflow")),
+            },
+            None => not_an_integer(),
+        }
+    }
+
+    pub(super) fn deduce_incrbyfloat(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, increment] => match parse_f64(increment) {
+                Some(increment) => Operation::IncrByFloat(key.clone(), increment),
+                None => not_a_float(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_append(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, value] => Operation::Append(key.clone(), value.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_strlen(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::StrLen(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_getrange(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, start, end] => match (parse_i64(start), parse_i64(end)) {
+                (Some(start), Some(end)) => Operation::GetRange(key.clone(), start, end),
+                _ => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_setrange(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, offset, value] => match parse_i64(offset) {
+                Some(offset) if offset < 0 => {
+                    Operation::Invalid(String::from("ERR offset is out of range"))
+                }
+                Some(offset) => Operation::SetRange(key.clone(), offset as usize, value.clone()),
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_getdel(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::GetDel(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_getex(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, options) = match args {
+            [key, options @ ..] => (key, options),
+            _ => return wrong_arity(op),
+        };
+        let option = match options {
+            [] => None,
+            [persist] if persist.eq_ignore_ascii_case(b"persist") => Some(GetExOption::Persist),
+            [option, time] => {
+                let option = option.to_ascii_lowercase();
+                let unit = match &option[..] {
+                    b"ex" | b"exat" => TimeUnit::Seconds,
+                    b"px" | b"pxat" => TimeUnit::Milliseconds,
+                    _ => return syntax_error(),
+                };
+                match parse_expiry_option(op, time, unit, option.ends_with(b"at")) {
+                    Ok(expiry) => Some(GetExOption::Expire(expiry)),
+                    Err(err) => return err,
+                }
+            }
+            _ => return syntax_error(),
+        };
+        Operation::GetEx(key.clone(), option)
+    }
+
+    pub(super) fn deduce_mget(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [] => wrong_arity(op),
+            keys => Operation::MGet(keys.to_vec()),
+        }
+    }
+
+    /// `MSET`, or `MSETNX` if `nx` is set.
+    pub(super) fn deduce_mset(&self, op: &str, args: &[Bytes], nx: bool) -> Operation {
+        if args.is_empty() || args.len() % 2 == 1 {
+            return wrong_arity(op);
+        }
+        let pairs = args
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        if nx {
+            Operation::MSetNx(pairs)
+        } else {
+            Operation::MSet(pairs)
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use bytes::Bytes;
+
+use super::{wrong_arity, Operation, StandardOperationDeducer};
+
+impl StandardOperationDeducer {
+    /// `MULTI`, `EXEC`, `DISCARD` and `UNWATCH`, none of which take arguments.
+    pub(super) fn deduce_transaction(&self, op: &str, args: &[Bytes]) -> Operation {
+        if !args.is_empty() {
+            return wrong_arity(op);
+        }
+        match op {
+            "multi" => Operation::Multi,
+            "exec" => Operation::Exec,
+            "discard" => Operation::Discard,
+            _ => Operation::Unwatch,
+        }
+    }
+
+    pub(super) fn deduce_watch(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [] => wrong_arity(op),
+            keys => Operation::Watch(keys.to_vec()),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use bytes::Bytes;
+
+use super::{
+    not_a_float, not_an_integer, parse_f64, parse_i64, syntax_error, wrong_arity, Operation,
+    RangeBy, StandardOperationDeducer, ZAddOptions, ZRangeOptions,
+};
+use crate::sorted_set::{LexBound, ScoreBound};
+
+/// What the bounds of a range command refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+impl StandardOperationDeducer {
+    pub(super) fn deduce_zadd(&self, op: &str, args: &[Bytes]) -> Operation {
+        let (key, mut args) = match args {
+            [key, args @ ..] if args.len() >= 2 => (key, args),
+            _ => return wrong_arity(op),
+        };
+        let mut options = ZAddOptions::default();
+        while let Some((flag, rest)) = args.split_first() {
+            let flag = match &flag.to_ascii_lowercase()[..] {
+                b"nx" => &mut options.nx,
+                b"xx" => &mut options.xx,
+                b"gt" => &mut options.gt,
+                b"lt" => &mut options.lt,
+                b"ch" => &mut options.ch,
+                b"incr" => &mut options.incr,
+                _ => break,
+            };
+            *flag = true;
+            args = rest;
+        }
+        if args.is_empty() || args.len() % 2 != 0 {
+            return syntax_error();
+        }
+        if options.nx && options.xx {
+            return Operation::Invalid(String::from(
+                "ERR XX and NX options at the same time are not compatible",
+            ));
+        }
+        if (options.gt && options.lt) || (options.nx && (options.gt || options.lt)) {
+            return Operation::Invalid(String::from(
+                "ERR GT, LT, and/or NX options at the same time are not compatible",
+            ));
+        }
+        if options.incr && args.len() > 2 {
+            return Operation::Invalid(String::from(
+                "ERR INCR option supports a single increment-element pair",
+            ));
+        }
+        let pairs = args
+            .chunks(2)
+            .map(|pair| Some((parse_f64(&pair[0])?, pair[1].clone())))
+            .collect::<Option<Vec<_>>>();
+        match pairs {
+            Some(pairs) => Operation::ZAdd(key.clone(), options, pairs),
+            None => not_a_float(),
+        }
+    }
+
+    /// `ZRANGE`, which takes the kind of range and the direction as options.
+    pub(super) fn deduce_zrange(&self, op: &str, args: &[Bytes]) -> Operation {
+        self.deduce_range(op, args, None, false)
+    }
+
+    /// The older range commands, whose name fixes the kind of range and the
+    /// direction.
+    pub(super) fn deduce_zrangeby(
+        &self,
+        op: &str,
+        args: &[Bytes],
+        kind: RangeKind,
+        rev: bool,
+    ) -> Operation {
+        self.deduce_range(op, args, Some(kind), rev)
+    }
+
+    fn deduce_range(
+        &self,
+        op: &str,
+        args: &[Bytes],
+        fixed: Option<RangeKind>,
+        mut rev: bool,
+    ) -> Operation {
+        let (key, start, stop, options) = match args {
+            [key, start, stop, options @ ..] => (key, start, stop, options),
+            _ => return wrong_arity(op),
+        };
+        let mut kind = fixed.unwrap_or(RangeKind::Rank);
+        let mut limit = None;
+        let mut with_scores = false;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match (&option.to_ascii_lowercase()[..], fixed) {
+                (b"withscores", _) => with_scores = true,
+                (b"limit", _) => match (options.next(), options.next()) {
+                    (Some(offset), Some(count)) => match (parse_i64(offset), parse_i64(count)) {
+                        (Some(offset), Some(count)) => limit = Some((offset, count)),
+                        _ => return not_an_integer(),
+                    },
+                    _ => return syntax_error(),
+                },
+                (b"byscore", None) => kind = RangeKind::Score,
+                (b"bylex", None) => kind = RangeKind::Lex,
+                (b"rev", None) => rev = true,
+                _ => return syntax_error(),
+            }
+        }
+        if limit.is_some() && kind == RangeKind::Rank {
+            return Operation::Invalid(String::from(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX",
+            ));
+        }
+        if with_scores && kind == RangeKind::Lex {
+            return Operation::Invalid(String::from(
+                "ERR syntax error, WITHSCORES not supported in combination with BYLEX",
+            ));
+        }
+        // Reversed score and lex ranges name the upper bound first.
+        let (min, max) = match (rev, kind) {
+            (true, RangeKind::Score | RangeKind::Lex) => (stop, start),
+            _ => (start, stop),
+        };
+        let by = match kind {
+            RangeKind::Rank => match (parse_i64(min), parse_i64(max)) {
+                (Some(start), Some(stop)) => RangeBy::Rank(start, stop),
+                _ => return not_an_integer(),
+            },
+            RangeKind::Score => match (parse_score_bound(min), parse_score_bound(max)) {
+                (Some(min), Some(max)) => RangeBy::Score(min, max),
+                _ => return Operation::Invalid(String::from("ERR min or max is not a float")),
+            },
+            RangeKind::Lex => match (parse_lex_bound(min), parse_lex_bound(max)) {
+                (Some(min), Some(max)) => RangeBy::Lex(min, max),
+                _ => {
+                    return Operation::Invalid(String::from(
+                        "ERR min or max not valid string range item",
+                    ))
+                }
+            },
+        };
+        Operation::ZRange(
+            key.clone(),
+            Box::new(ZRangeOptions {
+                by,
+                rev,
+                limit,
+                with_scores,
+            }),
+        )
+    }
+
+    pub(super) fn deduce_zrank(&self, op: &str, args: &[Bytes], rev: bool) -> Operation {
+        match args {
+            [key, member] => Operation::ZRank(key.clone(), member.clone(), rev, false),
+            [key, member, option] if option.eq_ignore_ascii_case(b"withscore") => {
+                Operation::ZRank(key.clone(), member.clone(), rev, true)
+            }
+            [_, _, _] => syntax_error(),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_zincrby(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, increment, member] => match parse_f64(increment) {
+                Some(increment) => Operation::ZIncrBy(key.clone(), increment, member.clone()),
+                None => not_a_float(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_zrem(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, members @ ..] if !members.is_empty() => {
+                Operation::ZRem(key.clone(), members.to_vec())
+            }
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_zcard(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key] => Operation::ZCard(key.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_zscore(&self, op: &str, args: &[Bytes]) -> Operation {
+        match args {
+            [key, member] => Operation::ZScore(key.clone(), member.clone()),
+            _ => wrong_arity(op),
+        }
+    }
+
+    pub(super) fn deduce_zpop(&self, op: &str, args: &[Bytes], max: bool) -> Operation {
+        match args {
+            [key] => Operation::ZPop(key.clone(), None, max),
+            [key, count] => match parse_i64(count) {
+                Some(count) if count >= 0 => {
+                    Operation::ZPop(key.clone(), Some(count as usize), max)
+                }
+                Some(_) => {
+                    Operation::Invalid(String::from("ERR value is out of range, must be positive"))
+                }
+                None => not_an_integer(),
+            },
+            _ => wrong_arity(op),
+        }
+    }
+}
+
+/// Parses a score bound like `1.5`, `(1.5` or `-inf`.
+fn parse_score_bound(bound: &[u8]) -> Option<ScoreBound> {
+    match bound.strip_prefix(b"(") {
+        Some(score) => parse_f64(score).map(ScoreBound::Exclusive),
+        None => parse_f64(bound).map(ScoreBound::Inclusive),
+    }
+}
+
+/// Parses a lexicographical bound: `-`, `+`, `[member` or `(member`.
+fn parse_lex_bound(bound: &[u8]) -> Option<LexBound> {
+    match bound.split_first() {
+        Some((b'-', [])) => Some(LexBound::Min),
+        Some((b'+', [])) => Some(LexBound::Max),
+        Some((b'[', member)) => Some(LexBound::Inclusive(Bytes::copy_from_slice(member))),
+        Some((b'(', member)) => Some(LexBound::Exclusive(Bytes::copy_from_slice(member))),
+        _ => None,
+    }
+}
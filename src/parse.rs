@@ -1,13 +1,20 @@
 use std::io::{self, Read};
 
+use bytes::Bytes;
+
 use crate::value::Value::{self, *};
 
 const BUF_SIZE: usize = 256;
 
 pub trait RedisParser<R: Read>: Send {
+    /// Parses a single value from `input`.
+    ///
+    /// Fails with [`io::ErrorKind::UnexpectedEof`] if the input ends before a
+    /// complete value could be read, so callers can wait for more data.
     fn parse(&self, input: &mut R) -> Result<Value, io::Error>;
 }
 
+#[derive(Default)]
 pub struct RespParser;
 
 unsafe impl Send for RespParser {}
@@ -21,18 +28,22 @@ impl RespParser {
 impl<R: Read> RedisParser<R> for RespParser {
     fn parse(&self, input: &mut R) -> Result<Value, io::Error> {
         let mut buf = [0u8; BUF_SIZE];
-        let key = input.bytes().next();
-        if let None = key {
-            return Err(io::Error::from(io::ErrorKind::InvalidInput));
-        }
-
-        let key = key.unwrap()?;
+        let key = self.read_byte(input)?;
         match key {
             b'*' => self.parse_array(input, &mut buf),
             b':' => self.parse_integer(input),
             b'+' => self.parse_simple_string(input),
             b'$' => self.parse_bulk_string(input, &mut buf),
             b'-' => self.parse_error(input),
+            b'_' => self.parse_null(input),
+            b'#' => self.parse_boolean(input),
+            b',' => self.parse_double(input),
+            b'(' => self.parse_big_number(input),
+            b'=' => self.parse_verbatim_string(input, &mut buf),
+            b'%' => Ok(Map(self.parse_pairs(input, &mut buf)?)),
+            b'~' => Ok(Set(self.parse_aggregate(input, &mut buf)?)),
+            b'>' => Ok(Push(self.parse_aggregate(input, &mut buf)?)),
+            b'|' => self.parse_attribute(input, &mut buf),
             _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
         }
     }
@@ -59,25 +70,49 @@ impl RespParser {
         stream: &mut impl Read,
         buf: &mut [u8; BUF_SIZE],
     ) -> Result<Value, io::Error> {
-        let len = self.parse_len(stream.bytes(), buf)?;
+        let len = self.parse_len(stream, buf)?;
         if len == -1 {
             return Ok(NullBulkString);
         }
+        Ok(BulkString(self.read_blob(stream, len, buf)?))
+    }
+
+    fn parse_verbatim_string(
+        &self,
+        stream: &mut impl Read,
+        buf: &mut [u8; BUF_SIZE],
+    ) -> Result<Value, io::Error> {
+        let len = self.parse_len(stream, buf)?;
+        let mut blob = self.read_blob(stream, len, buf)?;
+        if blob.len() < 4 || blob[3] != b':' {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let format = String::from_utf8_lossy(&blob.split_to(4)[..3]).into_owned();
+        Ok(Verbatim(format, blob))
+    }
+
+    fn read_blob(
+        &self,
+        stream: &mut impl Read,
+        len: i32,
+        buf: &mut [u8; BUF_SIZE],
+    ) -> Result<Bytes, io::Error> {
+        if len < 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
         let mut len = len as usize;
-        let mut string = String::with_capacity(len as usize);
-        let mut read_count = len / BUF_SIZE;
-        while read_count > 0 {
+        let mut bytes = Vec::with_capacity(len.min(BUF_SIZE));
+        while len >= BUF_SIZE {
             stream.read_exact(buf)?;
-            string.push_str(&String::from_utf8(buf.to_vec()).unwrap());
+            bytes.extend_from_slice(buf);
             len -= BUF_SIZE;
-            read_count -= 1;
         }
         if len > 0 {
             stream.read_exact(&mut buf[..len])?;
-            string.push_str(&String::from_utf8(buf[..len].to_vec()).unwrap());
+            bytes.extend_from_slice(&buf[..len]);
         }
-        self.skip_crlf(stream);
-        Ok(BulkString(string))
+        self.skip_crlf(stream)?;
+        Ok(Bytes::from(bytes))
     }
 
     fn parse_array<R: Read>(
@@ -85,16 +120,91 @@ impl RespParser {
         stream: &mut R,
         buf: &mut [u8; BUF_SIZE],
     ) -> Result<Value, io::Error> {
-        let len = self.parse_len(stream.bytes(), buf)?;
+        let len = self.parse_len(stream, buf)?;
         if len == -1 {
             return Ok(NullArray);
         }
-        let mut vec = Vec::with_capacity(len as usize);
+        Ok(Array(self.parse_elements(stream, len)?))
+    }
+
+    fn parse_aggregate<R: Read>(
+        &self,
+        stream: &mut R,
+        buf: &mut [u8; BUF_SIZE],
+    ) -> Result<Vec<Value>, io::Error> {
+        let len = self.parse_len(stream, buf)?;
+        self.parse_elements(stream, len)
+    }
+
+    fn parse_elements<R: Read>(&self, stream: &mut R, len: i32) -> Result<Vec<Value>, io::Error> {
+        if len < 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut vec = Vec::with_capacity((len as usize).min(BUF_SIZE));
 
         for _ in 0..len {
             vec.push(self.parse(stream)?)
         }
-        Ok(Array(vec))
+        Ok(vec)
+    }
+
+    fn parse_pairs<R: Read>(
+        &self,
+        stream: &mut R,
+        buf: &mut [u8; BUF_SIZE],
+    ) -> Result<Vec<(Value, Value)>, io::Error> {
+        let len = self.parse_len(stream, buf)?;
+        if len < 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut pairs = Vec::with_capacity((len as usize).min(BUF_SIZE));
+        for _ in 0..len {
+            let key = self.parse(stream)?;
+            let value = self.parse(stream)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+
+    fn parse_attribute<R: Read>(
+        &self,
+        stream: &mut R,
+        buf: &mut [u8; BUF_SIZE],
+    ) -> Result<Value, io::Error> {
+        let attributes = self.parse_pairs(stream, buf)?;
+        let value = self.parse(stream)?;
+        Ok(Attribute(attributes, Box::new(value)))
+    }
+
+    fn parse_null(&self, stream: &mut impl Read) -> Result<Value, io::Error> {
+        match &self.read_until_crlf(stream)?[..] {
+            "" => Ok(Null),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn parse_boolean(&self, stream: &mut impl Read) -> Result<Value, io::Error> {
+        match &self.read_until_crlf(stream)?[..] {
+            "t" => Ok(Boolean(true)),
+            "f" => Ok(Boolean(false)),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn parse_double(&self, stream: &mut impl Read) -> Result<Value, io::Error> {
+        match self.read_until_crlf(stream)?.parse::<f64>() {
+            Ok(double) => Ok(Double(double)),
+            Err(_) => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn parse_big_number(&self, stream: &mut impl Read) -> Result<Value, io::Error> {
+        let number = self.read_until_crlf(stream)?;
+        let digits = number.strip_prefix('-').unwrap_or(&number);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        Ok(BigNumber(number))
     }
 
     fn parse_error(&self, stream: &mut impl Read) -> Result<Value, io::Error> {
@@ -105,40 +215,40 @@ impl RespParser {
         }
     }
 
-    fn parse_len<T>(
+    fn parse_len(
         &self,
-        mut stream: std::io::Bytes<T>,
+        stream: &mut impl Read,
         buf: &mut [u8; BUF_SIZE],
-    ) -> Result<i32, io::Error>
-    where
-        T: io::Read,
-    {
+    ) -> Result<i32, io::Error> {
         let mut len = 0usize;
-        while let Some(byte) = stream.next() {
-            let byte = byte?;
-            if matches!(byte, b'0'..=b'9' | b'-') {
-                buf[len] = byte;
-                len += 1;
-            } else {
+        loop {
+            let byte = self.read_byte(stream)?;
+            if byte == b'\r' {
                 break;
             }
+            if !matches!(byte, b'0'..=b'9' | b'-') || len == BUF_SIZE {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            buf[len] = byte;
+            len += 1;
+        }
+        if self.read_byte(stream)? != b'\n' {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
         }
-        stream.next();
 
-        let array_len_str_rep = String::from_utf8(buf[..len].to_vec()).unwrap();
-        match array_len_str_rep.parse() {
+        let len_str_rep = std::str::from_utf8(&buf[..len]).unwrap();
+        match len_str_rep.parse() {
             Ok(len) => Ok(len),
             Err(_) => Err(io::Error::from(io::ErrorKind::InvalidInput)),
         }
     }
 
     fn read_until_crlf(&self, stream: &mut impl Read) -> Result<String, io::Error> {
-        let mut result = String::new();
-        let mut stream = stream.bytes();
+        let mut result = vec![];
         let mut found_cr = false;
 
-        while let Some(byte) = stream.next() {
-            let byte = byte?;
+        loop {
+            let byte = self.read_byte(stream)?;
             if !found_cr && byte == b'\r' {
                 found_cr = true;
                 continue;
@@ -147,17 +257,30 @@ impl RespParser {
                 break;
             }
             if found_cr {
-                result.push('\r');
-                found_cr = false;
+                result.push(b'\r');
+                found_cr = byte == b'\r';
+                if found_cr {
+                    continue;
+                }
             }
-            result.push(byte as char);
+            result.push(byte);
         }
-        Ok(result)
+        Ok(String::from_utf8_lossy(&result).into_owned())
     }
 
-    fn skip_crlf(&self, stream: &mut impl Read) {
-        stream.bytes().next();
-        stream.bytes().next();
+    fn skip_crlf(&self, stream: &mut impl Read) -> Result<(), io::Error> {
+        let mut crlf = [0u8; 2];
+        stream.read_exact(&mut crlf)?;
+        match &crlf {
+            b"\r\n" => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn read_byte(&self, stream: &mut impl Read) -> Result<u8, io::Error> {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        Ok(byte[0])
     }
 }
 
@@ -187,7 +310,7 @@ mod tests {
         let mut input = Cursor::new("$11\r\nhello world\r\n");
         let result = RespParser::new().parse(&mut input);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), BulkString(String::from("hello world")));
+        assert_eq!(result.unwrap(), BulkString(Bytes::from("hello world")));
     }
 
     #[test]
@@ -206,8 +329,8 @@ mod tests {
         assert_eq!(
             result.unwrap(),
             Array(vec![
-                BulkString(String::from("hello")),
-                BulkString(String::from("world")),
+                BulkString(Bytes::from("hello")),
+                BulkString(Bytes::from("world")),
                 Integer(-150)
             ])
         );
@@ -226,7 +349,7 @@ mod tests {
         let mut input = Cursor::new("$0\r\n\r\n");
         let result = RespParser::new().parse(&mut input);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), BulkString(String::from("")));
+        assert_eq!(result.unwrap(), BulkString(Bytes::from("")));
     }
 
     #[test]
@@ -244,4 +367,83 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), NullArray);
     }
+
+    #[test]
+    fn parse_incomplete_input() {
+        for input in [
+            "",
+            "*2\r\n$5\r\nhello\r\n",
+            "$11\r\nhello",
+            "+OK",
+            "$5\r\nhello\r",
+        ] {
+            let result = RespParser::new().parse(&mut Cursor::new(input));
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn parse_malformed_input() {
+        for input in ["?\r\n", "$abc\r\n", "$3\r\nhelloo\r\n"] {
+            let result = RespParser::new().parse(&mut Cursor::new(input));
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn parse_binary_bulk_string() {
+        let mut input = Cursor::new(&b"$4\r\n\xff\r\n\x00\r\n"[..]);
+        let result = RespParser::new().parse(&mut input);
+        assert_eq!(
+            result.unwrap(),
+            BulkString(Bytes::from_static(&[0xff, b'\r', b'\n', 0x00]))
+        );
+    }
+
+    #[test]
+    fn parse_resp3_scalars() {
+        let parser = RespParser::new();
+        let parse = |input: &str| parser.parse(&mut Cursor::new(input)).unwrap();
+        assert_eq!(parse("_\r\n"), Null);
+        assert_eq!(parse("#t\r\n"), Boolean(true));
+        assert_eq!(parse("#f\r\n"), Boolean(false));
+        assert_eq!(parse(",1.23\r\n"), Double(1.23));
+        assert_eq!(parse(",-inf\r\n"), Double(f64::NEG_INFINITY));
+        assert_eq!(
+            parse("(-12345678901234567890\r\n"),
+            BigNumber(String::from("-12345678901234567890"))
+        );
+        assert_eq!(
+            parse("=15\r\ntxt:Some string\r\n"),
+            Verbatim(String::from("txt"), Bytes::from("Some string"))
+        );
+    }
+
+    #[test]
+    fn parse_resp3_aggregates() {
+        let parser = RespParser::new();
+        let parse = |input: &str| parser.parse(&mut Cursor::new(input)).unwrap();
+        assert_eq!(
+            parse("%2\r\n+first\r\n:1\r\n+second\r\n#f\r\n"),
+            Map(vec![
+                (SimpleString(String::from("first")), Integer(1)),
+                (SimpleString(String::from("second")), Boolean(false)),
+            ])
+        );
+        assert_eq!(parse("~2\r\n:1\r\n_\r\n"), Set(vec![Integer(1), Null]));
+        assert_eq!(
+            parse(">2\r\n+message\r\n$2\r\nhi\r\n"),
+            Push(vec![
+                SimpleString(String::from("message")),
+                BulkString(Bytes::from("hi"))
+            ])
+        );
+        assert_eq!(
+            parse("|1\r\n+ttl\r\n:10\r\n*1\r\n:1\r\n"),
+            Attribute(
+                vec![(SimpleString(String::from("ttl")), Integer(10))],
+                Box::new(Array(vec![Integer(1)]))
+            )
+        );
+    }
 }
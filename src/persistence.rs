@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use bytes::Bytes;
+
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::rdb::Snapshot;
+use crate::store::Store;
+
+/// Keeps track of the snapshots written to disk.
+pub struct Persistence {
+    /// Whether a snapshot is being written, which only one may be at a time.
+    saving: AtomicBool,
+    /// When the last snapshot was written, or the server started, in Unix seconds.
+    last_save: AtomicI64,
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Self {
+            saving: AtomicBool::new(false),
+            last_save: AtomicI64::new(unix_time_millis() / 1000),
+        }
+    }
+
+    /// Claims the right to write a snapshot, returning false if one is
+    /// already being written.
+    pub fn start_save(&self) -> bool {
+        self.saving
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Releases the claim taken with [`Persistence::start_save`], noting the
+    /// time if the snapshot was written.
+    pub fn finish_save(&self, saved: bool) {
+        if saved {
+            self.last_save
+                .store(unix_time_millis() / 1000, Ordering::Relaxed);
+        }
+        self.saving.store(false, Ordering::Release);
+    }
+
+    pub fn last_save(&self) -> i64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Copies every live key out of `store`. Keys are read one at a time, so
+/// callers hold the transaction gate exclusively for the copy to be the
+/// dataset at a single point in time.
+pub fn snapshot<S: Store<Bytes, DataFrame<Data>>>(store: &S) -> Snapshot {
+    let mut entries = vec![];
+    store.for_each(|key, df| {
+        if df.data().is_some() && !df.has_expired() {
+            entries.push((key.clone(), df.clone()));
+        }
+    });
+    entries
+}
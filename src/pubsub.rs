@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, Notify};
+
+use crate::glob;
+use crate::value::Value;
+
+/// How many messages may wait for a subscriber before it is considered too
+/// slow and disconnected.
+pub const SUBSCRIBER_QUEUE_CAPACITY: usize = 1024;
+
+/// The sending end of a subscriber's message queue, kept by the registry for
+/// every channel and pattern the subscriber listens to.
+#[derive(Clone)]
+pub struct Subscriber {
+    sender: mpsc::Sender<Value>,
+    /// Signalled when the queue overflows.
+    overflow: Arc<Notify>,
+}
+
+/// The receiving end of a subscriber's message queue.
+pub struct Inbox {
+    receiver: mpsc::Receiver<Value>,
+    overflow: Arc<Notify>,
+}
+
+/// Creates a subscriber with an empty queue.
+pub fn subscriber() -> (Subscriber, Inbox) {
+    let (sender, receiver) = mpsc::channel(SUBSCRIBER_QUEUE_CAPACITY);
+    let overflow = Arc::new(Notify::new());
+    let subscriber = Subscriber {
+        sender,
+        overflow: Arc::clone(&overflow),
+    };
+    (subscriber, Inbox { receiver, overflow })
+}
+
+impl Inbox {
+    /// Waits for the next message, or returns `None` once the queue has
+    /// overflowed and the subscriber has to be disconnected.
+    pub async fn next(&mut self) -> Option<Value> {
+        tokio::select! {
+            biased;
+            _ = self.overflow.notified() => None,
+            message = self.receiver.recv() => message,
+        }
+    }
+
+    /// Completes once the queue has overflowed.
+    pub async fn overflowed(&self) {
+        self.overflow.notified().await
+    }
+}
+
+/// Keeps track of which clients listen to which channels and patterns, and
+/// delivers published messages to them.
+#[derive(Default)]
+pub struct PubSub {
+    inner: Mutex<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Subscribers by channel, then by client id.
+    channels: HashMap<Bytes, HashMap<u64, Subscriber>>,
+    /// Subscribers by glob pattern, then by client id.
+    patterns: HashMap<Bytes, HashMap<u64, Subscriber>>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self, channel: Bytes, id: u64, subscriber: &Subscriber) {
+        let mut registry = self.inner.lock().unwrap();
+        let subscribers = registry.channels.entry(channel).or_default();
+        subscribers.insert(id, subscriber.clone());
+    }
+
+    pub fn unsubscribe(&self, channel: &Bytes, id: u64) {
+        remove(&mut self.inner.lock().unwrap().channels, channel, id);
+    }
+
+    pub fn psubscribe(&self, pattern: Bytes, id: u64, subscriber: &Subscriber) {
+        let mut registry = self.inner.lock().unwrap();
+        let subscribers = registry.patterns.entry(pattern).or_default();
+        subscribers.insert(id, subscriber.clone());
+    }
+
+    pub fn punsubscribe(&self, pattern: &Bytes, id: u64) {
+        remove(&mut self.inner.lock().unwrap().patterns, pattern, id);
+    }
+
+    /// Queues `message` for everyone subscribed to `channel` or to a
+    /// pattern matching it, and returns how many deliveries were queued.
+    /// Subscribers whose queue is full are told to disconnect instead.
+    pub fn publish(&self, channel: &Bytes, message: &Bytes) -> usize {
+        let registry = self.inner.lock().unwrap();
+        let mut delivered = 0;
+        if let Some(subscribers) = registry.channels.get(channel) {
+            let message = Value::Push(vec![
+                Value::BulkString(Bytes::from_static(b"message")),
+                Value::BulkString(channel.clone()),
+                Value::BulkString(message.clone()),
+            ]);
+            for subscriber in subscribers.values() {
+                delivered += deliver(subscriber, message.clone()) as usize;
+            }
+        }
+        for (pattern, subscribers) in &registry.patterns {
+            if !glob::matches(pattern, channel) {
+                continue;
+            }
+            let message = Value::Push(vec![
+                Value::BulkString(Bytes::from_static(b"pmessage")),
+                Value::BulkString(pattern.clone()),
+                Value::BulkString(channel.clone()),
+                Value::BulkString(message.clone()),
+            ]);
+            for subscriber in subscribers.values() {
+                delivered += deliver(subscriber, message.clone()) as usize;
+            }
+        }
+        delivered
+    }
+
+    /// The channels with at least one subscriber, optionally only those
+    /// matching a glob `pattern`.
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        let registry = self.inner.lock().unwrap();
+        registry
+            .channels
+            .keys()
+            .filter(|channel| match pattern {
+                None => true,
+                Some(pattern) => glob::matches(pattern, channel),
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The number of clients subscribed to `channel`, not counting patterns.
+    pub fn subscriber_count(&self, channel: &Bytes) -> usize {
+        let registry = self.inner.lock().unwrap();
+        registry.channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    /// The number of patterns with at least one subscriber.
+    pub fn pattern_count(&self) -> usize {
+        self.inner.lock().unwrap().patterns.len()
+    }
+}
+
+fn remove(subscriptions: &mut HashMap<Bytes, HashMap<u64, Subscriber>>, name: &Bytes, id: u64) {
+    if let Some(subscribers) = subscriptions.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            subscriptions.remove(name);
+        }
+    }
+}
+
+/// Queues `message` for `subscriber`, signalling it to disconnect if its
+/// queue is full.
+fn deliver(subscriber: &Subscriber, message: Value) -> bool {
+    match subscriber.sender.try_send(message) {
+        Ok(()) => true,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            subscriber.overflow.notify_one();
+            false
+        }
+        // The client is disconnecting and about to unsubscribe.
+        Err(mpsc::error::TrySendError::Closed(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn messages_reach_channel_and_pattern_subscribers() {
+        let pubsub = PubSub::new();
+        let (subscriber, mut inbox) = subscriber();
+        pubsub.subscribe(Bytes::from_static(b"news"), 1, &subscriber);
+        pubsub.psubscribe(Bytes::from_static(b"n*"), 1, &subscriber);
+        let delivered = pubsub.publish(&Bytes::from_static(b"news"), &Bytes::from_static(b"hi"));
+        assert_eq!(delivered, 2);
+        assert!(matches!(inbox.next().await, Some(Value::Push(message)) if message.len() == 3));
+        assert!(matches!(inbox.next().await, Some(Value::Push(message)) if message.len() == 4));
+        pubsub.unsubscribe(&Bytes::from_static(b"news"), 1);
+        pubsub.punsubscribe(&Bytes::from_static(b"n*"), 1);
+        assert!(pubsub.channels(None).is_empty());
+        assert_eq!(pubsub.pattern_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn overflowing_the_queue_disconnects_the_subscriber() {
+        let pubsub = PubSub::new();
+        let (subscriber, mut inbox) = subscriber();
+        let channel = Bytes::from_static(b"news");
+        pubsub.subscribe(channel.clone(), 1, &subscriber);
+        for _ in 0..SUBSCRIBER_QUEUE_CAPACITY {
+            assert_eq!(pubsub.publish(&channel, &Bytes::new()), 1);
+        }
+        assert_eq!(pubsub.publish(&channel, &Bytes::new()), 0);
+        assert!(inbox.next().await.is_none());
+    }
+}
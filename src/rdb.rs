@@ -0,0 +1,944 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use bytes::Bytes;
+
+use crate::consumer_group::ConsumerGroup;
+use crate::crc64;
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::listpack::{self, Element};
+use crate::server::REDIS_VERSION;
+use crate::sorted_set::SortedSet;
+use crate::stream::{Entry, Stream, StreamId};
+
+/// The format version written, that of Redis 7.2.
+const VERSION: u32 = 11;
+/// The newest format version read. Version 12 only adds types for hash
+/// fields with their own deadlines, which fail to load as unknown types.
+const MAX_VERSION: u32 = 12;
+/// The oldest format version with a checksum at the end.
+const CHECKSUM_VERSION: u32 = 5;
+
+const OPCODE_SLOT_INFO: u8 = 0xf4;
+const OPCODE_FUNCTION: u8 = 0xf5;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// Quicklist nodes holding a single element as is, or a listpack of them.
+const QUICKLIST_PLAIN: u64 = 1;
+const QUICKLIST_PACKED: u64 = 2;
+
+const STREAM_ENTRY_DELETED: i64 = 1;
+/// The entry has the same fields as the master entry of its node, so only
+/// its values are stored.
+const STREAM_ENTRY_SAMEFIELDS: i64 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RdbError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not an RDB file")]
+    NotRdb,
+    #[error("unsupported RDB version {0}")]
+    Version(u32),
+    #[error("unexpected end of file")]
+    Truncated,
+    #[error("wrong checksum")]
+    Checksum,
+    #[error("unsupported value type {0}")]
+    UnsupportedType(u8),
+    #[error("corrupt {0}")]
+    Corrupt(&'static str),
+}
+
+type Result<T> = std::result::Result<T, RdbError>;
+
+/// Keys with their values and deadlines, as written to a snapshot.
+pub type Snapshot = Vec<(Bytes, DataFrame<Data>)>;
+
+/// Reads the snapshot at `path`, or returns `None` if there is none.
+pub fn load(path: &Path) -> Result<Option<Snapshot>> {
+    match fs::read(path) {
+        Ok(input) => decode(&input).map(Some),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes a snapshot of `entries` to `path`, replacing the previous one only
+/// once the new one is safely on disk.
+pub fn save(path: &Path, entries: &[(Bytes, DataFrame<Data>)]) -> io::Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(&encode(entries))?;
+    file.sync_all()?;
+    fs::rename(temp, path)
+}
+
+/// Serializes `entries` in the RDB format, keeping deadlines as absolute
+/// Unix times in milliseconds and ending with a CRC-64 of everything before.
+pub fn encode(entries: &[(Bytes, DataFrame<Data>)]) -> Vec<u8> {
+    encode_with_base_flag(entries, false)
+}
+
+/// Like [`encode`], but marked as the base of an append-only file, which
+/// the logged commands follow.
+pub fn encode_aof_base(entries: &[(Bytes, DataFrame<Data>)]) -> Vec<u8> {
+    encode_with_base_flag(entries, true)
+}
+
+fn encode_with_base_flag(entries: &[(Bytes, DataFrame<Data>)], aof_base: bool) -> Vec<u8> {
+    let mut out = format!("REDIS{VERSION:04}").into_bytes();
+    let ctime = (unix_time_millis() / 1000).to_string();
+    for (field, value) in [
+        ("redis-ver", REDIS_VERSION),
+        ("redis-bits", "64"),
+        ("ctime", &ctime),
+        ("aof-base", if aof_base { "1" } else { "0" }),
+    ] {
+        out.push(OPCODE_AUX);
+        write_string(&mut out, field.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
+    out.push(OPCODE_SELECTDB);
+    write_len(&mut out, 0);
+    let expiring = entries.iter().filter(|(_, df)| df.deadline().is_some());
+    out.push(OPCODE_RESIZEDB);
+    write_len(&mut out, entries.len() as u64);
+    write_len(&mut out, expiring.count() as u64);
+    for (key, df) in entries {
+        let Some(data) = df.data() else {
+            continue;
+        };
+        if let Some(deadline) = df.deadline() {
+            out.push(OPCODE_EXPIRETIME_MS);
+            out.extend(deadline.to_le_bytes());
+        }
+        out.push(value_type(data));
+        write_string(&mut out, key);
+        write_value(&mut out, data);
+    }
+    out.push(OPCODE_EOF);
+    let checksum = crc64::checksum(&out);
+    out.extend(checksum.to_le_bytes());
+    out
+}
+
+/// Serializes a single value the way `DUMP` does: as a snapshot lays the
+/// value out, followed by the format version and a CRC-64 of everything
+/// before it.
+pub fn dump(data: &Data) -> Vec<u8> {
+    let mut out = vec![value_type(data)];
+    write_value(&mut out, data);
+    out.extend((VERSION as u16).to_le_bytes());
+    let checksum = crc64::checksum(&out);
+    out.extend(checksum.to_le_bytes());
+    out
+}
+
+/// Reads a value serialized by [`dump`], or by a Redis version whose format
+/// this reads.
+pub fn undump(payload: &[u8]) -> Result<Data> {
+    let Some(body_len) = payload.len().checked_sub(10) else {
+        return Err(RdbError::Truncated);
+    };
+    let (body, footer) = payload.split_at(body_len);
+    let version = u16::from_le_bytes([footer[0], footer[1]]) as u32;
+    if version > MAX_VERSION {
+        return Err(RdbError::Version(version));
+    }
+    let expected = u64::from_le_bytes(footer[2..].try_into().expect("8 bytes"));
+    if expected != 0 && expected != crc64::checksum(&payload[..body_len + 2]) {
+        return Err(RdbError::Checksum);
+    }
+    let mut reader = Reader {
+        input: body,
+        pos: 0,
+    };
+    let value_type = reader.byte()?;
+    let data = reader.value(value_type)?;
+    if reader.pos != body.len() {
+        return Err(RdbError::Corrupt("value"));
+    }
+    Ok(data)
+}
+
+/// The type each value is written as: the plain encodings, which every
+/// Redis version reads and packs as it sees fit.
+fn value_type(data: &Data) -> u8 {
+    match data {
+        Data::String(_) => TYPE_STRING,
+        Data::List(_) => TYPE_LIST,
+        Data::Set(_) => TYPE_SET,
+        Data::SortedSet(_) => TYPE_ZSET_2,
+        Data::Hash(_) => TYPE_HASH,
+        Data::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, data: &Data) {
+    match data {
+        Data::String(string) => write_string(out, string),
+        Data::List(list) => {
+            write_len(out, list.len() as u64);
+            list.iter().for_each(|element| write_string(out, element));
+        }
+        Data::Set(set) => {
+            write_len(out, set.len() as u64);
+            set.iter().for_each(|member| write_string(out, member));
+        }
+        Data::SortedSet(set) => {
+            write_len(out, set.len() as u64);
+            for (member, score) in set.range(0..set.len(), false) {
+                write_string(out, member);
+                out.extend(score.to_le_bytes());
+            }
+        }
+        Data::Hash(hash) => {
+            write_len(out, hash.len() as u64);
+            for (field, value) in hash {
+                write_string(out, field);
+                write_string(out, value);
+            }
+        }
+        Data::Stream(stream) => write_stream(out, stream),
+    }
+}
+
+/// Writes a stream the way Redis lays it out: a listpack per block keyed by
+/// the ID of its first entry, then the stream's counters and its consumer
+/// groups with their pending entries.
+fn write_stream(out: &mut Vec<u8>, stream: &Stream) {
+    let blocks: Vec<Vec<Entry>> = stream.blocks().collect();
+    write_len(out, blocks.len() as u64);
+    for entries in &blocks {
+        let master = entries[0].id;
+        write_string(out, &raw_id(master));
+        write_string(out, &listpack::encode(&stream_node(master, entries)));
+    }
+    write_len(out, stream.len() as u64);
+    write_id(out, stream.last_id());
+    write_id(out, stream.first_id());
+    write_id(out, stream.max_deleted_id());
+    write_len(out, stream.entries_added());
+    write_len(out, stream.groups().len() as u64);
+    for (name, group) in stream.groups() {
+        write_string(out, name);
+        write_id(out, group.last_delivered);
+        // An unknown count is written as -1.
+        write_len(out, group.entries_read.unwrap_or(u64::MAX));
+        write_len(out, group.pending().len() as u64);
+        for (id, entry) in group.pending() {
+            out.extend(raw_id(*id));
+            out.extend(entry.delivered_at.to_le_bytes());
+            write_len(out, entry.deliveries);
+        }
+        write_len(out, group.consumers().len() as u64);
+        for (name, consumer) in group.consumers() {
+            write_string(out, name);
+            out.extend(consumer.seen_at.to_le_bytes());
+            out.extend(consumer.active_at.unwrap_or(-1).to_le_bytes());
+            write_len(out, consumer.pending().len() as u64);
+            for id in consumer.pending() {
+                out.extend(raw_id(*id));
+            }
+        }
+    }
+}
+
+/// The listpack elements of a stream node: a master entry holding the field
+/// names of the first entry, then every entry with its ID relative to the
+/// master ID, leaving out the field names when they match the master's.
+fn stream_node(master: StreamId, entries: &[Entry]) -> Vec<Element> {
+    let master_fields: Vec<&Bytes> = entries[0].fields.iter().step_by(2).collect();
+    let mut elements = vec![
+        Element::Int(entries.len() as i64),
+        // No deleted entries are kept around.
+        Element::Int(0),
+        Element::Int(master_fields.len() as i64),
+    ];
+    elements.extend(
+        master_fields
+            .iter()
+            .map(|&field| Element::String(field.clone())),
+    );
+    elements.push(Element::Int(0));
+    for entry in entries {
+        let fields = entry.fields.len() as i64 / 2;
+        let same = entry
+            .fields
+            .iter()
+            .step_by(2)
+            .eq(master_fields.iter().copied());
+        let flags = if same { STREAM_ENTRY_SAMEFIELDS } else { 0 };
+        elements.extend([
+            Element::Int(flags),
+            Element::Int(entry.id.ms.wrapping_sub(master.ms) as i64),
+            Element::Int(entry.id.seq.wrapping_sub(master.seq) as i64),
+        ]);
+        if same {
+            let values = entry.fields.iter().skip(1).step_by(2);
+            elements.extend(values.map(|value| Element::String(value.clone())));
+            elements.push(Element::Int(fields + 3));
+        } else {
+            elements.push(Element::Int(fields));
+            let pairs = entry.fields.iter();
+            elements.extend(pairs.map(|bytes| Element::String(bytes.clone())));
+            elements.push(Element::Int(2 * fields + 4));
+        }
+    }
+    elements
+}
+
+fn write_len(out: &mut Vec<u8>, len: u64) {
+    match len {
+        0..=0x3f => out.push(len as u8),
+        0x40..=0x3fff => out.extend([0x40 | (len >> 8) as u8, len as u8]),
+        0x4000..=0xffff_ffff => {
+            out.push(0x80);
+            out.extend((len as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0x81);
+            out.extend(len.to_be_bytes());
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, string: &[u8]) {
+    write_len(out, string.len() as u64);
+    out.extend_from_slice(string);
+}
+
+fn write_id(out: &mut Vec<u8>, id: StreamId) {
+    write_len(out, id.ms);
+    write_len(out, id.seq);
+}
+
+/// An ID as 16 big-endian bytes, which sort like the IDs themselves.
+fn raw_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+/// Deserializes a snapshot, checking its checksum unless it was written
+/// without one. Keys of every database end up in the one keyspace, and
+/// keys whose deadline has passed are kept for the caller to drop.
+pub fn decode(input: &[u8]) -> Result<Snapshot> {
+    decode_prefix(input).map(|(entries, _)| entries)
+}
+
+/// Like [`decode`], but for a snapshot other data may follow, as the base of
+/// an append-only file. Also returns the length of the snapshot.
+pub fn decode_prefix(input: &[u8]) -> Result<(Snapshot, usize)> {
+    let mut reader = Reader { input, pos: 0 };
+    let header = reader.take(9).map_err(|_| RdbError::NotRdb)?;
+    let version = match header.strip_prefix(b"REDIS") {
+        Some(version) => std::str::from_utf8(version)
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        None => None,
+    };
+    let version = version.ok_or(RdbError::NotRdb)?;
+    if !(1..=MAX_VERSION).contains(&version) {
+        return Err(RdbError::Version(version));
+    }
+    let mut entries = vec![];
+    let mut deadline = None;
+    loop {
+        match reader.byte()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => {
+                reader.len()?;
+            }
+            OPCODE_RESIZEDB => {
+                reader.len()?;
+                reader.len()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.len()?;
+                }
+            }
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            // There are no functions to load the libraries into.
+            OPCODE_FUNCTION => {
+                reader.string()?;
+            }
+            OPCODE_EXPIRETIME_MS => deadline = Some(reader.millis()?),
+            OPCODE_EXPIRETIME => {
+                let seconds = i32::from_le_bytes(reader.array()?);
+                deadline = Some(seconds as i64 * 1000);
+            }
+            // Eviction hints, which have no use here.
+            OPCODE_IDLE => {
+                reader.len()?;
+            }
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_MODULE_AUX => return Err(RdbError::UnsupportedType(OPCODE_MODULE_AUX)),
+            value_type => {
+                let key = reader.string()?;
+                let data = reader.value(value_type)?;
+                // Empty collections are dropped, as Redis does.
+                if !data.is_empty() {
+                    let df = match deadline {
+                        Some(deadline) => DataFrame::with_deadline(data, deadline),
+                        None => DataFrame::plain(data),
+                    };
+                    entries.push((key, df));
+                }
+                deadline = None;
+            }
+        }
+    }
+    if version >= CHECKSUM_VERSION {
+        let end = reader.pos;
+        let expected = u64::from_le_bytes(reader.array()?);
+        // Redis writes a zero checksum when checksums are turned off.
+        if expected != 0 && expected != crc64::checksum(&input[..end]) {
+            return Err(RdbError::Checksum);
+        }
+    }
+    Ok((entries, reader.pos))
+}
+
+/// A length or, for strings, a special encoding of the string.
+enum Length {
+    Len(u64),
+    Special(u8),
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: u64) -> Result<&'a [u8]> {
+        let rest = &self.input[self.pos..];
+        if len > rest.len() as u64 {
+            return Err(RdbError::Truncated);
+        }
+        self.pos += len as usize;
+        Ok(&rest[..len as usize])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N as u64)?.try_into().expect("took N bytes"))
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn millis(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn length(&mut self) -> Result<Length> {
+        let first = self.byte()?;
+        let len = match (first >> 6, first) {
+            (0, _) => (first & 0x3f) as u64,
+            (1, _) => ((first as u64 & 0x3f) << 8) | self.byte()? as u64,
+            (2, 0x80) => u32::from_be_bytes(self.array()?) as u64,
+            (2, 0x81) => u64::from_be_bytes(self.array()?),
+            (2, _) => return Err(RdbError::Corrupt("length")),
+            _ => return Ok(Length::Special(first & 0x3f)),
+        };
+        Ok(Length::Len(len))
+    }
+
+    fn len(&mut self) -> Result<u64> {
+        match self.length()? {
+            Length::Len(len) => Ok(len),
+            Length::Special(_) => Err(RdbError::Corrupt("length")),
+        }
+    }
+
+    /// A string, which may have been written as an integer or compressed.
+    fn string(&mut self) -> Result<Bytes> {
+        let int = match self.length()? {
+            Length::Len(len) => return Ok(Bytes::copy_from_slice(self.take(len)?)),
+            Length::Special(0) => i8::from_le_bytes(self.array()?) as i64,
+            Length::Special(1) => i16::from_le_bytes(self.array()?) as i64,
+            Length::Special(2) => i32::from_le_bytes(self.array()?) as i64,
+            Length::Special(3) => {
+                let compressed = self.len()?;
+                let len = self.len()?;
+                let compressed = self.take(compressed)?;
+                return lzf_decompress(compressed, len)
+                    .map(Bytes::from)
+                    .ok_or(RdbError::Corrupt("compressed string"));
+            }
+            Length::Special(_) => return Err(RdbError::Corrupt("string encoding")),
+        };
+        Ok(Bytes::from(int.to_string()))
+    }
+
+    fn strings(&mut self) -> Result<Vec<Bytes>> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+
+    fn listpack(&mut self) -> Result<Vec<Element>> {
+        listpack::decode(&self.string()?).ok_or(RdbError::Corrupt("listpack"))
+    }
+
+    /// A score written as text, with its length standing in for the
+    /// special values.
+    fn text_score(&mut self) -> Result<f64> {
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(self.take(len as u64)?),
+        }
+    }
+
+    fn id(&mut self) -> Result<StreamId> {
+        Ok(StreamId::new(self.len()?, self.len()?))
+    }
+
+    fn raw_id(&mut self) -> Result<StreamId> {
+        Ok(id_from_raw(self.array()?))
+    }
+
+    fn value(&mut self, value_type: u8) -> Result<Data> {
+        let data = match value_type {
+            TYPE_STRING => Data::String(self.string()?),
+            TYPE_LIST => Data::List(self.strings()?.into()),
+            TYPE_SET => Data::Set(self.strings()?.into_iter().collect()),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let mut set = SortedSet::new();
+                for _ in 0..self.len()? {
+                    let member = self.string()?;
+                    let score = match value_type {
+                        TYPE_ZSET => self.text_score()?,
+                        _ => f64::from_le_bytes(self.array()?),
+                    };
+                    set.insert(member, score);
+                }
+                Data::SortedSet(set)
+            }
+            TYPE_HASH => {
+                let mut hash = HashMap::new();
+                for _ in 0..self.len()? {
+                    hash.insert(self.string()?, self.string()?);
+                }
+                Data::Hash(hash)
+            }
+            TYPE_SET_INTSET => {
+                let intset = self.string()?;
+                let members = intset_members(&intset).ok_or(RdbError::Corrupt("intset"))?;
+                Data::Set(members)
+            }
+            TYPE_SET_LISTPACK => {
+                let members = self.listpack()?.into_iter().map(Element::into_bytes);
+                Data::Set(members.collect())
+            }
+            TYPE_HASH_LISTPACK => {
+                let mut elements = self.listpack()?.into_iter().map(Element::into_bytes);
+                let mut hash = HashMap::new();
+                while let Some(field) = elements.next() {
+                    let value = elements.next().ok_or(RdbError::Corrupt("hash"))?;
+                    hash.insert(field, value);
+                }
+                Data::Hash(hash)
+            }
+            TYPE_ZSET_LISTPACK => {
+                let mut elements = self.listpack()?.into_iter();
+                let mut set = SortedSet::new();
+                while let Some(member) = elements.next() {
+                    let score = match elements.next() {
+                        Some(Element::Int(score)) => score as f64,
+                        Some(Element::String(score)) => parse_score(&score)?,
+                        None => return Err(RdbError::Corrupt("sorted set")),
+                    };
+                    set.insert(member.into_bytes(), score);
+                }
+                Data::SortedSet(set)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.len()? {
+                    let container = self.len()?;
+                    match container {
+                        QUICKLIST_PLAIN => list.push_back(self.string()?),
+                        QUICKLIST_PACKED => {
+                            let elements = self.listpack()?.into_iter();
+                            list.extend(elements.map(Element::into_bytes));
+                        }
+                        _ => return Err(RdbError::Corrupt("quicklist node")),
+                    }
+                }
+                Data::List(list)
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                Data::Stream(self.stream(value_type)?)
+            }
+            value_type => return Err(RdbError::UnsupportedType(value_type)),
+        };
+        Ok(data)
+    }
+
+    /// Reads a stream laid out by [`write_stream`], or by older versions
+    /// that kept fewer counters.
+    fn stream(&mut self, value_type: u8) -> Result<Stream> {
+        let mut stream = Stream::new();
+        for _ in 0..self.len()? {
+            let key = self.string()?;
+            let master = match <[u8; 16]>::try_from(&key[..]) {
+                Ok(raw) => id_from_raw(raw),
+                Err(_) => return Err(RdbError::Corrupt("stream node key")),
+            };
+            let entries =
+                stream_entries(master, self.listpack()?).ok_or(RdbError::Corrupt("stream node"))?;
+            for (id, fields) in entries {
+                if id <= stream.last_id() {
+                    return Err(RdbError::Corrupt("stream entry order"));
+                }
+                stream.add(id, &fields);
+            }
+        }
+        let len = self.len()?;
+        let last_id = self.id()?;
+        let (max_deleted_id, entries_added) = match value_type {
+            TYPE_STREAM_LISTPACKS => (StreamId::MIN, len),
+            _ => {
+                let _first_id = self.id()?;
+                (self.id()?, self.len()?)
+            }
+        };
+        stream.restore_counters(last_id, entries_added, max_deleted_id);
+        for _ in 0..self.len()? {
+            let name = self.string()?;
+            let last_delivered = self.id()?;
+            let entries_read = match value_type {
+                TYPE_STREAM_LISTPACKS => None,
+                _ => Some(self.len()?).filter(|read| *read != u64::MAX),
+            };
+            let mut group = ConsumerGroup::new(last_delivered, entries_read);
+            let mut pending = BTreeMap::new();
+            for _ in 0..self.len()? {
+                let id = self.raw_id()?;
+                pending.insert(id, (self.millis()?, self.len()?));
+            }
+            for _ in 0..self.len()? {
+                let consumer = self.string()?;
+                let seen_at = self.millis()?;
+                let active_at = match value_type {
+                    TYPE_STREAM_LISTPACKS_3 => Some(self.millis()?).filter(|at| *at != -1),
+                    // The best guess older versions leave.
+                    _ => Some(seen_at),
+                };
+                group.touch_consumer(&consumer, seen_at);
+                if let Some(consumer) = group.consumer_mut(&consumer) {
+                    consumer.active_at = active_at;
+                }
+                for _ in 0..self.len()? {
+                    let id = self.raw_id()?;
+                    let Some((delivered_at, deliveries)) = pending.remove(&id) else {
+                        return Err(RdbError::Corrupt("consumer pending entry"));
+                    };
+                    group.assign(id, &consumer, delivered_at, deliveries);
+                }
+            }
+            if !stream.create_group(name, group) {
+                return Err(RdbError::Corrupt("duplicate consumer group"));
+            }
+        }
+        Ok(stream)
+    }
+}
+
+type NodeEntry = (StreamId, Vec<(Bytes, Bytes)>);
+
+/// The entries of a stream node laid out as [`stream_node`] does, leaving
+/// out deleted ones.
+fn stream_entries(master: StreamId, elements: Vec<Element>) -> Option<Vec<NodeEntry>> {
+    let mut elements = elements.into_iter();
+    let int = |elements: &mut std::vec::IntoIter<Element>| elements.next()?.as_int();
+    let _count = int(&mut elements)?;
+    let _deleted = int(&mut elements)?;
+    let master_fields = int(&mut elements)?;
+    let master_fields: Vec<Bytes> = (0..master_fields)
+        .map(|_| elements.next().map(Element::into_bytes))
+        .collect::<Option<_>>()?;
+    // The master entry ends with a zero.
+    int(&mut elements)?;
+    let mut entries = vec![];
+    while let Some(flags) = elements.next() {
+        let flags = flags.as_int()?;
+        let ms = master.ms.wrapping_add(int(&mut elements)? as u64);
+        let seq = master.seq.wrapping_add(int(&mut elements)? as u64);
+        let fields = if flags & STREAM_ENTRY_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Some((field.clone(), elements.next()?.into_bytes())))
+                .collect::<Option<Vec<_>>>()?
+        } else {
+            (0..int(&mut elements)?)
+                .map(|_| {
+                    let field = elements.next()?.into_bytes();
+                    Some((field, elements.next()?.into_bytes()))
+                })
+                .collect::<Option<Vec<_>>>()?
+        };
+        // How many elements the entry took, for walking the node backwards.
+        int(&mut elements)?;
+        if flags & STREAM_ENTRY_DELETED == 0 {
+            entries.push((StreamId::new(ms, seq), fields));
+        }
+    }
+    Some(entries)
+}
+
+fn id_from_raw(raw: [u8; 16]) -> StreamId {
+    let (ms, seq) = raw.split_at(8);
+    let ms = u64::from_be_bytes(ms.try_into().expect("8 bytes"));
+    StreamId::new(ms, u64::from_be_bytes(seq.try_into().expect("8 bytes")))
+}
+
+fn parse_score(score: &[u8]) -> Result<f64> {
+    let score = std::str::from_utf8(score).ok().and_then(|s| s.parse().ok());
+    score.ok_or(RdbError::Corrupt("score"))
+}
+
+/// The members of an intset: the width of its integers, their count, and
+/// the integers themselves, all little-endian.
+fn intset_members(intset: &[u8]) -> Option<HashSet<Bytes>> {
+    let width = u32::from_le_bytes(intset.get(..4)?.try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(intset.get(4..8)?.try_into().ok()?) as usize;
+    if !matches!(width, 2 | 4 | 8) || intset.len() != 8 + width * len {
+        return None;
+    }
+    let members = intset[8..].chunks(width).map(|int| {
+        let mut bytes = [0; 8];
+        bytes[8 - width..].copy_from_slice(int);
+        let int = i64::from_le_bytes(bytes) >> (8 * (8 - width));
+        Bytes::from(int.to_string())
+    });
+    Some(members.collect())
+}
+
+/// Expands a string compressed with LZF, which Redis compresses long
+/// strings in snapshots with, into the `len` bytes it was made from.
+fn lzf_decompress(input: &[u8], len: u64) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len.min(input.len() as u64 * 8) as usize);
+    let mut pos = 0;
+    while pos < input.len() {
+        let control = input[pos] as usize;
+        pos += 1;
+        if control < 32 {
+            // A run of literal bytes.
+            let literal = input.get(pos..pos + control + 1)?;
+            out.extend_from_slice(literal);
+            pos += control + 1;
+            continue;
+        }
+        // A back reference to bytes already written.
+        let mut count = control >> 5;
+        if count == 7 {
+            count += *input.get(pos)? as usize;
+            pos += 1;
+        }
+        let offset = ((control & 0x1f) << 8) + *input.get(pos)? as usize + 1;
+        pos += 1;
+        let start = out.len().checked_sub(offset)?;
+        for index in start..start + count + 2 {
+            out.push(out[index]);
+        }
+    }
+    (out.len() as u64 == len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::StreamId;
+
+    fn pairs(pairs: &[(&'static str, &'static str)]) -> Vec<(Bytes, Bytes)> {
+        pairs
+            .iter()
+            .map(|(field, value)| (Bytes::from(*field), Bytes::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn every_type_round_trips() {
+        let mut list = VecDeque::new();
+        list.extend([Bytes::from("a"), Bytes::from(vec![b'b'; 20000])]);
+        let mut set = SortedSet::new();
+        set.insert(Bytes::from("low"), -1.5);
+        set.insert(Bytes::from("high"), f64::INFINITY);
+        let mut stream = Stream::new();
+        for ms in 1..=250 {
+            let fields = match ms % 3 {
+                0 => pairs(&[("other", "x")]),
+                _ => pairs(&[("temp", "20"), ("unit", "C")]),
+            };
+            stream.add(StreamId::new(ms, ms % 2), &fields);
+        }
+        stream.remove(StreamId::new(100, 0));
+        let mut group = ConsumerGroup::new(StreamId::new(5, 1), Some(5));
+        group.touch_consumer(&Bytes::from("alice"), 1000);
+        group.touch_consumer(&Bytes::from("bob"), 2000);
+        group.assign(StreamId::new(3, 1), &Bytes::from("alice"), 1500, 2);
+        group.assign(StreamId::new(4, 0), &Bytes::from("bob"), 2500, 1);
+        stream.create_group(Bytes::from("readers"), group);
+        stream.create_group(Bytes::from("idle"), ConsumerGroup::new(StreamId::MIN, None));
+
+        let entries = vec![
+            (
+                Bytes::from("string"),
+                DataFrame::plain(Data::String(Bytes::from("value"))),
+            ),
+            (
+                Bytes::from("expiring"),
+                DataFrame::with_deadline(Data::String(Bytes::new()), 4102444800000),
+            ),
+            (Bytes::from("list"), DataFrame::plain(Data::List(list))),
+            (
+                Bytes::from("set"),
+                DataFrame::plain(Data::Set([Bytes::from("m")].into_iter().collect())),
+            ),
+            (
+                Bytes::from("hash"),
+                DataFrame::plain(Data::Hash(pairs(&[("f", "v")]).into_iter().collect())),
+            ),
+            (Bytes::from("zset"), DataFrame::plain(Data::SortedSet(set))),
+            (
+                Bytes::from("empty stream"),
+                DataFrame::plain(Data::Stream(Stream::new())),
+            ),
+        ];
+        let mut all = entries.clone();
+        all.push((
+            Bytes::from("stream"),
+            DataFrame::plain(Data::Stream(stream.clone())),
+        ));
+        let encoded = encode(&all);
+        assert!(encoded.starts_with(b"REDIS0011"));
+        let mut decoded = decode(&encoded).unwrap();
+        let Some(Data::Stream(restored)) = decoded.pop().and_then(|(_, df)| df.into_data()) else {
+            panic!("the stream was not restored");
+        };
+        assert!(decoded == entries);
+        // The entries are packed into blocks afresh, so only what the
+        // stream holds is compared.
+        let all = |stream: &Stream| stream.range(StreamId::MIN, StreamId::MAX, None);
+        assert_eq!(all(&restored), all(&stream));
+        assert_eq!(restored.last_id(), stream.last_id());
+        assert_eq!(restored.entries_added(), stream.entries_added());
+        assert_eq!(restored.max_deleted_id(), StreamId::new(100, 0));
+        assert_eq!(restored.groups(), stream.groups());
+    }
+
+    #[test]
+    fn damage_is_detected() {
+        let entries = vec![(
+            Bytes::from("key"),
+            DataFrame::plain(Data::String(Bytes::from("value"))),
+        )];
+        let mut encoded = encode(&entries);
+        let value = encoded.len() - 12;
+        encoded[value] ^= 1;
+        assert!(matches!(decode(&encoded), Err(RdbError::Checksum)));
+        assert!(matches!(
+            decode(&encoded[..encoded.len() - 20]),
+            Err(RdbError::Truncated)
+        ));
+        assert!(matches!(decode(b"REDIS"), Err(RdbError::NotRdb)));
+        assert!(matches!(
+            decode(b"REDIS0099\xff"),
+            Err(RdbError::Version(99))
+        ));
+    }
+
+    #[test]
+    fn dumped_values_round_trip() {
+        let data = Data::Hash(pairs(&[("f", "v"), ("g", "w")]).into_iter().collect());
+        let payload = dump(&data);
+        assert!(undump(&payload).unwrap() == data);
+        // The DUMP of the integer string "10" in the Redis documentation.
+        let redis = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+        assert!(undump(redis).unwrap() == Data::String(Bytes::from("10")));
+
+        let mut damaged = payload.clone();
+        damaged[1] ^= 1;
+        assert!(matches!(undump(&damaged), Err(RdbError::Checksum)));
+        assert!(matches!(undump(&payload[..5]), Err(RdbError::Truncated)));
+    }
+
+    #[test]
+    fn compact_encodings_are_read() {
+        let mut input = b"REDIS0003".to_vec();
+        // An integer-encoded string.
+        input.extend([TYPE_STRING, 1, b'n', 0xc1, 0x39, 0x30]);
+        // An intset of two 16-bit members.
+        input.extend([
+            TYPE_SET_INTSET,
+            1,
+            b's',
+            12,
+            2,
+            0,
+            0,
+            0,
+            2,
+            0,
+            0,
+            0,
+            0xff,
+            0xff,
+            7,
+            0,
+        ]);
+        // "aaaaaaaaaa" compressed: a literal "a", then 9 bytes from one back.
+        input.extend([TYPE_STRING, 1, b'z', 0xc3, 5, 10, 0, b'a', 0xe0, 0, 0]);
+        input.push(OPCODE_EOF);
+        let decoded = decode(&input).unwrap();
+        assert!(
+            decoded[0]
+                == (
+                    Bytes::from("n"),
+                    DataFrame::plain(Data::String(Bytes::from("12345")))
+                )
+        );
+        let members = [Bytes::from("-1"), Bytes::from("7")].into_iter().collect();
+        assert!(decoded[1] == (Bytes::from("s"), DataFrame::plain(Data::Set(members))));
+        let string = Data::String(Bytes::from("aaaaaaaaaa"));
+        assert!(decoded[2] == (Bytes::from("z"), DataFrame::plain(string)));
+    }
+}
@@ -0,0 +1,566 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use rand::Rng;
+use tokio::sync::{watch, MutexGuard};
+
+use crate::value::Value;
+
+/// The smallest backlog Redis allows, which `repl-backlog-size` is raised to.
+pub const MIN_BACKLOG_SIZE: usize = 16 * 1024;
+
+/// Where this server stands in replication, as a primary or a replica.
+///
+/// Writes are streamed to replicas through the backlog: a primary appends
+/// every write to it as it logs the write, and a replica appends the stream
+/// it gets from its primary as it applies it. Offsets count the bytes of the
+/// stream since its replication ID started it, so a replica that reconnects
+/// with the ID and the offset it got to resumes where it left off, as long
+/// as the backlog still holds the bytes it missed.
+pub struct Replication {
+    /// Whether this server follows a primary.
+    replica: AtomicBool,
+    /// Whether writes are appended to the backlog, which a primary does from
+    /// when the first replica syncs with it.
+    logging: AtomicBool,
+    state: Mutex<State>,
+    /// The offset the stream got to, watched by the tasks that send it on.
+    progress: watch::Sender<u64>,
+    /// Bumped whenever replicas have to reconnect or the primary changes.
+    changes: watch::Sender<u64>,
+    /// Bumped whenever a replica acknowledges an offset.
+    acks: watch::Sender<u64>,
+    /// Held while a command from the primary is applied and appended to the
+    /// backlog, and while a replica is synced, so that the two always agree.
+    applying: tokio::sync::Mutex<()>,
+}
+
+struct State {
+    replid: String,
+    /// The ID of the stream this one continues, after a replica was
+    /// promoted, and the offset up to which the two are the same.
+    previous: Option<(String, u64)>,
+    offset: u64,
+    backlog: Option<Backlog>,
+    /// Bumped when the stream sent so far no longer holds, so that replicas
+    /// reconnect.
+    epoch: u64,
+    /// The primary this server follows, if it is a replica.
+    primary: Option<Primary>,
+    replicas: Vec<ReplicaInfo>,
+    /// The port this server listens on, which it tells its primary.
+    port: u16,
+}
+
+struct Primary {
+    host: String,
+    port: u16,
+    link: Link,
+    /// Tells the task that keeps the link up whether it still follows the
+    /// same primary.
+    id: u64,
+}
+
+/// The state of a replica's link to its primary, as `ROLE` names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Connect,
+    Connecting,
+    Sync,
+    Connected,
+}
+
+impl Link {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Connecting => "connecting",
+            Self::Sync => "sync",
+            Self::Connected => "connected",
+        }
+    }
+}
+
+/// A replica streamed to, as `ROLE` lists it.
+struct ReplicaInfo {
+    id: u64,
+    ip: String,
+    port: u16,
+    /// The offset the replica last acknowledged.
+    offset: u64,
+}
+
+/// The primary a replica follows, as the task that keeps the link up sees it.
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub id: u64,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self {
+            replica: AtomicBool::new(false),
+            logging: AtomicBool::new(false),
+            state: Mutex::new(State {
+                replid: random_id(),
+                previous: None,
+                offset: 0,
+                backlog: None,
+                epoch: 0,
+                primary: None,
+                replicas: vec![],
+                port: 0,
+            }),
+            progress: watch::channel(0).0,
+            changes: watch::channel(0).0,
+            acks: watch::channel(0).0,
+            applying: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.replica.load(Ordering::Acquire)
+    }
+
+    /// Whether writes are streamed to replicas, which takes them being
+    /// logged one at a time.
+    pub fn is_logging(&self) -> bool {
+        self.logging.load(Ordering::Acquire)
+    }
+
+    pub fn set_port(&self, port: u16) {
+        self.state.lock().unwrap().port = port;
+    }
+
+    pub fn port(&self) -> u16 {
+        self.state.lock().unwrap().port
+    }
+
+    /// The replication ID and the offset the stream got to.
+    pub fn position(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    }
+
+    /// Appends a write to the stream, if it is streamed.
+    pub fn feed(&self, command: &[Bytes]) {
+        if !self.is_logging() {
+            return;
+        }
+        let mut buffer = vec![];
+        let command = command.iter().cloned().map(Value::BulkString).collect();
+        Value::Array(command)
+            .encode(&mut buffer)
+            .expect("Error while streaming a command");
+        self.append(&buffer);
+    }
+
+    /// Appends part of the stream a replica got from its primary.
+    pub fn feed_raw(&self, data: &[u8]) {
+        self.append(data);
+    }
+
+    fn append(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.offset += data.len() as u64;
+        if let Some(backlog) = state.backlog.as_mut() {
+            backlog.append(data);
+        }
+        self.progress.send_replace(state.offset);
+    }
+
+    /// Keeps the stream in a backlog of `size` bytes from now on, and starts
+    /// streaming writes if this is a primary. Returns the offset and the
+    /// epoch a replica that syncs now starts from. The caller holds every
+    /// client up, so the two match the dataset.
+    pub fn start_backlog(&self, size: usize) -> (u64, u64) {
+        let mut state = self.state.lock().unwrap();
+        let offset = state.offset;
+        state
+            .backlog
+            .get_or_insert_with(|| Backlog::new(size, offset));
+        if !self.is_replica() {
+            self.logging.store(true, Ordering::Release);
+        }
+        (offset, state.epoch)
+    }
+
+    pub fn resize_backlog(&self, size: usize) {
+        if let Some(backlog) = self.state.lock().unwrap().backlog.as_mut() {
+            backlog.resize(size);
+        }
+    }
+
+    /// Where a replica that asks to continue the stream `replid` from
+    /// `offset`, the first byte it lacks counting from 1, resumes, with the
+    /// epoch it resumes in. `None` means it has to sync from scratch.
+    pub fn resume(&self, replid: &[u8], offset: i64) -> Option<(u64, u64)> {
+        let state = self.state.lock().unwrap();
+        let from = u64::try_from(offset).ok()?.checked_sub(1)?;
+        let known = replid == state.replid.as_bytes()
+            || state
+                .previous
+                .as_ref()
+                .is_some_and(|(previous, end)| replid == previous.as_bytes() && from <= *end);
+        let backlog = state.backlog.as_ref()?;
+        if !known || from < backlog.start() || from > state.offset {
+            return None;
+        }
+        Some((from, state.epoch))
+    }
+
+    /// The stream from offset `from` on, or `None` if a replica reading from
+    /// there in `epoch` has to reconnect, because the stream it had no longer
+    /// holds or it fell further behind than the backlog reaches.
+    pub fn read_backlog(&self, from: u64, epoch: u64) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return None;
+        }
+        state.backlog.as_ref()?.read(from)
+    }
+
+    /// Watches the offset the stream got to.
+    pub fn progress(&self) -> watch::Receiver<u64> {
+        self.progress.subscribe()
+    }
+
+    /// Watches for replicas acknowledging offsets.
+    pub fn acks(&self) -> watch::Receiver<u64> {
+        self.acks.subscribe()
+    }
+
+    /// Watches for replicas having to reconnect and the primary changing.
+    pub fn changes(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// Holds off commands from the primary.
+    pub async fn applying(&self) -> MutexGuard<'_, ()> {
+        self.applying.lock().await
+    }
+
+    /// Starts following the primary at `host`, unless this server already
+    /// does. Replicas streamed to reconnect, as the stream changes hands.
+    /// Returns false if nothing changed.
+    pub fn follow(&self, host: String, port: u16) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(primary) = &state.primary {
+            if primary.host == host && primary.port == port {
+                return false;
+            }
+        }
+        let id = state.primary.as_ref().map_or(0, |primary| primary.id) + 1;
+        state.primary = Some(Primary {
+            host,
+            port,
+            link: Link::Connect,
+            id,
+        });
+        state.epoch += 1;
+        self.replica.store(true, Ordering::Release);
+        self.logging.store(false, Ordering::Release);
+        self.changes.send_modify(|changes| *changes += 1);
+        true
+    }
+
+    /// Stops following the primary and takes over the stream under a new
+    /// replication ID. Replicas that followed the same primary can go on
+    /// from where they got to, as the new stream continues the old one.
+    pub fn promote(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.primary.take().is_none() {
+            return;
+        }
+        let previous = std::mem::replace(&mut state.replid, random_id());
+        state.previous = Some((previous, state.offset));
+        let offset = state.offset;
+        state
+            .backlog
+            .get_or_insert_with(|| Backlog::new(MIN_BACKLOG_SIZE, offset));
+        self.logging.store(true, Ordering::Release);
+        self.replica.store(false, Ordering::Release);
+        self.changes.send_modify(|changes| *changes += 1);
+    }
+
+    /// The primary to follow, if any.
+    pub fn target(&self) -> Option<Target> {
+        let state = self.state.lock().unwrap();
+        state.primary.as_ref().map(|primary| Target {
+            host: primary.host.clone(),
+            port: primary.port,
+            id: primary.id,
+        })
+    }
+
+    /// Whether the primary with `id` is still the one to follow.
+    pub fn follows(&self, id: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .primary
+            .as_ref()
+            .is_some_and(|primary| primary.id == id)
+    }
+
+    pub fn set_link(&self, id: u64, link: Link) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(primary) = state.primary.as_mut().filter(|primary| primary.id == id) {
+            primary.link = link;
+        }
+    }
+
+    /// Whether the link to the primary is up, which it has to be for this
+    /// replica to stream to replicas of its own.
+    pub fn is_linked(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match &state.primary {
+            Some(primary) => primary.link == Link::Connected,
+            None => true,
+        }
+    }
+
+    /// Takes on the stream `replid` at `offset`, after loading the snapshot
+    /// the primary sent. Replicas streamed to reconnect, as what they had
+    /// no longer holds.
+    pub fn synced(&self, replid: String, offset: u64, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.previous = None;
+        state.offset = offset;
+        state.backlog = Some(Backlog::new(size, offset));
+        state.epoch += 1;
+        self.progress.send_replace(offset);
+        self.changes.send_modify(|changes| *changes += 1);
+    }
+
+    /// Takes on the stream `replid` after the primary agreed to continue
+    /// from where this replica got to, under a new ID if it was promoted.
+    pub fn continued(&self, replid: String) {
+        let mut state = self.state.lock().unwrap();
+        if replid != state.replid {
+            let previous = std::mem::replace(&mut state.replid, replid);
+            state.previous = Some((previous, state.offset));
+        }
+    }
+
+    pub fn add_replica(&self, id: u64, ip: String, port: u16, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replicas.push(ReplicaInfo {
+            id,
+            ip,
+            port,
+            offset,
+        });
+    }
+
+    /// Notes that the replica streamed to by client `id` applied the stream
+    /// up to `offset`.
+    pub fn ack(&self, id: u64, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.offset = replica.offset.max(offset);
+        }
+        self.acks.send_modify(|acks| *acks += 1);
+    }
+
+    /// How many replicas acknowledged `offset` or a later one.
+    pub fn acked(&self, offset: u64) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .replicas
+            .iter()
+            .filter(|replica| replica.offset >= offset)
+            .count()
+    }
+
+    pub fn remove_replica(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replicas.retain(|replica| replica.id != id);
+    }
+
+    /// The reply to `ROLE`.
+    pub fn role(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let bulk = |value: String| Value::BulkString(Bytes::from(value));
+        match &state.primary {
+            None => Value::Array(vec![
+                bulk(String::from("master")),
+                Value::Integer(state.offset as i64),
+                Value::Array(
+                    state
+                        .replicas
+                        .iter()
+                        .map(|replica| {
+                            Value::Array(vec![
+                                bulk(replica.ip.clone()),
+                                bulk(replica.port.to_string()),
+                                bulk(replica.offset.to_string()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ]),
+            Some(primary) => Value::Array(vec![
+                bulk(String::from("slave")),
+                bulk(primary.host.clone()),
+                Value::Integer(primary.port as i64),
+                bulk(String::from(primary.link.name())),
+                Value::Integer(state.offset as i64),
+            ]),
+        }
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A new replication ID or cluster node ID: 40 random hex digits.
+pub fn random_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..40)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+/// The last bytes of the stream, as many as fit in a ring of fixed size.
+struct Backlog {
+    ring: Vec<u8>,
+    /// The offset just past the last byte.
+    end: u64,
+    /// How many bytes the ring holds, which is all of it once it wrapped.
+    len: usize,
+}
+
+impl Backlog {
+    /// An empty backlog of `size` bytes for a stream at `offset`.
+    fn new(size: usize, offset: u64) -> Self {
+        Self {
+            ring: vec![0; size.max(MIN_BACKLOG_SIZE)],
+            end: offset,
+            len: 0,
+        }
+    }
+
+    /// The offset of the oldest byte held.
+    fn start(&self) -> u64 {
+        self.end - self.len as u64
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let size = self.ring.len();
+        // Only the bytes that fit survive.
+        let skipped = data.len().saturating_sub(size);
+        let mut pos = ((self.end + skipped as u64) % size as u64) as usize;
+        let mut rest = &data[skipped..];
+        while !rest.is_empty() {
+            let len = rest.len().min(size - pos);
+            self.ring[pos..pos + len].copy_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            pos = (pos + len) % size;
+        }
+        self.end += data.len() as u64;
+        self.len = (self.len + data.len()).min(size);
+    }
+
+    /// The bytes from offset `from` to the end, or `None` if the ring no
+    /// longer holds them all.
+    fn read(&self, from: u64) -> Option<Vec<u8>> {
+        if from < self.start() || from > self.end {
+            return None;
+        }
+        let size = self.ring.len();
+        let mut data = Vec::with_capacity((self.end - from) as usize);
+        let mut offset = from;
+        while offset < self.end {
+            let pos = (offset % size as u64) as usize;
+            let len = ((self.end - offset) as usize).min(size - pos);
+            data.extend_from_slice(&self.ring[pos..pos + len]);
+            offset += len as u64;
+        }
+        Some(data)
+    }
+
+    /// Changes the size of the ring, keeping the newest bytes that fit.
+    fn resize(&mut self, size: usize) {
+        let start = self.start();
+        let data = self.read(start).unwrap_or_default();
+        let mut resized = Self::new(size, start);
+        resized.append(&data);
+        *self = resized;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_keeps_the_newest_bytes_as_it_wraps() {
+        let mut backlog = Backlog::new(MIN_BACKLOG_SIZE, 100);
+        let data: Vec<u8> = (0..=255u8).cycle().take(MIN_BACKLOG_SIZE + 1000).collect();
+        backlog.append(&data[..10]);
+        assert_eq!(backlog.read(100).unwrap(), &data[..10]);
+        assert_eq!(backlog.read(105).unwrap(), &data[5..10]);
+        assert_eq!(backlog.read(110).unwrap(), b"");
+        assert_eq!(backlog.read(99), None);
+        assert_eq!(backlog.read(111), None);
+        backlog.append(&data[10..]);
+        let end = 100 + data.len() as u64;
+        assert_eq!(backlog.start(), end - MIN_BACKLOG_SIZE as u64);
+        assert_eq!(backlog.read(backlog.start() - 1), None);
+        assert_eq!(
+            backlog.read(end - 2000).unwrap(),
+            &data[data.len() - 2000..]
+        );
+        // More than fits at once.
+        let mut backlog = Backlog::new(MIN_BACKLOG_SIZE, 0);
+        backlog.append(&data[..3]);
+        backlog.append(&data);
+        assert_eq!(backlog.start(), 3 + 1000);
+        assert_eq!(backlog.read(1003).unwrap(), &data[1000..]);
+        backlog.resize(2 * MIN_BACKLOG_SIZE);
+        assert_eq!(backlog.read(1003).unwrap(), &data[1000..]);
+        backlog.append(&data[..500]);
+        let mut expected = data[1000..].to_vec();
+        expected.extend_from_slice(&data[..500]);
+        assert_eq!(backlog.read(1003).unwrap(), expected);
+    }
+
+    #[test]
+    fn replicas_resume_from_the_backlog_or_a_previous_stream() {
+        let replication = Replication::new();
+        let (replid, _) = replication.position();
+        assert_eq!(replication.resume(replid.as_bytes(), 1), None);
+        assert_eq!(replication.start_backlog(MIN_BACKLOG_SIZE), (0, 0));
+        assert!(replication.is_logging());
+        replication.feed(&[Bytes::from("PING")]);
+        let (_, offset) = replication.position();
+        assert_eq!(offset, 14);
+        assert_eq!(replication.resume(replid.as_bytes(), 1), Some((0, 0)));
+        assert_eq!(replication.resume(replid.as_bytes(), 15), Some((14, 0)));
+        assert_eq!(replication.resume(replid.as_bytes(), 16), None);
+        assert_eq!(replication.resume(b"other", 1), None);
+        assert_eq!(
+            replication.read_backlog(0, 0).unwrap(),
+            b"*1\r\n$4\r\nPING\r\n"
+        );
+        assert_eq!(replication.read_backlog(0, 1), None);
+
+        assert!(replication.follow(String::from("localhost"), 6379));
+        assert!(!replication.follow(String::from("localhost"), 6379));
+        assert!(replication.is_replica() && !replication.is_logging());
+        assert_eq!(replication.read_backlog(0, 0), None);
+        replication.promote();
+        let (promoted, _) = replication.position();
+        assert_ne!(promoted, replid);
+        assert_eq!(replication.resume(replid.as_bytes(), 15), Some((14, 1)));
+        replication.feed(&[Bytes::from("PING")]);
+        assert_eq!(replication.resume(replid.as_bytes(), 16), None);
+        assert_eq!(replication.resume(promoted.as_bytes(), 16), Some((15, 1)));
+    }
+}
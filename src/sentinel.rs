@@ -0,0 +1,815 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::replication::random_id;
+use crate::value::{bulk, Value};
+
+const DEFAULT_DOWN_AFTER: Duration = Duration::from_secs(30);
+const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(180);
+/// At most how much later than the failover timeout a sentinel tries to
+/// fail a primary over again, so that sentinels that split the vote do
+/// not keep trying at the same time.
+const MAX_DESYNC: Duration = Duration::from_secs(1);
+
+/// The host and port of an instance or sentinel.
+pub type Address = (String, u16);
+
+/// What this server knows of the primaries it monitors, when it runs as a
+/// sentinel.
+///
+/// A primary that does not answer in time is down for this sentinel
+/// (subjectively down). Once enough sentinels, the quorum, agree it is
+/// down (objectively down), one of them fails it over: the sentinels vote
+/// for a leader in a new epoch, and the leader promotes the replica that
+/// got furthest in the replication stream. Sentinels learn of each other,
+/// and of the outcome of failovers, from the hello messages they publish
+/// on every instance they monitor, the primary with the highest config
+/// epoch winning.
+pub struct Sentinel {
+    enabled: AtomicBool,
+    state: Mutex<State>,
+}
+
+struct State {
+    myid: String,
+    /// The address other sentinels reach this one at.
+    host: String,
+    port: u16,
+    /// The highest epoch seen, which a new failover starts past.
+    current_epoch: u64,
+    masters: BTreeMap<String, Master>,
+}
+
+struct Master {
+    host: String,
+    port: u16,
+    /// How many sentinels must agree the primary is down to fail it over.
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    /// The epoch of the failover that made the primary what it is.
+    config_epoch: u64,
+    last_reply: Instant,
+    sdown: bool,
+    odown: bool,
+    replicas: Vec<Replica>,
+    sentinels: Vec<Peer>,
+    /// The sentinel this one voted for to lead a failover, and in which
+    /// epoch.
+    leader: Option<(String, u64)>,
+    /// When this sentinel may next try to fail the primary over.
+    next_failover: Option<Instant>,
+}
+
+struct Replica {
+    host: String,
+    port: u16,
+    /// How far the replica got in the replication stream.
+    offset: u64,
+    last_reply: Option<Instant>,
+    /// Since when the replica says it is a primary.
+    claims_primary: Option<Instant>,
+}
+
+/// Another sentinel monitoring the same primary.
+struct Peer {
+    id: String,
+    host: String,
+    port: u16,
+    /// Whether it last said the primary is down.
+    down: bool,
+    /// The leader it last voted for, and in which epoch.
+    vote: Option<(String, u64)>,
+}
+
+/// A change in how this sentinel sees a primary, to tell clients of.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The primary went down or came back, for this sentinel or for the
+    /// quorum.
+    SDown(bool),
+    ODown(bool),
+}
+
+impl Sentinel {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            state: Mutex::new(State {
+                myid: random_id(),
+                host: String::from("127.0.0.1"),
+                port: 0,
+                current_epoch: 0,
+                masters: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Release);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Sets the address other sentinels reach this one at.
+    pub fn set_address(&self, host: String, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.host = host;
+        state.port = port;
+    }
+
+    pub fn myid(&self) -> String {
+        self.state.lock().unwrap().myid.clone()
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.state.lock().unwrap().current_epoch
+    }
+
+    /// Starts monitoring the primary at `host` and `port` under `name`.
+    pub fn monitor(
+        &self,
+        name: String,
+        host: String,
+        port: u16,
+        quorum: usize,
+    ) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.masters.contains_key(&name) {
+            return Err(String::from("ERR Duplicated master name"));
+        }
+        if quorum == 0 {
+            return Err(String::from("ERR Quorum must be 1 or greater."));
+        }
+        let master = Master {
+            host,
+            port,
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            config_epoch: 0,
+            last_reply: Instant::now(),
+            sdown: false,
+            odown: false,
+            replicas: vec![],
+            sentinels: vec![],
+            leader: None,
+            next_failover: None,
+        };
+        state.masters.insert(name, master);
+        Ok(())
+    }
+
+    /// Changes how the primary is monitored: `down-after-milliseconds`,
+    /// `failover-timeout` or `quorum`. Nothing changes unless every option
+    /// and value is valid.
+    pub fn set(&self, name: &str, options: &[(Bytes, Bytes)]) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let master = state.master_mut(name)?;
+        let invalid = |arg: &[u8]| {
+            format!(
+                "ERR Invalid argument '{}' for SENTINEL SET '{name}'",
+                String::from_utf8_lossy(arg)
+            )
+        };
+        let mut changes = Vec::with_capacity(options.len());
+        for (option, value) in options {
+            let parsed: u64 = std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&value| value > 0)
+                .ok_or_else(|| invalid(value))?;
+            let option = option.to_ascii_lowercase();
+            if !matches!(
+                &option[..],
+                b"down-after-milliseconds" | b"failover-timeout" | b"quorum"
+            ) {
+                return Err(invalid(&option));
+            }
+            changes.push((option, parsed));
+        }
+        for (option, value) in changes {
+            match &option[..] {
+                b"down-after-milliseconds" => master.down_after = Duration::from_millis(value),
+                b"failover-timeout" => master.failover_timeout = Duration::from_millis(value),
+                _ => master.quorum = value as usize,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.state.lock().unwrap().masters.keys().cloned().collect()
+    }
+
+    pub fn master_addr(&self, name: &str) -> Option<Address> {
+        let state = self.state.lock().unwrap();
+        let master = state.masters.get(name)?;
+        Some((master.host.clone(), master.port))
+    }
+
+    /// The addresses of the primary's replicas.
+    pub fn replicas(&self, name: &str) -> Vec<Address> {
+        let state = self.state.lock().unwrap();
+        state.masters.get(name).map_or(vec![], |master| {
+            master
+                .replicas
+                .iter()
+                .map(|replica| (replica.host.clone(), replica.port))
+                .collect()
+        })
+    }
+
+    /// The addresses of the other sentinels monitoring the primary.
+    pub fn sentinels(&self, name: &str) -> Vec<Address> {
+        let state = self.state.lock().unwrap();
+        state.masters.get(name).map_or(vec![], |master| {
+            master
+                .sentinels
+                .iter()
+                .map(|peer| (peer.host.clone(), peer.port))
+                .collect()
+        })
+    }
+
+    /// Every instance monitored, primaries and replicas, which hello
+    /// messages go through.
+    pub fn instances(&self) -> Vec<Address> {
+        let state = self.state.lock().unwrap();
+        let mut instances: Vec<Address> = state
+            .masters
+            .values()
+            .flat_map(|master| {
+                let replicas = master.replicas.iter();
+                std::iter::once((master.host.clone(), master.port))
+                    .chain(replicas.map(|replica| (replica.host.clone(), replica.port)))
+            })
+            .collect();
+        instances.sort();
+        instances.dedup();
+        instances
+    }
+
+    /// Notes that the primary answered, with the addresses of its replicas.
+    pub fn master_replied(&self, name: &str, replicas: Vec<Address>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(master) = state.masters.get_mut(name) else {
+            return;
+        };
+        master.last_reply = Instant::now();
+        for (host, port) in replicas {
+            let known = |replica: &Replica| replica.host == host && replica.port == port;
+            if !master.replicas.iter().any(known) && (host != master.host || port != master.port) {
+                master.replicas.push(Replica {
+                    host,
+                    port,
+                    offset: 0,
+                    last_reply: None,
+                    claims_primary: None,
+                });
+            }
+        }
+    }
+
+    /// Notes that a replica answered, having got to `offset`.
+    pub fn replica_replied(&self, name: &str, host: &str, port: u16, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(master) = state.masters.get_mut(name) else {
+            return;
+        };
+        let known = |replica: &&mut Replica| replica.host == host && replica.port == port;
+        if let Some(replica) = master.replicas.iter_mut().find(known) {
+            replica.offset = offset;
+            replica.last_reply = Some(Instant::now());
+            replica.claims_primary = None;
+        }
+    }
+
+    /// Notes that a replica says it is a primary, and tells whether it has
+    /// for long enough to be made a replica again: the primary that came
+    /// back after a failover, rather than one promoted by a failover this
+    /// sentinel did not hear of yet.
+    pub fn claims_primary(&self, name: &str, host: &str, port: u16) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(master) = state.masters.get_mut(name) else {
+            return false;
+        };
+        let down_after = master.down_after;
+        let known = |replica: &&mut Replica| replica.host == host && replica.port == port;
+        let Some(replica) = master.replicas.iter_mut().find(known) else {
+            return false;
+        };
+        replica.last_reply = Some(Instant::now());
+        let since = *replica.claims_primary.get_or_insert_with(Instant::now);
+        !master.sdown && since.elapsed() > down_after
+    }
+
+    /// Whether the primary is down for this sentinel, and the event if
+    /// that changed. Sentinels that said it was down have to say so again
+    /// once it is back up and down again.
+    pub fn check_sdown(&self, name: &str) -> (bool, Option<Event>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(master) = state.masters.get_mut(name) else {
+            return (false, None);
+        };
+        let sdown = master.last_reply.elapsed() > master.down_after;
+        if sdown == master.sdown {
+            return (sdown, None);
+        }
+        master.sdown = sdown;
+        if !sdown {
+            master.odown = false;
+            for peer in &mut master.sentinels {
+                peer.down = false;
+            }
+        }
+        (sdown, Some(Event::SDown(sdown)))
+    }
+
+    /// Notes what the sentinel at `host` and `port` replied when asked
+    /// whether the primary is down.
+    pub fn peer_replied(
+        &self,
+        name: &str,
+        host: &str,
+        port: u16,
+        down: bool,
+        vote: Option<(String, u64)>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let Some(master) = state.masters.get_mut(name) else {
+            return;
+        };
+        let known = |peer: &&mut Peer| peer.host == host && peer.port == port;
+        if let Some(peer) = master.sentinels.iter_mut().find(known) {
+            peer.down = down;
+            if vote.is_some() {
+                peer.vote = vote;
+            }
+        }
+    }
+
+    /// Whether enough sentinels agree the primary is down, and the event if
+    /// that changed.
+    pub fn check_odown(&self, name: &str) -> (bool, Option<Event>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(master) = state.masters.get_mut(name) else {
+            return (false, None);
+        };
+        let agreeing =
+            master.sentinels.iter().filter(|peer| peer.down).count() + master.sdown as usize;
+        let odown = master.sdown && agreeing >= master.quorum;
+        if odown == master.odown {
+            return (odown, None);
+        }
+        master.odown = odown;
+        (odown, Some(Event::ODown(odown)))
+    }
+
+    /// Whether a primary at `host` and `port` is down for this sentinel.
+    pub fn is_down(&self, host: &str, port: u16) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .masters
+            .values()
+            .any(|master| master.host == host && master.port == port && master.sdown)
+    }
+
+    /// Votes for `candidate` to fail over the primary at `host` and `port`
+    /// in `epoch`, unless this sentinel voted in that epoch already, and
+    /// replies with the leader voted for. Each sentinel votes once per
+    /// epoch, for the first to ask, so that at most one leader wins it.
+    pub fn vote(
+        &self,
+        host: &str,
+        port: u16,
+        epoch: u64,
+        candidate: &str,
+    ) -> Option<(String, u64)> {
+        let mut state = self.state.lock().unwrap();
+        if epoch > state.current_epoch {
+            state.current_epoch = epoch;
+        }
+        let current_epoch = state.current_epoch;
+        let myid = state.myid.clone();
+        let master = state
+            .masters
+            .values_mut()
+            .find(|master| master.host == host && master.port == port)?;
+        let voted_before = master
+            .leader
+            .as_ref()
+            .is_some_and(|(_, voted)| *voted >= epoch);
+        if !voted_before && current_epoch <= epoch {
+            master.leader = Some((candidate.to_string(), epoch));
+            // Leaves the failover to the candidate for a while.
+            if candidate != myid {
+                master.next_failover = Some(Instant::now() + master.failover_timeout);
+            }
+        }
+        master.leader.clone()
+    }
+
+    /// Starts an election to fail the primary over, if it is objectively
+    /// down and this sentinel did not try too recently, and returns its
+    /// epoch. This sentinel votes for itself.
+    pub fn start_election(&self, name: &str) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let master = state.masters.get(name)?;
+        if !master.odown || master.next_failover.is_some_and(|next| now < next) {
+            return None;
+        }
+        state.current_epoch += 1;
+        let epoch = state.current_epoch;
+        let myid = state.myid.clone();
+        let master = state.masters.get_mut(name)?;
+        let desync = rand::thread_rng().gen_range(0..=MAX_DESYNC.as_millis() as u64);
+        master.next_failover = Some(now + master.failover_timeout + Duration::from_millis(desync));
+        master.leader = Some((myid, epoch));
+        Some(epoch)
+    }
+
+    /// Whether this sentinel won the election in `epoch`: a majority of the
+    /// sentinels, and at least the quorum, voted for it.
+    pub fn elected(&self, name: &str, epoch: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        let Some(master) = state.masters.get(name) else {
+            return false;
+        };
+        let votes = master
+            .sentinels
+            .iter()
+            .filter(|peer| peer.vote.as_ref() == Some(&(state.myid.clone(), epoch)))
+            .count()
+            + 1;
+        let voters = master.sentinels.len() + 1;
+        let majority = voters / 2 + 1;
+        votes >= majority.max(master.quorum)
+    }
+
+    /// The replica to promote: among those that answered lately, the one
+    /// that got furthest in the replication stream, and the lowest address
+    /// of those that got as far.
+    pub fn best_replica(&self, name: &str) -> Option<Address> {
+        let state = self.state.lock().unwrap();
+        let master = state.masters.get(name)?;
+        master
+            .replicas
+            .iter()
+            .filter(|replica| {
+                replica
+                    .last_reply
+                    .is_some_and(|last_reply| last_reply.elapsed() <= master.down_after)
+            })
+            .min_by(|a, b| {
+                b.offset
+                    .cmp(&a.offset)
+                    .then_with(|| (&a.host, a.port).cmp(&(&b.host, b.port)))
+            })
+            .map(|replica| (replica.host.clone(), replica.port))
+    }
+
+    /// Makes the instance at `host` and `port` the primary, as of the
+    /// failover in `epoch`. The old primary is kept as a replica, to follow
+    /// the new one once it is back. Returns the old primary's address.
+    pub fn switch_master(
+        &self,
+        name: &str,
+        host: String,
+        port: u16,
+        epoch: u64,
+    ) -> Option<Address> {
+        let mut state = self.state.lock().unwrap();
+        let master = state.masters.get_mut(name)?;
+        let old = (
+            std::mem::replace(&mut master.host, host),
+            std::mem::replace(&mut master.port, port),
+        );
+        master.config_epoch = epoch;
+        master.last_reply = Instant::now();
+        master.sdown = false;
+        master.odown = false;
+        for peer in &mut master.sentinels {
+            peer.down = false;
+        }
+        let (host, port) = (master.host.clone(), master.port);
+        master
+            .replicas
+            .retain(|replica| replica.host != host || replica.port != port);
+        master.replicas.push(Replica {
+            host: old.0.clone(),
+            port: old.1,
+            offset: 0,
+            last_reply: None,
+            claims_primary: None,
+        });
+        Some(old)
+    }
+
+    /// The hello message this sentinel publishes for the primary: its own
+    /// address, ID and epoch, then the primary's name, address and config
+    /// epoch.
+    pub fn hello(&self, name: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let master = state.masters.get(name)?;
+        Some(format!(
+            "{},{},{},{},{name},{},{},{}",
+            state.host,
+            state.port,
+            state.myid,
+            state.current_epoch,
+            master.host,
+            master.port,
+            master.config_epoch
+        ))
+    }
+
+    /// Takes in a hello message from another sentinel, learning of it and
+    /// of the primary it monitors, if a failover with a higher epoch moved
+    /// it. Returns the name and the old and new address of a primary that
+    /// moved.
+    pub fn receive_hello(&self, message: &[u8]) -> Option<(String, Address, Address)> {
+        let message = std::str::from_utf8(message).ok()?;
+        let fields: Vec<&str> = message.split(',').collect();
+        let [host, port, id, current_epoch, name, master_host, master_port, config_epoch] =
+            fields[..]
+        else {
+            return None;
+        };
+        let port: u16 = port.parse().ok()?;
+        let current_epoch: u64 = current_epoch.parse().ok()?;
+        let master_port: u16 = master_port.parse().ok()?;
+        let config_epoch: u64 = config_epoch.parse().ok()?;
+        let mut state = self.state.lock().unwrap();
+        if id == state.myid {
+            return None;
+        }
+        if current_epoch > state.current_epoch {
+            state.current_epoch = current_epoch;
+        }
+        let master = state.masters.get_mut(name)?;
+        match master.sentinels.iter_mut().find(|peer| peer.id == id) {
+            Some(peer) => {
+                peer.host = host.to_string();
+                peer.port = port;
+            }
+            None => master.sentinels.push(Peer {
+                id: id.to_string(),
+                host: host.to_string(),
+                port,
+                down: false,
+                vote: None,
+            }),
+        }
+        let moved = master.host != master_host || master.port != master_port;
+        if config_epoch <= master.config_epoch || !moved {
+            return None;
+        }
+        drop(state);
+        let old = self.switch_master(name, master_host.to_string(), master_port, config_epoch)?;
+        Some((
+            name.to_string(),
+            old,
+            (master_host.to_string(), master_port),
+        ))
+    }
+
+    /// The reply to `SENTINEL MASTERS`.
+    pub fn masters(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        Value::Array(
+            state
+                .masters
+                .iter()
+                .map(|(name, master)| master.info(name))
+                .collect(),
+        )
+    }
+
+    /// The reply to `SENTINEL MASTER`.
+    pub fn master(&self, name: &str) -> Result<Value, String> {
+        let state = self.state.lock().unwrap();
+        let master = state.masters.get(name).ok_or_else(no_such_master)?;
+        Ok(master.info(name))
+    }
+
+    /// The reply to `SENTINEL REPLICAS`.
+    pub fn replicas_info(&self, name: &str) -> Result<Value, String> {
+        let state = self.state.lock().unwrap();
+        let master = state.masters.get(name).ok_or_else(no_such_master)?;
+        let replicas = master.replicas.iter().map(|replica| {
+            let up = replica
+                .last_reply
+                .is_some_and(|last_reply| last_reply.elapsed() <= master.down_after);
+            Value::Map(vec![
+                (
+                    bulk("name"),
+                    bulk(&format!("{}:{}", replica.host, replica.port)),
+                ),
+                (bulk("ip"), bulk(&replica.host)),
+                (bulk("port"), bulk(&replica.port.to_string())),
+                (
+                    bulk("flags"),
+                    bulk(if up { "slave" } else { "s_down,slave" }),
+                ),
+                (bulk("master-host"), bulk(&master.host)),
+                (bulk("master-port"), bulk(&master.port.to_string())),
+                (bulk("slave-repl-offset"), bulk(&replica.offset.to_string())),
+            ])
+        });
+        Ok(Value::Array(replicas.collect()))
+    }
+
+    /// The reply to `SENTINEL SENTINELS`.
+    pub fn sentinels_info(&self, name: &str) -> Result<Value, String> {
+        let state = self.state.lock().unwrap();
+        let master = state.masters.get(name).ok_or_else(no_such_master)?;
+        let sentinels = master.sentinels.iter().map(|peer| {
+            Value::Map(vec![
+                (bulk("name"), bulk(&peer.id)),
+                (bulk("ip"), bulk(&peer.host)),
+                (bulk("port"), bulk(&peer.port.to_string())),
+                (bulk("runid"), bulk(&peer.id)),
+                (bulk("flags"), bulk("sentinel")),
+            ])
+        });
+        Ok(Value::Array(sentinels.collect()))
+    }
+
+    /// The reply to `ROLE`.
+    pub fn role(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        Value::Array(vec![
+            bulk("sentinel"),
+            Value::Array(state.masters.keys().map(|name| bulk(name)).collect()),
+        ])
+    }
+}
+
+impl Default for Sentinel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn master_mut(&mut self, name: &str) -> Result<&mut Master, String> {
+        self.masters.get_mut(name).ok_or_else(no_such_master)
+    }
+}
+
+impl Master {
+    fn info(&self, name: &str) -> Value {
+        let mut flags = String::from("master");
+        if self.sdown {
+            flags.push_str(",s_down");
+        }
+        if self.odown {
+            flags.push_str(",o_down");
+        }
+        Value::Map(vec![
+            (bulk("name"), bulk(name)),
+            (bulk("ip"), bulk(&self.host)),
+            (bulk("port"), bulk(&self.port.to_string())),
+            (bulk("flags"), bulk(&flags)),
+            (bulk("num-slaves"), bulk(&self.replicas.len().to_string())),
+            (
+                bulk("num-other-sentinels"),
+                bulk(&self.sentinels.len().to_string()),
+            ),
+            (bulk("quorum"), bulk(&self.quorum.to_string())),
+            (
+                bulk("down-after-milliseconds"),
+                bulk(&self.down_after.as_millis().to_string()),
+            ),
+            (
+                bulk("failover-timeout"),
+                bulk(&self.failover_timeout.as_millis().to_string()),
+            ),
+            (bulk("config-epoch"), bulk(&self.config_epoch.to_string())),
+        ])
+    }
+}
+
+fn no_such_master() -> String {
+    String::from("ERR No such master with that name")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sentinel_with_replicas() -> Sentinel {
+        let sentinel = Sentinel::new();
+        let host = String::from("127.0.0.1");
+        sentinel
+            .monitor(String::from("primary"), host.clone(), 6379, 2)
+            .unwrap();
+        let replicas = vec![(host.clone(), 6380), (host.clone(), 6381), (host, 6382)];
+        sentinel.master_replied("primary", replicas);
+        sentinel
+    }
+
+    #[test]
+    fn set_changes_nothing_unless_every_option_is_valid() {
+        let sentinel = sentinel_with_replicas();
+        let options = |pairs: &[(&'static str, &'static str)]| -> Vec<(Bytes, Bytes)> {
+            pairs
+                .iter()
+                .map(|(option, value)| (Bytes::from(*option), Bytes::from(*value)))
+                .collect()
+        };
+        let quorum = |sentinel: &Sentinel| sentinel.state.lock().unwrap().masters["primary"].quorum;
+        assert_eq!(
+            sentinel.set("primary", &options(&[("quorum", "3"), ("unknown", "1")])),
+            Err(String::from(
+                "ERR Invalid argument 'unknown' for SENTINEL SET 'primary'"
+            ))
+        );
+        assert_eq!(
+            sentinel.set(
+                "primary",
+                &options(&[("quorum", "3"), ("failover-timeout", "0")])
+            ),
+            Err(String::from(
+                "ERR Invalid argument '0' for SENTINEL SET 'primary'"
+            ))
+        );
+        assert_eq!(quorum(&sentinel), 2);
+        let valid = options(&[("QUORUM", "3"), ("down-after-milliseconds", "500")]);
+        sentinel.set("primary", &valid).unwrap();
+        assert_eq!(quorum(&sentinel), 3);
+    }
+
+    #[test]
+    fn the_replica_furthest_along_is_promoted() {
+        let sentinel = sentinel_with_replicas();
+        assert_eq!(sentinel.best_replica("primary"), None);
+        sentinel.replica_replied("primary", "127.0.0.1", 6380, 10);
+        sentinel.replica_replied("primary", "127.0.0.1", 6381, 30);
+        sentinel.replica_replied("primary", "127.0.0.1", 6382, 30);
+        assert_eq!(
+            sentinel.best_replica("primary"),
+            Some((String::from("127.0.0.1"), 6381))
+        );
+
+        let old = sentinel.switch_master("primary", String::from("127.0.0.1"), 6381, 1);
+        assert_eq!(old, Some((String::from("127.0.0.1"), 6379)));
+        assert_eq!(
+            sentinel.master_addr("primary"),
+            Some((String::from("127.0.0.1"), 6381))
+        );
+        let replicas = sentinel.replicas("primary");
+        assert_eq!(replicas.len(), 3);
+        assert!(replicas.contains(&(String::from("127.0.0.1"), 6379)));
+        assert!(!replicas.contains(&(String::from("127.0.0.1"), 6381)));
+    }
+
+    #[test]
+    fn sentinels_vote_once_per_epoch() {
+        let sentinel = sentinel_with_replicas();
+        let vote = |epoch, candidate| sentinel.vote("127.0.0.1", 6379, epoch, candidate);
+        assert_eq!(vote(1, "a"), Some((String::from("a"), 1)));
+        assert_eq!(vote(1, "b"), Some((String::from("a"), 1)));
+        assert_eq!(vote(2, "b"), Some((String::from("b"), 2)));
+        assert_eq!(vote(1, "c"), Some((String::from("b"), 2)));
+        assert_eq!(sentinel.vote("127.0.0.1", 7000, 3, "c"), None);
+    }
+
+    #[test]
+    fn hellos_introduce_sentinels_and_newer_primaries() {
+        let sentinel = sentinel_with_replicas();
+        let hello = b"127.0.0.1,26380,other,1,primary,127.0.0.1,6379,0";
+        assert_eq!(sentinel.receive_hello(hello), None);
+        assert_eq!(
+            sentinel.sentinels("primary"),
+            vec![(String::from("127.0.0.1"), 26380)]
+        );
+        // A failover in a later epoch moved the primary.
+        let hello = b"127.0.0.1,26380,other,2,primary,127.0.0.1,6380,2";
+        assert_eq!(
+            sentinel.receive_hello(hello),
+            Some((
+                String::from("primary"),
+                (String::from("127.0.0.1"), 6379),
+                (String::from("127.0.0.1"), 6380)
+            ))
+        );
+        // Older news does not move it back.
+        let hello = b"127.0.0.1,26380,other,2,primary,127.0.0.1,6379,1";
+        assert_eq!(sentinel.receive_hello(hello), None);
+        assert_eq!(
+            sentinel.master_addr("primary"),
+            Some((String::from("127.0.0.1"), 6380))
+        );
+        assert_eq!(sentinel.sentinels("primary").len(), 1);
+    }
+}
@@ -1,33 +1,106 @@
+mod cluster;
+mod config;
+mod consumer_group;
+mod expire;
+mod hash;
+mod keyspace;
+mod list;
+mod migrate;
+mod notify;
+mod persistence;
+mod propagate;
+mod pubsub;
+mod replication;
+mod sentinel;
+mod set;
+mod stream;
+mod string;
+mod transaction;
+mod zset;
+
 use std::io;
 use std::io::Cursor;
-use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use std::time::Instant;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net;
-use rand;
 
-use crate::dataframe::DataFrame;
+use bytes::Bytes;
+
+use propagate::{command_args, propagation, Propagation};
+use pubsub::{allowed_while_subscribed, not_allowed_while_subscribed, Subscription};
+use replication::ReplicaSync;
+use sentinel::allowed_in_sentinel_mode;
+use transaction::{written_keys, Transaction, Watched};
+
+use crate::aof::AppendOnlyFile;
+use crate::blocking::BlockingRegistry;
+use crate::cluster::Cluster;
+use crate::config::{AppendFsync, Config, KeyspaceEvents};
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::frame::FrameDecoder;
 use crate::operation::Operation;
 use crate::operation::OperationDeducer;
-use crate::operation::SetOptions;
 use crate::operation::StandardOperationDeducer;
+use crate::operation::{Expiry, SetCondition, SetOptions};
 use crate::parse::RedisParser;
 use crate::parse::RespParser;
+use crate::persistence::Persistence;
+use crate::pubsub::PubSub;
+use crate::replication::Replication;
+use crate::sentinel::Sentinel;
 use crate::store::ConcurrentHashtable;
+use crate::store::Entries;
 use crate::store::Store;
+use crate::transaction::{SharedGate, Transactions};
+use crate::value::Protocol;
 use crate::value::Value;
 
 const CLEANER_TASK_FREQUENCY: Duration = Duration::from_millis(10);
 const CLEANER_TASK_SAMPLE_SIZE: usize = 20;
 const CLEANER_TASK_SUCCESS_FACTOR: usize = 4;
+const APPENDONLY_FSYNC_FREQUENCY: Duration = Duration::from_secs(1);
+
+/// The Redis version whose behaviour this server implements, reported by `HELLO`.
+pub const REDIS_VERSION: &str = "7.2.0";
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
 struct Context<P, D, S> {
     parser: Arc<P>,
     deducer: Arc<D>,
     store: Arc<S>,
+    blocking: Arc<BlockingRegistry>,
+    transactions: Arc<Transactions>,
+    pubsub: Arc<PubSub>,
+    config: Arc<Config>,
+    persistence: Arc<Persistence>,
+    aof: Arc<AppendOnlyFile>,
+    replication: Arc<Replication>,
+    cluster: Arc<Cluster>,
+    sentinel: Arc<Sentinel>,
+}
+
+impl<P, D, S> Clone for Context<P, D, S> {
+    fn clone(&self) -> Self {
+        Self {
+            parser: Arc::clone(&self.parser),
+            deducer: Arc::clone(&self.deducer),
+            store: Arc::clone(&self.store),
+            blocking: Arc::clone(&self.blocking),
+            transactions: Arc::clone(&self.transactions),
+            pubsub: Arc::clone(&self.pubsub),
+            config: Arc::clone(&self.config),
+            persistence: Arc::clone(&self.persistence),
+            aof: Arc::clone(&self.aof),
+            replication: Arc::clone(&self.replication),
+            cluster: Arc::clone(&self.cluster),
+            sentinel: Arc::clone(&self.sentinel),
+        }
+    }
 }
 
 unsafe impl<P, D, S> Send for Context<P, D, S>
@@ -38,53 +111,163 @@ where
 {
 }
 
+/// Per-connection state.
+struct Client {
+    id: u64,
+    protocol: Protocol,
+    /// The transaction opened by `MULTI`, if any.
+    transaction: Option<Transaction>,
+    /// The keys passed to `WATCH` since the last `EXEC`, `DISCARD` or `UNWATCH`.
+    watched: Vec<Watched>,
+    /// The channels and patterns the client listens to, if any.
+    subscription: Option<Subscription>,
+    /// Set by `PSYNC`, after which the client is a replica that is streamed to.
+    replica: Option<ReplicaSync>,
+    /// The port a replica said it listens on.
+    listening_port: Option<u16>,
+    /// Whether the client replays writes made before, from the primary or
+    /// the append-only file, which take effect wherever they land.
+    replaying: bool,
+    /// Set by `ASKING`, for the next command, or the next transaction, to
+    /// use a slot this node is importing.
+    asking: bool,
+    /// The address the client connects from, unless it replays writes.
+    addr: Option<std::net::SocketAddr>,
+}
+
+impl Client {
+    fn new() -> Self {
+        Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: Protocol::default(),
+            transaction: None,
+            watched: vec![],
+            subscription: None,
+            replica: None,
+            listening_port: None,
+            replaying: false,
+            asking: false,
+            addr: None,
+        }
+    }
+}
+
 pub struct Server<
     P = RespParser,
     D = StandardOperationDeducer,
-    S = ConcurrentHashtable<String, DataFrame<String>>,
+    S = ConcurrentHashtable<Bytes, DataFrame<Data>>,
 > {
     port: String,
     parser: Arc<P>,
     deducer: Arc<D>,
     store: Arc<S>,
+    blocking: Arc<BlockingRegistry>,
+    transactions: Arc<Transactions>,
+    pubsub: Arc<PubSub>,
+    config: Arc<Config>,
+    persistence: Arc<Persistence>,
+    aof: Arc<AppendOnlyFile>,
+    replication: Arc<Replication>,
+    cluster: Arc<Cluster>,
+    sentinel: Arc<Sentinel>,
 }
 
-impl Server<RespParser, StandardOperationDeducer, ConcurrentHashtable<String, DataFrame<String>>> {
+impl Server<RespParser, StandardOperationDeducer, ConcurrentHashtable<Bytes, DataFrame<Data>>> {
     pub fn new(port: impl Into<String>) -> Self {
         Self {
             port: port.into(),
             parser: Arc::new(RespParser::new()),
             deducer: Arc::new(StandardOperationDeducer::new()),
             store: Arc::new(ConcurrentHashtable::with_shards(100000)),
+            blocking: Arc::new(BlockingRegistry::new()),
+            transactions: Arc::new(Transactions::new()),
+            pubsub: Arc::new(PubSub::new()),
+            config: Arc::new(Config::new()),
+            persistence: Arc::new(Persistence::new()),
+            aof: Arc::new(AppendOnlyFile::new()),
+            replication: Arc::new(Replication::new()),
+            cluster: Arc::new(Cluster::new()),
+            sentinel: Arc::new(Sentinel::new()),
         }
     }
 }
 
 impl<P, D, S> Server<P, D, S>
 where
-    P: RedisParser<Cursor<String>> + 'static + Sync,
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
     D: OperationDeducer + 'static + Sync,
-    S: Store<String, DataFrame<String>> + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
 {
+    /// Sets configuration parameters, before the server starts.
+    pub fn configure(&self, parameters: &[(String, String)]) -> Result<(), String> {
+        let pairs: Vec<(&[u8], &[u8])> = parameters
+            .iter()
+            .map(|(name, value)| (name.as_bytes(), value.as_bytes()))
+            .collect();
+        self.config.set(&pairs)
+    }
+
+    /// Loads the dataset, from the append-only file if appendonly is on or
+    /// from the snapshot otherwise, then serves clients.
     pub async fn listen(&self) {
-        self.spawn_expiration_cleaner_task(CLEANER_TASK_FREQUENCY).await;
+        if self.sentinel.is_enabled() {
+            // A sentinel keeps no data.
+        } else if self.config.appendonly() {
+            if let Err(err) = self.load_appendonly().await {
+                println!("Error loading the append only file: {}", err);
+                return;
+            }
+        } else if let Err(err) = self.load() {
+            println!("Error loading snapshot: {}", err);
+            return;
+        }
         let port = &self.port;
         let addr = format!("localhost:{port}");
         let listener = net::TcpListener::bind(addr).await;
         match listener {
-            Ok(listener) => loop {
-                let stream = listener.accept().await;
+            Ok(listener) => self.accept(listener).await,
+            Err(err) => println!("Error starting server: {}", err),
+        }
+    }
 
-                let context = Context {
-                    parser: Arc::clone(&self.parser),
-                    deducer: Arc::clone(&self.deducer),
-                    store: Arc::clone(&self.store),
-                };
-                tokio::task::spawn(async move {
-                    Self::serve(context, stream).await;
-                });
-            },
-            Err(err) => println!("Error starting server: {}", err.to_string()),
+    /// Serves clients connecting to an already bound `listener`.
+    pub async fn accept(&self, listener: net::TcpListener) {
+        if let Ok(addr) = listener.local_addr() {
+            self.replication.set_port(addr.port());
+            self.cluster.set_address(addr.ip().to_string(), addr.port());
+            self.sentinel
+                .set_address(addr.ip().to_string(), addr.port());
+        }
+        self.spawn_expiration_cleaner_task(CLEANER_TASK_FREQUENCY)
+            .await;
+        self.spawn_appendonly_fsync_task(APPENDONLY_FSYNC_FREQUENCY);
+        self.spawn_replication_task();
+        self.spawn_cluster_task();
+        self.spawn_sentinel_task();
+        loop {
+            let stream = listener.accept().await;
+
+            let context = self.context();
+            tokio::task::spawn(async move {
+                Self::serve(context, stream).await;
+            });
+        }
+    }
+
+    fn context(&self) -> Context<P, D, S> {
+        Context {
+            parser: Arc::clone(&self.parser),
+            deducer: Arc::clone(&self.deducer),
+            store: Arc::clone(&self.store),
+            blocking: Arc::clone(&self.blocking),
+            transactions: Arc::clone(&self.transactions),
+            pubsub: Arc::clone(&self.pubsub),
+            config: Arc::clone(&self.config),
+            persistence: Arc::clone(&self.persistence),
+            aof: Arc::clone(&self.aof),
+            replication: Arc::clone(&self.replication),
+            cluster: Arc::clone(&self.cluster),
+            sentinel: Arc::clone(&self.sentinel),
         }
     }
 
@@ -93,141 +276,1064 @@ where
         stream: Result<(net::TcpStream, std::net::SocketAddr), io::Error>,
     ) {
         match stream {
-            Ok((mut stream, _)) => loop {
-                let input = Self::read_stream(&mut stream).await.unwrap();
-                let mut input = Cursor::new(input);
-                let token = context.parser.as_ref().parse(&mut input);
-                match token {
-                    Ok(token) => Self::handle_input(&context, token, &mut stream).await,
-                    Err(_) => break,
-                };
-            },
+            Ok((mut stream, addr)) => {
+                let mut client = Client::new();
+                client.addr = Some(addr);
+                let max_bulk_len = context.config.proto_max_bulk_len();
+                let mut decoder = FrameDecoder::with_max_bulk_len(max_bulk_len);
+                loop {
+                    let mut buf = vec![];
+                    let read = match client.subscription.as_mut() {
+                        None => stream.read_buf(decoder.buffer_mut()).await,
+                        // Subscribers also get messages pushed to them at any time.
+                        Some(subscription) => tokio::select! {
+                            read = stream.read_buf(decoder.buffer_mut()) => read,
+                            message = subscription.inbox.next() => {
+                                // No message means the subscriber fell too far behind.
+                                let Some(message) = message else { break };
+                                message
+                                    .for_protocol(client.protocol)
+                                    .encode(&mut buf)
+                                    .expect("Error while handling request");
+                                // A subscriber that stops reading must not hold up
+                                // noticing that its queue overflowed.
+                                tokio::select! {
+                                    written = stream.write_all(&buf) => {
+                                        if written.is_err() {
+                                            break;
+                                        }
+                                    }
+                                    _ = subscription.inbox.overflowed() => break,
+                                }
+                                continue;
+                            }
+                        },
+                    };
+                    match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {}
+                    }
+                    // Frames before one that cannot be read are still answered,
+                    // then the connection is closed, as the rest is garbled.
+                    let mut garbled = false;
+                    loop {
+                        match decoder.decode(context.parser.as_ref()) {
+                            Ok(Some(token)) => {
+                                Self::handle_input(&context, &mut client, token, &mut buf).await
+                            }
+                            Ok(None) => break,
+                            Err(err) => {
+                                Value::Error(format!("ERR Protocol error: {err}"))
+                                    .encode(&mut buf)
+                                    .expect("Error while handling request");
+                                garbled = true;
+                                break;
+                            }
+                        }
+                    }
+                    Self::write_appendonly(&context);
+                    if stream.write_all(&buf).await.is_err() || garbled {
+                        break;
+                    }
+                    if let Some(sync) = client.replica.take() {
+                        Self::feed_replica(&context, &mut stream, &mut decoder, &client, sync)
+                            .await;
+                        break;
+                    }
+                }
+                Self::unwatch_all(&context, &mut client);
+                Self::unsubscribe_all(&context, &mut client);
+            }
             Err(e) => {
                 println!("error: {}", e);
             }
         }
     }
 
-    async fn handle_input(context: &Context<P, D, S>, value: Value, stream: &mut net::TcpStream) {
+    async fn handle_input(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        value: Value,
+        buf: &mut Vec<u8>,
+    ) {
         let op = context.deducer.deduce_operation(&value);
-        let mut buf = vec![];
-        match op {
-            Operation::Ping => write!(buf, "{}", Value::SimpleString(String::from("PONG")))
-                .expect("Error while handling request"),
-            Operation::Echo(msg) => {
-                write!(buf, "{}", Value::BulkString(msg)).expect("Error while handling request")
+        let command = command_args(&value);
+        let asking = matches!(op, Operation::Asking);
+        let replies = match op {
+            op if context.sentinel.is_enabled() && !allowed_in_sentinel_mode(&op) => {
+                let name = command.first().map(|name| String::from_utf8_lossy(name));
+                vec![Value::Error(format!(
+                    "ERR unknown command '{}'",
+                    name.unwrap_or_default()
+                ))]
             }
-            Operation::Get(key) => Self::handle_get(&context, key, &mut buf)
-                .await
-                .expect("Error while handling get"),
-            Operation::Set(key, val, options) => {
-                Self::handle_set(context, key, val, options, &mut buf)
-                    .await
-                    .expect("Error while handling set")
+            op if client.transaction.is_some() => {
+                vec![Self::handle_in_transaction(context, client, op, command).await]
+            }
+            Operation::Subscribe(_)
+            | Operation::Unsubscribe(_)
+            | Operation::PSubscribe(_)
+            | Operation::PUnsubscribe(_) => Self::handle_subscription(context, client, op),
+            op if client.subscription.is_some()
+                && client.protocol == Protocol::Resp2
+                && !allowed_while_subscribed(&op) =>
+            {
+                vec![not_allowed_while_subscribed(&value)]
             }
-            Operation::Invalid(msg) => {
-                write!(buf, "{}", Value::Error(msg)).expect("Error while handling request")
+            Operation::ReplicaOf(target) => vec![Self::handle_replicaof(context, target).await],
+            Operation::PSync(replid, offset) => {
+                vec![Self::handle_psync(context, client, replid, offset).await]
+            }
+            op if Self::read_only(context, client, &op) => vec![read_only()],
+            op if Self::appendonly_failed(context, &op) => vec![Self::appendonly_error(context)],
+            // Logged writes take effect one at a time, and so do the reads
+            // that delete expired keys they come across, while other reads
+            // go on. Blocking commands give up their turn while they wait.
+            op if Self::propagating(context) && takes_turns(&op) => {
+                let gate = context.transactions.logged().await;
+                vec![Self::execute_or_redirect(context, client, op, command, Some(gate)).await]
+            }
+            op => {
+                let gate = context.transactions.shared().await;
+                vec![Self::execute_or_redirect(context, client, op, command, Some(gate)).await]
             }
         };
-        stream.write_all(&buf).await.unwrap();
+        if !asking && client.transaction.is_none() {
+            client.asking = false;
+        }
+        for reply in replies {
+            reply
+                .for_protocol(client.protocol)
+                .encode(buf)
+                .expect("Error while handling request");
+        }
     }
 
-    async fn handle_get(
-        context: &Context<P, D, S>,
-        key: String,
-        buf: &mut Vec<u8>,
-    ) -> Result<(), std::io::Error> {
-        let result = context.store.get(key.clone());
-        match result {
-            None => write!(buf, "{}", Value::NullBulkString),
-            Some(df) => {
-                if df.has_expired() {
-                    context.store.remove(key.clone());
-                    return write!(buf, "{}", Value::NullBulkString);
-                }
-                match df {
-                    DataFrame::Plain(data)
-                    | DataFrame::Expiring {
-                        data,
-                        expiration: _,
-                        timestamp: _,
-                    } => write!(buf, "{}", Value::BulkString(data)),
-                    DataFrame::Empty => panic!("_"), // should nevere happen
-                }
+    /// Runs a single command, deduced from `command`. `gate` is the shared
+    /// transaction gate, or `None` when `EXEC` holds the gate exclusively.
+    async fn execute<'a>(
+        context: &'a Context<P, D, S>,
+        client: &mut Client,
+        op: Operation,
+        command: Vec<Bytes>,
+        mut gate: Option<SharedGate<'a>>,
+    ) -> Value {
+        let written = written_keys(&op);
+        let propagation = if Self::propagating(context) {
+            propagation(&op, &command)
+        } else {
+            Propagation::Nothing
+        };
+        // Keys that may have gained list elements or stream entries, which
+        // clients blocked on them are waiting for.
+        let ready_key = match &op {
+            Operation::Push(key, ..) | Operation::PushX(key, ..) | Operation::LInsert(key, ..) => {
+                Some(key.clone())
+            }
+            Operation::LMove(_, destination, ..)
+            | Operation::BLMove(_, destination, ..)
+            | Operation::Rename(_, destination)
+            | Operation::RenameNx(_, destination) => Some(destination.clone()),
+            // Clients blocked in XREADGROUP learn that their group is gone.
+            Operation::XAdd(key, ..) | Operation::XGroupDestroy(key, _) => Some(key.clone()),
+            Operation::Restore(key, _) => Some(key.clone()),
+            _ => None,
+        };
+        let reply = match op {
+            // Subscribed RESP2 clients could not tell a plain PONG from a message.
+            Operation::Ping
+                if client.subscription.is_some() && client.protocol == Protocol::Resp2 =>
+            {
+                Value::Array(vec![
+                    Value::BulkString(Bytes::from_static(b"pong")),
+                    Value::BulkString(Bytes::new()),
+                ])
+            }
+            Operation::Ping => Value::SimpleString(String::from("PONG")),
+            Operation::Echo(msg) => Value::BulkString(msg),
+            Operation::Get(key) => Self::handle_get(context, key).await,
+            Operation::Set(key, val, options) => Self::handle_set(context, key, val, options).await,
+            Operation::Hello(protocol) => Self::handle_hello(context, client, protocol),
+            Operation::Push(key, values, end) => Self::handle_push(context, key, values, end).await,
+            Operation::PushX(key, values, end) => {
+                Self::handle_pushx(context, key, values, end).await
+            }
+            Operation::Pop(key, count, end) => Self::handle_pop(context, key, count, end).await,
+            Operation::LLen(key) => Self::handle_llen(context, key).await,
+            Operation::LRange(key, start, stop) => {
+                Self::handle_lrange(context, key, start, stop).await
+            }
+            Operation::LIndex(key, index) => Self::handle_lindex(context, key, index).await,
+            Operation::LSet(key, index, value) => {
+                Self::handle_lset(context, key, index, value).await
+            }
+            Operation::LTrim(key, start, stop) => {
+                Self::handle_ltrim(context, key, start, stop).await
+            }
+            Operation::LRem(key, count, value) => {
+                Self::handle_lrem(context, key, count, value).await
+            }
+            Operation::LInsert(key, position, pivot, value) => {
+                Self::handle_linsert(context, key, position, pivot, value).await
+            }
+            Operation::LMove(source, destination, from, to) => {
+                Self::handle_lmove(context, source, destination, from, to).await
+            }
+            Operation::BPop(keys, end, timeout) => {
+                Self::handle_blocking_pop(context, keys, end, timeout, &mut gate).await
+            }
+            Operation::BLMove(source, destination, from, to, timeout) => {
+                Self::handle_blmove(context, source, destination, from, to, timeout, &mut gate)
+                    .await
+            }
+            Operation::HSet(key, pairs) => Self::handle_hset(context, key, pairs).await,
+            Operation::HMSet(key, pairs) => Self::handle_hmset(context, key, pairs).await,
+            Operation::HSetNx(key, field, value) => {
+                Self::handle_hsetnx(context, key, field, value).await
+            }
+            Operation::HGet(key, field) => Self::handle_hget(context, key, field).await,
+            Operation::HMGet(key, fields) => Self::handle_hmget(context, key, fields).await,
+            Operation::HDel(key, fields) => Self::handle_hdel(context, key, fields).await,
+            Operation::HGetAll(key) => Self::handle_hgetall(context, key).await,
+            Operation::HIncrBy(key, field, increment) => {
+                Self::handle_hincrby(context, key, field, increment).await
+            }
+            Operation::HIncrByFloat(key, field, increment) => {
+                Self::handle_hincrbyfloat(context, key, field, increment).await
+            }
+            Operation::HExists(key, field) => Self::handle_hexists(context, key, field).await,
+            Operation::HKeys(key) => Self::handle_hkeys(context, key).await,
+            Operation::HVals(key) => Self::handle_hvals(context, key).await,
+            Operation::HLen(key) => Self::handle_hlen(context, key).await,
+            Operation::HStrLen(key, field) => Self::handle_hstrlen(context, key, field).await,
+            Operation::SAdd(key, members) => Self::handle_sadd(context, key, members).await,
+            Operation::SRem(key, members) => Self::handle_srem(context, key, members).await,
+            Operation::SMembers(key) => Self::handle_smembers(context, key).await,
+            Operation::SIsMember(key, member) => Self::handle_sismember(context, key, member).await,
+            Operation::SMIsMember(key, members) => {
+                Self::handle_smismember(context, key, members).await
+            }
+            Operation::SCard(key) => Self::handle_scard(context, key).await,
+            Operation::SCombine(keys, operator) => {
+                Self::handle_scombine(context, keys, operator).await
+            }
+            Operation::SCombineStore(destination, keys, operator) => {
+                Self::handle_scombinestore(context, destination, keys, operator).await
+            }
+            Operation::SMove(source, destination, member) => {
+                Self::handle_smove(context, source, destination, member).await
+            }
+            Operation::SRandMember(key, count) => {
+                Self::handle_srandmember(context, key, count).await
+            }
+            Operation::SPop(key, count) => Self::handle_spop(context, key, count).await,
+            Operation::ZAdd(key, options, pairs) => {
+                Self::handle_zadd(context, key, options, pairs).await
+            }
+            Operation::ZRange(key, options) => {
+                Self::handle_zrange(context, key, options, client.protocol).await
+            }
+            Operation::ZRank(key, member, rev, with_score) => {
+                Self::handle_zrank(context, key, member, rev, with_score).await
+            }
+            Operation::ZIncrBy(key, increment, member) => {
+                Self::handle_zincrby(context, key, increment, member).await
+            }
+            Operation::ZRem(key, members) => Self::handle_zrem(context, key, members).await,
+            Operation::ZCard(key) => Self::handle_zcard(context, key).await,
+            Operation::ZScore(key, member) => Self::handle_zscore(context, key, member).await,
+            Operation::ZPop(key, count, max) => {
+                Self::handle_zpop(context, key, count, max, client.protocol).await
+            }
+            Operation::Del(keys) => Self::handle_del(context, keys).await,
+            Operation::Exists(keys) => Self::handle_exists(context, keys).await,
+            Operation::Type(key) => Self::handle_type(context, key).await,
+            Operation::Rename(key, new_key) => {
+                Self::handle_rename(context, key, new_key, false).await
+            }
+            Operation::RenameNx(key, new_key) => {
+                Self::handle_rename(context, key, new_key, true).await
             }
+            Operation::Keys(pattern) => Self::handle_keys(context, pattern).await,
+            Operation::Expire(key, expiry, conditions) => {
+                Self::handle_expire(context, key, expiry, conditions).await
+            }
+            Operation::Ttl(key, unit) => Self::handle_ttl(context, key, unit).await,
+            Operation::ExpireTime(key, unit) => Self::handle_expiretime(context, key, unit).await,
+            Operation::Persist(key) => Self::handle_persist(context, key).await,
+            Operation::IncrBy(key, increment) => Self::handle_incrby(context, key, increment).await,
+            Operation::IncrByFloat(key, increment) => {
+                Self::handle_incrbyfloat(context, key, increment).await
+            }
+            Operation::Append(key, suffix) => Self::handle_append(context, key, suffix).await,
+            Operation::StrLen(key) => Self::handle_strlen(context, key).await,
+            Operation::GetRange(key, start, end) => {
+                Self::handle_getrange(context, key, start, end).await
+            }
+            Operation::SetRange(key, offset, patch) => {
+                Self::handle_setrange(context, key, offset, patch).await
+            }
+            Operation::GetDel(key) => Self::handle_getdel(context, key).await,
+            Operation::GetEx(key, option) => Self::handle_getex(context, key, option).await,
+            Operation::MGet(keys) => Self::handle_mget(context, keys).await,
+            Operation::MSet(pairs) => Self::handle_mset(context, pairs, false).await,
+            Operation::MSetNx(pairs) => Self::handle_mset(context, pairs, true).await,
+            Operation::Multi => Self::handle_multi(client),
+            Operation::Exec => Value::Error(String::from("ERR EXEC without MULTI")),
+            Operation::Discard => Value::Error(String::from("ERR DISCARD without MULTI")),
+            Operation::Watch(keys) => Self::handle_watch(context, client, keys),
+            Operation::Unwatch => Self::handle_unwatch(context, client),
+            // Outside transactions these never get here, see `handle_input`.
+            Operation::Subscribe(_)
+            | Operation::Unsubscribe(_)
+            | Operation::PSubscribe(_)
+            | Operation::PUnsubscribe(_)
+            | Operation::ReplicaOf(_)
+            | Operation::PSync(..) => {
+                Value::Error(String::from("ERR Command not allowed inside a transaction"))
+            }
+            Operation::Publish(channel, message) => {
+                Self::handle_publish(context, channel, message).await
+            }
+            Operation::PubSubChannels(pattern) => {
+                Self::handle_pubsub_channels(context, pattern).await
+            }
+            Operation::PubSubNumSub(channels) => {
+                Self::handle_pubsub_numsub(context, channels).await
+            }
+            Operation::PubSubNumPat => Self::handle_pubsub_numpat(context).await,
+            Operation::ConfigGet(patterns) => Self::handle_config_get(context, patterns).await,
+            Operation::ConfigSet(pairs) => Self::handle_config_set(context, pairs).await,
+            Operation::XAdd(key, options, id, pairs) => {
+                Self::handle_xadd(context, key, options, id, pairs).await
+            }
+            Operation::XRange(key, start, end, count, rev) => {
+                Self::handle_xrange(context, key, start, end, count, rev).await
+            }
+            Operation::XLen(key) => Self::handle_xlen(context, key).await,
+            Operation::XDel(key, ids) => Self::handle_xdel(context, key, ids).await,
+            Operation::XTrim(key, trim) => Self::handle_xtrim(context, key, trim).await,
+            Operation::XRead(reads, options) => {
+                Self::handle_xread(context, reads, options, client.protocol, &mut gate).await
+            }
+            Operation::XGroupCreate(key, group, from, entries_read, mkstream) => {
+                Self::handle_xgroup_create(context, key, group, from, entries_read, mkstream).await
+            }
+            Operation::XGroupSetId(key, group, from, entries_read) => {
+                Self::handle_xgroup_setid(context, key, group, from, entries_read).await
+            }
+            Operation::XGroupDestroy(key, group) => {
+                Self::handle_xgroup_destroy(context, key, group).await
+            }
+            Operation::XGroupCreateConsumer(key, group, consumer) => {
+                Self::handle_xgroup_createconsumer(context, key, group, consumer).await
+            }
+            Operation::XGroupDelConsumer(key, group, consumer) => {
+                Self::handle_xgroup_delconsumer(context, key, group, consumer).await
+            }
+            Operation::XReadGroup(group, consumer, reads, options) => {
+                let protocol = client.protocol;
+                Self::handle_xreadgroup(
+                    context, group, consumer, reads, options, protocol, &mut gate,
+                )
+                .await
+            }
+            Operation::XAck(key, group, ids) => Self::handle_xack(context, key, group, ids).await,
+            Operation::XPending(key, group, range) => {
+                Self::handle_xpending(context, key, group, range).await
+            }
+            Operation::XClaim(key, group, consumer, options) => {
+                Self::handle_xclaim(context, key, group, consumer, options).await
+            }
+            Operation::XAutoClaim(key, group, consumer, options) => {
+                Self::handle_xautoclaim(context, key, group, consumer, options).await
+            }
+            Operation::XInfoStream(key) => Self::handle_xinfo_stream(context, key).await,
+            Operation::XInfoGroups(key) => Self::handle_xinfo_groups(context, key).await,
+            Operation::XInfoConsumers(key, group) => {
+                Self::handle_xinfo_consumers(context, key, group).await
+            }
+            Operation::Save => Self::handle_save(context, &mut gate).await,
+            Operation::BgSave => Self::handle_bgsave(context, &mut gate).await,
+            Operation::LastSave => Self::handle_lastsave(context).await,
+            Operation::BgRewriteAof => Self::handle_bgrewriteaof(context).await,
+            Operation::ReplConf(pairs) => Self::handle_replconf(client, pairs),
+            Operation::Role => Self::handle_role(context),
+            Operation::Wait(numreplicas, timeout) => {
+                Self::handle_wait(context, numreplicas, timeout, &mut gate).await
+            }
+            op @ (Operation::ClusterInfo
+            | Operation::ClusterMyId
+            | Operation::ClusterNodes
+            | Operation::ClusterSlots
+            | Operation::ClusterShards
+            | Operation::ClusterKeySlot(_)
+            | Operation::ClusterMeet(..)
+            | Operation::ClusterAddSlots(_)
+            | Operation::ClusterDelSlots(_)
+            | Operation::ClusterGossip(..)
+            | Operation::ClusterSetSlot(..)
+            | Operation::ClusterCountKeysInSlot(_)
+            | Operation::ClusterGetKeysInSlot(..)) => Self::handle_cluster(context, client, op),
+            Operation::Asking => Self::handle_asking(context, client),
+            op @ (Operation::SentinelMyId
+            | Operation::SentinelMasters
+            | Operation::SentinelMaster(_)
+            | Operation::SentinelReplicas(_)
+            | Operation::SentinelSentinels(_)
+            | Operation::SentinelGetMasterAddr(_)
+            | Operation::SentinelMonitor(..)
+            | Operation::SentinelSet(..)
+            | Operation::SentinelIsMasterDownByAddr(..)) => Self::handle_sentinel(context, op),
+            Operation::Dump(key) => Self::handle_dump(context, key).await,
+            Operation::Restore(key, options) => Self::handle_restore(context, key, *options).await,
+            Operation::Migrate(options) => Self::handle_migrate(context, *options, &mut gate).await,
+            Operation::Invalid(msg) => Value::Error(msg),
+        };
+        Self::propagate_write(context, propagation, command, &reply);
+        context.transactions.touch(&written);
+        if let Some(key) = ready_key {
+            Self::serve_blocked_clients(context, key);
         }
+        drop(gate);
+        reply
     }
 
+    async fn handle_get(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::NullBulkString,
+            Some(Data::String(data)) => Value::BulkString(data.clone()),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    /// Writes a string, checking the NX/XX condition and reading the old
+    /// value for GET in the same atomic step as the write.
     async fn handle_set(
         context: &Context<P, D, S>,
-        key: String,
-        val: String,
+        key: Bytes,
+        val: Bytes,
         options: SetOptions,
-        buf: &mut Vec<u8>,
-    ) -> Result<(), std::io::Error> {
-        let df = match options.expiration {
-            Some(expiration) => DataFrame::with_expiration(val, expiration),
-            None => DataFrame::Plain(val),
+    ) -> Value {
+        let (reply, written) = Self::update_key(context, key.clone(), |entry| {
+            let previous = match entry.as_ref().and_then(|df| df.data()) {
+                None => None,
+                Some(Data::String(previous)) => Some(previous.clone()),
+                Some(_) if options.get => return (wrong_type(), false),
+                Some(_) => None,
+            };
+            let write = match options.condition {
+                None => true,
+                Some(SetCondition::Nx) => entry.is_none(),
+                Some(SetCondition::Xx) => entry.is_some(),
+            };
+            if write {
+                let deadline = match options.expiry {
+                    Some(Expiry::In(millis)) => Some(unix_time_millis().saturating_add(millis)),
+                    Some(Expiry::At(deadline)) => Some(deadline),
+                    None if options.keep_ttl => entry.as_ref().and_then(|df| df.deadline()),
+                    None => None,
+                };
+                *entry = Some(match deadline {
+                    Some(deadline) => DataFrame::with_deadline(Data::String(val), deadline),
+                    None => DataFrame::Plain(Data::String(val)),
+                });
+            }
+            let reply = match (options.get, write) {
+                (true, _) => previous.map_or(Value::NullBulkString, Value::BulkString),
+                (false, true) => Value::SimpleString(String::from("OK")),
+                (false, false) => Value::NullBulkString,
+            };
+            (reply, write)
+        });
+        if written {
+            Self::notify(context, KeyspaceEvents::STRING, "set", &key);
+            if options.expiry.is_some() {
+                Self::notify(context, KeyspaceEvents::GENERIC, "expire", &key);
+            }
+        }
+        reply
+    }
+
+    /// Runs `f` on the data stored under `key`, treating expired keys as absent.
+    fn read_key<R>(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        f: impl FnOnce(Option<&Data>) -> R,
+    ) -> R {
+        Self::read_frame(context, key, |df| f(df.and_then(|df| df.data())))
+    }
+
+    /// Like `read_key`, but gives `f` the whole frame, including its deadline.
+    fn read_frame<R>(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        f: impl FnOnce(Option<&DataFrame<Data>>) -> R,
+    ) -> R {
+        let mut expired = false;
+        let result = context.store.read_with(key.clone(), |df| {
+            expired = df.is_some_and(|df| df.has_expired());
+            f(df.filter(|_| !expired))
+        });
+        // A replica leaves deleting the key to its primary. While writes are
+        // logged, deleting it has to wait for a turn among them, unless one
+        // is free, and is otherwise left to the next write or the sweeper.
+        if !expired || context.replication.is_replica() {
+            return result;
+        }
+        let _turn = match Self::propagating(context) {
+            true => match context.transactions.try_turn() {
+                Some(turn) => Some(turn),
+                None => return result,
+            },
+            false => None,
         };
+        if context.store.remove_if(&key, |df| df.has_expired()) {
+            Self::expired(context, &key);
+        }
+        result
+    }
 
-        context.store.set(key, df);
-        write!(buf, "{}", Value::SimpleString(String::from("OK")))
+    /// Runs `f` on the entry for `key` atomically, dropping it first if it
+    /// has expired. A replica keeps the entry, as its primary decides.
+    fn update_key<R>(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        f: impl FnOnce(&mut Option<DataFrame<Data>>) -> R,
+    ) -> R {
+        let mut expired = false;
+        let replica = context.replication.is_replica();
+        let result = context.store.update_with(key.clone(), |entry| {
+            if !replica && entry.as_ref().is_some_and(|df| df.has_expired()) {
+                *entry = None;
+                expired = true;
+            }
+            f(entry)
+        });
+        if expired {
+            Self::expired(context, &key);
+        }
+        result
     }
 
-    async fn read_stream(stream: &mut net::TcpStream) -> Result<String, io::Error> {
-        let mut buf = [0u8; 512];
-        stream.read(&mut buf).await?;
-        match String::from_utf8(buf.to_vec()) {
-            Ok(s) => Ok(s),
-            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::InvalidData)),
+    /// Runs `f` on the entries for all of `keys` atomically, dropping expired
+    /// ones first, unless this is a replica. Used by commands that read or
+    /// write several keys at once.
+    fn update_keys<R>(
+        context: &Context<P, D, S>,
+        keys: Vec<Bytes>,
+        f: impl FnOnce(&mut Entries<Bytes, DataFrame<Data>>) -> R,
+    ) -> R {
+        let mut expired = vec![];
+        let replica = context.replication.is_replica();
+        let result = context.store.update_many(keys, |entries| {
+            for (key, entry) in entries.iter_mut() {
+                if !replica && entry.as_ref().is_some_and(|df| df.has_expired()) {
+                    *entry = None;
+                    expired.push(key.clone());
+                }
+            }
+            f(entries)
+        });
+        for key in &expired {
+            Self::expired(context, key);
         }
+        result
     }
 
-    async fn spawn_expiration_cleaner_task(&self, duration: Duration) {
-        use tokio::time::interval;
-        let context = Context {
-            parser: Arc::clone(&self.parser),
-            deducer: Arc::clone(&self.deducer),
-            store: Arc::clone(&self.store),
+    /// Announces that `key` expired and logs its deletion, so that replicas
+    /// and the append-only file drop it whatever their clocks say.
+    fn expired(context: &Context<P, D, S>, key: &Bytes) {
+        Self::notify(context, KeyspaceEvents::EXPIRED, "expired", key);
+        Self::propagate(context, vec![Bytes::from_static(b"DEL"), key.clone()]);
+    }
+
+    fn handle_hello(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        protocol: Option<Protocol>,
+    ) -> Value {
+        if let Some(protocol) = protocol {
+            client.protocol = protocol;
+        }
+        let version = match client.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let field = |name: &'static str| Value::BulkString(Bytes::from_static(name.as_bytes()));
+        let mode = if context.cluster.is_enabled() {
+            "cluster"
+        } else if context.sentinel.is_enabled() {
+            "sentinel"
+        } else {
+            "standalone"
         };
+        Value::Map(vec![
+            (field("server"), field("redis")),
+            (field("version"), field(REDIS_VERSION)),
+            (field("proto"), Value::Integer(version)),
+            (field("id"), Value::Integer(client.id as i64)),
+            (field("mode"), field(mode)),
+            (
+                field("role"),
+                field(if context.replication.is_replica() {
+                    "replica"
+                } else {
+                    "master"
+                }),
+            ),
+            (field("modules"), Value::Array(vec![])),
+        ])
+    }
+
+    /// Flushes the append-only file to disk every `period` under
+    /// `appendfsync everysec`.
+    fn spawn_appendonly_fsync_task(&self, period: Duration) {
+        let context = self.context();
+        tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                if !Self::propagating(&context)
+                    || context.config.appendfsync() != AppendFsync::EverySec
+                {
+                    continue;
+                }
+                let aof = Arc::clone(&context.aof);
+                let written = tokio::task::spawn_blocking(move || aof.write(true)).await;
+                if let Ok(Err(err)) = written {
+                    println!("Error writing to the append only file: {}", err);
+                }
+            }
+        });
+    }
+
+    async fn spawn_expiration_cleaner_task(&self, duration: Duration) {
+        use tokio::time::{interval, MissedTickBehavior};
+        let context = self.context();
         tokio::task::spawn(async move {
             let mut ticker = interval(duration);
+            // A pass can take longer than the period on a large keyspace, and
+            // catching up on missed ticks would keep the cleaner from yielding.
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
             loop {
                 ticker.tick().await;
                 Self::clean_expired(&context).await;
             }
-        }); 
-    } 
+        });
+    }
 
+    /// Deletes expired keys a sample at a time. Replicas leave it to their
+    /// primary, which logs a `DEL` for every key it deletes, and sentinels
+    /// keep no keys.
     async fn clean_expired(context: &Context<P, D, S>) {
+        if context.replication.is_replica() || context.sentinel.is_enabled() {
+            return;
+        }
         let mut is_done = false;
-        while ! is_done {
-
+        while !is_done {
             use rand::prelude::*;
             let mut expired_keys = vec![];
             context.store.for_each(|k, v| {
-                if let DataFrame::Expiring { data: _, expiration, timestamp } = v {
-                    expired_keys.push((k.clone(), expiration.clone(), timestamp.clone()))
+                if let Some(deadline) = v.deadline() {
+                    expired_keys.push((k.clone(), deadline))
                 }
             });
-            let mut rng = thread_rng();
-            let sampled_keys = expired_keys 
+            let sampled_keys = expired_keys
                 .into_iter()
-                .choose_multiple(&mut rng, CLEANER_TASK_SAMPLE_SIZE);
-                
-            if sampled_keys.len() < CLEANER_TASK_SAMPLE_SIZE {
-                return;
-            }
+                .choose_multiple(&mut thread_rng(), CLEANER_TASK_SAMPLE_SIZE);
+
+            // A short sample means every key with a deadline is in it, so
+            // one pass is enough. Skipping it would leave a handful of
+            // volatile keys around until something reads them, and their
+            // expirations unannounced.
+            let sampled_all = sampled_keys.len() < CLEANER_TASK_SAMPLE_SIZE;
             let mut removed_count: usize = 0;
-            for (key, expiration, timestamp) in sampled_keys {
-                if expiration > (Instant::now() - timestamp) {
+            let now = unix_time_millis();
+            for (key, deadline) in sampled_keys {
+                if deadline > now {
                     continue;
                 }
-                removed_count += context.store.remove(key) as usize;
+                // Logged writes must not come between deleting the key and
+                // logging it.
+                let _gate = context.transactions.logged().await;
+                // The key may have been written again since it was sampled.
+                if context.store.remove_if(&key, |df| df.has_expired()) {
+                    Self::expired(context, &key);
+                    removed_count += 1;
+                }
+            }
+            is_done = sampled_all
+                || removed_count <= CLEANER_TASK_SAMPLE_SIZE / CLEANER_TASK_SUCCESS_FACTOR;
+        }
+    }
+}
+
+/// Whether `op` has to take its turn among logged writes: it writes, or it
+/// reads through `update_key` or `update_keys`, which delete the expired
+/// keys they find.
+fn takes_turns(op: &Operation) -> bool {
+    !written_keys(op).is_empty()
+        || matches!(
+            op,
+            Operation::MGet(_) | Operation::SCombine(..) | Operation::GetEx(_, None)
+        )
+}
+
+fn read_only() -> Value {
+    Value::Error(String::from(
+        "READONLY You can't write against a read only replica.",
+    ))
+}
+
+fn wrong_type() -> Value {
+    Value::Error(String::from(
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    ))
+}
+
+/// The entry for `key` among those `update_keys` locked, or an error if the
+/// command did not ask for it to be.
+fn locked<'a>(
+    entries: &'a mut Entries<Bytes, DataFrame<Data>>,
+    key: &Bytes,
+) -> Result<&'a mut Option<DataFrame<Data>>, Value> {
+    entries
+        .entry(key)
+        .ok_or_else(|| Value::Error(String::from("ERR key was not locked")))
+}
+
+/// Deletes the key if it holds a collection that has become empty, as Redis
+/// never keeps empty collections around, and returns whether it did.
+fn remove_if_empty(entry: &mut Option<DataFrame<Data>>) -> bool {
+    let empty = entry
+        .as_ref()
+        .and_then(|df| df.data())
+        .is_some_and(|data| data.is_empty());
+    if empty {
+        *entry = None;
+    }
+    empty
+}
+
+/// Converts an inclusive `start..=stop` range of possibly negative indexes
+/// into positions in a sequence of `len` elements, or `None` if it selects
+/// nothing.
+fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    pub(super) use crate::value::bulk;
+
+    pub(super) fn bulks(values: &[&str]) -> Value {
+        Value::Array(values.iter().map(|value| bulk(value)).collect())
+    }
+
+    pub(super) fn ok() -> Value {
+        Value::SimpleString(String::from("OK"))
+    }
+
+    pub(super) fn error(message: &str) -> Value {
+        Value::Error(String::from(message))
+    }
+
+    pub(super) async fn start_server() -> std::net::SocketAddr {
+        start_server_with(Server::new("0")).await
+    }
+
+    /// Serves clients with `server`, set up by the caller beforehand, on a
+    /// free port.
+    pub(super) async fn start_server_with(server: Server) -> std::net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.accept(listener).await });
+        addr
+    }
+
+    /// Sends `args` until `done` holds for the reply, which it returns.
+    pub(super) async fn wait_for_reply(
+        stream: &mut net::TcpStream,
+        args: &[&str],
+        done: impl Fn(&Value) -> bool,
+    ) -> Value {
+        for _ in 0..1000 {
+            let reply = call(stream, args).await;
+            if done(&reply) {
+                return reply;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting on {args:?}");
+    }
+
+    /// Polls until `done` holds.
+    pub(super) async fn wait_for(mut done: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
             }
-            is_done = removed_count <= CLEANER_TASK_SAMPLE_SIZE / CLEANER_TASK_SUCCESS_FACTOR;
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        panic!("timed out");
+    }
 
+    pub(super) async fn connect() -> net::TcpStream {
+        net::TcpStream::connect(start_server().await).await.unwrap()
     }
 
+    pub(super) fn command(args: &[&[u8]]) -> Vec<u8> {
+        let args = args
+            .iter()
+            .map(|arg| Value::BulkString(Bytes::copy_from_slice(arg)))
+            .collect();
+        let mut buf = vec![];
+        Value::Array(args).encode(&mut buf).unwrap();
+        buf
+    }
+
+    pub(super) async fn read_replies(stream: &mut net::TcpStream, count: usize) -> Vec<Value> {
+        let parser = RespParser::new();
+        let mut decoder = FrameDecoder::new();
+        let mut replies = vec![];
+        while replies.len() < count {
+            match decoder.decode(&parser).unwrap() {
+                Some(reply) => replies.push(reply),
+                None => assert_ne!(stream.read_buf(decoder.buffer_mut()).await.unwrap(), 0),
+            }
+        }
+        replies
+    }
+
+    /// Sends a single command and waits for its reply.
+    pub(super) async fn call(stream: &mut net::TcpStream, args: &[&str]) -> Value {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        stream.write_all(&command(&args)).await.unwrap();
+        read_replies(stream, 1).await.remove(0)
+    }
+
+    #[tokio::test]
+    async fn pipelined_commands_are_answered_in_order() {
+        let mut stream = connect().await;
+        let mut input = command(&[b"SET", b"key", b"value"]);
+        input.extend(command(&[b"PING"]));
+        input.extend(command(&[b"GET", b"key"]));
+        input.extend(command(&[b"ECHO", b"hello"]));
+        stream.write_all(&input).await.unwrap();
+        assert_eq!(
+            read_replies(&mut stream, 4).await,
+            vec![
+                Value::SimpleString(String::from("OK")),
+                Value::SimpleString(String::from("PONG")),
+                Value::BulkString(Bytes::from("value")),
+                Value::BulkString(Bytes::from("hello")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn command_split_across_writes() {
+        let mut stream = connect().await;
+        for byte in command(&[b"ECHO", b"hello"]) {
+            stream.write_all(&[byte]).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+        assert_eq!(
+            read_replies(&mut stream, 1).await,
+            vec![Value::BulkString(Bytes::from("hello"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn protocol_errors_close_the_connection_after_earlier_replies() {
+        let mut stream = connect().await;
+        let mut input = command(&[b"SET", b"key", b"value"]);
+        input.extend(command(&[b"GET", b"key"]));
+        input.extend(b"*1\r\n$-7\r\n");
+        stream.write_all(&input).await.unwrap();
+        assert_eq!(
+            read_replies(&mut stream, 3).await,
+            vec![
+                Value::SimpleString(String::from("OK")),
+                Value::BulkString(Bytes::from("value")),
+                Value::Error(String::from("ERR Protocol error: invalid bulk length")),
+            ]
+        );
+        assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn binary_values_round_trip() {
+        let mut stream = connect().await;
+        let key: &[u8] = &[0x00, 0xff, b'k'];
+        let val: Vec<u8> = (0..=255u8).cycle().take(2000).collect();
+        stream
+            .write_all(&command(&[b"SET", key, &val]))
+            .await
+            .unwrap();
+        stream.write_all(&command(&[b"GET", key])).await.unwrap();
+        assert_eq!(
+            read_replies(&mut stream, 2).await,
+            vec![
+                Value::SimpleString(String::from("OK")),
+                Value::BulkString(Bytes::from(val)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hello_switches_protocol() {
+        let mut stream = connect().await;
+        stream
+            .write_all(&command(&[b"GET", b"missing"]))
+            .await
+            .unwrap();
+        stream.write_all(&command(&[b"HELLO", b"3"])).await.unwrap();
+        stream
+            .write_all(&command(&[b"GET", b"missing"]))
+            .await
+            .unwrap();
+        stream.write_all(&command(&[b"HELLO", b"4"])).await.unwrap();
+        stream.write_all(&command(&[b"HELLO", b"2"])).await.unwrap();
+        let replies = read_replies(&mut stream, 5).await;
+        assert_eq!(replies[0], Value::NullBulkString);
+        match &replies[1] {
+            Value::Map(pairs) => assert!(
+                pairs.contains(&(Value::BulkString(Bytes::from("proto")), Value::Integer(3)))
+            ),
+            reply => panic!("unexpected HELLO reply {reply:?}"),
+        }
+        assert_eq!(replies[2], Value::Null);
+        assert_eq!(
+            replies[3],
+            Value::Error(String::from("NOPROTO unsupported protocol version"))
+        );
+        match &replies[4] {
+            Value::Array(fields) => assert_eq!(fields.len(), 14),
+            reply => panic!("unexpected HELLO reply {reply:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_options_cannot_forge_replies() {
+        let mut stream = connect().await;
+        stream
+            .write_all(&command(&[b"HELLO", b"3", b"x\r\n+OK"]))
+            .await
+            .unwrap();
+        stream.write_all(&command(&[b"PING"])).await.unwrap();
+        assert_eq!(
+            read_replies(&mut stream, 2).await,
+            vec![
+                Value::Error(String::from("ERR Syntax error in HELLO option 'x  +OK'")),
+                Value::SimpleString(String::from("PONG")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn set_conditions_and_get() {
+        let mut stream = connect().await;
+        let ok = Value::SimpleString(String::from("OK"));
+        let value = |s: &str| Value::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+        assert_eq!(
+            call(&mut stream, &["SET", "k", "a", "XX"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(call(&mut stream, &["SET", "k", "a", "NX"]).await, ok);
+        assert_eq!(
+            call(&mut stream, &["SET", "k", "b", "NX"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["SET", "k", "b", "GET", "XX"]).await,
+            value("a")
+        );
+        assert_eq!(
+            call(&mut stream, &["SET", "k", "c", "NX", "GET"]).await,
+            value("b")
+        );
+        assert_eq!(call(&mut stream, &["GET", "k"]).await, value("b"));
+        assert_eq!(
+            call(&mut stream, &["SET", "new", "x", "GET"]).await,
+            Value::NullBulkString
+        );
+        call(&mut stream, &["RPUSH", "list", "x"]).await;
+        assert_eq!(
+            call(&mut stream, &["SET", "list", "x", "GET"]).await,
+            wrong_type()
+        );
+        assert_eq!(call(&mut stream, &["SET", "list", "x"]).await, ok);
+    }
+
+    #[tokio::test]
+    async fn set_expiration_options() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "k", "v", "EX", "100"]).await;
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(100));
+        call(&mut stream, &["SET", "k", "w", "KEEPTTL"]).await;
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(100));
+        call(&mut stream, &["SET", "k", "w"]).await;
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(-1));
+        let deadline = unix_time_millis() / 1000 + 200;
+        call(
+            &mut stream,
+            &["SET", "k", "v", "EXAT", &deadline.to_string()],
+        )
+        .await;
+        assert_eq!(
+            call(&mut stream, &["EXPIRETIME", "k"]).await,
+            Value::Integer(deadline)
+        );
+        call(&mut stream, &["SET", "k", "v", "PXAT", "1"]).await;
+        assert_eq!(
+            call(&mut stream, &["GET", "k"]).await,
+            Value::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn set_rejects_conflicting_options() {
+        let mut stream = connect().await;
+        let syntax = Value::Error(String::from("ERR syntax error"));
+        for args in [
+            &["SET", "k", "v", "NX", "XX"][..],
+            &["SET", "k", "v", "EX", "1", "PX", "1"],
+            &["SET", "k", "v", "EX", "1", "KEEPTTL"],
+            &["SET", "k", "v", "KEEPTTL", "PXAT", "1"],
+            &["SET", "k", "v", "EX"],
+            &["SET", "k", "v", "BOGUS"],
+        ] {
+            assert_eq!(call(&mut stream, args).await, syntax, "{args:?}");
+        }
+        assert_eq!(
+            call(&mut stream, &["SET", "k", "v", "EX", "0"]).await,
+            Value::Error(String::from("ERR invalid expire time in 'set' command"))
+        );
+        assert_eq!(
+            call(&mut stream, &["SET", "k", "v", "PX", "soon"]).await,
+            Value::Error(String::from("ERR value is not an integer or out of range"))
+        );
+        assert_eq!(
+            call(&mut stream, &["SET", "k", "v", "NX", "NX"]).await,
+            Value::SimpleString(String::from("OK"))
+        );
+    }
 }
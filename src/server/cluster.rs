@@ -0,0 +1,683 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::TcpStream;
+
+use super::replication::{invalid_data, request};
+use super::{written_keys, Client, Context, Server};
+use crate::cluster::{key_slot, Owner, SetSlot};
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::frame::FrameDecoder;
+use crate::operation::{Operation, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::transaction::SharedGate;
+use crate::value::Value;
+
+/// How often every node tells every other what it knows of the cluster.
+const GOSSIP_PERIOD: Duration = Duration::from_millis(100);
+/// How long a node has to answer before its link counts as down.
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The open connections to other nodes, by address.
+type Links = HashMap<(String, u16), (TcpStream, FrameDecoder)>;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Turns on cluster mode, before the server starts.
+    pub fn enable_cluster(&self) {
+        self.cluster.enable();
+    }
+
+    pub(super) fn handle_cluster(
+        context: &Context<P, D, S>,
+        client: &Client,
+        op: Operation,
+    ) -> Value {
+        if !context.cluster.is_enabled() {
+            return Value::Error(String::from(
+                "ERR This instance has cluster support disabled",
+            ));
+        }
+        let done = |result: Result<(), String>| match result {
+            Ok(()) => Value::SimpleString(String::from("OK")),
+            Err(err) => Value::Error(err),
+        };
+        match op {
+            Operation::ClusterInfo => {
+                Value::Verbatim(String::from("txt"), Bytes::from(context.cluster.info()))
+            }
+            Operation::ClusterMyId => Value::BulkString(Bytes::from(context.cluster.myid())),
+            Operation::ClusterNodes => {
+                Value::Verbatim(String::from("txt"), Bytes::from(context.cluster.nodes()))
+            }
+            Operation::ClusterSlots => context.cluster.slots(),
+            Operation::ClusterShards => {
+                let (_, offset) = context.replication.position();
+                context.cluster.shards(offset)
+            }
+            Operation::ClusterKeySlot(key) => Value::Integer(key_slot(&key) as i64),
+            Operation::ClusterMeet(host, port) => {
+                context.cluster.meet(host, port);
+                Value::SimpleString(String::from("OK"))
+            }
+            Operation::ClusterAddSlots(slots) => done(context.cluster.add_slots(&slots)),
+            Operation::ClusterDelSlots(slots) => done(context.cluster.del_slots(&slots)),
+            Operation::ClusterSetSlot(slot, change) => {
+                if let SetSlot::Node(id) = &change {
+                    if *id != context.cluster.myid()
+                        && context.cluster.owner(slot) == Owner::Myself
+                        && !Self::keys_in_slot(context, slot, 1).is_empty()
+                    {
+                        return Value::Error(format!(
+                            "ERR Can't assign hashslot {slot} to a different node while I \
+                             still hold keys for this hash slot."
+                        ));
+                    }
+                }
+                done(context.cluster.set_slot(slot, change))
+            }
+            Operation::ClusterCountKeysInSlot(slot) => {
+                Value::Integer(Self::keys_in_slot(context, slot, usize::MAX).len() as i64)
+            }
+            Operation::ClusterGetKeysInSlot(slot, count) => Value::Array(
+                Self::keys_in_slot(context, slot, count)
+                    .into_iter()
+                    .map(Value::BulkString)
+                    .collect(),
+            ),
+            // A node meeting this one has to connect from the address it
+            // claims, so that clients cannot pass themselves off as nodes.
+            Operation::ClusterGossip(true, message) if !claims_address(client, &message) => {
+                Value::Error(String::from("ERR Invalid cluster message"))
+            }
+            Operation::ClusterGossip(meet, message) => {
+                match context.cluster.receive(&message, meet) {
+                    Ok(()) => Value::Array(
+                        context
+                            .cluster
+                            .message()
+                            .into_iter()
+                            .map(|arg| Value::BulkString(Bytes::from(arg)))
+                            .collect(),
+                    ),
+                    Err(err) => Value::Error(err),
+                }
+            }
+            _ => unreachable!("not a cluster command"),
+        }
+    }
+
+    pub(super) fn handle_asking(context: &Context<P, D, S>, client: &mut Client) -> Value {
+        if !context.cluster.is_enabled() {
+            return Value::Error(String::from(
+                "ERR This instance has cluster support disabled",
+            ));
+        }
+        client.asking = true;
+        Value::SimpleString(String::from("OK"))
+    }
+
+    /// At most `count` of the keys in `slot`.
+    fn keys_in_slot(context: &Context<P, D, S>, slot: u16, count: usize) -> Vec<Bytes> {
+        let mut keys = vec![];
+        context.store.for_each(|key, df| {
+            if keys.len() < count && !df.has_expired() && key_slot(key) == slot {
+                keys.push(key.clone());
+            }
+        });
+        keys
+    }
+
+    /// Runs `op`, unless it has to be served elsewhere. Which node serves it
+    /// is decided under the gate, so that no key moves away in between.
+    pub(super) async fn execute_or_redirect<'a>(
+        context: &'a Context<P, D, S>,
+        client: &mut Client,
+        op: Operation,
+        command: Vec<Bytes>,
+        gate: Option<SharedGate<'a>>,
+    ) -> Value {
+        match Self::redirect(context, client, &op) {
+            Some(redirect) => redirect,
+            None => Self::execute(context, client, op, command, gate).await,
+        }
+    }
+
+    /// The error that sends the client elsewhere if this node does not serve
+    /// the keys `op` touches, or that rejects `op` if they are spread over
+    /// several slots.
+    pub(super) fn redirect(
+        context: &Context<P, D, S>,
+        client: &Client,
+        op: &Operation,
+    ) -> Option<Value> {
+        let asking =
+            client.asking || matches!(op, Operation::Restore(_, options) if options.asking);
+        Self::redirect_keys(context, client, &command_keys(op), asking)
+    }
+
+    /// What `redirect` replies for commands touching `keys`. A client
+    /// `asking` may use a slot this node is importing.
+    pub(super) fn redirect_keys(
+        context: &Context<P, D, S>,
+        client: &Client,
+        keys: &[Bytes],
+        asking: bool,
+    ) -> Option<Value> {
+        if !context.cluster.is_enabled() || client.replaying {
+            return None;
+        }
+        let (first, rest) = keys.split_first()?;
+        let slot = key_slot(first);
+        if rest.iter().any(|key| key_slot(key) != slot) {
+            return Some(Value::Error(String::from(
+                "CROSSSLOT Keys in request don't hash to the same slot",
+            )));
+        }
+        match context.cluster.owner(slot) {
+            Owner::Myself => {
+                let (host, port) = context.cluster.migrating(slot)?;
+                // Keys that already moved are served by the node they moved
+                // to, and the others here until they move too.
+                let present = keys
+                    .iter()
+                    .filter(|key| Self::read_frame(context, (*key).clone(), |df| df.is_some()))
+                    .count();
+                match present {
+                    0 => Some(Value::Error(format!("ASK {slot} {host}:{port}"))),
+                    present if present == keys.len() => None,
+                    _ => Some(Value::Error(String::from(
+                        "TRYAGAIN Multiple keys request during rehashing of slot",
+                    ))),
+                }
+            }
+            _ if asking && context.cluster.importing(slot) => None,
+            Owner::Node(host, port) => Some(Value::Error(format!("MOVED {slot} {host}:{port}"))),
+            Owner::Unassigned => Some(Value::Error(String::from(
+                "CLUSTERDOWN Hash slot not served",
+            ))),
+        }
+    }
+
+    /// Keeps telling the other nodes what this one knows of the cluster, and
+    /// taking in what they know in return.
+    pub(super) fn spawn_cluster_task(&self) {
+        if !self.cluster.is_enabled() {
+            return;
+        }
+        let context = self.context();
+        tokio::task::spawn(async move {
+            let mut links = Links::new();
+            let mut period = tokio::time::interval(GOSSIP_PERIOD);
+            loop {
+                period.tick().await;
+                for (host, port) in context.cluster.peers() {
+                    let gossip = Self::gossip(&context, &mut links, &host, port);
+                    let connected = matches!(
+                        tokio::time::timeout(GOSSIP_TIMEOUT, gossip).await,
+                        Ok(Ok(()))
+                    );
+                    if !connected {
+                        links.remove(&(host.clone(), port));
+                    }
+                    context.cluster.set_link(&host, port, connected);
+                }
+            }
+        });
+    }
+
+    /// Sends what this node knows of the cluster to the node at `host` and
+    /// `port`, and takes in the reply.
+    async fn gossip(
+        context: &Context<P, D, S>,
+        links: &mut Links,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        let address = (host.to_string(), port);
+        if !links.contains_key(&address) {
+            let stream = TcpStream::connect((host, port)).await?;
+            // The address the other node sees this one at is the one to
+            // tell the rest of the cluster.
+            let local = stream.local_addr()?;
+            context.cluster.set_host(local.ip().to_string());
+            links.insert(address.clone(), (stream, FrameDecoder::new()));
+        }
+        let (stream, decoder) = links.get_mut(&address).unwrap();
+        let message = context.cluster.message();
+        let kind = match context.cluster.is_meeting(host, port) {
+            true => "MEET",
+            false => "PING",
+        };
+        let mut args = vec!["CLUSTER", "GOSSIP", kind];
+        args.extend(message.iter().map(String::as_str));
+        let reply = request(stream, decoder, context.parser.as_ref(), &args).await?;
+        let reply = match reply {
+            Value::Array(args) => args
+                .into_iter()
+                .map(|arg| match arg {
+                    Value::BulkString(arg) => Some(arg),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+            _ => None,
+        };
+        let reply = reply.ok_or_else(|| invalid_data("unexpected reply to CLUSTER GOSSIP"))?;
+        // The node answered this one, so it is welcome whether known or not.
+        context
+            .cluster
+            .receive(&reply, true)
+            .map_err(|err| invalid_data(&err))
+    }
+}
+
+/// Whether `client` connects from the host the cluster `message` says its
+/// sender is at.
+fn claims_address(client: &Client, message: &[Bytes]) -> bool {
+    match (client.addr, message.get(1)) {
+        (Some(addr), Some(host)) => addr.ip().to_string().as_bytes() == &host[..],
+        _ => false,
+    }
+}
+
+/// The keys `op` reads or writes, which decide the node that serves it.
+pub(super) fn command_keys(op: &Operation) -> Vec<Bytes> {
+    // Moves whichever of its keys are here, wherever they belong.
+    if let Operation::Migrate(_) = op {
+        return vec![];
+    }
+    let mut keys = written_keys(op);
+    match op {
+        Operation::Get(key)
+        | Operation::LLen(key)
+        | Operation::LRange(key, ..)
+        | Operation::LIndex(key, _)
+        | Operation::HGet(key, _)
+        | Operation::HMGet(key, _)
+        | Operation::HGetAll(key)
+        | Operation::HExists(key, _)
+        | Operation::HKeys(key)
+        | Operation::HVals(key)
+        | Operation::HLen(key)
+        | Operation::HStrLen(key, _)
+        | Operation::SMembers(key)
+        | Operation::SIsMember(key, _)
+        | Operation::SMIsMember(key, _)
+        | Operation::SCard(key)
+        | Operation::SRandMember(key, _)
+        | Operation::ZRange(key, _)
+        | Operation::ZRank(key, ..)
+        | Operation::ZCard(key)
+        | Operation::ZScore(key, _)
+        | Operation::Type(key)
+        | Operation::Ttl(key, _)
+        | Operation::ExpireTime(key, _)
+        | Operation::StrLen(key)
+        | Operation::GetRange(key, ..)
+        | Operation::GetEx(key, None)
+        | Operation::XRange(key, ..)
+        | Operation::XLen(key)
+        | Operation::XPending(key, ..)
+        | Operation::XInfoStream(key)
+        | Operation::XInfoGroups(key)
+        | Operation::XInfoConsumers(key, _)
+        | Operation::Dump(key) => keys.push(key.clone()),
+        Operation::SCombine(sources, _)
+        | Operation::SCombineStore(_, sources, _)
+        | Operation::Exists(sources)
+        | Operation::MGet(sources)
+        | Operation::Watch(sources) => keys.extend(sources.iter().cloned()),
+        Operation::XRead(reads, _) => keys.extend(reads.iter().map(|(key, _)| key.clone())),
+        _ => {}
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::Entry;
+    use std::net::SocketAddr;
+
+    use super::super::tests::{
+        bulk, call, connect, error, ok, start_server_with, wait_for_reply,
+    };
+    use super::*;
+
+    async fn start_node() -> SocketAddr {
+        let server = Server::new("0");
+        server.enable_cluster();
+        start_server_with(server).await
+    }
+
+    /// Whether a bulk string reply contains `expected`.
+    fn mentions(expected: &str) -> impl Fn(&Value) -> bool + '_ {
+        move |reply| match reply {
+            Value::BulkString(reply) => String::from_utf8_lossy(reply).contains(expected),
+            _ => false,
+        }
+    }
+
+    /// Increments `key` `times` times from the node at `addr`, following
+    /// redirects as a cluster client does.
+    async fn incr_following_redirects(addr: SocketAddr, key: &str, times: usize) {
+        let mut links: HashMap<u16, TcpStream> = HashMap::new();
+        let mut home = addr.port();
+        let mut port = home;
+        let mut asking = false;
+        let mut done = 0;
+        while done < times {
+            let stream = match links.entry(port) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(TcpStream::connect(("127.0.0.1", port)).await.unwrap())
+                }
+            };
+            if asking {
+                assert_eq!(call(stream, &["ASKING"]).await, ok());
+            }
+            match call(stream, &["INCR", key]).await {
+                Value::Integer(_) => {
+                    done += 1;
+                    port = home;
+                    asking = false;
+                }
+                Value::Error(err) => {
+                    let (kind, address) = match err.split(' ').collect::<Vec<_>>()[..] {
+                        [kind, _, address] => (kind.to_string(), address.to_string()),
+                        _ => panic!("unexpected error {err}"),
+                    };
+                    port = address.rsplit(':').next().unwrap().parse().unwrap();
+                    asking = kind == "ASK";
+                    if kind == "MOVED" {
+                        home = port;
+                    }
+                }
+                reply => panic!("unexpected reply to INCR {reply:?}"),
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_commands_need_cluster_mode() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["CLUSTER", "KEYSLOT", "foo"]).await,
+            error("ERR This instance has cluster support disabled")
+        );
+        assert_eq!(call(&mut stream, &["SET", "foo", "1"]).await, ok());
+    }
+
+    #[tokio::test]
+    async fn gossip_is_only_taken_from_nodes() {
+        let mut stream = TcpStream::connect(start_node().await).await.unwrap();
+        let message = ["0123", "127.0.0.1", "7000", "0", "0-16383"];
+        let mut ping = vec!["CLUSTER", "GOSSIP", "PING"];
+        ping.extend(message);
+        assert_eq!(
+            call(&mut stream, &ping).await,
+            error("ERR Gossip from an unknown node")
+        );
+        let mut meet = vec!["CLUSTER", "GOSSIP", "MEET"];
+        meet.extend(message);
+        meet[4] = "10.0.0.1";
+        assert_eq!(
+            call(&mut stream, &meet).await,
+            error("ERR Invalid cluster message")
+        );
+        // Neither message took, so nobody serves the slots.
+        assert!(matches!(
+            call(&mut stream, &["SET", "foo", "1"]).await,
+            Value::Error(err) if err.starts_with("CLUSTERDOWN")
+        ));
+        match call(&mut stream, &["HELLO"]).await {
+            Value::Array(fields) => assert!(fields.contains(&bulk("cluster"))),
+            reply => panic!("unexpected HELLO reply {reply:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn nodes_share_slots_and_redirect_clients() {
+        let addrs = [start_node().await, start_node().await, start_node().await];
+        let mut nodes = vec![];
+        for addr in addrs {
+            nodes.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let ranges = [("0", "5460"), ("5461", "10922"), ("10923", "16383")];
+        for (node, (start, end)) in nodes.iter_mut().zip(ranges) {
+            let addslots = ["CLUSTER", "ADDSLOTSRANGE", start, end];
+            assert_eq!(call(node, &addslots).await, ok());
+        }
+        assert_eq!(
+            call(&mut nodes[0], &["CLUSTER", "ADDSLOTS", "100"]).await,
+            error("ERR Slot 100 is already busy")
+        );
+        assert_eq!(
+            call(&mut nodes[0], &["CLUSTER", "INFO"]).await,
+            Value::BulkString(Bytes::from(
+                "cluster_enabled:1\r\ncluster_state:fail\r\ncluster_slots_assigned:5461\r\n\
+                 cluster_slots_ok:5461\r\ncluster_slots_pfail:0\r\ncluster_slots_fail:0\r\n\
+                 cluster_known_nodes:1\r\ncluster_size:1\r\ncluster_current_epoch:0\r\n\
+                 cluster_my_epoch:0\r\n"
+            ))
+        );
+        // Meeting one node is enough to learn of the others.
+        for addr in &addrs[1..] {
+            let port = addr.port().to_string();
+            let meet = ["CLUSTER", "MEET", "127.0.0.1", &port];
+            assert_eq!(call(&mut nodes[0], &meet).await, ok());
+        }
+        for node in nodes.iter_mut() {
+            wait_for_reply(node, &["CLUSTER", "INFO"], mentions("cluster_state:ok")).await;
+            wait_for_reply(node, &["CLUSTER", "INFO"], mentions("cluster_known_nodes:3")).await;
+        }
+
+        let slots = match call(&mut nodes[1], &["CLUSTER", "SLOTS"]).await {
+            Value::Array(slots) => slots,
+            reply => panic!("unexpected reply to CLUSTER SLOTS {reply:?}"),
+        };
+        assert_eq!(slots.len(), 3);
+        for (range, ((start, end), addr)) in slots.iter().zip(ranges.iter().zip(addrs)) {
+            let range = match range {
+                Value::Array(range) => range,
+                range => panic!("unexpected slot range {range:?}"),
+            };
+            assert_eq!(range[0], Value::Integer(start.parse().unwrap()));
+            assert_eq!(range[1], Value::Integer(end.parse().unwrap()));
+            let node = match &range[2] {
+                Value::Array(node) => node,
+                node => panic!("unexpected node {node:?}"),
+            };
+            assert_eq!(node[1], Value::Integer(addr.port() as i64));
+        }
+        let lines = match call(&mut nodes[1], &["CLUSTER", "NODES"]).await {
+            Value::BulkString(lines) => String::from_utf8_lossy(&lines).into_owned(),
+            reply => panic!("unexpected reply to CLUSTER NODES {reply:?}"),
+        };
+        assert_eq!(lines.lines().count(), 3);
+        assert!(lines
+            .lines()
+            .any(|line| line.contains("myself,master") && line.ends_with(" 5461-10922")));
+        assert!(matches!(
+            call(&mut nodes[2], &["CLUSTER", "SHARDS"]).await,
+            Value::Array(shards) if shards.len() == 3
+        ));
+
+        assert_eq!(
+            call(&mut nodes[0], &["CLUSTER", "KEYSLOT", "foo"]).await,
+            Value::Integer(12182)
+        );
+        let moved = format!("MOVED 12182 127.0.0.1:{}", addrs[2].port());
+        assert_eq!(
+            call(&mut nodes[0], &["SET", "foo", "1"]).await,
+            error(&moved)
+        );
+        assert_eq!(call(&mut nodes[1], &["GET", "foo"]).await, error(&moved));
+        assert_eq!(call(&mut nodes[2], &["SET", "foo", "1"]).await, ok());
+        assert_eq!(
+            call(&mut nodes[2], &["MSET", "{foo}a", "1", "{foo}b", "2"]).await,
+            ok()
+        );
+        let crossslot = error("CROSSSLOT Keys in request don't hash to the same slot");
+        assert_eq!(call(&mut nodes[2], &["MGET", "foo", "a"]).await, crossslot);
+        // Keyless commands are served anywhere.
+        assert_eq!(
+            call(&mut nodes[0], &["PING"]).await,
+            Value::SimpleString(String::from("PONG"))
+        );
+
+        // Transactions take a single slot too.
+        call(&mut nodes[2], &["MULTI"]).await;
+        assert_eq!(call(&mut nodes[2], &["GET", "bar"]).await, {
+            error(&format!("MOVED 5061 127.0.0.1:{}", addrs[0].port()))
+        });
+        assert!(matches!(
+            call(&mut nodes[2], &["EXEC"]).await,
+            Value::Error(err) if err.starts_with("EXECABORT")
+        ));
+        call(&mut nodes[2], &["MULTI"]).await;
+        call(&mut nodes[2], &["INCR", "foo"]).await;
+        call(&mut nodes[2], &["INCR", "a"]).await;
+        assert_eq!(call(&mut nodes[2], &["EXEC"]).await, crossslot);
+        assert_eq!(
+            call(&mut nodes[2], &["GET", "foo"]).await,
+            Value::BulkString(Bytes::from("1"))
+        );
+
+        assert_eq!(
+            call(&mut nodes[2], &["CLUSTER", "DELSLOTS", "12182"]).await,
+            ok()
+        );
+        let down = error("CLUSTERDOWN Hash slot not served");
+        assert_eq!(call(&mut nodes[2], &["GET", "foo"]).await, down);
+        let info = ["CLUSTER", "INFO"];
+        wait_for_reply(&mut nodes[0], &info, mentions("cluster_state:fail")).await;
+        assert_eq!(call(&mut nodes[0], &["GET", "foo"]).await, down);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn slots_move_between_nodes_while_clients_keep_going() {
+        let addrs = [start_node().await, start_node().await];
+        let mut nodes = vec![];
+        let mut ids = vec![];
+        for addr in addrs {
+            let mut node = TcpStream::connect(addr).await.unwrap();
+            match call(&mut node, &["CLUSTER", "MYID"]).await {
+                Value::BulkString(id) => ids.push(String::from_utf8(id.to_vec()).unwrap()),
+                reply => panic!("unexpected reply to CLUSTER MYID {reply:?}"),
+            }
+            nodes.push(node);
+        }
+        let addslots = ["CLUSTER", "ADDSLOTSRANGE", "0", "16383"];
+        assert_eq!(call(&mut nodes[0], &addslots).await, ok());
+        let port = addrs[1].port().to_string();
+        let meet = ["CLUSTER", "MEET", "127.0.0.1", &port];
+        assert_eq!(call(&mut nodes[0], &meet).await, ok());
+        for node in nodes.iter_mut() {
+            wait_for_reply(node, &["CLUSTER", "INFO"], mentions("cluster_known_nodes:2")).await;
+        }
+        call(&mut nodes[0], &["SET", "foo", "1"]).await;
+        call(&mut nodes[0], &["SET", "{foo}bar", "2"]).await;
+        let counter = tokio::spawn(incr_following_redirects(addrs[0], "{foo}count", 200));
+
+        let importing = ["CLUSTER", "SETSLOT", "12182", "IMPORTING", &ids[0]];
+        assert_eq!(call(&mut nodes[1], &importing).await, ok());
+        let migrating = ["CLUSTER", "SETSLOT", "12182", "MIGRATING", &ids[1]];
+        assert_eq!(call(&mut nodes[0], &migrating).await, ok());
+        wait_for_reply(
+            &mut nodes[0],
+            &["CLUSTER", "NODES"],
+            mentions(&format!("[12182->-{}]", ids[1])),
+        )
+        .await;
+
+        let migrate = ["MIGRATE", "127.0.0.1", &port, "foo", "0", "5000"];
+        assert_eq!(call(&mut nodes[0], &migrate).await, ok());
+        let ask = error(&format!("ASK 12182 127.0.0.1:{port}"));
+        assert_eq!(call(&mut nodes[0], &["GET", "foo"]).await, ask);
+        assert_eq!(
+            call(&mut nodes[0], &["GET", "{foo}bar"]).await,
+            Value::BulkString(Bytes::from("2"))
+        );
+        assert_eq!(
+            call(&mut nodes[0], &["MGET", "foo", "{foo}bar"]).await,
+            error("TRYAGAIN Multiple keys request during rehashing of slot")
+        );
+        let moved = error(&format!("MOVED 12182 127.0.0.1:{}", addrs[0].port()));
+        assert_eq!(call(&mut nodes[1], &["GET", "foo"]).await, moved);
+        assert_eq!(call(&mut nodes[1], &["ASKING"]).await, ok());
+        assert_eq!(
+            call(&mut nodes[1], &["GET", "foo"]).await,
+            Value::BulkString(Bytes::from("1"))
+        );
+        assert_eq!(call(&mut nodes[1], &["GET", "foo"]).await, moved);
+        let node = ["CLUSTER", "SETSLOT", "12182", "NODE", &ids[1]];
+        assert_eq!(
+            call(&mut nodes[0], &node).await,
+            error(
+                "ERR Can't assign hashslot 12182 to a different node while I still hold keys \
+                 for this hash slot."
+            )
+        );
+
+        // Moves what is left, as the counter may still be here.
+        loop {
+            let keys = match call(&mut nodes[0], &["CLUSTER", "GETKEYSINSLOT", "12182", "10"]).await
+            {
+                Value::Array(keys) => keys,
+                reply => panic!("unexpected reply to CLUSTER GETKEYSINSLOT {reply:?}"),
+            };
+            if keys.is_empty() {
+                break;
+            }
+            let mut migrate = vec!["MIGRATE", "127.0.0.1", &port, "", "0", "5000", "KEYS"];
+            let keys: Vec<String> = keys
+                .into_iter()
+                .map(|key| match key {
+                    Value::BulkString(key) => String::from_utf8(key.to_vec()).unwrap(),
+                    key => panic!("unexpected key {key:?}"),
+                })
+                .collect();
+            migrate.extend(keys.iter().map(String::as_str));
+            assert_eq!(call(&mut nodes[0], &migrate).await, ok());
+        }
+        assert_eq!(
+            call(&mut nodes[0], &["CLUSTER", "COUNTKEYSINSLOT", "12182"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(call(&mut nodes[1], &node).await, ok());
+        assert_eq!(call(&mut nodes[0], &node).await, ok());
+
+        counter.await.unwrap();
+        assert_eq!(
+            call(&mut nodes[1], &["GET", "{foo}count"]).await,
+            Value::BulkString(Bytes::from("200"))
+        );
+        assert_eq!(
+            call(&mut nodes[1], &["CLUSTER", "COUNTKEYSINSLOT", "12182"]).await,
+            Value::Integer(3)
+        );
+        let moved = error(&format!("MOVED 12182 127.0.0.1:{port}"));
+        assert_eq!(call(&mut nodes[0], &["GET", "foo"]).await, moved);
+        // The other node takes the slot over for good once it hears of it.
+        wait_for_reply(
+            &mut nodes[0],
+            &["CLUSTER", "INFO"],
+            mentions("cluster_current_epoch:1"),
+        )
+        .await;
+        assert_eq!(call(&mut nodes[0], &["GET", "foo"]).await, moved);
+        assert_eq!(
+            call(&mut nodes[1], &["GET", "foo"]).await,
+            Value::BulkString(Bytes::from("1"))
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{Context, Server};
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::OperationDeducer;
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Replies with every parameter matching any of `patterns`, once each.
+    pub(super) async fn handle_config_get(
+        context: &Context<P, D, S>,
+        patterns: Vec<Bytes>,
+    ) -> Value {
+        let mut parameters: Vec<(&str, String)> = vec![];
+        for pattern in patterns {
+            for (name, value) in context.config.get(&pattern) {
+                if parameters.iter().all(|(seen, _)| *seen != name) {
+                    parameters.push((name, value));
+                }
+            }
+        }
+        let pairs = parameters
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    Value::BulkString(Bytes::from_static(name.as_bytes())),
+                    Value::BulkString(Bytes::from(value)),
+                )
+            })
+            .collect();
+        Value::Map(pairs)
+    }
+
+    pub(super) async fn handle_config_set(
+        context: &Context<P, D, S>,
+        pairs: Vec<(Bytes, Bytes)>,
+    ) -> Value {
+        let pairs: Vec<(&[u8], &[u8])> = pairs
+            .iter()
+            .map(|(name, value)| (&name[..], &value[..]))
+            .collect();
+        if let Err(err) = context.config.set(&pairs) {
+            return Value::Error(err);
+        }
+        context
+            .replication
+            .resize_backlog(context.config.repl_backlog_size());
+        match (context.config.appendonly(), Self::propagating(context)) {
+            (true, false) => {
+                Self::start_rewrite(context);
+            }
+            (false, true) => context.aof.close(),
+            _ => {}
+        }
+        Value::SimpleString(String::from("OK"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{call, connect};
+    use super::*;
+
+    #[tokio::test]
+    async fn config_get_and_set() {
+        let mut stream = connect().await;
+        let parameter = |value: &str| {
+            Value::Array(vec![
+                Value::BulkString(Bytes::from("notify-keyspace-events")),
+                Value::BulkString(Bytes::from(value.to_string())),
+            ])
+        };
+        assert_eq!(
+            call(&mut stream, &["CONFIG", "GET", "notify-*"]).await,
+            parameter("")
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["CONFIG", "SET", "notify-keyspace-events", "lEK"]
+            )
+            .await,
+            Value::SimpleString(String::from("OK"))
+        );
+        assert_eq!(
+            call(&mut stream, &["CONFIG", "GET", "*events", "notify*"]).await,
+            parameter("lKE")
+        );
+        assert_eq!(
+            call(&mut stream, &["CONFIG", "GET", "maxmemory"]).await,
+            Value::Array(vec![])
+        );
+        assert!(matches!(
+            call(
+                &mut stream,
+                &["CONFIG", "SET", "notify-keyspace-events", "Q"]
+            )
+            .await,
+            Value::Error(_)
+        ));
+        assert!(matches!(
+            call(&mut stream, &["CONFIG", "SET", "maxmemory"]).await,
+            Value::Error(_)
+        ));
+        assert_eq!(
+            call(&mut stream, &["CONFIG", "GET", "notify-keyspace-events"]).await,
+            parameter("lKE")
+        );
+    }
+}
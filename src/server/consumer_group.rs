@@ -0,0 +1,1208 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::stream::{
+    entries_reply, entry_reply, id_reply, stream_mut, stream_or_insert, streams_reply,
+};
+use super::{wrong_type, Context, Server};
+use crate::blocking::BlockedOperation;
+use crate::config::KeyspaceEvents;
+use crate::consumer_group::ConsumerGroup;
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::operation::{
+    AutoClaimOptions, ClaimOptions, OperationDeducer, PendingRange, ReadFrom, XReadOptions,
+};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::stream::{Entry, Stream, StreamId};
+use crate::transaction::SharedGate;
+use crate::value::{Protocol, Value};
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    pub(super) async fn handle_xgroup_create(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        from: ReadFrom,
+        entries_read: Option<u64>,
+        mkstream: bool,
+    ) -> Value {
+        let created = Self::update_key(context, key.clone(), |entry| {
+            if entry.is_none() && mkstream {
+                stream_or_insert(entry)?;
+            }
+            let stream = stream_mut(entry)?.ok_or_else(key_required)?;
+            let last_delivered = match from {
+                ReadFrom::After(id) => id,
+                ReadFrom::New => stream.last_id(),
+            };
+            if !stream.create_group(group, ConsumerGroup::new(last_delivered, entries_read)) {
+                return Err(Value::Error(String::from(
+                    "BUSYGROUP Consumer Group name already exists",
+                )));
+            }
+            Ok(())
+        });
+        match created {
+            Err(err) => err,
+            Ok(()) => {
+                Self::notify(context, KeyspaceEvents::STREAM, "xgroup-create", &key);
+                ok()
+            }
+        }
+    }
+
+    /// Moves the group's last delivered ID, so it reads the entries after it
+    /// next, whether it delivered them before or not.
+    pub(super) async fn handle_xgroup_setid(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        from: ReadFrom,
+        entries_read: Option<u64>,
+    ) -> Value {
+        let set = Self::update_key(context, key.clone(), |entry| {
+            let stream = stream_mut(entry)?.ok_or_else(key_required)?;
+            let last_id = stream.last_id();
+            let group = stream
+                .group_mut(&group)
+                .ok_or_else(|| no_such_group(&key, &group))?;
+            group.last_delivered = match from {
+                ReadFrom::After(id) => id,
+                ReadFrom::New => last_id,
+            };
+            group.entries_read = entries_read;
+            Ok(())
+        });
+        match set {
+            Err(err) => err,
+            Ok(()) => {
+                Self::notify(context, KeyspaceEvents::STREAM, "xgroup-setid", &key);
+                ok()
+            }
+        }
+    }
+
+    pub(super) async fn handle_xgroup_destroy(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+    ) -> Value {
+        let destroyed = Self::update_key(context, key.clone(), |entry| {
+            let stream = stream_mut(entry)?.ok_or_else(key_required)?;
+            Ok(stream.destroy_group(&group))
+        });
+        match destroyed {
+            Err(err) => err,
+            Ok(destroyed) => {
+                if destroyed {
+                    Self::notify(context, KeyspaceEvents::STREAM, "xgroup-destroy", &key);
+                }
+                Value::Integer(destroyed as i64)
+            }
+        }
+    }
+
+    pub(super) async fn handle_xgroup_createconsumer(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+    ) -> Value {
+        let created = Self::update_key(context, key.clone(), |entry| {
+            let stream = stream_mut(entry)?.ok_or_else(key_required)?;
+            let group = stream
+                .group_mut(&group)
+                .ok_or_else(|| no_such_group(&key, &group))?;
+            Ok(group.create_consumer(&consumer, unix_time_millis()))
+        });
+        match created {
+            Err(err) => err,
+            Ok(created) => {
+                if created {
+                    Self::notify(
+                        context,
+                        KeyspaceEvents::STREAM,
+                        "xgroup-createconsumer",
+                        &key,
+                    );
+                }
+                Value::Integer(created as i64)
+            }
+        }
+    }
+
+    /// Deletes a consumer, dropping its pending entries, and replies with how
+    /// many it had.
+    pub(super) async fn handle_xgroup_delconsumer(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+    ) -> Value {
+        let deleted = Self::update_key(context, key.clone(), |entry| {
+            let stream = stream_mut(entry)?.ok_or_else(key_required)?;
+            let group = stream
+                .group_mut(&group)
+                .ok_or_else(|| no_such_group(&key, &group))?;
+            Ok(group.delete_consumer(&consumer))
+        });
+        match deleted {
+            Err(err) => err,
+            Ok(None) => Value::Integer(0),
+            Ok(Some(pending)) => {
+                Self::notify(context, KeyspaceEvents::STREAM, "xgroup-delconsumer", &key);
+                Value::Integer(pending as i64)
+            }
+        }
+    }
+
+    /// Reads for `consumer` on behalf of `group`: with `>`, entries no member
+    /// of the group got yet, which become pending for the consumer; with an
+    /// ID, the consumer's own pending entries after it. With BLOCK and only
+    /// `>` reads, waits for new entries if there are none.
+    pub(super) async fn handle_xreadgroup<'a>(
+        context: &'a Context<P, D, S>,
+        group: Bytes,
+        consumer: Bytes,
+        reads: Vec<(Bytes, ReadFrom)>,
+        options: Box<XReadOptions>,
+        protocol: Protocol,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        // Check every stream up front, so a missing group reads nothing at all.
+        for (key, _) in &reads {
+            let found = Self::read_key(context, key.clone(), |data| match data {
+                Some(Data::Stream(stream)) => Ok(stream.group(&group).is_some()),
+                None => Ok(false),
+                Some(_) => Err(wrong_type()),
+            });
+            match found {
+                Err(err) => return err,
+                Ok(false) => return no_such_read_group(key, &group),
+                Ok(true) => {}
+            }
+        }
+        let history = reads
+            .iter()
+            .any(|(_, from)| matches!(from, ReadFrom::After(_)));
+        let mut streams = vec![];
+        // Reads are logged as they happen, under the registry lock like those
+        // of blocked clients, so that they are logged in the order they happen.
+        let waiters = context.blocking.lock();
+        for (key, from) in &reads {
+            let read = Self::update_key(context, key.clone(), |entry| {
+                let stream = stream_mut(entry)?.ok_or_else(|| no_such_read_group(key, &group))?;
+                let now = unix_time_millis();
+                let created = stream
+                    .group_mut(&group)
+                    .ok_or_else(|| no_such_read_group(key, &group))?
+                    .touch_consumer(&consumer, now);
+                let entries = match from {
+                    ReadFrom::New => stream
+                        .read_group(&group, &consumer, options.count, options.noack, now)
+                        .map(entries_reply),
+                    ReadFrom::After(id) => stream
+                        .read_pending(&group, &consumer, *id, options.count, now)
+                        .map(pending_entries_reply),
+                };
+                Ok((created, entries.unwrap()))
+            });
+            let (created, entries) = match read {
+                Err(err) => return err,
+                Ok(read) => read,
+            };
+            let read = !matches!(&entries, Value::Array(entries) if entries.is_empty());
+            if created || read {
+                let from = match from {
+                    ReadFrom::New => Bytes::from_static(b">"),
+                    ReadFrom::After(id) => Bytes::from(id.to_string()),
+                };
+                let command =
+                    read_group_command(key, &group, &consumer, options.count, options.noack, from);
+                Self::propagate(context, command);
+            }
+            if created {
+                Self::notify(
+                    context,
+                    KeyspaceEvents::STREAM,
+                    "xgroup-createconsumer",
+                    key,
+                );
+            }
+            // History reads reply for every stream, even those without entries.
+            if history || read {
+                streams.push((key.clone(), entries));
+            }
+        }
+        drop(waiters);
+        if !streams.is_empty() {
+            return streams_reply(streams, protocol);
+        }
+        if !options.block {
+            return Value::NullArray;
+        }
+        let keys = reads.into_iter().map(|(key, _)| key).collect();
+        let operation = BlockedOperation::ReadGroup {
+            group,
+            consumer,
+            count: options.count,
+            noack: options.noack,
+            protocol,
+        };
+        Self::block_on(
+            context,
+            keys,
+            operation,
+            options.timeout,
+            Value::NullArray,
+            gate,
+        )
+        .await
+    }
+
+    /// The reply for a client blocked in `XREADGROUP` if the stream at `key`
+    /// has entries the group has not seen, or `None` if it has to keep
+    /// waiting. The client gets an error once the stream or group is gone.
+    pub(super) fn serve_group_reader(
+        context: &Context<P, D, S>,
+        key: &Bytes,
+        group: &Bytes,
+        consumer: &Bytes,
+        count: Option<usize>,
+        noack: bool,
+        protocol: Protocol,
+    ) -> Option<Value> {
+        let entries = Self::update_key(context, key.clone(), |entry| {
+            let stream = stream_mut(entry)?.ok_or_else(|| {
+                Value::Error(String::from("UNBLOCKED the stream key no longer exists"))
+            })?;
+            let now = unix_time_millis();
+            let no_group = || {
+                Value::Error(String::from(
+                    "NOGROUP the consumer group this client was blocked on no longer exists",
+                ))
+            };
+            stream
+                .group_mut(group)
+                .ok_or_else(no_group)?
+                .touch_consumer(consumer, now);
+            stream
+                .read_group(group, consumer, count, noack, now)
+                .ok_or_else(no_group)
+        });
+        match entries {
+            Err(err) => Some(err),
+            Ok(entries) if entries.is_empty() => None,
+            Ok(entries) => {
+                let from = Bytes::from_static(b">");
+                let command = read_group_command(key, group, consumer, count, noack, from);
+                Self::propagate(context, command);
+                Some(streams_reply(
+                    vec![(key.clone(), entries_reply(entries))],
+                    protocol,
+                ))
+            }
+        }
+    }
+
+    pub(super) async fn handle_xack(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        ids: Vec<StreamId>,
+    ) -> Value {
+        let acked = Self::update_key(context, key, |entry| {
+            let group = stream_mut(entry)?.and_then(|stream| stream.group_mut(&group));
+            Ok(group.map_or(0, |group| {
+                ids.into_iter().filter(|id| group.ack(*id)).count()
+            }))
+        });
+        match acked {
+            Err(err) => err,
+            Ok(acked) => Value::Integer(acked as i64),
+        }
+    }
+
+    /// Replies with a summary of the group's pending entries: how many there
+    /// are, the smallest and greatest ID, and how many each consumer has. With
+    /// a range, replies with the pending entries in it instead.
+    pub(super) async fn handle_xpending(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        range: Option<Box<PendingRange>>,
+    ) -> Value {
+        Self::read_key(context, key.clone(), |data| {
+            let stream = match data {
+                None => return no_such_key_or_group(&key, &group),
+                Some(Data::Stream(stream)) => stream,
+                Some(_) => return wrong_type(),
+            };
+            let Some(group) = stream.group(&group) else {
+                return no_such_key_or_group(&key, &group);
+            };
+            match range {
+                None => pending_summary(group),
+                Some(range) => pending_range(group, &range, unix_time_millis()),
+            }
+        })
+    }
+
+    /// Hands the given pending entries over to `consumer`, skipping those
+    /// delivered less than the minimum idle time ago.
+    pub(super) async fn handle_xclaim(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        options: Box<ClaimOptions>,
+    ) -> Value {
+        Self::update_key(context, key.clone(), |entry| {
+            let stream = match stream_mut(entry) {
+                Err(err) => return err,
+                Ok(None) => return no_such_key_or_group(&key, &group),
+                Ok(Some(stream)) => stream,
+            };
+            if stream.group(&group).is_none() {
+                return no_such_key_or_group(&key, &group);
+            }
+            let now = unix_time_millis();
+            let delivered_at = match (options.time, options.idle) {
+                (Some(time), _) => time,
+                (None, Some(idle)) => now - idle,
+                (None, None) => now,
+            };
+            let delivered_at = match delivered_at {
+                delivered_at if (0..=now).contains(&delivered_at) => delivered_at,
+                _ => now,
+            };
+            let claim = Claim {
+                consumer: &consumer,
+                min_idle: options.min_idle,
+                delivered_at,
+                retry_count: options.retry_count,
+                justid: options.justid,
+                now,
+            };
+            {
+                let group = stream.group_mut(&group).unwrap();
+                group.touch_consumer(&consumer, now);
+                if let Some(last_id) = options.last_id {
+                    group.last_delivered = group.last_delivered.max(last_id);
+                }
+            }
+            let mut claimed = vec![];
+            for &id in &options.ids {
+                let exists = stream.contains(id);
+                let group = stream.group_mut(&group).unwrap();
+                if options.force && exists && !group.pending().contains_key(&id) {
+                    group.assign(id, &consumer, now, 1);
+                }
+                if claim.apply(group, id, exists) == Claimed::Yes {
+                    claimed.push(id);
+                }
+            }
+            claimed_reply(stream, &group, &consumer, claimed, options.justid, now)
+        })
+    }
+
+    /// Claims pending entries idle for long enough, scanning from `start`, and
+    /// replies with where to continue, the claimed entries, and the IDs of
+    /// pending entries found deleted from the stream, which are dropped.
+    pub(super) async fn handle_xautoclaim(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        options: Box<AutoClaimOptions>,
+    ) -> Value {
+        Self::update_key(context, key.clone(), |entry| {
+            let stream = match stream_mut(entry) {
+                Err(err) => return err,
+                Ok(None) => return no_such_key_or_group(&key, &group),
+                Ok(Some(stream)) => stream,
+            };
+            let now = unix_time_millis();
+            let Some(pending) = stream.group_mut(&group).map(|group| {
+                group.touch_consumer(&consumer, now);
+                // Scan at most ten pending entries for every one to claim.
+                let attempts = options.count.saturating_mul(10);
+                let ids = group.pending().range(options.start..).map(|(id, _)| *id);
+                ids.take(attempts.saturating_add(1)).collect::<Vec<_>>()
+            }) else {
+                return no_such_key_or_group(&key, &group);
+            };
+            let claim = Claim {
+                consumer: &consumer,
+                min_idle: options.min_idle,
+                delivered_at: now,
+                retry_count: None,
+                justid: options.justid,
+                now,
+            };
+            let mut claimed = vec![];
+            let mut deleted = vec![];
+            let mut scanned = 0;
+            for &id in pending.iter().take(options.count.saturating_mul(10)) {
+                if claimed.len() == options.count {
+                    break;
+                }
+                scanned += 1;
+                let exists = stream.contains(id);
+                match claim.apply(stream.group_mut(&group).unwrap(), id, exists) {
+                    Claimed::Yes => claimed.push(id),
+                    Claimed::Deleted => deleted.push(id),
+                    Claimed::No => {}
+                }
+            }
+            let next = pending.get(scanned).copied().unwrap_or(StreamId::MIN);
+            Value::Array(vec![
+                id_reply(next),
+                claimed_reply(stream, &group, &consumer, claimed, options.justid, now),
+                Value::Array(deleted.into_iter().map(id_reply).collect()),
+            ])
+        })
+    }
+
+    pub(super) async fn handle_xinfo_stream(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| {
+            let stream = match data {
+                None => return no_such_key(),
+                Some(Data::Stream(stream)) => stream,
+                Some(_) => return wrong_type(),
+            };
+            let entry = |entry: Option<Entry>| entry.map_or(Value::NullBulkString, entry_reply);
+            Value::Map(vec![
+                (field("length"), Value::Integer(stream.len() as i64)),
+                (
+                    field("radix-tree-keys"),
+                    Value::Integer(stream.block_count() as i64),
+                ),
+                (
+                    field("radix-tree-nodes"),
+                    Value::Integer(stream.block_count() as i64),
+                ),
+                (field("last-generated-id"), id_reply(stream.last_id())),
+                (
+                    field("max-deleted-entry-id"),
+                    id_reply(stream.max_deleted_id()),
+                ),
+                (
+                    field("entries-added"),
+                    Value::Integer(stream.entries_added() as i64),
+                ),
+                (
+                    field("recorded-first-entry-id"),
+                    id_reply(stream.first_id()),
+                ),
+                (
+                    field("groups"),
+                    Value::Integer(stream.groups().len() as i64),
+                ),
+                (field("first-entry"), entry(stream.first_entry())),
+                (field("last-entry"), entry(stream.last_entry())),
+            ])
+        })
+    }
+
+    pub(super) async fn handle_xinfo_groups(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| {
+            let stream = match data {
+                None => return no_such_key(),
+                Some(Data::Stream(stream)) => stream,
+                Some(_) => return wrong_type(),
+            };
+            let optional = |count: Option<u64>| {
+                count.map_or(Value::NullBulkString, |count| Value::Integer(count as i64))
+            };
+            let groups = stream.groups().iter().map(|(name, group)| {
+                Value::Map(vec![
+                    (field("name"), Value::BulkString(name.clone())),
+                    (
+                        field("consumers"),
+                        Value::Integer(group.consumers().len() as i64),
+                    ),
+                    (
+                        field("pending"),
+                        Value::Integer(group.pending().len() as i64),
+                    ),
+                    (field("last-delivered-id"), id_reply(group.last_delivered)),
+                    (field("entries-read"), optional(group.entries_read)),
+                    (field("lag"), optional(stream.lag(group))),
+                ])
+            });
+            Value::Array(groups.collect())
+        })
+    }
+
+    pub(super) async fn handle_xinfo_consumers(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+    ) -> Value {
+        Self::read_key(context, key.clone(), |data| {
+            let stream = match data {
+                None => return no_such_key(),
+                Some(Data::Stream(stream)) => stream,
+                Some(_) => return wrong_type(),
+            };
+            let Some(group) = stream.group(&group) else {
+                return no_such_group(&key, &group);
+            };
+            let now = unix_time_millis();
+            let consumers = group.consumers().iter().map(|(name, consumer)| {
+                let inactive = consumer.active_at.map_or(-1, |active_at| now - active_at);
+                Value::Map(vec![
+                    (field("name"), Value::BulkString(name.clone())),
+                    (
+                        field("pending"),
+                        Value::Integer(consumer.pending().len() as i64),
+                    ),
+                    (field("idle"), Value::Integer(now - consumer.seen_at)),
+                    (field("inactive"), Value::Integer(inactive)),
+                ])
+            });
+            Value::Array(consumers.collect())
+        })
+    }
+}
+
+/// The terms `XCLAIM` and `XAUTOCLAIM` take pending entries over on.
+struct Claim<'a> {
+    consumer: &'a Bytes,
+    min_idle: i64,
+    delivered_at: i64,
+    retry_count: Option<u64>,
+    justid: bool,
+    now: i64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Claimed {
+    Yes,
+    /// Not pending, or not idle for long enough.
+    No,
+    /// Deleted from the stream, and so dropped from the pending entries.
+    Deleted,
+}
+
+impl Claim<'_> {
+    fn apply(&self, group: &mut ConsumerGroup, id: StreamId, exists: bool) -> Claimed {
+        let Some(pending) = group.pending().get(&id) else {
+            return Claimed::No;
+        };
+        if !exists {
+            group.ack(id);
+            return Claimed::Deleted;
+        }
+        if self.min_idle > 0 && self.now - pending.delivered_at < self.min_idle {
+            return Claimed::No;
+        }
+        let deliveries = match self.retry_count {
+            Some(count) => count,
+            None if self.justid => pending.deliveries,
+            None => pending.deliveries + 1,
+        };
+        group.assign(id, self.consumer, self.delivered_at, deliveries);
+        Claimed::Yes
+    }
+}
+
+/// Replies with the claimed entries, or only their IDs with JUSTID, and marks
+/// the consumer active if it got any.
+fn claimed_reply(
+    stream: &mut Stream,
+    group: &[u8],
+    consumer: &Bytes,
+    claimed: Vec<StreamId>,
+    justid: bool,
+    now: i64,
+) -> Value {
+    if !claimed.is_empty() {
+        let group = stream.group_mut(group).unwrap();
+        if let Some(consumer) = group.consumer_mut(consumer) {
+            consumer.active_at = Some(now);
+        }
+    }
+    if justid {
+        return Value::Array(claimed.into_iter().map(id_reply).collect());
+    }
+    let entries = claimed.into_iter().filter_map(|id| stream.get(id));
+    Value::Array(entries.map(entry_reply).collect())
+}
+
+fn pending_summary(group: &ConsumerGroup) -> Value {
+    let pending = group.pending();
+    let (Some((first, _)), Some((last, _))) = (pending.first_key_value(), pending.last_key_value())
+    else {
+        return Value::Array(vec![
+            Value::Integer(0),
+            Value::NullBulkString,
+            Value::NullBulkString,
+            Value::NullArray,
+        ]);
+    };
+    let consumers = group
+        .consumers()
+        .iter()
+        .filter(|(_, consumer)| !consumer.pending().is_empty())
+        .map(|(name, consumer)| {
+            Value::Array(vec![
+                Value::BulkString(name.clone()),
+                Value::BulkString(Bytes::from(consumer.pending().len().to_string())),
+            ])
+        });
+    Value::Array(vec![
+        Value::Integer(pending.len() as i64),
+        id_reply(*first),
+        id_reply(*last),
+        Value::Array(consumers.collect()),
+    ])
+}
+
+fn pending_range(group: &ConsumerGroup, range: &PendingRange, now: i64) -> Value {
+    if range.start > range.end {
+        return Value::Array(vec![]);
+    }
+    let entries = group
+        .pending()
+        .range(range.start..=range.end)
+        .filter(|(_, entry)| match &range.consumer {
+            Some(consumer) => entry.consumer == consumer,
+            None => true,
+        })
+        .filter(|(_, entry)| {
+            !matches!(range.min_idle, Some(min_idle) if now - entry.delivered_at < min_idle)
+        })
+        .take(range.count)
+        .map(|(id, entry)| {
+            Value::Array(vec![
+                id_reply(*id),
+                Value::BulkString(entry.consumer.clone()),
+                Value::Integer(now - entry.delivered_at),
+                Value::Integer(entry.deliveries as i64),
+            ])
+        });
+    Value::Array(entries.collect())
+}
+
+/// Replies with pending entries, leaving out the fields of those deleted
+/// from the stream since they were delivered.
+fn pending_entries_reply(entries: Vec<(StreamId, Option<Entry>)>) -> Value {
+    let entries = entries.into_iter().map(|(id, entry)| match entry {
+        Some(entry) => entry_reply(entry),
+        None => Value::Array(vec![id_reply(id), Value::NullArray]),
+    });
+    Value::Array(entries.collect())
+}
+
+fn field(name: &'static str) -> Value {
+    Value::BulkString(Bytes::from_static(name.as_bytes()))
+}
+
+fn ok() -> Value {
+    Value::SimpleString(String::from("OK"))
+}
+
+fn key_required() -> Value {
+    Value::Error(String::from(
+        "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+    ))
+}
+
+fn no_such_key() -> Value {
+    Value::Error(String::from("ERR no such key"))
+}
+
+fn no_such_group(key: &[u8], group: &[u8]) -> Value {
+    Value::Error(format!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        String::from_utf8_lossy(group),
+        String::from_utf8_lossy(key)
+    ))
+}
+
+fn no_such_key_or_group(key: &[u8], group: &[u8]) -> Value {
+    Value::Error(format!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    ))
+}
+
+fn no_such_read_group(key: &[u8], group: &[u8]) -> Value {
+    Value::Error(format!(
+        "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    ))
+}
+
+/// The `XREADGROUP` that reads what a read of the stream at `key` did.
+fn read_group_command(
+    key: &Bytes,
+    group: &Bytes,
+    consumer: &Bytes,
+    count: Option<usize>,
+    noack: bool,
+    from: Bytes,
+) -> Vec<Bytes> {
+    let mut command = vec![
+        Bytes::from_static(b"XREADGROUP"),
+        Bytes::from_static(b"GROUP"),
+        group.clone(),
+        consumer.clone(),
+    ];
+    if let Some(count) = count {
+        command.push(Bytes::from_static(b"COUNT"));
+        command.push(Bytes::from(count.to_string()));
+    }
+    if noack {
+        command.push(Bytes::from_static(b"NOACK"));
+    }
+    command.extend([Bytes::from_static(b"STREAMS"), key.clone(), from]);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpStream;
+    use tokio::time::sleep;
+
+    use super::super::tests::{bulk, call, connect, error, start_server};
+    use super::*;
+
+    fn entry(id: &str, fields: &[&str]) -> Value {
+        Value::Array(vec![
+            bulk(id),
+            Value::Array(fields.iter().map(|field| bulk(field)).collect()),
+        ])
+    }
+
+    fn read_reply(key: &str, entries: Vec<Value>) -> Value {
+        Value::Array(vec![Value::Array(vec![bulk(key), Value::Array(entries)])])
+    }
+
+    /// The value of `name` in a map replied as a flat RESP2 array.
+    fn field_of(reply: &Value, name: &str) -> Value {
+        let Value::Array(pairs) = reply else {
+            panic!("not a map: {reply:?}");
+        };
+        let index = pairs.iter().position(|key| *key == bulk(name)).unwrap();
+        pairs[index + 1].clone()
+    }
+
+    #[tokio::test]
+    async fn groups_share_entries_between_consumers() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["XGROUP", "CREATE", "s", "g", "$"]).await,
+            error("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.")
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]
+            )
+            .await,
+            ok()
+        );
+        assert_eq!(
+            call(&mut stream, &["XGROUP", "CREATE", "s", "g", "0"]).await,
+            error("BUSYGROUP Consumer Group name already exists")
+        );
+        for seq in 1..=3 {
+            let id = format!("1-{seq}");
+            call(&mut stream, &["XADD", "s", &id, "n", &seq.to_string()]).await;
+        }
+        let args = [
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "alice",
+            "COUNT",
+            "2",
+            "STREAMS",
+            "s",
+            ">",
+        ];
+        assert_eq!(
+            call(&mut stream, &args).await,
+            read_reply(
+                "s",
+                vec![entry("1-1", &["n", "1"]), entry("1-2", &["n", "2"])]
+            )
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]
+            )
+            .await,
+            read_reply("s", vec![entry("1-3", &["n", "3"])])
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]
+            )
+            .await,
+            Value::NullArray
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XREADGROUP", "GROUP", "nope", "bob", "STREAMS", "s", ">"]
+            )
+            .await,
+            error(
+                "NOGROUP No such key 's' or consumer group 'nope' in XREADGROUP with GROUP option"
+            )
+        );
+
+        // Reading from an ID replays the consumer's own pending entries.
+        call(&mut stream, &["XDEL", "s", "1-1"]).await;
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "0"]
+            )
+            .await,
+            read_reply(
+                "s",
+                vec![
+                    Value::Array(vec![bulk("1-1"), Value::NullArray]),
+                    entry("1-2", &["n", "2"]),
+                ]
+            )
+        );
+        assert_eq!(
+            call(&mut stream, &["XACK", "s", "g", "1-1", "1-2", "9-9"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "0"]
+            )
+            .await,
+            read_reply("s", vec![])
+        );
+
+        call(&mut stream, &["XADD", "s", "2-0", "n", "4"]).await;
+        let noack = [
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "carol",
+            "NOACK",
+            "STREAMS",
+            "s",
+            ">",
+        ];
+        assert_eq!(
+            call(&mut stream, &noack).await,
+            read_reply("s", vec![entry("2-0", &["n", "4"])])
+        );
+        assert_eq!(
+            call(&mut stream, &["XPENDING", "s", "g"]).await,
+            Value::Array(vec![
+                Value::Integer(1),
+                bulk("1-3"),
+                bulk("1-3"),
+                Value::Array(vec![Value::Array(vec![bulk("bob"), bulk("1")])]),
+            ])
+        );
+        match call(&mut stream, &["XPENDING", "s", "g", "-", "+", "10", "bob"]).await {
+            Value::Array(entries) => match &entries[..] {
+                [Value::Array(entry)] => {
+                    assert_eq!(entry[0], bulk("1-3"));
+                    assert_eq!(entry[1], bulk("bob"));
+                    assert_eq!(entry[3], Value::Integer(1));
+                }
+                entries => panic!("unexpected pending entries {entries:?}"),
+            },
+            reply => panic!("unexpected XPENDING reply {reply:?}"),
+        }
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XPENDING", "s", "g", "-", "+", "10", "alice"]
+            )
+            .await,
+            Value::Array(vec![])
+        );
+
+        let groups = call(&mut stream, &["XINFO", "GROUPS", "s"]).await;
+        let groups = match groups {
+            Value::Array(groups) => groups,
+            reply => panic!("unexpected XINFO GROUPS reply {reply:?}"),
+        };
+        assert_eq!(field_of(&groups[0], "name"), bulk("g"));
+        assert_eq!(field_of(&groups[0], "consumers"), Value::Integer(3));
+        assert_eq!(field_of(&groups[0], "pending"), Value::Integer(1));
+        assert_eq!(field_of(&groups[0], "last-delivered-id"), bulk("2-0"));
+        let consumers = call(&mut stream, &["XINFO", "CONSUMERS", "s", "g"]).await;
+        let consumers = match consumers {
+            Value::Array(consumers) => consumers,
+            reply => panic!("unexpected XINFO CONSUMERS reply {reply:?}"),
+        };
+        assert_eq!(field_of(&consumers[1], "name"), bulk("bob"));
+        assert_eq!(field_of(&consumers[1], "pending"), Value::Integer(1));
+        let info = call(&mut stream, &["XINFO", "STREAM", "s"]).await;
+        assert_eq!(field_of(&info, "length"), Value::Integer(3));
+        assert_eq!(field_of(&info, "groups"), Value::Integer(1));
+        assert_eq!(field_of(&info, "entries-added"), Value::Integer(4));
+
+        assert_eq!(
+            call(&mut stream, &["XGROUP", "DELCONSUMER", "s", "g", "bob"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["XGROUP", "CREATECONSUMER", "s", "g", "bob"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["XGROUP", "SETID", "s", "g", "0"]).await,
+            ok()
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]
+            )
+            .await,
+            read_reply(
+                "s",
+                vec![
+                    entry("1-2", &["n", "2"]),
+                    entry("1-3", &["n", "3"]),
+                    entry("2-0", &["n", "4"]),
+                ]
+            )
+        );
+        assert_eq!(
+            call(&mut stream, &["XGROUP", "DESTROY", "s", "g"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["XGROUP", "DESTROY", "s", "g"]).await,
+            Value::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn stuck_entries_change_hands() {
+        let mut stream = connect().await;
+        call(
+            &mut stream,
+            &["XGROUP", "CREATE", "s", "g", "0", "MKSTREAM"],
+        )
+        .await;
+        for ms in 1..=4 {
+            call(
+                &mut stream,
+                &["XADD", "s", &ms.to_string(), "n", &ms.to_string()],
+            )
+            .await;
+        }
+        call(
+            &mut stream,
+            &["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", ">"],
+        )
+        .await;
+
+        // Nothing has been pending for a minute yet, unless IDLE says so.
+        assert_eq!(
+            call(&mut stream, &["XCLAIM", "s", "g", "bob", "60000", "1-0"]).await,
+            Value::Array(vec![])
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XCLAIM", "s", "g", "bob", "0", "1-0", "2-0", "JUSTID"]
+            )
+            .await,
+            Value::Array(vec![bulk("1-0"), bulk("2-0")])
+        );
+        let claim = [
+            "XCLAIM",
+            "s",
+            "g",
+            "carol",
+            "0",
+            "1-0",
+            "IDLE",
+            "60000",
+            "RETRYCOUNT",
+            "7",
+        ];
+        assert_eq!(
+            call(&mut stream, &claim).await,
+            Value::Array(vec![entry("1-0", &["n", "1"])])
+        );
+        // Only the entry claimed with an idle time has been pending that long.
+        let pending = ["XPENDING", "s", "g", "IDLE", "60000", "-", "+", "10"];
+        match call(&mut stream, &pending).await {
+            Value::Array(entries) => match &entries[..] {
+                [Value::Array(entry)] => {
+                    assert_eq!(entry[..2], [bulk("1-0"), bulk("carol")]);
+                    assert!(matches!(entry[2], Value::Integer(idle) if idle >= 60000));
+                    assert_eq!(entry[3], Value::Integer(7));
+                }
+                entries => panic!("unexpected pending entries {entries:?}"),
+            },
+            reply => panic!("unexpected XPENDING reply {reply:?}"),
+        }
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XCLAIM", "s", "g", "bob", "0", "1-0", "BOGUS"]
+            )
+            .await,
+            error("ERR Unrecognized XCLAIM option 'BOGUS'")
+        );
+
+        let autoclaim = ["XAUTOCLAIM", "s", "g", "dave", "0", "0", "COUNT", "2"];
+        assert_eq!(
+            call(&mut stream, &autoclaim).await,
+            Value::Array(vec![
+                bulk("3-0"),
+                Value::Array(vec![entry("1-0", &["n", "1"]), entry("2-0", &["n", "2"])]),
+                Value::Array(vec![]),
+            ])
+        );
+        // Entries deleted meanwhile are dropped instead of claimed.
+        call(&mut stream, &["XDEL", "s", "3-0"]).await;
+        let autoclaim = ["XAUTOCLAIM", "s", "g", "dave", "0", "3-0", "JUSTID"];
+        assert_eq!(
+            call(&mut stream, &autoclaim).await,
+            Value::Array(vec![
+                bulk("0-0"),
+                Value::Array(vec![bulk("4-0")]),
+                Value::Array(vec![bulk("3-0")]),
+            ])
+        );
+        assert_eq!(
+            call(&mut stream, &["XPENDING", "s", "g"]).await,
+            Value::Array(vec![
+                Value::Integer(3),
+                bulk("1-0"),
+                bulk("4-0"),
+                Value::Array(vec![Value::Array(vec![bulk("dave"), bulk("3")])]),
+            ])
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XAUTOCLAIM", "s", "missing", "dave", "0", "0"]
+            )
+            .await,
+            error("NOGROUP No such key 's' or consumer group 'missing'")
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_group_readers_split_new_entries() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        call(
+            &mut stream,
+            &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"],
+        )
+        .await;
+        let mut readers = vec![];
+        for consumer in ["alice", "bob"] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            readers.push(tokio::spawn(async move {
+                call(
+                    &mut stream,
+                    &[
+                        "XREADGROUP",
+                        "GROUP",
+                        "g",
+                        consumer,
+                        "BLOCK",
+                        "0",
+                        "STREAMS",
+                        "s",
+                        ">",
+                    ],
+                )
+                .await
+            }));
+            sleep(Duration::from_millis(50)).await;
+        }
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let other = tokio::spawn(async move {
+            call(
+                &mut other,
+                &[
+                    "XREADGROUP",
+                    "GROUP",
+                    "h",
+                    "carol",
+                    "BLOCK",
+                    "0",
+                    "STREAMS",
+                    "t",
+                    ">",
+                ],
+            )
+            .await
+        });
+        sleep(Duration::from_millis(50)).await;
+
+        call(&mut stream, &["XADD", "s", "1-0", "f", "a"]).await;
+        call(&mut stream, &["XADD", "s", "2-0", "f", "b"]).await;
+        let bob = readers.pop().unwrap();
+        let alice = readers.pop().unwrap();
+        assert_eq!(
+            alice.await.unwrap(),
+            read_reply("s", vec![entry("1-0", &["f", "a"])])
+        );
+        assert_eq!(
+            bob.await.unwrap(),
+            read_reply("s", vec![entry("2-0", &["f", "b"])])
+        );
+        assert_eq!(
+            other.await.unwrap(),
+            error("NOGROUP No such key 't' or consumer group 'h' in XREADGROUP with GROUP option")
+        );
+
+        let mut blocked = TcpStream::connect(addr).await.unwrap();
+        let blocked = tokio::spawn(async move {
+            call(
+                &mut blocked,
+                &[
+                    "XREADGROUP",
+                    "GROUP",
+                    "g",
+                    "dave",
+                    "BLOCK",
+                    "0",
+                    "STREAMS",
+                    "s",
+                    ">",
+                ],
+            )
+            .await
+        });
+        sleep(Duration::from_millis(50)).await;
+        call(&mut stream, &["XGROUP", "DESTROY", "s", "g"]).await;
+        assert_eq!(
+            blocked.await.unwrap(),
+            error("NOGROUP the consumer group this client was blocked on no longer exists")
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::operation::{ExpireConditions, Expiry, OperationDeducer, TimeUnit};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Sets the deadline of an existing key if `conditions` allow it. A
+    /// deadline in the past deletes the key right away.
+    pub(super) async fn handle_expire(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        expiry: Expiry,
+        conditions: ExpireConditions,
+    ) -> Value {
+        let now = unix_time_millis();
+        let deadline = match expiry {
+            Expiry::In(millis) => now.saturating_add(millis),
+            Expiry::At(deadline) => deadline,
+        };
+        let event = Self::update_key(context, key.clone(), |entry| {
+            let df = entry.as_mut()?;
+            // A key without a deadline counts as one that never expires, so
+            // GT never applies to it and LT always does.
+            let allowed = match df.deadline() {
+                None => !(conditions.xx || conditions.gt),
+                Some(current) => {
+                    !(conditions.nx
+                        || conditions.gt && deadline <= current
+                        || conditions.lt && deadline >= current)
+                }
+            };
+            if !allowed {
+                return None;
+            }
+            if deadline <= now {
+                *entry = None;
+                Some("del")
+            } else {
+                df.set_deadline(Some(deadline));
+                Some("expire")
+            }
+        });
+        match event {
+            Some(event) => {
+                Self::notify(context, KeyspaceEvents::GENERIC, event, &key);
+                Value::Integer(1)
+            }
+            None => Value::Integer(0),
+        }
+    }
+
+    /// The time left to live, -1 for keys without a deadline, and -2 for
+    /// missing keys.
+    pub(super) async fn handle_ttl(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        unit: TimeUnit,
+    ) -> Value {
+        Self::read_frame(context, key, |df| match df.map(|df| df.deadline()) {
+            None => Value::Integer(-2),
+            Some(None) => Value::Integer(-1),
+            Some(Some(deadline)) => {
+                Value::Integer(in_unit((deadline - unix_time_millis()).max(0), unit))
+            }
+        })
+    }
+
+    /// Like `TTL`, but replies with the deadline as a Unix time.
+    pub(super) async fn handle_expiretime(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        unit: TimeUnit,
+    ) -> Value {
+        Self::read_frame(context, key, |df| match df.map(|df| df.deadline()) {
+            None => Value::Integer(-2),
+            Some(None) => Value::Integer(-1),
+            Some(Some(deadline)) => Value::Integer(in_unit(deadline, unit)),
+        })
+    }
+
+    pub(super) async fn handle_persist(context: &Context<P, D, S>, key: Bytes) -> Value {
+        let persisted = Self::update_key(context, key.clone(), |entry| match entry {
+            Some(df) if df.deadline().is_some() => {
+                df.set_deadline(None);
+                true
+            }
+            _ => false,
+        });
+        if persisted {
+            Self::notify(context, KeyspaceEvents::GENERIC, "persist", &key);
+        }
+        Value::Integer(persisted as i64)
+    }
+}
+
+/// Converts milliseconds to `unit`, rounding to the nearest second.
+fn in_unit(millis: i64, unit: TimeUnit) -> i64 {
+    match unit {
+        TimeUnit::Seconds => millis / 1000 + i64::from(millis % 1000 >= 500),
+        TimeUnit::Milliseconds => millis,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{call, connect};
+    use super::*;
+
+    fn integer(value: Value) -> i64 {
+        match value {
+            Value::Integer(value) => value,
+            value => panic!("not an integer: {value:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn expire_and_inspect() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "k", "v"]).await;
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(-1));
+        assert_eq!(
+            call(&mut stream, &["TTL", "missing"]).await,
+            Value::Integer(-2)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "100"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(100));
+        let pttl = integer(call(&mut stream, &["PTTL", "k"]).await);
+        assert!((99_000..=100_000).contains(&pttl));
+        let deadline = integer(call(&mut stream, &["PEXPIRETIME", "k"]).await);
+        assert!((deadline - unix_time_millis() - 100_000).abs() < 1000);
+        call(&mut stream, &["PEXPIREAT", "k", "4102444800499"]).await;
+        assert_eq!(
+            call(&mut stream, &["EXPIRETIME", "k"]).await,
+            Value::Integer(4102444800)
+        );
+        call(&mut stream, &["PEXPIREAT", "k", "4102444800500"]).await;
+        assert_eq!(
+            call(&mut stream, &["EXPIRETIME", "k"]).await,
+            Value::Integer(4102444801)
+        );
+        assert_eq!(
+            call(&mut stream, &["PERSIST", "k"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["PERSIST", "k"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRETIME", "k"]).await,
+            Value::Integer(-1)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "missing", "100"]).await,
+            Value::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn absolute_deadlines() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "k", "v"]).await;
+        let deadline = (unix_time_millis() / 1000 + 1000).to_string();
+        assert_eq!(
+            call(&mut stream, &["EXPIREAT", "k", &deadline]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRETIME", "k"]).await,
+            Value::Integer(deadline.parse().unwrap())
+        );
+        assert_eq!(
+            call(&mut stream, &["PEXPIREAT", "k", "1000"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["EXISTS", "k"]).await, Value::Integer(0));
+        call(&mut stream, &["SET", "k", "v"]).await;
+        assert_eq!(
+            call(&mut stream, &["PEXPIRE", "k", "50"]).await,
+            Value::Integer(1)
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(
+            call(&mut stream, &["GET", "k"]).await,
+            Value::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn farthest_deadline_converts_to_seconds() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "k", "v"]).await;
+        assert_eq!(
+            call(&mut stream, &["PEXPIREAT", "k", "9223372036854775807"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRETIME", "k"]).await,
+            Value::Integer(9223372036854776)
+        );
+        assert_eq!(
+            call(&mut stream, &["PEXPIRETIME", "k"]).await,
+            Value::Integer(i64::MAX)
+        );
+        let ttl = integer(call(&mut stream, &["TTL", "k"]).await);
+        assert!((9_223_370_000_000_000..=9_223_372_036_854_776).contains(&ttl));
+    }
+
+    #[tokio::test]
+    async fn expire_conditions() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "k", "v"]).await;
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "100", "XX"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "100", "GT"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "100", "LT"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "200", "NX"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "50", "GT"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "200", "GT"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "300", "LT"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "150", "LT", "XX"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(150));
+        call(&mut stream, &["SET", "plain", "v"]).await;
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "plain", "100", "LT", "XX"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "1", "NX", "GT"]).await,
+            Value::Error(String::from(
+                "ERR NX and XX, GT or LT options at the same time are not compatible"
+            ))
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "1", "GT", "LT"]).await,
+            Value::Error(String::from(
+                "ERR GT and LT options at the same time are not compatible"
+            ))
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "9223372036854775807"]).await,
+            Value::Error(String::from("ERR invalid expire time in 'expire' command"))
+        );
+        assert_eq!(
+            call(&mut stream, &["EXPIRE", "k", "-1"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(-2));
+    }
+}
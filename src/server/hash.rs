@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{remove_if_empty, wrong_type, Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::decimal;
+use crate::operation::OperationDeducer;
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Sets hash fields, replying with the number of new fields.
+    pub(super) async fn handle_hset(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| match hash_or_insert(entry) {
+            Err(err) => err,
+            Ok(hash) => {
+                let added = pairs
+                    .into_iter()
+                    .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+                    .count();
+                Value::Integer(added as i64)
+            }
+        });
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::HASH, "hset", &key);
+        }
+        reply
+    }
+
+    /// The legacy form of `HSET`, which replies with OK.
+    pub(super) async fn handle_hmset(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+    ) -> Value {
+        match Self::handle_hset(context, key, pairs).await {
+            Value::Integer(_) => Value::SimpleString(String::from("OK")),
+            err => err,
+        }
+    }
+
+    pub(super) async fn handle_hsetnx(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        field: Bytes,
+        value: Bytes,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| match hash_or_insert(entry) {
+            Err(err) => err,
+            Ok(hash) if hash.contains_key(&field) => Value::Integer(0),
+            Ok(hash) => {
+                hash.insert(field, value);
+                Value::Integer(1)
+            }
+        });
+        if matches!(reply, Value::Integer(1)) {
+            Self::notify(context, KeyspaceEvents::HASH, "hset", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_hget(context: &Context<P, D, S>, key: Bytes, field: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::NullBulkString,
+            Some(Data::Hash(hash)) => match hash.get(&field) {
+                Some(value) => Value::BulkString(value.clone()),
+                None => Value::NullBulkString,
+            },
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_hmget(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        fields: Vec<Bytes>,
+    ) -> Value {
+        let empty = HashMap::new();
+        Self::read_key(context, key, |data| {
+            let hash = match data {
+                None => &empty,
+                Some(Data::Hash(hash)) => hash,
+                Some(_) => return wrong_type(),
+            };
+            Value::Array(
+                fields
+                    .iter()
+                    .map(|field| match hash.get(field) {
+                        Some(value) => Value::BulkString(value.clone()),
+                        None => Value::NullBulkString,
+                    })
+                    .collect(),
+            )
+        })
+    }
+
+    pub(super) async fn handle_hdel(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        fields: Vec<Bytes>,
+    ) -> Value {
+        let (reply, removed) = Self::update_key(context, key.clone(), |entry| {
+            let reply = match hash_mut(entry) {
+                Err(err) => err,
+                Ok(None) => Value::Integer(0),
+                Ok(Some(hash)) => {
+                    let removed = fields
+                        .iter()
+                        .filter(|field| hash.remove(*field).is_some())
+                        .count();
+                    Value::Integer(removed as i64)
+                }
+            };
+            (reply, remove_if_empty(entry))
+        });
+        if matches!(reply, Value::Integer(deleted) if deleted > 0) {
+            Self::notify(context, KeyspaceEvents::HASH, "hdel", &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_hgetall(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Map(vec![]),
+            Some(Data::Hash(hash)) => Value::Map(
+                hash.iter()
+                    .map(|(field, value)| {
+                        (
+                            Value::BulkString(field.clone()),
+                            Value::BulkString(value.clone()),
+                        )
+                    })
+                    .collect(),
+            ),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_hincrby(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        field: Bytes,
+        increment: i64,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| {
+            let reply = match hash_or_insert(entry) {
+                Err(err) => err,
+                Ok(hash) => {
+                    let current = match hash.get(&field) {
+                        None => Some(0),
+                        Some(value) => std::str::from_utf8(value)
+                            .ok()
+                            .and_then(|value| value.parse::<i64>().ok()),
+                    };
+                    match current.map(|current| current.checked_add(increment)) {
+                        None => Value::Error(String::from("ERR hash value is not an integer")),
+                        Some(None) => {
+                            Value::Error(String::from("ERR increment or decrement would overflow"))
+                        }
+                        Some(Some(value)) => {
+                            hash.insert(field, Bytes::from(value.to_string()));
+                            Value::Integer(value)
+                        }
+                    }
+                }
+            };
+            remove_if_empty(entry);
+            reply
+        });
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::HASH, "hincrby", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_hincrbyfloat(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        field: Bytes,
+        increment: f64,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| {
+            let reply = match hash_or_insert(entry) {
+                Err(err) => err,
+                Ok(hash) => {
+                    let current = match hash.get(&field) {
+                        None => Some(0.0),
+                        Some(value) => std::str::from_utf8(value)
+                            .ok()
+                            .and_then(|value| value.parse::<f64>().ok())
+                            .filter(|value| value.is_finite()),
+                    };
+                    match current {
+                        None => Value::Error(String::from("ERR hash value is not a float")),
+                        Some(current) if !(current + increment).is_finite() => {
+                            Value::Error(String::from(
+                                "ERR increment would produce NaN or Infinity",
+                            ))
+                        }
+                        Some(current) => {
+                            let value = Bytes::from(decimal::add(current, increment));
+                            hash.insert(field, value.clone());
+                            Value::BulkString(value)
+                        }
+                    }
+                }
+            };
+            remove_if_empty(entry);
+            reply
+        });
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::HASH, "hincrbyfloat", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_hexists(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        field: Bytes,
+    ) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::Hash(hash)) => Value::Integer(hash.contains_key(&field) as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_hkeys(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Array(vec![]),
+            Some(Data::Hash(hash)) => {
+                Value::Array(hash.keys().cloned().map(Value::BulkString).collect())
+            }
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_hvals(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Array(vec![]),
+            Some(Data::Hash(hash)) => {
+                Value::Array(hash.values().cloned().map(Value::BulkString).collect())
+            }
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_hlen(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::Hash(hash)) => Value::Integer(hash.len() as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_hstrlen(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        field: Bytes,
+    ) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::Hash(hash)) => {
+                Value::Integer(hash.get(&field).map_or(0, |value| value.len() as i64))
+            }
+            Some(_) => wrong_type(),
+        })
+    }
+}
+
+/// The hash stored in `entry`, or the WRONGTYPE error if it holds another type.
+fn hash_mut(
+    entry: &mut Option<DataFrame<Data>>,
+) -> Result<Option<&mut HashMap<Bytes, Bytes>>, Value> {
+    match entry.as_mut().and_then(|df| df.data_mut()) {
+        None => Ok(None),
+        Some(Data::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Like [`hash_mut`], but stores an empty hash first if the key is absent.
+fn hash_or_insert(
+    entry: &mut Option<DataFrame<Data>>,
+) -> Result<&mut HashMap<Bytes, Bytes>, Value> {
+    if entry.is_none() {
+        *entry = Some(DataFrame::Plain(Data::Hash(HashMap::new())));
+    }
+    hash_mut(entry).map(|hash| hash.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{bulk, call, connect};
+    use super::*;
+    use crate::value::Protocol;
+
+    fn sorted(value: Value) -> Value {
+        match value {
+            Value::Array(mut values) => {
+                values.sort_by_key(|value| format!("{value}"));
+                Value::Array(values)
+            }
+            value => value,
+        }
+    }
+
+    #[tokio::test]
+    async fn set_get_and_delete_fields() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["HSET", "h", "a", "1", "b", "2"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["HSET", "h", "a", "3", "c", "4"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["HGET", "h", "a"]).await, bulk("3"));
+        assert_eq!(
+            call(&mut stream, &["HGET", "h", "x"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["HMGET", "h", "b", "x", "c"]).await,
+            Value::Array(vec![bulk("2"), Value::NullBulkString, bulk("4")])
+        );
+        assert_eq!(
+            call(&mut stream, &["HSETNX", "h", "a", "9"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(call(&mut stream, &["HLEN", "h"]).await, Value::Integer(3));
+        assert_eq!(
+            call(&mut stream, &["HEXISTS", "h", "b"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["HSTRLEN", "h", "b"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            sorted(call(&mut stream, &["HKEYS", "h"]).await),
+            Value::Array(vec![bulk("a"), bulk("b"), bulk("c")])
+        );
+        assert_eq!(
+            sorted(call(&mut stream, &["HVALS", "h"]).await),
+            Value::Array(vec![bulk("2"), bulk("3"), bulk("4")])
+        );
+        assert_eq!(
+            call(&mut stream, &["HDEL", "h", "a", "b", "x"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["HDEL", "h", "c"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["HLEN", "h"]).await, Value::Integer(0));
+        assert_eq!(
+            call(&mut stream, &["HMSET", "h", "a", "1"]).await,
+            Value::SimpleString(String::from("OK"))
+        );
+    }
+
+    #[tokio::test]
+    async fn getall_replies_with_map_in_resp3() {
+        let mut stream = connect().await;
+        call(&mut stream, &["HSET", "h", "a", "1"]).await;
+        assert_eq!(
+            call(&mut stream, &["HGETALL", "h"]).await,
+            Value::Array(vec![bulk("a"), bulk("1")])
+        );
+        call(&mut stream, &["HELLO", "3"]).await;
+        assert_eq!(
+            call(&mut stream, &["HGETALL", "h"]).await,
+            Value::Map(vec![(bulk("a"), bulk("1"))])
+        );
+        assert_eq!(
+            call(&mut stream, &["HGETALL", "missing"]).await,
+            Value::Map(vec![]).for_protocol(Protocol::Resp3)
+        );
+    }
+
+    #[tokio::test]
+    async fn increment_fields() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["HINCRBY", "h", "n", "5"]).await,
+            Value::Integer(5)
+        );
+        assert_eq!(
+            call(&mut stream, &["HINCRBY", "h", "n", "-7"]).await,
+            Value::Integer(-2)
+        );
+        assert_eq!(
+            call(&mut stream, &["HINCRBYFLOAT", "h", "f", "10.5"]).await,
+            bulk("10.5")
+        );
+        assert_eq!(
+            call(&mut stream, &["HINCRBYFLOAT", "h", "f", "0.1"]).await,
+            bulk("10.6")
+        );
+        call(&mut stream, &["HSET", "h", "g", "0.2"]).await;
+        assert_eq!(
+            call(&mut stream, &["HINCRBYFLOAT", "h", "g", "0.1"]).await,
+            bulk("0.3")
+        );
+        call(&mut stream, &["HSET", "h", "s", "abc"]).await;
+        assert_eq!(
+            call(&mut stream, &["HINCRBY", "h", "s", "1"]).await,
+            Value::Error(String::from("ERR hash value is not an integer"))
+        );
+        call(&mut stream, &["HSET", "h", "big", &i64::MAX.to_string()]).await;
+        assert_eq!(
+            call(&mut stream, &["HINCRBY", "h", "big", "1"]).await,
+            Value::Error(String::from("ERR increment or decrement would overflow"))
+        );
+        assert_eq!(
+            call(&mut stream, &["HINCRBY", "fresh", "s", "x"]).await,
+            Value::Error(String::from("ERR value is not an integer or out of range"))
+        );
+        call(&mut stream, &["RPUSH", "l", "a"]).await;
+        assert_eq!(call(&mut stream, &["HGET", "l", "a"]).await, wrong_type());
+        assert_eq!(
+            call(&mut stream, &["HSET", "l", "a", "b"]).await,
+            wrong_type()
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{locked, Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::glob;
+use crate::operation::OperationDeducer;
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Deletes all of `keys` at once, replying with how many existed.
+    pub(super) async fn handle_del(context: &Context<P, D, S>, keys: Vec<Bytes>) -> Value {
+        let deleted: Vec<Bytes> = Self::update_keys(context, keys, |entries| {
+            entries
+                .iter_mut()
+                .filter_map(|(key, entry)| entry.take().map(|_| key.clone()))
+                .collect()
+        });
+        for key in &deleted {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", key);
+        }
+        Value::Integer(deleted.len() as i64)
+    }
+
+    /// Counts the keys that exist. A key given twice is counted twice.
+    pub(super) async fn handle_exists(context: &Context<P, D, S>, keys: Vec<Bytes>) -> Value {
+        let existing = keys
+            .into_iter()
+            .filter(|key| Self::read_key(context, key.clone(), |data| data.is_some()))
+            .count();
+        Value::Integer(existing as i64)
+    }
+
+    pub(super) async fn handle_type(context: &Context<P, D, S>, key: Bytes) -> Value {
+        let name = Self::read_key(context, key, |data| data.map_or("none", Data::type_name));
+        Value::SimpleString(String::from(name))
+    }
+
+    /// Moves the value of `key`, along with its expiration, to `new_key`,
+    /// replacing whatever `new_key` held. With `nx` the rename only happens
+    /// if `new_key` does not exist.
+    pub(super) async fn handle_rename(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        new_key: Bytes,
+        nx: bool,
+    ) -> Value {
+        let keys = vec![key.clone(), new_key.clone()];
+        let (reply, renamed) = Self::update_keys(context, keys, |entries| {
+            if entries.get(&key).is_none() {
+                return (Value::Error(String::from("ERR no such key")), false);
+            }
+            if nx {
+                if entries.get(&new_key).is_some() {
+                    return (Value::Integer(0), false);
+                }
+            } else if key == new_key {
+                return (Value::SimpleString(String::from("OK")), false);
+            }
+            let value = match locked(entries, &key) {
+                Ok(entry) => entry.take(),
+                Err(err) => return (err, false),
+            };
+            match locked(entries, &new_key) {
+                Ok(entry) => *entry = value,
+                Err(err) => return (err, false),
+            }
+            match nx {
+                true => (Value::Integer(1), true),
+                false => (Value::SimpleString(String::from("OK")), true),
+            }
+        });
+        if renamed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "rename_from", &key);
+            Self::notify(context, KeyspaceEvents::GENERIC, "rename_to", &new_key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_keys(context: &Context<P, D, S>, pattern: Bytes) -> Value {
+        let mut keys = vec![];
+        context.store.for_each(|key, df| {
+            if !df.has_expired() && glob::matches(&pattern, key) {
+                keys.push(Value::BulkString(key.clone()));
+            }
+        });
+        Value::Array(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{bulk, call, connect, ok};
+    use super::*;
+
+    #[tokio::test]
+    async fn delete_and_check_existence() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "a", "1"]).await;
+        call(&mut stream, &["RPUSH", "b", "1"]).await;
+        assert_eq!(
+            call(&mut stream, &["EXISTS", "a", "b", "a", "c"]).await,
+            Value::Integer(3)
+        );
+        assert_eq!(
+            call(&mut stream, &["DEL", "a", "b", "a", "c"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXISTS", "a", "b"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["GET", "a"]).await,
+            Value::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn type_of_each_value() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "string", "1"]).await;
+        call(&mut stream, &["RPUSH", "list", "1"]).await;
+        call(&mut stream, &["HSET", "hash", "f", "1"]).await;
+        call(&mut stream, &["SADD", "set", "1"]).await;
+        call(&mut stream, &["ZADD", "zset", "1", "a"]).await;
+        for name in ["string", "list", "hash", "set", "zset"] {
+            assert_eq!(
+                call(&mut stream, &["TYPE", name]).await,
+                Value::SimpleString(String::from(name))
+            );
+        }
+        assert_eq!(
+            call(&mut stream, &["TYPE", "missing"]).await,
+            Value::SimpleString(String::from("none"))
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_keys() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "a", "1"]).await;
+        call(&mut stream, &["RPUSH", "b", "x"]).await;
+        assert_eq!(call(&mut stream, &["RENAME", "a", "b"]).await, ok());
+        assert_eq!(call(&mut stream, &["GET", "b"]).await, bulk("1"));
+        assert_eq!(call(&mut stream, &["EXISTS", "a"]).await, Value::Integer(0));
+        assert_eq!(
+            call(&mut stream, &["RENAME", "a", "b"]).await,
+            Value::Error(String::from("ERR no such key"))
+        );
+        assert_eq!(call(&mut stream, &["RENAME", "b", "b"]).await, ok());
+        assert_eq!(call(&mut stream, &["GET", "b"]).await, bulk("1"));
+        call(&mut stream, &["SET", "c", "2"]).await;
+        assert_eq!(
+            call(&mut stream, &["RENAMENX", "b", "c"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["RENAMENX", "b", "d"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["GET", "d"]).await, bulk("1"));
+    }
+
+    #[tokio::test]
+    async fn keys_matching_pattern() {
+        let mut stream = connect().await;
+        for key in ["hello", "hallo", "hxllo", "hllo", "heeeello", "h*llo"] {
+            call(&mut stream, &["SET", key, "1"]).await;
+        }
+        let keys = |value: Value| {
+            let mut keys = match value {
+                Value::Array(keys) => keys,
+                value => panic!("not an array: {value:?}"),
+            };
+            keys.sort_by_key(|key| format!("{key}"));
+            keys
+        };
+        assert_eq!(
+            keys(call(&mut stream, &["KEYS", "h?llo"]).await),
+            [bulk("h*llo"), bulk("hallo"), bulk("hello"), bulk("hxllo")]
+        );
+        assert_eq!(
+            keys(call(&mut stream, &["KEYS", "h[^e]llo"]).await),
+            [bulk("h*llo"), bulk("hallo"), bulk("hxllo")]
+        );
+        assert_eq!(
+            keys(call(&mut stream, &["KEYS", "h[a-e]llo"]).await),
+            [bulk("hallo"), bulk("hello")]
+        );
+        assert_eq!(
+            keys(call(&mut stream, &["KEYS", "h\\*llo"]).await),
+            [bulk("h*llo")]
+        );
+        assert_eq!(keys(call(&mut stream, &["KEYS", "*"]).await).len(), 6);
+    }
+}
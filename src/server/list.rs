@@ -0,0 +1,902 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use super::{locked, normalize_range, remove_if_empty, wrong_type, Context, Server};
+use crate::blocking::{BlockedOperation, Waiter};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::{InsertPosition, ListEnd, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::transaction::SharedGate;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    pub(super) async fn handle_push(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        values: Vec<Bytes>,
+        end: ListEnd,
+    ) -> Value {
+        let reply = Self::push_values(context, key.clone(), values, end);
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::LIST, push_event(end), &key);
+        }
+        reply
+    }
+
+    fn push_values(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        values: Vec<Bytes>,
+        end: ListEnd,
+    ) -> Value {
+        Self::update_key(context, key, |entry| match push_entry(entry, values, end) {
+            Err(err) => err,
+            Ok(len) => Value::Integer(len as i64),
+        })
+    }
+
+    pub(super) async fn handle_pushx(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        values: Vec<Bytes>,
+        end: ListEnd,
+    ) -> Value {
+        let (reply, pushed) =
+            Self::update_key(context, key.clone(), |entry| match list_mut(entry) {
+                Err(err) => (err, false),
+                Ok(Some(list)) => {
+                    push_all(list, values, end);
+                    (Value::Integer(list.len() as i64), true)
+                }
+                Ok(None) => (Value::Integer(0), false),
+            });
+        if pushed {
+            Self::notify(context, KeyspaceEvents::LIST, push_event(end), &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_pop(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        count: Option<usize>,
+        end: ListEnd,
+    ) -> Value {
+        let (reply, removed) = Self::update_key(context, key.clone(), |entry| {
+            let reply = match (list_mut(entry), count) {
+                (Err(err), _) => err,
+                (Ok(None), None) => Value::NullBulkString,
+                (Ok(None), Some(_)) => Value::NullArray,
+                (Ok(Some(list)), None) => match pop(list, end) {
+                    Some(value) => Value::BulkString(value),
+                    None => Value::NullBulkString,
+                },
+                (Ok(Some(list)), Some(count)) => Value::Array(
+                    (0..count.min(list.len()))
+                        .filter_map(|_| pop(list, end))
+                        .map(Value::BulkString)
+                        .collect(),
+                ),
+            };
+            (reply, remove_if_empty(entry))
+        });
+        let popped = match &reply {
+            Value::BulkString(_) => true,
+            Value::Array(values) => !values.is_empty(),
+            _ => false,
+        };
+        if popped {
+            Self::notify(context, KeyspaceEvents::LIST, pop_event(end), &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_llen(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::List(list)) => Value::Integer(list.len() as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_lrange(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    ) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Array(vec![]),
+            Some(Data::List(list)) => match normalize_range(start, stop, list.len()) {
+                None => Value::Array(vec![]),
+                Some((start, stop)) => Value::Array(
+                    list.range(start..=stop)
+                        .cloned()
+                        .map(Value::BulkString)
+                        .collect(),
+                ),
+            },
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_lindex(context: &Context<P, D, S>, key: Bytes, index: i64) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::NullBulkString,
+            Some(Data::List(list)) => match normalize_index(index, list.len()) {
+                Some(index) => Value::BulkString(list[index].clone()),
+                None => Value::NullBulkString,
+            },
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_lset(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        index: i64,
+        value: Bytes,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| match list_mut(entry) {
+            Err(err) => err,
+            Ok(None) => Value::Error(String::from("ERR no such key")),
+            Ok(Some(list)) => match normalize_index(index, list.len()) {
+                Some(index) => {
+                    list[index] = value;
+                    Value::SimpleString(String::from("OK"))
+                }
+                None => Value::Error(String::from("ERR index out of range")),
+            },
+        });
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::LIST, "lset", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_ltrim(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    ) -> Value {
+        let (reply, trimmed, removed) = Self::update_key(context, key.clone(), |entry| {
+            let (reply, trimmed) = match list_mut(entry) {
+                Err(err) => (err, false),
+                Ok(None) => (Value::SimpleString(String::from("OK")), false),
+                Ok(Some(list)) => {
+                    match normalize_range(start, stop, list.len()) {
+                        Some((start, stop)) => {
+                            list.truncate(stop + 1);
+                            list.drain(..start);
+                        }
+                        None => list.clear(),
+                    }
+                    (Value::SimpleString(String::from("OK")), true)
+                }
+            };
+            (reply, trimmed, remove_if_empty(entry))
+        });
+        if trimmed {
+            Self::notify(context, KeyspaceEvents::LIST, "ltrim", &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_lrem(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        count: i64,
+        value: Bytes,
+    ) -> Value {
+        let (reply, removed) = Self::update_key(context, key.clone(), |entry| {
+            let reply = match list_mut(entry) {
+                Err(err) => err,
+                Ok(None) => Value::Integer(0),
+                Ok(Some(list)) => {
+                    let total = list.iter().filter(|element| **element == value).count();
+                    let limit = match count {
+                        0 => total,
+                        _ => (count.unsigned_abs() as usize).min(total),
+                    };
+                    // Removing from the tail means keeping the first matches.
+                    let mut skip = if count < 0 { total - limit } else { 0 };
+                    let mut remaining = limit;
+                    list.retain(|element| {
+                        if *element != value || remaining == 0 {
+                            return true;
+                        }
+                        if skip > 0 {
+                            skip -= 1;
+                            return true;
+                        }
+                        remaining -= 1;
+                        false
+                    });
+                    Value::Integer(limit as i64)
+                }
+            };
+            (reply, remove_if_empty(entry))
+        });
+        if matches!(reply, Value::Integer(removed) if removed > 0) {
+            Self::notify(context, KeyspaceEvents::LIST, "lrem", &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_linsert(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        position: InsertPosition,
+        pivot: Bytes,
+        value: Bytes,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| match list_mut(entry) {
+            Err(err) => err,
+            Ok(None) => Value::Integer(0),
+            Ok(Some(list)) => match list.iter().position(|element| *element == pivot) {
+                None => Value::Integer(-1),
+                Some(i) => {
+                    let i = match position {
+                        InsertPosition::Before => i,
+                        InsertPosition::After => i + 1,
+                    };
+                    list.insert(i, value);
+                    Value::Integer(list.len() as i64)
+                }
+            },
+        });
+        if matches!(reply, Value::Integer(len) if len > 0) {
+            Self::notify(context, KeyspaceEvents::LIST, "linsert", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_lmove(
+        context: &Context<P, D, S>,
+        source: Bytes,
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Value {
+        match Self::move_element(context, &source, &destination, from, to) {
+            Err(err) => err,
+            Ok(Some(value)) => Value::BulkString(value),
+            Ok(None) => Value::NullBulkString,
+        }
+    }
+
+    pub(super) async fn handle_blocking_pop<'a>(
+        context: &'a Context<P, D, S>,
+        keys: Vec<Bytes>,
+        end: ListEnd,
+        timeout: Option<Duration>,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        let operation = BlockedOperation::Pop(end);
+        Self::block_on(context, keys, operation, timeout, Value::NullArray, gate).await
+    }
+
+    pub(super) async fn handle_blmove<'a>(
+        context: &'a Context<P, D, S>,
+        source: Bytes,
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+        timeout: Option<Duration>,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        let operation = BlockedOperation::Move(from, destination, to);
+        Self::block_on(
+            context,
+            vec![source],
+            operation,
+            timeout,
+            Value::NullBulkString,
+            gate,
+        )
+        .await
+    }
+
+    /// Serves the client right away if one of `keys` has what it waits for,
+    /// and otherwise parks it until a write serves it or `timeout` passes.
+    ///
+    /// The shared `gate` is released while waiting so a transaction can run
+    /// meanwhile, and taken again before returning. Without a gate the
+    /// command is part of a running transaction and, as in Redis, never waits.
+    pub(super) async fn block_on<'a>(
+        context: &'a Context<P, D, S>,
+        keys: Vec<Bytes>,
+        operation: BlockedOperation,
+        timeout: Option<Duration>,
+        timeout_reply: Value,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        let (id, mut receiver) = {
+            let mut waiters = context.blocking.lock();
+            for key in &keys {
+                if let Some(reply) = Self::try_serve(context, key, &operation) {
+                    return reply;
+                }
+            }
+            if gate.is_none() {
+                return timeout_reply;
+            }
+            let (reply, receiver) = oneshot::channel();
+            let id = waiters.register(Waiter {
+                keys,
+                operation,
+                reply,
+            });
+            (id, receiver)
+        };
+        *gate = None;
+        let served = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, &mut receiver).await.ok(),
+            None => Some((&mut receiver).await),
+        };
+        *gate = Some(context.transactions.shared().await);
+        if let Some(Ok(reply)) = served {
+            return reply;
+        }
+        context.blocking.lock().remove(id);
+        // A push may have served the client between the timeout and the removal.
+        receiver.try_recv().unwrap_or(timeout_reply)
+    }
+
+    /// Hands elements of `key` to the clients blocked on it, longest waiting
+    /// first, for as long as the list has elements, or new entries of the
+    /// stream at `key` to every client waiting for them.
+    pub(super) fn serve_blocked_clients(context: &Context<P, D, S>, key: Bytes) {
+        let mut waiters = context.blocking.lock();
+        let mut ready = VecDeque::from([key]);
+        while let Some(key) = ready.pop_front() {
+            for id in waiters.queued(&key) {
+                let waiter = match waiters.get(id) {
+                    Some(waiter) => waiter,
+                    // Served through another key meanwhile.
+                    None => continue,
+                };
+                if waiter.reply.is_closed() {
+                    waiters.remove(id);
+                    continue;
+                }
+                let operation = waiter.operation.clone();
+                let reply = match Self::try_serve(context, &key, &operation) {
+                    Some(reply) => reply,
+                    // Readers may wait for entries past the ones just added,
+                    // but once a list is empty nobody else can be served.
+                    None if matches!(
+                        operation,
+                        BlockedOperation::Read { .. } | BlockedOperation::ReadGroup { .. }
+                    ) =>
+                    {
+                        continue
+                    }
+                    None => break,
+                };
+                let waiter = waiters.remove(id).unwrap();
+                match (operation, waiter.reply.send(reply)) {
+                    (BlockedOperation::Move(_, destination, _), _) => {
+                        context.transactions.touch([&destination]);
+                        ready.push_back(destination);
+                    }
+                    // The client went away after being picked, so the element goes back.
+                    (BlockedOperation::Pop(end), Err(Value::Array(mut reply))) => {
+                        if let Some(Value::BulkString(value)) = reply.pop() {
+                            Self::push_values(context, key.clone(), vec![value.clone()], end);
+                            Self::notify(context, KeyspaceEvents::LIST, push_event(end), &key);
+                            let push = match end {
+                                ListEnd::Left => "LPUSH",
+                                ListEnd::Right => "RPUSH",
+                            };
+                            Self::propagate(context, vec![Bytes::from(push), key.clone(), value]);
+                        }
+                    }
+                    (
+                        BlockedOperation::Pop(_)
+                        | BlockedOperation::Read { .. }
+                        | BlockedOperation::ReadGroup { .. },
+                        _,
+                    ) => {}
+                }
+            }
+        }
+    }
+
+    /// Performs `operation` on `key` for a blocked client, or returns `None`
+    /// if the list is empty and the client has to keep waiting. What it
+    /// does is logged here, under the registry lock, so that it is logged in
+    /// the order it happened.
+    fn try_serve(
+        context: &Context<P, D, S>,
+        key: &Bytes,
+        operation: &BlockedOperation,
+    ) -> Option<Value> {
+        match operation {
+            BlockedOperation::Pop(end) => {
+                let reply = Self::pop_for_blocked(context, key, *end)?;
+                if !matches!(reply, Value::Error(_)) {
+                    let pop = match end {
+                        ListEnd::Left => "LPOP",
+                        ListEnd::Right => "RPOP",
+                    };
+                    Self::propagate(context, vec![Bytes::from(pop), key.clone()]);
+                }
+                Some(reply)
+            }
+            BlockedOperation::Move(from, destination, to) => {
+                match Self::move_element(context, key, destination, *from, *to) {
+                    Err(err) => Some(err),
+                    Ok(value) => {
+                        let value = value?;
+                        let command = vec![
+                            Bytes::from_static(b"LMOVE"),
+                            key.clone(),
+                            destination.clone(),
+                            end_name(*from),
+                            end_name(*to),
+                        ];
+                        Self::propagate(context, command);
+                        Some(Value::BulkString(value))
+                    }
+                }
+            }
+            BlockedOperation::Read {
+                after,
+                count,
+                protocol,
+            } => {
+                let (_, after) = after.iter().find(|(read, _)| read == key)?;
+                Self::serve_reader(context, key, *after, *count, *protocol)
+            }
+            BlockedOperation::ReadGroup {
+                group,
+                consumer,
+                count,
+                noack,
+                protocol,
+            } => Self::serve_group_reader(context, key, group, consumer, *count, *noack, *protocol),
+        }
+    }
+
+    /// Pops an element from the list at `key` for a client blocked on it, or
+    /// returns `None` if there is none.
+    fn pop_for_blocked(context: &Context<P, D, S>, key: &Bytes, end: ListEnd) -> Option<Value> {
+        let (reply, removed) = Self::update_key(context, key.clone(), |entry| {
+            let reply = match list_mut(entry) {
+                Err(err) => Some(err),
+                Ok(list) => list.and_then(|list| pop(list, end)).map(|value| {
+                    Value::Array(vec![
+                        Value::BulkString(key.clone()),
+                        Value::BulkString(value),
+                    ])
+                }),
+            };
+            (reply, remove_if_empty(entry))
+        });
+        if matches!(reply, Some(Value::Array(_))) {
+            Self::notify(context, KeyspaceEvents::LIST, pop_event(end), key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", key);
+        }
+        reply
+    }
+
+    /// Pops an element from `source` and pushes it to `destination`, as one
+    /// atomic step even when the keys live in different shards.
+    fn move_element(
+        context: &Context<P, D, S>,
+        source: &Bytes,
+        destination: &Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<Bytes>, Value> {
+        let keys = vec![source.clone(), destination.clone()];
+        let moved = Self::update_keys(context, keys, |entries| {
+            if list_mut(locked(entries, source)?)?.is_none() {
+                return Ok(None);
+            }
+            list_mut(locked(entries, destination)?)?;
+            let Some(value) = list_mut(locked(entries, source)?)?.and_then(|list| pop(list, from))
+            else {
+                return Ok(None);
+            };
+            remove_if_empty(locked(entries, source)?);
+            push_entry(locked(entries, destination)?, vec![value.clone()], to)?;
+            // Moving within one list empties it only for a moment.
+            let removed = locked(entries, source)?.is_none();
+            Ok(Some((value, removed)))
+        })?;
+        let Some((value, removed)) = moved else {
+            return Ok(None);
+        };
+        Self::notify(context, KeyspaceEvents::LIST, push_event(to), destination);
+        Self::notify(context, KeyspaceEvents::LIST, pop_event(from), source);
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", source);
+        }
+        Ok(Some(value))
+    }
+}
+
+/// The list stored in `entry`, or the WRONGTYPE error if it holds another type.
+fn list_mut(entry: &mut Option<DataFrame<Data>>) -> Result<Option<&mut VecDeque<Bytes>>, Value> {
+    match entry.as_mut().and_then(|df| df.data_mut()) {
+        None => Ok(None),
+        Some(Data::List(list)) => Ok(Some(list)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Pushes `values` to the list in `entry`, creating it if the key is absent,
+/// and returns the new length.
+fn push_entry(
+    entry: &mut Option<DataFrame<Data>>,
+    values: Vec<Bytes>,
+    end: ListEnd,
+) -> Result<usize, Value> {
+    if let Some(list) = list_mut(entry)? {
+        push_all(list, values, end);
+        return Ok(list.len());
+    }
+    let mut list = VecDeque::with_capacity(values.len());
+    push_all(&mut list, values, end);
+    let len = list.len();
+    *entry = Some(DataFrame::Plain(Data::List(list)));
+    Ok(len)
+}
+
+fn push_all(list: &mut VecDeque<Bytes>, values: Vec<Bytes>, end: ListEnd) {
+    for value in values {
+        match end {
+            ListEnd::Left => list.push_front(value),
+            ListEnd::Right => list.push_back(value),
+        }
+    }
+}
+
+/// The event pushing to `end` publishes.
+fn push_event(end: ListEnd) -> &'static str {
+    match end {
+        ListEnd::Left => "lpush",
+        ListEnd::Right => "rpush",
+    }
+}
+
+/// The event popping from `end` publishes.
+fn pop_event(end: ListEnd) -> &'static str {
+    match end {
+        ListEnd::Left => "lpop",
+        ListEnd::Right => "rpop",
+    }
+}
+
+/// How `LMOVE` names `end`.
+fn end_name(end: ListEnd) -> Bytes {
+    Bytes::from_static(match end {
+        ListEnd::Left => b"LEFT",
+        ListEnd::Right => b"RIGHT",
+    })
+}
+
+fn pop(list: &mut VecDeque<Bytes>, end: ListEnd) -> Option<Bytes> {
+    match end {
+        ListEnd::Left => list.pop_front(),
+        ListEnd::Right => list.pop_back(),
+    }
+}
+
+fn normalize_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{bulk, bulks, call, connect, start_server};
+    use super::*;
+    use tokio::net::TcpStream;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn push_pop_and_range() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["RPUSH", "l", "a", "b", "c"]).await,
+            Value::Integer(3)
+        );
+        assert_eq!(
+            call(&mut stream, &["LPUSH", "l", "x", "y"]).await,
+            Value::Integer(5)
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "l", "0", "-1"]).await,
+            bulks(&["y", "x", "a", "b", "c"])
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "l", "-2", "100"]).await,
+            bulks(&["b", "c"])
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "l", "3", "1"]).await,
+            bulks(&[])
+        );
+        assert_eq!(call(&mut stream, &["LPOP", "l"]).await, bulk("y"));
+        assert_eq!(
+            call(&mut stream, &["RPOP", "l", "2"]).await,
+            bulks(&["c", "b"])
+        );
+        assert_eq!(call(&mut stream, &["LLEN", "l"]).await, Value::Integer(2));
+        assert_eq!(
+            call(&mut stream, &["LPOP", "l", "5"]).await,
+            bulks(&["x", "a"])
+        );
+        assert_eq!(call(&mut stream, &["LLEN", "l"]).await, Value::Integer(0));
+        assert_eq!(
+            call(&mut stream, &["LPOP", "l"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["LPOP", "l", "1"]).await,
+            Value::NullArray
+        );
+        assert_eq!(
+            call(&mut stream, &["LPUSHX", "l", "a"]).await,
+            Value::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn index_set_and_insert() {
+        let mut stream = connect().await;
+        call(&mut stream, &["RPUSH", "l", "a", "b", "c"]).await;
+        assert_eq!(call(&mut stream, &["LINDEX", "l", "-1"]).await, bulk("c"));
+        assert_eq!(
+            call(&mut stream, &["LINDEX", "l", "3"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["LSET", "l", "1", "B"]).await,
+            Value::SimpleString(String::from("OK"))
+        );
+        assert_eq!(
+            call(&mut stream, &["LSET", "l", "5", "B"]).await,
+            Value::Error(String::from("ERR index out of range"))
+        );
+        assert_eq!(
+            call(&mut stream, &["LSET", "missing", "0", "B"]).await,
+            Value::Error(String::from("ERR no such key"))
+        );
+        assert_eq!(
+            call(&mut stream, &["LINSERT", "l", "BEFORE", "B", "x"]).await,
+            Value::Integer(4)
+        );
+        assert_eq!(
+            call(&mut stream, &["LINSERT", "l", "after", "c", "y"]).await,
+            Value::Integer(5)
+        );
+        assert_eq!(
+            call(&mut stream, &["LINSERT", "l", "after", "z", "y"]).await,
+            Value::Integer(-1)
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "l", "0", "-1"]).await,
+            bulks(&["a", "x", "B", "c", "y"])
+        );
+    }
+
+    #[tokio::test]
+    async fn trim_and_remove() {
+        let mut stream = connect().await;
+        call(
+            &mut stream,
+            &["RPUSH", "l", "a", "b", "a", "c", "a", "b", "a"],
+        )
+        .await;
+        assert_eq!(
+            call(&mut stream, &["LREM", "l", "-2", "a"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "l", "0", "-1"]).await,
+            bulks(&["a", "b", "a", "c", "b"])
+        );
+        assert_eq!(
+            call(&mut stream, &["LREM", "l", "1", "b"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["LREM", "l", "0", "a"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "l", "0", "-1"]).await,
+            bulks(&["c", "b"])
+        );
+        call(&mut stream, &["RPUSH", "l", "d", "e"]).await;
+        assert_eq!(
+            call(&mut stream, &["LTRIM", "l", "1", "-2"]).await,
+            Value::SimpleString(String::from("OK"))
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "l", "0", "-1"]).await,
+            bulks(&["b", "d"])
+        );
+        call(&mut stream, &["LTRIM", "l", "5", "10"]).await;
+        assert_eq!(call(&mut stream, &["LLEN", "l"]).await, Value::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn wrong_type_is_rejected() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "s", "value"]).await;
+        call(&mut stream, &["RPUSH", "l", "a"]).await;
+        for args in [
+            &["LPUSH", "s", "a"][..],
+            &["LRANGE", "s", "0", "1"],
+            &["LPOP", "s"],
+        ] {
+            assert_eq!(call(&mut stream, args).await, wrong_type());
+        }
+        assert_eq!(call(&mut stream, &["GET", "l"]).await, wrong_type());
+        assert_eq!(
+            call(&mut stream, &["LPUSH", "l"]).await,
+            Value::Error(String::from(
+                "ERR wrong number of arguments for 'lpush' command"
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_pop_returns_available_element() {
+        let mut stream = connect().await;
+        call(&mut stream, &["RPUSH", "b", "x", "y"]).await;
+        assert_eq!(
+            call(&mut stream, &["BLPOP", "a", "b", "0"]).await,
+            bulks(&["b", "x"])
+        );
+        assert_eq!(
+            call(&mut stream, &["BRPOP", "b", "0"]).await,
+            bulks(&["b", "y"])
+        );
+        assert_eq!(
+            call(&mut stream, &["BLPOP", "a", "-1"]).await,
+            Value::Error(String::from("ERR timeout is negative"))
+        );
+        let out_of_range = Value::Error(String::from("ERR timeout is out of range"));
+        assert_eq!(
+            call(&mut stream, &["BLPOP", "a", "1e20"]).await,
+            out_of_range
+        );
+        assert_eq!(
+            call(&mut stream, &["BLMOVE", "a", "b", "LEFT", "RIGHT", "1e20"]).await,
+            out_of_range
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_pop_times_out() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["BLPOP", "a", "0.05"]).await,
+            Value::NullArray
+        );
+        assert_eq!(
+            call(&mut stream, &["BLMOVE", "a", "b", "LEFT", "RIGHT", "0.05"]).await,
+            Value::NullBulkString
+        );
+        call(&mut stream, &["RPUSH", "a", "x"]).await;
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "a", "0", "-1"]).await,
+            bulks(&["x"])
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_clients_are_served_in_fifo_order() {
+        let addr = start_server().await;
+        let mut blocked = vec![];
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            blocked.push(tokio::spawn(async move {
+                call(&mut stream, &["BLPOP", "other", "queue", "0"]).await
+            }));
+            // Give the client time to block before the next one does.
+            sleep(Duration::from_millis(50)).await;
+        }
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            call(&mut stream, &["RPUSH", "queue", "1", "2"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(blocked.remove(0).await.unwrap(), bulks(&["queue", "1"]));
+        assert_eq!(blocked.remove(0).await.unwrap(), bulks(&["queue", "2"]));
+        assert_eq!(
+            call(&mut stream, &["LLEN", "queue"]).await,
+            Value::Integer(0)
+        );
+        call(&mut stream, &["LPUSH", "other", "3"]).await;
+        assert_eq!(blocked.remove(0).await.unwrap(), bulks(&["other", "3"]));
+    }
+
+    #[tokio::test]
+    async fn blocking_move_chains_to_clients_blocked_on_destination() {
+        let addr = start_server().await;
+        let mut mover = TcpStream::connect(addr).await.unwrap();
+        let mover = tokio::spawn(async move {
+            call(
+                &mut mover,
+                &["BLMOVE", "source", "destination", "RIGHT", "LEFT", "0"],
+            )
+            .await
+        });
+        sleep(Duration::from_millis(50)).await;
+        let mut popper = TcpStream::connect(addr).await.unwrap();
+        let popper =
+            tokio::spawn(async move { call(&mut popper, &["BRPOP", "destination", "1"]).await });
+        sleep(Duration::from_millis(50)).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        call(&mut stream, &["RPUSH", "source", "a", "b"]).await;
+        assert_eq!(mover.await.unwrap(), bulk("b"));
+        assert_eq!(popper.await.unwrap(), bulks(&["destination", "b"]));
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "source", "0", "-1"]).await,
+            bulks(&["a"])
+        );
+        assert_eq!(
+            call(&mut stream, &["LLEN", "destination"]).await,
+            Value::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn lmove_between_and_within_lists() {
+        let mut stream = connect().await;
+        call(&mut stream, &["RPUSH", "a", "1", "2", "3"]).await;
+        assert_eq!(
+            call(&mut stream, &["LMOVE", "a", "a", "LEFT", "RIGHT"]).await,
+            bulk("1")
+        );
+        assert_eq!(call(&mut stream, &["RPOPLPUSH", "a", "b"]).await, bulk("1"));
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "a", "0", "-1"]).await,
+            bulks(&["2", "3"])
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "b", "0", "-1"]).await,
+            bulks(&["1"])
+        );
+        call(&mut stream, &["SET", "s", "x"]).await;
+        assert_eq!(
+            call(&mut stream, &["LMOVE", "a", "s", "LEFT", "LEFT"]).await,
+            wrong_type()
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "a", "0", "-1"]).await,
+            bulks(&["2", "3"])
+        );
+    }
+}
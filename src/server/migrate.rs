@@ -0,0 +1,387 @@
+use std::io::{self, Cursor};
+
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time;
+
+use super::replication::read_more;
+use super::{Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::frame::FrameDecoder;
+use crate::operation::{MigrateOptions, OperationDeducer, RestoreOptions};
+use crate::parse::RedisParser;
+use crate::rdb::{self, RdbError};
+use crate::store::Store;
+use crate::transaction::SharedGate;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    pub(super) async fn handle_dump(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            Some(data) => Value::BulkString(Bytes::from(rdb::dump(data))),
+            None => Value::NullBulkString,
+        })
+    }
+
+    /// Creates `key` from a `DUMP` payload, with the deadline in `options`.
+    pub(super) async fn handle_restore(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        options: RestoreOptions,
+    ) -> Value {
+        let data = match rdb::undump(&options.payload) {
+            Ok(data) => data,
+            Err(RdbError::Version(_) | RdbError::Checksum) => {
+                return Value::Error(String::from(
+                    "ERR DUMP payload version or checksum are wrong",
+                ))
+            }
+            Err(_) => return Value::Error(String::from("ERR Bad data format")),
+        };
+        let now = unix_time_millis();
+        let deadline = match options.ttl {
+            0 => None,
+            ttl if options.absttl => Some(ttl),
+            ttl => Some(now.saturating_add(ttl)),
+        };
+        let restored = Self::update_key(context, key.clone(), |entry| {
+            if entry.is_some() && !options.replace {
+                return Err(());
+            }
+            let replaced = entry.is_some();
+            // A deadline already passed restores nothing, but still deletes
+            // the key it replaces.
+            *entry = match deadline {
+                Some(deadline) if deadline <= now => None,
+                Some(deadline) => Some(DataFrame::with_deadline(data, deadline)),
+                None => Some(DataFrame::plain(data)),
+            };
+            Ok(entry.is_some() || !replaced)
+        });
+        match restored {
+            Ok(true) => Self::notify(context, KeyspaceEvents::GENERIC, "restore", &key),
+            Ok(false) => Self::notify(context, KeyspaceEvents::GENERIC, "del", &key),
+            Err(()) => {
+                return Value::Error(String::from("BUSYKEY Target key name already exists."))
+            }
+        }
+        Value::SimpleString(String::from("OK"))
+    }
+
+    /// Moves keys to another instance, deleting them here once the other
+    /// instance has them, unless asked to copy them.
+    pub(super) async fn handle_migrate<'a>(
+        context: &'a Context<P, D, S>,
+        options: MigrateOptions,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        // No other command may touch the keys between reading them and
+        // deleting them, however long the other instance takes. Without a
+        // shared gate the gate is already held exclusively.
+        let exclusive = match gate.take() {
+            Some(shared) => {
+                drop(shared);
+                Some(context.transactions.exclusive().await)
+            }
+            None => None,
+        };
+        let reply = Self::migrate(context, options).await;
+        if exclusive.is_some() {
+            drop(exclusive);
+            *gate = Some(context.transactions.shared().await);
+        }
+        reply
+    }
+
+    async fn migrate(context: &Context<P, D, S>, options: MigrateOptions) -> Value {
+        let now = unix_time_millis();
+        let dumps: Vec<(Bytes, i64, Vec<u8>)> = options
+            .keys
+            .iter()
+            .filter_map(|key| {
+                Self::read_frame(context, key.clone(), |df| {
+                    let df = df?;
+                    let ttl = df.deadline().map_or(0, |deadline| (deadline - now).max(1));
+                    Some((key.clone(), ttl, rdb::dump(df.data()?)))
+                })
+            })
+            .collect();
+        if dumps.is_empty() {
+            return Value::SimpleString(String::from("NOKEY"));
+        }
+
+        // Keys go into a slot the other node is still importing.
+        let restore: &'static [u8] = match context.cluster.is_enabled() {
+            true => b"RESTORE-ASKING",
+            false => b"RESTORE",
+        };
+        let mut buf = vec![];
+        for (key, ttl, payload) in &dumps {
+            let mut args = vec![
+                Bytes::from_static(restore),
+                key.clone(),
+                Bytes::from(ttl.to_string()),
+                Bytes::from(payload.clone()),
+            ];
+            if options.replace {
+                args.push(Bytes::from_static(b"REPLACE"));
+            }
+            Value::Array(args.into_iter().map(Value::BulkString).collect())
+                .encode(&mut buf)
+                .expect("Error while encoding a command");
+        }
+
+        let address = (options.host.as_str(), options.port);
+        let mut stream = match time::timeout(options.timeout, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => stream,
+            _ => return ioerr("connecting to the client"),
+        };
+        // This instance could not take the keys in while holding every
+        // client up for them.
+        if connects_to_itself(&stream, context.replication.port()) {
+            return Value::Error(String::from("ERR Target instance is the source instance"));
+        }
+        if !matches!(
+            time::timeout(options.timeout, stream.write_all(&buf)).await,
+            Ok(Ok(()))
+        ) {
+            return ioerr("writing to target instance");
+        }
+        let mut decoder = FrameDecoder::new();
+        let mut moved = vec![];
+        let mut error = None;
+        for (key, ..) in dumps {
+            let reply = read_reply(&mut stream, &mut decoder, context.parser.as_ref());
+            match time::timeout(options.timeout, reply).await {
+                Ok(Ok(Value::Error(err))) => {
+                    error.get_or_insert(err);
+                }
+                Ok(Ok(_)) => moved.push(key),
+                Ok(Err(_)) | Err(_) => return ioerr("reading to target instance"),
+            }
+        }
+
+        if !options.copy {
+            let deleted: Vec<Bytes> = Self::update_keys(context, moved, |entries| {
+                entries
+                    .iter_mut()
+                    .filter_map(|(key, entry)| entry.take().map(|_| key.clone()))
+                    .collect()
+            });
+            for key in &deleted {
+                Self::notify(context, KeyspaceEvents::GENERIC, "del", key);
+            }
+            if Self::propagating(context) && !deleted.is_empty() {
+                let mut command = vec![Bytes::from_static(b"DEL")];
+                command.extend(deleted);
+                Self::propagate(context, command);
+            }
+        }
+        match error {
+            Some(err) => Value::Error(format!("ERR Target instance replied with error: {err}")),
+            None => Value::SimpleString(String::from("OK")),
+        }
+    }
+}
+
+async fn read_reply<P>(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    parser: &P,
+) -> io::Result<Value>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>>,
+{
+    loop {
+        match decoder.decode(parser)? {
+            Some(reply) => return Ok(reply),
+            None => read_more(stream, decoder).await?,
+        }
+    }
+}
+
+/// Whether `stream` leads back to `port` on this host, where this server listens.
+fn connects_to_itself(stream: &TcpStream, port: u16) -> bool {
+    match (stream.local_addr(), stream.peer_addr()) {
+        (Ok(local), Ok(peer)) => {
+            peer.port() == port && (peer.ip().is_loopback() || peer.ip() == local.ip())
+        }
+        _ => false,
+    }
+}
+
+fn ioerr(doing: &str) -> Value {
+    Value::Error(format!("IOERR error or timeout {doing}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{bulk, call, command, connect, ok, read_replies, start_server};
+    use super::*;
+
+    /// Like `call`, for binary arguments.
+    async fn call_bytes(stream: &mut TcpStream, args: &[&[u8]]) -> Value {
+        stream.write_all(&command(args)).await.unwrap();
+        read_replies(stream, 1).await.remove(0)
+    }
+
+    #[tokio::test]
+    async fn dumped_keys_are_restored() {
+        let mut stream = connect().await;
+        call(&mut stream, &["RPUSH", "list", "a", "b"]).await;
+        let payload = match call(&mut stream, &["DUMP", "list"]).await {
+            Value::BulkString(payload) => payload,
+            reply => panic!("unexpected reply to DUMP {reply:?}"),
+        };
+        assert_eq!(
+            call(&mut stream, &["DUMP", "missing"]).await,
+            Value::NullBulkString
+        );
+
+        let restore = |key: &'static [u8], ttl: &'static [u8], options: &[&'static [u8]]| {
+            let mut args = vec![b"RESTORE".as_slice(), key, ttl, &payload];
+            args.extend(options);
+            args
+        };
+        assert_eq!(
+            call_bytes(&mut stream, &restore(b"copy", b"0", &[])).await,
+            ok()
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "copy", "0", "-1"]).await,
+            Value::Array(vec![bulk("a"), bulk("b")])
+        );
+        assert_eq!(
+            call_bytes(&mut stream, &restore(b"copy", b"0", &[])).await,
+            Value::Error(String::from("BUSYKEY Target key name already exists."))
+        );
+        assert_eq!(
+            call_bytes(&mut stream, &restore(b"copy", b"5000", &[b"REPLACE"])).await,
+            ok()
+        );
+        assert!(matches!(
+            call(&mut stream, &["PTTL", "copy"]).await,
+            Value::Integer(ttl) if ttl > 0 && ttl <= 5000
+        ));
+        // A deadline already passed replaces the key with nothing.
+        assert_eq!(
+            call_bytes(
+                &mut stream,
+                &restore(b"copy", b"1", &[b"REPLACE", b"ABSTTL"])
+            )
+            .await,
+            ok()
+        );
+        assert_eq!(
+            call(&mut stream, &["EXISTS", "copy"]).await,
+            Value::Integer(0)
+        );
+
+        let mut damaged = payload.to_vec();
+        damaged[1] ^= 1;
+        assert_eq!(
+            call_bytes(&mut stream, &[b"RESTORE", b"other", b"0", &damaged]).await,
+            Value::Error(String::from(
+                "ERR DUMP payload version or checksum are wrong"
+            ))
+        );
+        assert_eq!(
+            call_bytes(&mut stream, &restore(b"other", b"-1", &[])).await,
+            Value::Error(String::from("ERR Invalid TTL value, must be >= 0"))
+        );
+    }
+
+    #[tokio::test]
+    async fn migrate_moves_keys_to_another_instance() {
+        let mut source = connect().await;
+        let target_addr = start_server().await;
+        let mut target = TcpStream::connect(target_addr).await.unwrap();
+        let port = target_addr.port().to_string();
+        call(&mut source, &["SET", "a", "1"]).await;
+        call(&mut source, &["SET", "b", "2", "PX", "100000"]).await;
+
+        let migrate = [
+            "MIGRATE",
+            "127.0.0.1",
+            &port,
+            "",
+            "0",
+            "1000",
+            "KEYS",
+            "a",
+            "b",
+        ];
+        assert_eq!(call(&mut source, &migrate).await, ok());
+        assert_eq!(
+            call(&mut source, &["EXISTS", "a", "b"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(call(&mut target, &["GET", "a"]).await, bulk("1"));
+        assert!(matches!(
+            call(&mut target, &["PTTL", "b"]).await,
+            Value::Integer(ttl) if ttl > 0 && ttl <= 100000
+        ));
+        assert_eq!(
+            call(&mut source, &migrate).await,
+            Value::SimpleString(String::from("NOKEY"))
+        );
+
+        call(&mut source, &["SET", "a", "3"]).await;
+        assert_eq!(
+            call(
+                &mut source,
+                &["MIGRATE", "127.0.0.1", &port, "a", "0", "1000"]
+            )
+            .await,
+            Value::Error(String::from(
+                "ERR Target instance replied with error: BUSYKEY Target key name already exists."
+            ))
+        );
+        assert_eq!(call(&mut source, &["GET", "a"]).await, bulk("3"));
+        let copy = [
+            "MIGRATE",
+            "127.0.0.1",
+            &port,
+            "a",
+            "0",
+            "1000",
+            "COPY",
+            "REPLACE",
+        ];
+        assert_eq!(call(&mut source, &copy).await, ok());
+        assert_eq!(call(&mut source, &["GET", "a"]).await, bulk("3"));
+        assert_eq!(call(&mut target, &["GET", "a"]).await, bulk("3"));
+
+        // Nothing listens on the port of a closed listener.
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = closed.local_addr().unwrap().port().to_string();
+        assert!(matches!(
+            call(&mut source, &["MIGRATE", "127.0.0.1", &closed, "a", "0", "100"]).await,
+            Value::Error(err) if err.starts_with("IOERR")
+        ));
+    }
+
+    #[tokio::test]
+    async fn migrating_to_itself_is_refused() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        call(&mut stream, &["SET", "key", "value"]).await;
+        let port = addr.port().to_string();
+        for host in ["127.0.0.1", "localhost"] {
+            let migrate = ["MIGRATE", host, &port, "key", "0", "1000", "REPLACE"];
+            assert_eq!(
+                call(&mut stream, &migrate).await,
+                Value::Error(String::from("ERR Target instance is the source instance"))
+            );
+        }
+        assert_eq!(call(&mut stream, &["GET", "key"]).await, bulk("value"));
+    }
+}
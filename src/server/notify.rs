@@ -0,0 +1,245 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::OperationDeducer;
+use crate::parse::RedisParser;
+use crate::store::Store;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Publishes `event` on `key` to `__keyspace@0__:<key>` and
+    /// `__keyevent@0__:<event>`, as far as `notify-keyspace-events` enables
+    /// the event's class and each of the two channels.
+    pub(super) fn notify(
+        context: &Context<P, D, S>,
+        class: KeyspaceEvents,
+        event: &'static str,
+        key: &Bytes,
+    ) {
+        let enabled = context.config.keyspace_events();
+        if !enabled.intersects(class) {
+            return;
+        }
+        let event = Bytes::from_static(event.as_bytes());
+        if enabled.intersects(KeyspaceEvents::KEYSPACE) {
+            let mut channel = b"__keyspace@0__:".to_vec();
+            channel.extend_from_slice(key);
+            context.pubsub.publish(&Bytes::from(channel), &event);
+        }
+        if enabled.intersects(KeyspaceEvents::KEYEVENT) {
+            let mut channel = b"__keyevent@0__:".to_vec();
+            channel.extend_from_slice(&event);
+            context.pubsub.publish(&Bytes::from(channel), key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    use super::super::tests::{bulk, call, command, read_replies, start_server};
+    use crate::value::Value;
+
+    fn pmessage(pattern: &str, channel: &str, message: &str) -> Value {
+        Value::Array(vec![
+            bulk("pmessage"),
+            bulk(pattern),
+            bulk(channel),
+            bulk(message),
+        ])
+    }
+
+    async fn psubscribe(stream: &mut TcpStream, pattern: &str) {
+        stream
+            .write_all(&command(&[b"PSUBSCRIBE", pattern.as_bytes()]))
+            .await
+            .unwrap();
+        read_replies(stream, 1).await;
+    }
+
+    #[tokio::test]
+    async fn writes_publish_keyspace_and_keyevent_messages() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        psubscribe(&mut subscriber, "__key*__:*").await;
+
+        // Nothing is published until notifications are turned on.
+        call(&mut client, &["SET", "ignored", "v"]).await;
+        assert_eq!(
+            call(
+                &mut client,
+                &["CONFIG", "SET", "notify-keyspace-events", "KEg$"]
+            )
+            .await,
+            Value::SimpleString(String::from("OK"))
+        );
+        call(&mut client, &["SET", "session", "v", "EX", "100"]).await;
+        call(&mut client, &["EXPIRE", "session", "200"]).await;
+        call(&mut client, &["DEL", "session", "missing"]).await;
+        let pattern = "__key*__:*";
+        assert_eq!(
+            read_replies(&mut subscriber, 8).await,
+            vec![
+                pmessage(pattern, "__keyspace@0__:session", "set"),
+                pmessage(pattern, "__keyevent@0__:set", "session"),
+                pmessage(pattern, "__keyspace@0__:session", "expire"),
+                pmessage(pattern, "__keyevent@0__:expire", "session"),
+                pmessage(pattern, "__keyspace@0__:session", "expire"),
+                pmessage(pattern, "__keyevent@0__:expire", "session"),
+                pmessage(pattern, "__keyspace@0__:session", "del"),
+                pmessage(pattern, "__keyevent@0__:del", "session"),
+            ]
+        );
+
+        // Only the classes that are turned on are published.
+        call(
+            &mut client,
+            &["CONFIG", "SET", "notify-keyspace-events", "Kg"],
+        )
+        .await;
+        call(&mut client, &["SET", "session", "v"]).await;
+        call(&mut client, &["EXPIRE", "session", "0"]).await;
+        assert_eq!(
+            read_replies(&mut subscriber, 1).await,
+            vec![pmessage(pattern, "__keyspace@0__:session", "del")]
+        );
+    }
+
+    #[tokio::test]
+    async fn collection_and_string_writes_publish_their_events() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        let pattern = "__keyevent@0__:*";
+        psubscribe(&mut subscriber, pattern).await;
+        call(
+            &mut client,
+            &["CONFIG", "SET", "notify-keyspace-events", "Egl$hsz"],
+        )
+        .await;
+        let commands: &[&[&str]] = &[
+            &["RPUSH", "list", "a"],
+            &["LPOP", "list"],
+            // Writes that change nothing publish nothing.
+            &["LPOP", "list"],
+            &["HSET", "hash", "f", "v"],
+            &["HDEL", "hash", "f"],
+            &["SADD", "source", "a"],
+            &["SREM", "source", "missing"],
+            &["SMOVE", "source", "destination", "a"],
+            &["ZADD", "zset", "1", "a"],
+            &["ZINCRBY", "zset", "1", "a"],
+            &["ZPOPMIN", "zset"],
+            &["INCR", "counter"],
+            &["APPEND", "counter", "0"],
+            &["GETDEL", "counter"],
+        ];
+        for args in commands {
+            call(&mut client, args).await;
+        }
+        let events = [
+            ("rpush", "list"),
+            ("lpop", "list"),
+            ("del", "list"),
+            ("hset", "hash"),
+            ("hdel", "hash"),
+            ("del", "hash"),
+            ("sadd", "source"),
+            ("srem", "source"),
+            ("del", "source"),
+            ("sadd", "destination"),
+            ("zadd", "zset"),
+            ("zincr", "zset"),
+            ("zpopmin", "zset"),
+            ("del", "zset"),
+            ("incrby", "counter"),
+            ("append", "counter"),
+            ("del", "counter"),
+        ];
+        let expected: Vec<Value> = events
+            .iter()
+            .map(|(event, key)| pmessage(pattern, &format!("__keyevent@0__:{event}"), key))
+            .collect();
+        assert_eq!(read_replies(&mut subscriber, events.len()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn renames_and_persists_publish_generic_events() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        let pattern = "__keyevent@0__:*";
+        psubscribe(&mut subscriber, pattern).await;
+        call(
+            &mut client,
+            &["CONFIG", "SET", "notify-keyspace-events", "Eg"],
+        )
+        .await;
+        let commands: &[&[&str]] = &[
+            &["SET", "old", "v", "EX", "100"],
+            &["RENAME", "old", "new"],
+            // Renames and persists that change nothing publish nothing.
+            &["RENAME", "new", "new"],
+            &["RENAME", "missing", "other"],
+            &["SET", "taken", "v"],
+            &["RENAMENX", "new", "taken"],
+            &["RENAMENX", "new", "newer"],
+            &["PERSIST", "newer"],
+            &["PERSIST", "newer"],
+        ];
+        for args in commands {
+            call(&mut client, args).await;
+        }
+        let events = [
+            ("expire", "old"),
+            ("rename_from", "old"),
+            ("rename_to", "new"),
+            ("rename_from", "new"),
+            ("rename_to", "newer"),
+            ("persist", "newer"),
+        ];
+        let expected: Vec<Value> = events
+            .iter()
+            .map(|(event, key)| pmessage(pattern, &format!("__keyevent@0__:{event}"), key))
+            .collect();
+        assert_eq!(read_replies(&mut subscriber, events.len()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn expirations_are_published_without_reading_the_key() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        psubscribe(&mut subscriber, "__keyevent@0__:expired").await;
+        call(
+            &mut client,
+            &["CONFIG", "SET", "notify-keyspace-events", "Ex"],
+        )
+        .await;
+        call(&mut client, &["SET", "session", "v", "PX", "50"]).await;
+        let expired =
+            tokio::time::timeout(Duration::from_secs(5), read_replies(&mut subscriber, 1)).await;
+        assert_eq!(
+            expired.unwrap(),
+            vec![pmessage(
+                "__keyevent@0__:expired",
+                "__keyevent@0__:expired",
+                "session"
+            )]
+        );
+    }
+}
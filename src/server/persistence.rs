@@ -0,0 +1,529 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use super::{Client, Context, Server};
+use crate::aof::{self, AofError};
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::OperationDeducer;
+use crate::parse::RedisParser;
+use crate::persistence::snapshot;
+use crate::rdb::{self, RdbError, Snapshot};
+use crate::store::Store;
+use crate::transaction::SharedGate;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Loads the snapshot in the configured directory, if there is one,
+    /// leaving out keys whose deadline passed while the server was down.
+    /// Returns how many keys were loaded.
+    pub fn load(&self) -> Result<usize, RdbError> {
+        let Some(entries) = rdb::load(&self.config.snapshot_path())? else {
+            return Ok(0);
+        };
+        let mut loaded = 0;
+        for (key, df) in entries.into_iter().filter(|(_, df)| !df.has_expired()) {
+            self.store.set(key, df);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Replays the append-only file, cutting off a command the server went
+    /// down halfway through logging, or a transaction it did not get to
+    /// log the end of. Without a file, loads the snapshot and starts the
+    /// file with it. Either way, writes are logged to the file from then on.
+    pub async fn load_appendonly(&self) -> Result<(), AofError> {
+        let path = self.config.appendonly_path();
+        let Some(contents) = aof::load(&path)? else {
+            self.load()?;
+            aof::create(&path, &rdb::encode_aof_base(&snapshot(self.store.as_ref())))?;
+            self.aof.open(&path)?;
+            return Ok(());
+        };
+        let base = contents.base.into_iter().flatten();
+        for (key, df) in base.filter(|(_, df)| !df.has_expired()) {
+            self.store.set(key, df);
+        }
+        let context = self.context();
+        let mut client = Client::new();
+        client.replaying = true;
+        let mut replies = vec![];
+        let mut complete = contents.start;
+        for (command, end) in contents.commands {
+            let command = Value::Array(command.into_iter().map(Value::BulkString).collect());
+            Self::handle_input(&context, &mut client, command, &mut replies).await;
+            replies.clear();
+            if client.transaction.is_none() {
+                complete = end;
+            }
+        }
+        if complete < contents.len {
+            println!(
+                "Truncating the append only file from {} to {} bytes",
+                contents.len, complete
+            );
+            aof::truncate(&path, complete)?;
+        }
+        self.aof.open(&path)?;
+        Ok(())
+    }
+
+    /// Writes a snapshot and replies once it is on disk. Unlike in Redis,
+    /// other clients are served while it is written.
+    pub(super) async fn handle_save<'a>(
+        context: &'a Context<P, D, S>,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        if !context.persistence.start_save() {
+            return save_in_progress();
+        }
+        let entries = Self::copy_dataset(context, gate).await;
+        let path = context.config.snapshot_path();
+        let written = tokio::task::spawn_blocking(move || rdb::save(&path, &entries)).await;
+        let saved = matches!(written, Ok(Ok(())));
+        context.persistence.finish_save(saved);
+        match written {
+            Ok(Ok(())) => Value::SimpleString(String::from("OK")),
+            Ok(Err(err)) => {
+                println!("Error saving snapshot: {}", err);
+                Value::Error(String::from("ERR"))
+            }
+            Err(_) => Value::Error(String::from("ERR")),
+        }
+    }
+
+    /// Copies the dataset, then replies right away and writes the snapshot
+    /// on a blocking thread.
+    pub(super) async fn handle_bgsave<'a>(
+        context: &'a Context<P, D, S>,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        if !context.persistence.start_save() {
+            return save_in_progress();
+        }
+        let entries = Self::copy_dataset(context, gate).await;
+        let persistence = Arc::clone(&context.persistence);
+        let path = context.config.snapshot_path();
+        tokio::task::spawn_blocking(move || {
+            let written = rdb::save(&path, &entries);
+            if let Err(err) = &written {
+                println!("Error saving snapshot in the background: {}", err);
+            }
+            persistence.finish_save(written.is_ok());
+        });
+        Value::SimpleString(String::from("Background saving started"))
+    }
+
+    /// Copies the dataset with every other client held up, so that no
+    /// write, however many keys it touches, is caught halfway. Without a
+    /// shared `gate` the gate is already held exclusively.
+    async fn copy_dataset<'a>(
+        context: &'a Context<P, D, S>,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Snapshot {
+        let Some(shared) = gate.take() else {
+            return snapshot(context.store.as_ref());
+        };
+        drop(shared);
+        let entries = {
+            let _gate = context.transactions.exclusive().await;
+            snapshot(context.store.as_ref())
+        };
+        *gate = Some(context.transactions.shared().await);
+        entries
+    }
+
+    pub(super) async fn handle_lastsave(context: &Context<P, D, S>) -> Value {
+        Value::Integer(context.persistence.last_save())
+    }
+
+    /// Replies right away and rewrites the append-only file in the
+    /// background, as clients go on writing.
+    pub(super) async fn handle_bgrewriteaof(context: &Context<P, D, S>) -> Value {
+        if !Self::start_rewrite(context) {
+            return Value::Error(String::from(
+                "ERR Background append only file rewriting already in progress",
+            ));
+        }
+        Value::SimpleString(String::from(
+            "Background append only file rewriting started",
+        ))
+    }
+
+    /// Starts rewriting the append-only file, unless a rewrite is running.
+    pub(super) fn start_rewrite(context: &Context<P, D, S>) -> bool {
+        if !context.aof.claim_rewrite() {
+            return false;
+        }
+        let context = context.clone();
+        tokio::spawn(async move {
+            Self::rewrite_appendonly(&context).await;
+            context.aof.end_rewrite();
+        });
+        true
+    }
+
+    /// Takes a snapshot with every client held up, so that the writes
+    /// logged from then on are exactly those it misses, and writes it out
+    /// followed by them as clients go on. Rewrites again if appendonly was
+    /// turned on too late for the rewrite to start logging.
+    async fn rewrite_appendonly(context: &Context<P, D, S>) {
+        loop {
+            let (entries, path, enabling) = {
+                let _gate = context.transactions.exclusive().await;
+                let enabling = context.config.appendonly() && !Self::propagating(context);
+                context.aof.begin_rewrite(enabling);
+                let entries = snapshot(context.store.as_ref());
+                (entries, context.config.appendonly_path(), enabling)
+            };
+            let aof = Arc::clone(&context.aof);
+            let written = tokio::task::spawn_blocking(move || {
+                aof.finish_rewrite(&path, &rdb::encode_aof_base(&entries))
+            })
+            .await;
+            if !matches!(written, Ok(Ok(()))) {
+                if let Ok(Err(err)) = written {
+                    println!("Error rewriting the append only file: {}", err);
+                }
+                // Logging to a file that was never created would lose writes.
+                if enabling {
+                    context.aof.close();
+                }
+                return;
+            }
+            if !context.config.appendonly() || Self::propagating(context) {
+                return;
+            }
+        }
+    }
+}
+
+fn save_in_progress() -> Value {
+    Value::Error(String::from("ERR Background save already in progress"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use tokio::net;
+
+    use super::super::tests::{bulk, call, connect, start_server_with, wait_for};
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mini-redis-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Starts a server with the given parameters that loads what is in
+    /// `dir` before serving.
+    async fn restart(dir: &Path, parameters: &[(&str, &str)]) -> net::TcpStream {
+        let server = Server::new("0");
+        let mut parameters: Vec<(String, String)> = parameters
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        parameters.push((String::from("dir"), dir.to_str().unwrap().to_string()));
+        server.configure(&parameters).unwrap();
+        if server.config.appendonly() {
+            server.load_appendonly().await.unwrap();
+        } else {
+            server.load().unwrap();
+        }
+        net::TcpStream::connect(start_server_with(server).await).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn snapshots_survive_a_restart() {
+        let dir = temp_dir("save");
+        let mut stream = connect().await;
+        let ok = Value::SimpleString(String::from("OK"));
+        let set_dir = ["CONFIG", "SET", "dir", dir.to_str().unwrap()];
+        assert_eq!(call(&mut stream, &set_dir).await, ok);
+        call(&mut stream, &["SET", "string", "value"]).await;
+        call(&mut stream, &["SET", "expiring", "value", "PX", "100000"]).await;
+        call(&mut stream, &["SET", "expired", "value", "PX", "100"]).await;
+        call(&mut stream, &["RPUSH", "list", "a", "b"]).await;
+        call(&mut stream, &["HSET", "hash", "field", "value"]).await;
+        call(&mut stream, &["SADD", "set", "member"]).await;
+        call(&mut stream, &["ZADD", "zset", "1.5", "member"]).await;
+        call(&mut stream, &["XADD", "stream", "1-1", "field", "value"]).await;
+        call(&mut stream, &["XGROUP", "CREATE", "stream", "group", "0"]).await;
+        let read = "XREADGROUP GROUP group alice STREAMS stream >";
+        let read: Vec<&str> = read.split(' ').collect();
+        call(&mut stream, &read).await;
+        assert_eq!(call(&mut stream, &["SAVE"]).await, ok);
+        assert!(dir.join("dump.rdb").exists());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let mut stream = restart(&dir, &[]).await;
+        assert_eq!(call(&mut stream, &["GET", "string"]).await, bulk("value"));
+        let ttl = call(&mut stream, &["PTTL", "expiring"]).await;
+        assert!(matches!(ttl, Value::Integer(ttl) if ttl > 0 && ttl <= 100000));
+        assert_eq!(
+            call(&mut stream, &["EXISTS", "expired"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "list", "0", "-1"]).await,
+            Value::Array(vec![bulk("a"), bulk("b")])
+        );
+        assert_eq!(
+            call(&mut stream, &["HGET", "hash", "field"]).await,
+            bulk("value")
+        );
+        assert_eq!(
+            call(&mut stream, &["SISMEMBER", "set", "member"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZSCORE", "zset", "member"]).await,
+            bulk("1.5")
+        );
+        let pending = ["XPENDING", "stream", "group", "-", "+", "10", "alice"];
+        match call(&mut stream, &pending).await {
+            Value::Array(entries) => assert_eq!(entries.len(), 1),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saving_in_a_transaction_includes_its_earlier_writes() {
+        let dir = temp_dir("save-in-exec");
+        let mut stream = connect().await;
+        call(
+            &mut stream,
+            &["CONFIG", "SET", "dir", dir.to_str().unwrap()],
+        )
+        .await;
+        call(&mut stream, &["MULTI"]).await;
+        call(&mut stream, &["SET", "key", "value"]).await;
+        call(&mut stream, &["SAVE"]).await;
+        let ok = Value::SimpleString(String::from("OK"));
+        assert_eq!(
+            call(&mut stream, &["EXEC"]).await,
+            Value::Array(vec![ok.clone(), ok])
+        );
+
+        let mut stream = restart(&dir, &[]).await;
+        assert_eq!(call(&mut stream, &["GET", "key"]).await, bulk("value"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bgsave_writes_in_the_background() {
+        let dir = temp_dir("bgsave");
+        let mut stream = connect().await;
+        call(
+            &mut stream,
+            &["CONFIG", "SET", "dir", dir.to_str().unwrap()],
+        )
+        .await;
+        call(&mut stream, &["SET", "key", "value"]).await;
+        assert_eq!(
+            call(&mut stream, &["BGSAVE"]).await,
+            Value::SimpleString(String::from("Background saving started"))
+        );
+        // The snapshot is renamed into place once complete.
+        let path = dir.join("dump.rdb");
+        wait_for(|| path.exists()).await;
+        let mut stream = restart(&dir, &[]).await;
+        assert_eq!(call(&mut stream, &["GET", "key"]).await, bulk("value"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn appendonly_file_is_replayed_after_a_restart() {
+        let dir = temp_dir("aof");
+        let appendonly = [("appendonly", "yes")];
+        let mut stream = restart(&dir, &appendonly).await;
+        call(&mut stream, &["SET", "string", "value", "EX", "100"]).await;
+        for _ in 0..3 {
+            call(&mut stream, &["INCR", "counter"]).await;
+        }
+        call(&mut stream, &["RPUSH", "list", "a", "b", "c"]).await;
+        call(&mut stream, &["BLPOP", "list", "0"]).await;
+        call(&mut stream, &["PEXPIRE", "list", "100000"]).await;
+        call(&mut stream, &["SADD", "set", "a", "b", "c"]).await;
+        let popped = call(&mut stream, &["SPOP", "set"]).await;
+        let id = call(&mut stream, &["XADD", "stream", "*", "field", "value"]).await;
+        call(&mut stream, &["XGROUP", "CREATE", "stream", "group", "0"]).await;
+        let read = "XREADGROUP GROUP group alice STREAMS stream >";
+        call(&mut stream, &read.split(' ').collect::<Vec<_>>()).await;
+        call(&mut stream, &["MULTI"]).await;
+        call(&mut stream, &["SET", "multi", "x"]).await;
+        call(&mut stream, &["INCR", "counter"]).await;
+        call(&mut stream, &["EXEC"]).await;
+        call(&mut stream, &["SET", "ignored", "x", "EX", "-1"]).await;
+        call(&mut stream, &["DEL", "missing"]).await;
+
+        let mut stream = restart(&dir, &appendonly).await;
+        assert_eq!(call(&mut stream, &["GET", "string"]).await, bulk("value"));
+        let ttl = call(&mut stream, &["PTTL", "string"]).await;
+        assert!(matches!(ttl, Value::Integer(ttl) if ttl > 0 && ttl <= 100000));
+        assert_eq!(call(&mut stream, &["GET", "counter"]).await, bulk("4"));
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "list", "0", "-1"]).await,
+            Value::Array(vec![bulk("b"), bulk("c")])
+        );
+        let ttl = call(&mut stream, &["PTTL", "list"]).await;
+        assert!(matches!(ttl, Value::Integer(ttl) if ttl > 0 && ttl <= 100000));
+        let popped = match &popped {
+            Value::BulkString(popped) => std::str::from_utf8(popped).unwrap(),
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(
+            call(&mut stream, &["SISMEMBER", "set", popped]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["SCARD", "set"]).await,
+            Value::Integer(2)
+        );
+        match call(&mut stream, &["XRANGE", "stream", "-", "+"]).await {
+            Value::Array(entries) => match &entries[..] {
+                [Value::Array(entry)] => assert_eq!(entry[0], id),
+                _ => panic!("unexpected entries {:?}", entries),
+            },
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        let pending = ["XPENDING", "stream", "group", "-", "+", "10", "alice"];
+        match call(&mut stream, &pending).await {
+            Value::Array(entries) => assert_eq!(entries.len(), 1),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        assert_eq!(call(&mut stream, &["GET", "multi"]).await, bulk("x"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clients_served_by_a_push_are_logged() {
+        let dir = temp_dir("aof-blocked");
+        let appendonly = [("appendonly", "yes")];
+        let mut stream = restart(&dir, &appendonly).await;
+        let addr = stream.peer_addr().unwrap();
+        let mut blocked = net::TcpStream::connect(addr).await.unwrap();
+        let pop = super::super::tests::command(&[b"BLPOP", b"queue", b"0"]);
+        tokio::io::AsyncWriteExt::write_all(&mut blocked, &pop)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        call(&mut stream, &["RPUSH", "queue", "a", "b"]).await;
+        let popped = super::super::tests::read_replies(&mut blocked, 1).await;
+        assert_eq!(popped, vec![Value::Array(vec![bulk("queue"), bulk("a")])]);
+
+        let mut stream = restart(&dir, &appendonly).await;
+        assert_eq!(
+            call(&mut stream, &["LRANGE", "queue", "0", "-1"]).await,
+            Value::Array(vec![bulk("b")])
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_are_logged_in_the_order_they_took_effect() {
+        let dir = temp_dir("aof-concurrent");
+        let appendonly = [("appendonly", "yes")];
+        let mut stream = restart(&dir, &appendonly).await;
+        let addr = stream.peer_addr().unwrap();
+        let mut clients = vec![];
+        for client in 0..8 {
+            clients.push(tokio::spawn(async move {
+                let mut stream = net::TcpStream::connect(addr).await.unwrap();
+                for i in 0..50 {
+                    let value = format!("{client}-{i}");
+                    let end = if i % 2 == 0 { "LPUSH" } else { "RPUSH" };
+                    call(&mut stream, &[end, "list", &value]).await;
+                    call(&mut stream, &["LLEN", "list"]).await;
+                }
+            }));
+        }
+        for client in clients {
+            client.await.unwrap();
+        }
+        let range = ["LRANGE", "list", "0", "-1"];
+        let written = call(&mut stream, &range).await;
+
+        let mut stream = restart(&dir, &appendonly).await;
+        assert_eq!(call(&mut stream, &range).await, written);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn torn_commands_are_cut_off() {
+        let dir = temp_dir("aof-torn");
+        let path = dir.join("appendonly.aof");
+        let set = "*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let unfinished = "*1\r\n$5\r\nMULTI\r\n*2\r\n$4\r\nINCR\r\n$1\r\nn\r\n";
+        std::fs::write(
+            &path,
+            format!("{set}{unfinished}*2\r\n$3\r\nDEL\r\n$3\r\nke"),
+        )
+        .unwrap();
+
+        let mut stream = restart(&dir, &[("appendonly", "yes")]).await;
+        assert_eq!(call(&mut stream, &["GET", "key"]).await, bulk("value"));
+        assert_eq!(call(&mut stream, &["EXISTS", "n"]).await, Value::Integer(0));
+        assert_eq!(std::fs::read(&path).unwrap(), set.as_bytes());
+        std::fs::write(&path, "+OK\r\n").unwrap();
+        let server = Server::new("0");
+        server
+            .configure(&[(String::from("dir"), dir.to_str().unwrap().to_string())])
+            .unwrap();
+        server
+            .configure(&[(String::from("appendonly"), String::from("yes"))])
+            .unwrap();
+        assert!(matches!(
+            server.load_appendonly().await,
+            Err(AofError::Corrupt(0))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rewrites_compact_the_log_as_writes_go_on() {
+        let dir = temp_dir("aof-rewrite");
+        let path = dir.join("appendonly.aof");
+        let mut stream = restart(&dir, &[]).await;
+        let ok = Value::SimpleString(String::from("OK"));
+        assert_eq!(
+            call(&mut stream, &["CONFIG", "SET", "appendonly", "yes"]).await,
+            ok
+        );
+        wait_for(|| path.exists()).await;
+        for _ in 0..50 {
+            call(&mut stream, &["INCR", "counter"]).await;
+        }
+        let logged = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(
+            call(&mut stream, &["BGREWRITEAOF"]).await,
+            Value::SimpleString(String::from(
+                "Background append only file rewriting started"
+            ))
+        );
+        for _ in 0..10 {
+            call(&mut stream, &["INCR", "counter"]).await;
+        }
+        // Once rewritten, the file starts with a snapshot and logs no INCR
+        // taken into it.
+        wait_for(|| std::fs::metadata(&path).unwrap().len() < logged).await;
+        call(&mut stream, &["INCR", "counter"]).await;
+
+        let mut stream = restart(&dir, &[("appendonly", "yes")]).await;
+        assert_eq!(call(&mut stream, &["GET", "counter"]).await, bulk("61"));
+        assert!(std::fs::read(&path).unwrap().starts_with(b"REDIS"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::transaction::written_keys;
+use super::{Context, Server};
+use crate::config::AppendFsync;
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::{Expiry, GetExOption, Operation, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::stream::{NewId, StreamId};
+use crate::value::Value;
+
+/// How a write is logged once it ran, decided before it runs.
+pub(super) enum Propagation {
+    /// Not a write, or one that logs its effects as they happen: the
+    /// blocking commands, whose clients may be served by someone else's push.
+    Nothing,
+    /// The command as it was sent.
+    Command,
+    /// `SET` with a deadline relative to when it ran.
+    Set(Bytes),
+    /// `EXPIRE`, `PEXPIRE` or `GETEX` with a deadline relative to when it ran.
+    Deadline(Bytes),
+    /// `SPOP`, whose members are picked at random.
+    SPop(Bytes),
+    /// `XADD` with an ID the server picks, at the given argument.
+    XAdd(usize),
+    /// `XCLAIM`, whose entries depend on how long they have been idle.
+    XClaim(Bytes, Bytes, Bytes, Vec<StreamId>),
+    /// `XAUTOCLAIM`, likewise.
+    XAutoClaim(Bytes, Bytes, Bytes, bool),
+    /// `RESTORE` with its payload, whose deadline may be relative.
+    Restore(Bytes, Bytes),
+}
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Whether writes are logged, to the append-only file or the stream to
+    /// replicas. They then take effect one at a time, so that they are
+    /// logged in the order they took effect.
+    pub(super) fn propagating(context: &Context<P, D, S>) -> bool {
+        context.aof.is_enabled() || context.replication.is_logging()
+    }
+
+    /// Whether `op` is a write refused because the append-only file could
+    /// not be written to, as Redis refuses them until it can be again.
+    pub(super) fn appendonly_failed(context: &Context<P, D, S>, op: &Operation) -> bool {
+        context.aof.write_error().is_some() && !written_keys(op).is_empty()
+    }
+
+    /// The error refused writes get while the append-only file cannot be
+    /// written to.
+    pub(super) fn appendonly_error(context: &Context<P, D, S>) -> Value {
+        Value::Error(format!(
+            "MISCONF Errors writing to the AOF file: {}",
+            context.aof.write_error().unwrap_or_default()
+        ))
+    }
+
+    /// Logs a write.
+    pub(super) fn propagate(context: &Context<P, D, S>, command: Vec<Bytes>) {
+        context.aof.feed(&command);
+        context.replication.feed(&command);
+    }
+
+    /// Logs the write `command` once it ran and replied with `reply`,
+    /// rewritten where replaying it as sent could have another effect:
+    /// relative deadlines become absolute ones, and whatever the server
+    /// picked, such as the members `SPOP` removed, is spelled out.
+    pub(super) fn propagate_write(
+        context: &Context<P, D, S>,
+        propagation: Propagation,
+        mut command: Vec<Bytes>,
+        reply: &Value,
+    ) {
+        if matches!(reply, Value::Error(_)) {
+            return;
+        }
+        match propagation {
+            Propagation::Nothing => {}
+            Propagation::Command => Self::propagate(context, command),
+            Propagation::Set(key) => {
+                let mut args = command.drain(3..);
+                let mut options = vec![];
+                while let Some(option) = args.next() {
+                    if option.eq_ignore_ascii_case(b"ex") || option.eq_ignore_ascii_case(b"px") {
+                        args.next();
+                    } else {
+                        options.push(option);
+                    }
+                }
+                drop(args);
+                command.extend(options);
+                // A SET that did not write leaves the deadline it found,
+                // and replaying it does not write either.
+                if let Some(deadline) = Self::deadline(context, &key).flatten() {
+                    command.push(Bytes::from_static(b"PXAT"));
+                    command.push(Bytes::from(deadline.to_string()));
+                }
+                Self::propagate(context, command);
+            }
+            Propagation::Deadline(key) => {
+                if matches!(reply, Value::Integer(0) | Value::NullBulkString) {
+                    return;
+                }
+                let command = match Self::deadline(context, &key) {
+                    None => vec![Bytes::from_static(b"DEL"), key],
+                    Some(None) => vec![Bytes::from_static(b"PERSIST"), key],
+                    Some(Some(deadline)) => vec![
+                        Bytes::from_static(b"PEXPIREAT"),
+                        key,
+                        Bytes::from(deadline.to_string()),
+                    ],
+                };
+                Self::propagate(context, command);
+            }
+            Propagation::SPop(key) => {
+                let members: Vec<Bytes> = match reply {
+                    Value::BulkString(member) => vec![member.clone()],
+                    Value::Array(members) | Value::Set(members) => {
+                        members.iter().filter_map(bulk_string).collect()
+                    }
+                    _ => vec![],
+                };
+                if !members.is_empty() {
+                    let mut command = vec![Bytes::from_static(b"SREM"), key];
+                    command.extend(members);
+                    Self::propagate(context, command);
+                }
+            }
+            Propagation::XAdd(position) => {
+                if let Value::BulkString(id) = reply {
+                    command[position] = id.clone();
+                    Self::propagate(context, command);
+                }
+            }
+            Propagation::XClaim(key, group, consumer, ids) => {
+                let Value::Array(claimed) = reply else {
+                    return;
+                };
+                let claimed: Vec<Bytes> = claimed.iter().filter_map(claimed_id).collect();
+                let pending = Self::pending_ids(context, &key, &group, &ids);
+                let options = command.split_off(5 + ids.len());
+                // Entries still pending but not claimed were not idle long
+                // enough. The others were either claimed, or dropped from the
+                // pending entries for having been deleted, or never pending,
+                // which replaying does the same with whatever the idle time.
+                let ids = ids
+                    .iter()
+                    .zip(pending)
+                    .map(|(id, pending)| (Bytes::from(id.to_string()), pending))
+                    .filter(|(id, pending)| !pending || claimed.contains(id))
+                    .map(|(id, _)| id)
+                    .collect();
+                Self::propagate_claim(context, key, group, consumer, ids, options);
+            }
+            Propagation::XAutoClaim(key, group, consumer, justid) => {
+                let Value::Array(reply) = reply else {
+                    return;
+                };
+                let ids = match &reply[..] {
+                    [_, Value::Array(claimed), Value::Array(deleted)] => claimed
+                        .iter()
+                        .filter_map(claimed_id)
+                        .chain(deleted.iter().filter_map(bulk_string))
+                        .collect(),
+                    _ => return,
+                };
+                let options = if justid {
+                    vec![Bytes::from_static(b"JUSTID")]
+                } else {
+                    vec![]
+                };
+                Self::propagate_claim(context, key, group, consumer, ids, options);
+            }
+            Propagation::Restore(key, payload) => {
+                // A deadline already passed restores nothing, but still
+                // replaces the key.
+                let command = match Self::deadline(context, &key) {
+                    None => vec![Bytes::from_static(b"DEL"), key],
+                    Some(deadline) => vec![
+                        Bytes::from_static(b"RESTORE"),
+                        key,
+                        Bytes::from(deadline.unwrap_or(0).to_string()),
+                        payload,
+                        Bytes::from_static(b"REPLACE"),
+                        Bytes::from_static(b"ABSTTL"),
+                    ],
+                };
+                Self::propagate(context, command);
+            }
+        }
+    }
+
+    /// Logs a claim as an `XCLAIM` of exactly the entries it took, whatever
+    /// their idle time. A claim that took none still creates the consumer.
+    fn propagate_claim(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        ids: Vec<Bytes>,
+        options: Vec<Bytes>,
+    ) {
+        let command = if ids.is_empty() {
+            vec![
+                Bytes::from_static(b"XGROUP"),
+                Bytes::from_static(b"CREATECONSUMER"),
+                key,
+                group,
+                consumer,
+            ]
+        } else {
+            let mut command = vec![
+                Bytes::from_static(b"XCLAIM"),
+                key,
+                group,
+                consumer,
+                Bytes::from_static(b"0"),
+            ];
+            command.extend(ids);
+            command.extend(options);
+            command
+        };
+        Self::propagate(context, command);
+    }
+
+    /// The deadline of `key`, or `None` if the key is missing. A key whose
+    /// deadline already passed is not deleted here, as logging the `DEL`
+    /// would come before the write that set the deadline.
+    fn deadline(context: &Context<P, D, S>, key: &Bytes) -> Option<Option<i64>> {
+        context
+            .store
+            .read_with(key, |df| df.map(|df| df.deadline()))
+    }
+
+    /// Whether each of `ids` is pending in the group.
+    fn pending_ids(
+        context: &Context<P, D, S>,
+        key: &Bytes,
+        group: &Bytes,
+        ids: &[StreamId],
+    ) -> Vec<bool> {
+        Self::read_key(context, key.clone(), |data| {
+            let pending = match data {
+                Some(Data::Stream(stream)) => stream.group(group).map(|group| group.pending()),
+                _ => None,
+            };
+            ids.iter()
+                .map(|id| pending.is_some_and(|pending| pending.contains_key(id)))
+                .collect()
+        })
+    }
+
+    /// Writes out the commands logged while serving a batch of commands,
+    /// before their replies go out.
+    pub(super) fn write_appendonly(context: &Context<P, D, S>) {
+        if !Self::propagating(context) {
+            return;
+        }
+        let fsync = context.config.appendfsync() == AppendFsync::Always;
+        if let Err(err) = context.aof.write(fsync) {
+            println!("Error writing to the append only file: {}", err);
+        }
+    }
+}
+
+/// How `op` is logged once it ran.
+pub(super) fn propagation(op: &Operation, command: &[Bytes]) -> Propagation {
+    match op {
+        Operation::Set(key, _, options) if matches!(options.expiry, Some(Expiry::In(_))) => {
+            Propagation::Set(key.clone())
+        }
+        Operation::Expire(key, Expiry::In(_), _)
+        | Operation::GetEx(key, Some(GetExOption::Expire(Expiry::In(_)))) => {
+            Propagation::Deadline(key.clone())
+        }
+        Operation::SPop(key, _) => Propagation::SPop(key.clone()),
+        Operation::XAdd(_, _, NewId::Auto | NewId::AutoSeq(_), pairs) => {
+            Propagation::XAdd(command.len() - 2 * pairs.len() - 1)
+        }
+        Operation::XClaim(key, group, consumer, options) => Propagation::XClaim(
+            key.clone(),
+            group.clone(),
+            consumer.clone(),
+            options.ids.clone(),
+        ),
+        Operation::XAutoClaim(key, group, consumer, options) => {
+            Propagation::XAutoClaim(key.clone(), group.clone(), consumer.clone(), options.justid)
+        }
+        Operation::Restore(key, options) => {
+            Propagation::Restore(key.clone(), options.payload.clone())
+        }
+        Operation::BPop(..) | Operation::BLMove(..) | Operation::XReadGroup(..) => {
+            Propagation::Nothing
+        }
+        // Logs deleting the keys that moved itself, as some may not have.
+        Operation::Migrate(_) => Propagation::Nothing,
+        op if !super::written_keys(op).is_empty() => Propagation::Command,
+        _ => Propagation::Nothing,
+    }
+}
+
+/// The arguments of a command, an array of bulk strings.
+pub(super) fn command_args(value: &Value) -> Vec<Bytes> {
+    match value {
+        Value::Array(args) => args.iter().filter_map(bulk_string).collect(),
+        _ => vec![],
+    }
+}
+
+fn bulk_string(value: &Value) -> Option<Bytes> {
+    match value {
+        Value::BulkString(bytes) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+/// The ID of an entry in a claim reply, which is the entry itself unless
+/// the claim asked for IDs only.
+fn claimed_id(value: &Value) -> Option<Bytes> {
+    match value {
+        Value::Array(entry) => entry.first().and_then(bulk_string),
+        value => bulk_string(value),
+    }
+}
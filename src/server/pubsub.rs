@@ -0,0 +1,444 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{Client, Context, Server};
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::{Operation, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::pubsub::{self, Inbox, Subscriber};
+use crate::store::Store;
+use crate::value::Value;
+
+/// The channels and patterns a client listens to. Clients have one only
+/// while they are subscribed to something, which is what puts a connection
+/// in subscriber mode.
+pub(super) struct Subscription {
+    subscriber: Subscriber,
+    pub(super) inbox: Inbox,
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+}
+
+impl Subscription {
+    fn new() -> Self {
+        let (subscriber, inbox) = pubsub::subscriber();
+        Self {
+            subscriber,
+            inbox,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Handles the subscription commands, which reply once for every channel
+    /// or pattern they are given.
+    pub(super) fn handle_subscription(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        op: Operation,
+    ) -> Vec<Value> {
+        match op {
+            Operation::Subscribe(channels) => Self::subscribe(context, client, channels, false),
+            Operation::PSubscribe(patterns) => Self::subscribe(context, client, patterns, true),
+            Operation::Unsubscribe(channels) => Self::unsubscribe(context, client, channels, false),
+            Operation::PUnsubscribe(patterns) => Self::unsubscribe(context, client, patterns, true),
+            _ => unreachable!("not a subscription command"),
+        }
+    }
+
+    fn subscribe(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        names: Vec<Bytes>,
+        pattern: bool,
+    ) -> Vec<Value> {
+        let subscription = client.subscription.get_or_insert_with(Subscription::new);
+        let kind = if pattern { "psubscribe" } else { "subscribe" };
+        let mut replies = Vec::with_capacity(names.len());
+        for name in names {
+            let added = if pattern {
+                subscription.patterns.insert(name.clone())
+            } else {
+                subscription.channels.insert(name.clone())
+            };
+            match (added, pattern) {
+                (false, _) => {}
+                (true, false) => {
+                    context
+                        .pubsub
+                        .subscribe(name.clone(), client.id, &subscription.subscriber)
+                }
+                (true, true) => {
+                    context
+                        .pubsub
+                        .psubscribe(name.clone(), client.id, &subscription.subscriber)
+                }
+            }
+            replies.push(confirmation(
+                kind,
+                Value::BulkString(name),
+                subscription.count(),
+            ));
+        }
+        replies
+    }
+
+    /// Leaves `names`, or every channel or pattern if it is empty. The client
+    /// leaves subscriber mode once it has no subscriptions left.
+    fn unsubscribe(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        names: Vec<Bytes>,
+        pattern: bool,
+    ) -> Vec<Value> {
+        let kind = if pattern {
+            "punsubscribe"
+        } else {
+            "unsubscribe"
+        };
+        let subscription = match client.subscription.as_mut() {
+            Some(subscription) => subscription,
+            None if names.is_empty() => return vec![confirmation(kind, Value::Null, 0)],
+            None => {
+                return names
+                    .into_iter()
+                    .map(|name| confirmation(kind, Value::BulkString(name), 0))
+                    .collect()
+            }
+        };
+        // Unsubscribing from channels leaves the patterns alone, and the other way round.
+        let (subscribed, others) = if pattern {
+            (&mut subscription.patterns, subscription.channels.len())
+        } else {
+            (&mut subscription.channels, subscription.patterns.len())
+        };
+        let names = if names.is_empty() {
+            subscribed.iter().cloned().collect()
+        } else {
+            names
+        };
+        let mut replies = Vec::with_capacity(names.len().max(1));
+        for name in names {
+            if subscribed.remove(&name) {
+                if pattern {
+                    context.pubsub.punsubscribe(&name, client.id);
+                } else {
+                    context.pubsub.unsubscribe(&name, client.id);
+                }
+            }
+            let count = subscribed.len() + others;
+            replies.push(confirmation(kind, Value::BulkString(name), count));
+        }
+        if replies.is_empty() {
+            replies.push(confirmation(kind, Value::Null, subscription.count()));
+        }
+        if subscription.count() == 0 {
+            client.subscription = None;
+        }
+        replies
+    }
+
+    /// Drops all of the client's subscriptions, as when it disconnects.
+    pub(super) fn unsubscribe_all(context: &Context<P, D, S>, client: &mut Client) {
+        if let Some(subscription) = client.subscription.take() {
+            for channel in &subscription.channels {
+                context.pubsub.unsubscribe(channel, client.id);
+            }
+            for pattern in &subscription.patterns {
+                context.pubsub.punsubscribe(pattern, client.id);
+            }
+        }
+    }
+
+    pub(super) async fn handle_publish(
+        context: &Context<P, D, S>,
+        channel: Bytes,
+        message: Bytes,
+    ) -> Value {
+        Value::Integer(context.pubsub.publish(&channel, &message) as i64)
+    }
+
+    pub(super) async fn handle_pubsub_channels(
+        context: &Context<P, D, S>,
+        pattern: Option<Bytes>,
+    ) -> Value {
+        let channels = context.pubsub.channels(pattern.as_deref());
+        Value::Array(channels.into_iter().map(Value::BulkString).collect())
+    }
+
+    /// Channel and subscriber count pairs for every channel given.
+    pub(super) async fn handle_pubsub_numsub(
+        context: &Context<P, D, S>,
+        channels: Vec<Bytes>,
+    ) -> Value {
+        let mut counts = Vec::with_capacity(channels.len() * 2);
+        for channel in channels {
+            let count = context.pubsub.subscriber_count(&channel);
+            counts.push(Value::BulkString(channel));
+            counts.push(Value::Integer(count as i64));
+        }
+        Value::Array(counts)
+    }
+
+    pub(super) async fn handle_pubsub_numpat(context: &Context<P, D, S>) -> Value {
+        Value::Integer(context.pubsub.pattern_count() as i64)
+    }
+}
+
+/// The reply confirming a subscription change, with the number of channels
+/// and patterns the client is left subscribed to.
+fn confirmation(kind: &'static str, name: Value, count: usize) -> Value {
+    Value::Push(vec![
+        Value::BulkString(Bytes::from_static(kind.as_bytes())),
+        name,
+        Value::Integer(count as i64),
+    ])
+}
+
+/// Whether a RESP2 client in subscriber mode may run `op`. RESP3 clients can
+/// run anything, since pushed messages are told apart from replies there.
+pub(super) fn allowed_while_subscribed(op: &Operation) -> bool {
+    matches!(
+        op,
+        Operation::Subscribe(_)
+            | Operation::Unsubscribe(_)
+            | Operation::PSubscribe(_)
+            | Operation::PUnsubscribe(_)
+            | Operation::Ping
+    )
+}
+
+/// The error for a command a RESP2 subscriber sent, named as in `input`.
+pub(super) fn not_allowed_while_subscribed(input: &Value) -> Value {
+    let name = match input {
+        Value::Array(args) => match args.first() {
+            Some(Value::BulkString(name)) => String::from_utf8_lossy(name).to_lowercase(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    };
+    Value::Error(format!(
+        "ERR Can't execute '{name}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::super::tests::{bulk, call, command, read_replies, start_server};
+    use super::*;
+
+    fn array(values: &[&str]) -> Value {
+        Value::Array(values.iter().map(|value| bulk(value)).collect())
+    }
+
+    fn confirmation(kind: &str, name: &str, count: i64) -> Value {
+        Value::Array(vec![bulk(kind), bulk(name), Value::Integer(count)])
+    }
+
+    async fn send(stream: &mut TcpStream, args: &[&str]) {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        stream.write_all(&command(&args)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_published_messages() {
+        let addr = start_server().await;
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        send(&mut subscriber, &["SUBSCRIBE", "news", "weather"]).await;
+        assert_eq!(
+            read_replies(&mut subscriber, 2).await,
+            vec![
+                confirmation("subscribe", "news", 1),
+                confirmation("subscribe", "weather", 2)
+            ]
+        );
+        assert_eq!(
+            call(&mut publisher, &["PUBLISH", "news", "hello"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut publisher, &["PUBLISH", "sports", "goal"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            read_replies(&mut subscriber, 1).await,
+            vec![array(&["message", "news", "hello"])]
+        );
+
+        send(&mut subscriber, &["PSUBSCRIBE", "n*"]).await;
+        assert_eq!(
+            read_replies(&mut subscriber, 1).await,
+            vec![confirmation("psubscribe", "n*", 3)]
+        );
+        assert_eq!(
+            call(&mut publisher, &["PUBLISH", "news", "again"]).await,
+            Value::Integer(2)
+        );
+        let mut messages = read_replies(&mut subscriber, 2).await;
+        messages.sort_by_key(|message| format!("{message}"));
+        assert_eq!(
+            messages,
+            vec![
+                array(&["message", "news", "again"]),
+                array(&["pmessage", "n*", "news", "again"])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriber_mode_limits_resp2_commands() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send(&mut stream, &["SUBSCRIBE", "a"]).await;
+        read_replies(&mut stream, 1).await;
+        assert_eq!(
+            call(&mut stream, &["GET", "k"]).await,
+            Value::Error(String::from(
+                "ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"
+            ))
+        );
+        assert_eq!(call(&mut stream, &["PING"]).await, array(&["pong", ""]));
+        assert_eq!(
+            call(&mut stream, &["UNSUBSCRIBE"]).await,
+            confirmation("unsubscribe", "a", 0)
+        );
+        assert_eq!(
+            call(&mut stream, &["GET", "k"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["UNSUBSCRIBE"]).await,
+            Value::Array(vec![
+                bulk("unsubscribe"),
+                Value::NullBulkString,
+                Value::Integer(0)
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn resp3_subscribers_can_run_any_command() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        call(&mut stream, &["HELLO", "3"]).await;
+        assert_eq!(
+            call(&mut stream, &["SUBSCRIBE", "a"]).await,
+            Value::Push(vec![bulk("subscribe"), bulk("a"), Value::Integer(1)])
+        );
+        assert_eq!(call(&mut stream, &["GET", "k"]).await, Value::Null);
+        assert_eq!(
+            call(&mut stream, &["PING"]).await,
+            Value::SimpleString(String::from("PONG"))
+        );
+    }
+
+    #[tokio::test]
+    async fn pubsub_introspection() {
+        let addr = start_server().await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send(&mut first, &["SUBSCRIBE", "news.tech", "weather"]).await;
+        read_replies(&mut first, 2).await;
+        send(&mut second, &["SUBSCRIBE", "news.tech"]).await;
+        send(&mut second, &["PSUBSCRIBE", "news.*"]).await;
+        read_replies(&mut second, 2).await;
+        let mut channels = match call(&mut stream, &["PUBSUB", "CHANNELS"]).await {
+            Value::Array(channels) => channels,
+            reply => panic!("unexpected PUBSUB CHANNELS reply {reply:?}"),
+        };
+        channels.sort_by_key(|channel| format!("{channel:?}"));
+        assert_eq!(channels, vec![bulk("news.tech"), bulk("weather")]);
+        assert_eq!(
+            call(&mut stream, &["PUBSUB", "CHANNELS", "news.*"]).await,
+            array(&["news.tech"])
+        );
+        assert_eq!(
+            call(&mut stream, &["PUBSUB", "NUMSUB", "news.tech", "none"]).await,
+            Value::Array(vec![
+                bulk("news.tech"),
+                Value::Integer(2),
+                bulk("none"),
+                Value::Integer(0)
+            ])
+        );
+        assert_eq!(
+            call(&mut stream, &["PUBSUB", "NUMPAT"]).await,
+            Value::Integer(1)
+        );
+        drop(second);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            call(&mut stream, &["PUBSUB", "NUMSUB", "news.tech"]).await,
+            Value::Array(vec![bulk("news.tech"), Value::Integer(1)])
+        );
+        assert_eq!(
+            call(&mut stream, &["PUBSUB", "NUMPAT"]).await,
+            Value::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_are_disconnected() {
+        let addr = start_server().await;
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        send(&mut subscriber, &["SUBSCRIBE", "firehose"]).await;
+        read_replies(&mut subscriber, 1).await;
+        // The subscriber stops reading, so messages back up in the socket
+        // buffers first and in its queue after that.
+        let payload = "x".repeat(16 * 1024);
+        let mut batch = vec![];
+        for _ in 0..256 {
+            batch.extend(command(&[b"PUBLISH", b"firehose", payload.as_bytes()]));
+        }
+        let mut published = 0;
+        loop {
+            publisher.write_all(&batch).await.unwrap();
+            let replies = read_replies(&mut publisher, 256).await;
+            published += replies
+                .iter()
+                .filter(|reply| **reply == Value::Integer(1))
+                .count();
+            if replies.contains(&Value::Integer(0)) {
+                break;
+            }
+            assert!(published < 100_000, "subscriber was never disconnected");
+        }
+        assert!(published >= pubsub::SUBSCRIBER_QUEUE_CAPACITY);
+        let mut buf = vec![0; 64 * 1024];
+        let closed = tokio::time::timeout(Duration::from_secs(10), async {
+            while let Ok(read) = subscriber.read(&mut buf).await {
+                if read == 0 {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(closed.is_ok());
+        assert_eq!(
+            call(&mut publisher, &["PUBSUB", "NUMSUB", "firehose"]).await,
+            Value::Array(vec![bulk("firehose"), Value::Integer(0)])
+        );
+    }
+}
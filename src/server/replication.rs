@@ -0,0 +1,821 @@
+use std::io::{self, Cursor};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use super::{written_keys, Client, Context, Server};
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::frame::FrameDecoder;
+use crate::operation::{Operation, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::persistence::snapshot;
+use crate::rdb::{self, Snapshot};
+use crate::replication::{Link, Target};
+use crate::store::Store;
+use crate::transaction::SharedGate;
+use crate::value::Value;
+
+/// How long a replica waits before connecting to its primary again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How often a replica tells its primary the offset it got to.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Where the stream to a replica that sent `PSYNC` starts.
+pub(super) struct ReplicaSync {
+    /// The snapshot to send first, for a replica that syncs from scratch.
+    snapshot: Option<Snapshot>,
+    offset: u64,
+    epoch: u64,
+}
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Follows the primary at `host`, before the server starts.
+    pub fn replicaof(&self, host: String, port: u16) {
+        self.replication.follow(host, port);
+    }
+
+    /// Starts following a primary, which replaces the dataset with its own,
+    /// or stops following one and takes writes from then on.
+    pub(super) async fn handle_replicaof(
+        context: &Context<P, D, S>,
+        target: Option<(String, u16)>,
+    ) -> Value {
+        if context.cluster.is_enabled() {
+            return Value::Error(String::from("ERR REPLICAOF not allowed in cluster mode."));
+        }
+        // Not halfway through applying a command from the old primary.
+        let _applying = context.replication.applying().await;
+        let _gate = context.transactions.exclusive().await;
+        match target {
+            None => context.replication.promote(),
+            Some((host, port)) => {
+                if !context.replication.follow(host, port) {
+                    return Value::SimpleString(String::from(
+                        "OK Already connected to specified master",
+                    ));
+                }
+            }
+        }
+        Value::SimpleString(String::from("OK"))
+    }
+
+    /// Answers a replica that asks to continue the stream `replid` from
+    /// `offset`, or has to sync from scratch with a snapshot. The client
+    /// is streamed to from then on, see `feed_replica`.
+    pub(super) async fn handle_psync(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        replid: Bytes,
+        offset: i64,
+    ) -> Value {
+        if !context.replication.is_linked() {
+            return Value::Error(String::from(
+                "NOMASTERLINK Can't SYNC while not connected with my master",
+            ));
+        }
+        let _applying = context.replication.applying().await;
+        let _gate = context.transactions.exclusive().await;
+        if let Some((offset, epoch)) = context.replication.resume(&replid, offset) {
+            client.replica = Some(ReplicaSync {
+                snapshot: None,
+                offset,
+                epoch,
+            });
+            let (replid, _) = context.replication.position();
+            return Value::SimpleString(format!("CONTINUE {replid}"));
+        }
+        let size = context.config.repl_backlog_size();
+        let (offset, epoch) = context.replication.start_backlog(size);
+        client.replica = Some(ReplicaSync {
+            snapshot: Some(snapshot(context.store.as_ref())),
+            offset,
+            epoch,
+        });
+        let (replid, _) = context.replication.position();
+        Value::SimpleString(format!("FULLRESYNC {replid} {offset}"))
+    }
+
+    pub(super) fn handle_replconf(client: &mut Client, pairs: Vec<(Bytes, Bytes)>) -> Value {
+        for (option, value) in pairs {
+            if option.eq_ignore_ascii_case(b"listening-port") {
+                let port = std::str::from_utf8(&value)
+                    .ok()
+                    .and_then(|port| port.parse().ok());
+                match port {
+                    Some(port) => client.listening_port = Some(port),
+                    None => {
+                        return Value::Error(String::from(
+                            "ERR value is not an integer or out of range",
+                        ))
+                    }
+                }
+            }
+        }
+        Value::SimpleString(String::from("OK"))
+    }
+
+    pub(super) fn handle_role(context: &Context<P, D, S>) -> Value {
+        if context.sentinel.is_enabled() {
+            return context.sentinel.role();
+        }
+        context.replication.role()
+    }
+
+    /// Waits until `numreplicas` replicas acknowledged every write made so
+    /// far, or `timeout` passes, and replies with how many did. Replicas
+    /// are asked to acknowledge right away rather than on their own time.
+    ///
+    /// As with the blocking commands, the shared `gate` is released while
+    /// waiting, and a transaction, which holds no gate, never waits.
+    pub(super) async fn handle_wait<'a>(
+        context: &'a Context<P, D, S>,
+        numreplicas: usize,
+        timeout: Option<Duration>,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        if context.replication.is_replica() {
+            return Value::Error(String::from(
+                "ERR WAIT cannot be used with replica instances.",
+            ));
+        }
+        let (_, offset) = context.replication.position();
+        let mut acks = context.replication.acks();
+        let acked = context.replication.acked(offset);
+        if acked >= numreplicas || gate.is_none() {
+            return Value::Integer(acked as i64);
+        }
+        context.replication.feed(&[
+            Bytes::from_static(b"REPLCONF"),
+            Bytes::from_static(b"GETACK"),
+            Bytes::from_static(b"*"),
+        ]);
+        *gate = None;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            acks.borrow_and_update();
+            if context.replication.acked(offset) >= numreplicas {
+                break;
+            }
+            let timed_out = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = acks.changed() => {}
+                _ = timed_out => break,
+            }
+        }
+        *gate = Some(context.transactions.shared().await);
+        Value::Integer(context.replication.acked(offset) as i64)
+    }
+
+    /// Whether `op` is a write that a replica turns down, as only its
+    /// primary writes to it.
+    pub(super) fn read_only(context: &Context<P, D, S>, client: &Client, op: &Operation) -> bool {
+        context.replication.is_replica() && !client.replaying && !written_keys(op).is_empty()
+    }
+
+    /// Streams to a replica, starting with the snapshot if it syncs from
+    /// scratch, until it disconnects or falls further behind than the
+    /// backlog reaches. Notes the offsets the replica acknowledges.
+    pub(super) async fn feed_replica(
+        context: &Context<P, D, S>,
+        stream: &mut TcpStream,
+        decoder: &mut FrameDecoder,
+        client: &Client,
+        sync: ReplicaSync,
+    ) {
+        let mut progress = context.replication.progress();
+        let mut changes = context.replication.changes();
+        if let Some(snapshot) = sync.snapshot {
+            let Ok(payload) = tokio::task::spawn_blocking(move || rdb::encode(&snapshot)).await
+            else {
+                return;
+            };
+            let header = format!("${}\r\n", payload.len());
+            if stream.write_all(header.as_bytes()).await.is_err()
+                || stream.write_all(&payload).await.is_err()
+            {
+                return;
+            }
+        }
+        let Ok(peer) = stream.peer_addr() else {
+            return;
+        };
+        let port = client.listening_port.unwrap_or(peer.port());
+        context
+            .replication
+            .add_replica(client.id, peer.ip().to_string(), port, sync.offset);
+        let mut offset = sync.offset;
+        loop {
+            progress.borrow_and_update();
+            changes.borrow_and_update();
+            let Some(data) = context.replication.read_backlog(offset, sync.epoch) else {
+                break;
+            };
+            if !data.is_empty() {
+                if stream.write_all(&data).await.is_err() {
+                    break;
+                }
+                offset += data.len() as u64;
+                continue;
+            }
+            tokio::select! {
+                _ = progress.changed() => {}
+                _ = changes.changed() => {}
+                read = stream.read_buf(decoder.buffer_mut()) => {
+                    if !matches!(read, Ok(n) if n > 0) {
+                        break;
+                    }
+                    if !Self::read_acks(context, decoder, client.id) {
+                        break;
+                    }
+                }
+            }
+        }
+        context.replication.remove_replica(client.id);
+    }
+
+    /// Takes in the `REPLCONF ACK <offset>` a replica sent, ignoring
+    /// anything else. Returns false if what it sent is not RESP.
+    fn read_acks(context: &Context<P, D, S>, decoder: &mut FrameDecoder, id: u64) -> bool {
+        loop {
+            match decoder.decode(context.parser.as_ref()) {
+                Ok(Some(frame)) => {
+                    if let Some(offset) = ack_offset(&frame) {
+                        context.replication.ack(id, offset);
+                    }
+                }
+                Ok(None) => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Keeps the link to the primary up while this server is a replica,
+    /// reconnecting whenever it drops and following whichever primary
+    /// `REPLICAOF` names last.
+    pub(super) fn spawn_replication_task(&self) {
+        let context = self.context();
+        tokio::task::spawn(async move {
+            let mut changes = context.replication.changes();
+            loop {
+                changes.borrow_and_update();
+                let Some(target) = context.replication.target() else {
+                    if changes.changed().await.is_err() {
+                        return;
+                    }
+                    continue;
+                };
+                if let Err(err) = Self::follow_primary(&context, &target).await {
+                    println!(
+                        "Error replicating from {}:{}: {}",
+                        target.host, target.port, err
+                    );
+                }
+                if context.replication.follows(target.id) {
+                    context.replication.set_link(target.id, Link::Connect);
+                    tokio::select! {
+                        _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                        _ = changes.changed() => {}
+                    }
+                }
+            }
+        });
+    }
+
+    /// Syncs with the primary, from scratch or from where this replica got
+    /// to, then applies the writes it streams, acknowledging the offset it
+    /// got to every so often and whenever the primary asks. Returns once the
+    /// link drops, or this server stops following the primary.
+    async fn follow_primary(context: &Context<P, D, S>, target: &Target) -> io::Result<()> {
+        let parser = context.parser.as_ref();
+        context.replication.set_link(target.id, Link::Connecting);
+        let mut stream = TcpStream::connect((target.host.as_str(), target.port)).await?;
+        let mut decoder = FrameDecoder::new();
+        let port = context.replication.port().to_string();
+        for args in [
+            &["PING"][..],
+            &["REPLCONF", "listening-port", &port],
+            &["REPLCONF", "capa", "psync2"],
+        ] {
+            request(&mut stream, &mut decoder, parser, args).await?;
+        }
+        let (replid, offset) = context.replication.position();
+        let psync = ["PSYNC", &replid, &(offset + 1).to_string()];
+        let reply = request(&mut stream, &mut decoder, parser, &psync).await?;
+        context.replication.set_link(target.id, Link::Sync);
+        let Value::SimpleString(reply) = reply else {
+            return Err(invalid_data("unexpected reply to PSYNC"));
+        };
+        match reply.split(' ').collect::<Vec<_>>()[..] {
+            ["FULLRESYNC", replid, offset] => {
+                let offset = offset.parse().map_err(|_| invalid_data("invalid offset"))?;
+                let payload = loop {
+                    if let Some(payload) = decoder.decode_payload()? {
+                        break payload;
+                    }
+                    read_more(&mut stream, &mut decoder).await?;
+                };
+                let snapshot = tokio::task::spawn_blocking(move || rdb::decode(&payload))
+                    .await
+                    .map_err(io::Error::other)?
+                    .map_err(io::Error::other)?;
+                let _applying = context.replication.applying().await;
+                if !context.replication.follows(target.id) {
+                    return Ok(());
+                }
+                let _gate = context.transactions.exclusive().await;
+                Self::replace_dataset(context, snapshot);
+                let size = context.config.repl_backlog_size();
+                context.replication.synced(replid.to_string(), offset, size);
+                // The log has to start over from the new dataset.
+                if Self::propagating(context) {
+                    Self::start_rewrite(context);
+                }
+            }
+            ["CONTINUE"] => {}
+            ["CONTINUE", replid] => context.replication.continued(replid.to_string()),
+            _ => return Err(invalid_data("unexpected reply to PSYNC")),
+        }
+        context.replication.set_link(target.id, Link::Connected);
+        let mut client = Client::new();
+        client.replaying = true;
+        let mut changes = context.replication.changes();
+        let mut acks = tokio::time::interval(ACK_PERIOD);
+        loop {
+            while let Some((command, raw)) = decoder.decode_frame(parser)? {
+                let getack = is_getack(&command);
+                let _applying = context.replication.applying().await;
+                if !context.replication.follows(target.id) {
+                    return Ok(());
+                }
+                let mut replies = vec![];
+                Self::handle_input(context, &mut client, command, &mut replies).await;
+                // The offset acknowledged leaves out the request itself.
+                if getack {
+                    send_ack(context, &mut stream).await?;
+                }
+                context.replication.feed_raw(&raw);
+            }
+            Self::write_appendonly(context);
+            tokio::select! {
+                read = read_more(&mut stream, &mut decoder) => read?,
+                _ = acks.tick() => send_ack(context, &mut stream).await?,
+                _ = changes.changed() => {
+                    if !context.replication.follows(target.id) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces every key with those in `snapshot`, keeping the keys whose
+    /// deadline passed, as the primary deletes them when it sees fit.
+    fn replace_dataset(context: &Context<P, D, S>, snapshot: Snapshot) {
+        let mut keys = vec![];
+        context.store.for_each(|key, _| keys.push(key.clone()));
+        for key in &keys {
+            context.store.remove(key);
+        }
+        context.transactions.touch(&keys);
+        for (key, df) in snapshot {
+            context.transactions.touch([&key]);
+            context.store.set(key, df);
+        }
+    }
+}
+
+/// Sends a command to another server and waits for its reply, which must
+/// not be an error.
+pub(super) async fn request<P>(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    parser: &P,
+    args: &[&str],
+) -> io::Result<Value>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>>,
+{
+    stream.write_all(&encode_command(args)).await?;
+    loop {
+        match decoder.decode(parser)? {
+            Some(Value::Error(err)) => return Err(io::Error::other(err)),
+            Some(reply) => return Ok(reply),
+            None => read_more(stream, decoder).await?,
+        }
+    }
+}
+
+/// Tells the primary the offset this replica got to.
+async fn send_ack<P, D, S>(context: &Context<P, D, S>, stream: &mut TcpStream) -> io::Result<()> {
+    let (_, offset) = context.replication.position();
+    let ack = encode_command(&["REPLCONF", "ACK", &offset.to_string()]);
+    stream.write_all(&ack).await
+}
+
+pub(super) fn encode_command(args: &[&str]) -> Vec<u8> {
+    let args = args
+        .iter()
+        .map(|arg| Value::BulkString(Bytes::copy_from_slice(arg.as_bytes())))
+        .collect();
+    let mut buf = vec![];
+    Value::Array(args)
+        .encode(&mut buf)
+        .expect("Error while encoding a command");
+    buf
+}
+
+/// The offset in a `REPLCONF ACK <offset>` command.
+fn ack_offset(frame: &Value) -> Option<u64> {
+    match frame {
+        Value::Array(args) => match &args[..] {
+            [Value::BulkString(command), Value::BulkString(option), Value::BulkString(offset)]
+                if command.eq_ignore_ascii_case(b"replconf")
+                    && option.eq_ignore_ascii_case(b"ack") =>
+            {
+                std::str::from_utf8(offset).ok()?.parse().ok()
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `frame` is the primary asking for a `REPLCONF ACK`.
+fn is_getack(frame: &Value) -> bool {
+    match frame {
+        Value::Array(args) => matches!(
+            &args[..],
+            [Value::BulkString(command), Value::BulkString(option), ..]
+                if command.eq_ignore_ascii_case(b"replconf")
+                    && option.eq_ignore_ascii_case(b"getack")
+        ),
+        _ => false,
+    }
+}
+
+pub(super) async fn read_more(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+) -> io::Result<()> {
+    if stream.read_buf(decoder.buffer_mut()).await? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed by the other end",
+        ));
+    }
+    Ok(())
+}
+
+pub(super) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::net;
+
+    use super::super::tests::{
+        bulk, call, command, ok, start_server, start_server_with, wait_for, wait_for_reply,
+    };
+    use super::*;
+    use crate::parse::RespParser;
+    use crate::store::ConcurrentHashtable;
+
+    type TestStore = ConcurrentHashtable<Bytes, DataFrame<Data>>;
+
+    /// Starts a server, keeping a handle on its keys to tell what it holds
+    /// apart from what it replies.
+    async fn start_with_store() -> (SocketAddr, Arc<TestStore>) {
+        let server = Server::new("0");
+        let store = Arc::clone(&server.store);
+        (start_server_with(server).await, store)
+    }
+
+    /// Reads the next frame the other end sends, with the bytes it took.
+    async fn next_frame(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> (Value, Bytes) {
+        loop {
+            if let Some(frame) = decoder.decode_frame(&RespParser::new()).unwrap() {
+                return frame;
+            }
+            assert_ne!(stream.read_buf(decoder.buffer_mut()).await.unwrap(), 0);
+        }
+    }
+
+    /// Syncs as a replica, returning the replication ID and the offset the
+    /// reply to `PSYNC` named.
+    async fn psync(
+        stream: &mut TcpStream,
+        decoder: &mut FrameDecoder,
+        replid: &str,
+        offset: &str,
+    ) -> Vec<String> {
+        let psync = command(&[b"PSYNC", replid.as_bytes(), offset.as_bytes()]);
+        stream.write_all(&psync).await.unwrap();
+        let Value::SimpleString(reply) = next_frame(stream, decoder).await.0 else {
+            panic!("unexpected reply to PSYNC");
+        };
+        let reply: Vec<String> = reply.split(' ').map(String::from).collect();
+        if reply[0] == "FULLRESYNC" {
+            loop {
+                if let Some(payload) = decoder.decode_payload().unwrap() {
+                    assert!(rdb::decode(&payload).is_ok());
+                    break;
+                }
+                assert_ne!(stream.read_buf(decoder.buffer_mut()).await.unwrap(), 0);
+            }
+        }
+        reply
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn replicas_sync_then_follow_every_write() {
+        let primary_addr = start_server().await;
+        let mut primary = TcpStream::connect(primary_addr).await.unwrap();
+        call(&mut primary, &["SET", "counter", "1"]).await;
+        call(&mut primary, &["RPUSH", "list", "a", "b"]).await;
+        call(&mut primary, &["SET", "volatile", "v", "EX", "100"]).await;
+
+        let (replica_addr, replica_store) = start_with_store().await;
+        let mut replica = TcpStream::connect(replica_addr).await.unwrap();
+        let port = primary_addr.port().to_string();
+        let replicaof = ["REPLICAOF", "127.0.0.1", &port];
+        assert_eq!(call(&mut replica, &replicaof).await, ok());
+        assert_eq!(
+            call(&mut replica, &replicaof).await,
+            Value::SimpleString(String::from("OK Already connected to specified master"))
+        );
+        wait_for_reply(&mut replica, &["GET", "counter"], |reply| *reply == bulk("1")).await;
+
+        call(&mut primary, &["INCR", "counter"]).await;
+        call(&mut primary, &["LPOP", "list"]).await;
+        call(&mut primary, &["SET", "short", "v", "PX", "30"]).await;
+        call(&mut primary, &["MULTI"]).await;
+        call(&mut primary, &["SADD", "set", "x"]).await;
+        call(&mut primary, &["SET", "done", "yes"]).await;
+        call(&mut primary, &["EXEC"]).await;
+        wait_for_reply(&mut replica, &["GET", "done"], |reply| *reply == bulk("yes")).await;
+        assert_eq!(call(&mut replica, &["GET", "counter"]).await, bulk("2"));
+        assert_eq!(
+            call(&mut replica, &["LRANGE", "list", "0", "-1"]).await,
+            Value::Array(vec![bulk("b")])
+        );
+        assert!(matches!(
+            call(&mut replica, &["TTL", "volatile"]).await,
+            Value::Integer(99 | 100)
+        ));
+        // The primary deletes the key once it expires and tells the replica.
+        wait_for(|| !replica_store.contains(Bytes::from("short"))).await;
+
+        let read_only = Value::Error(String::from(
+            "READONLY You can't write against a read only replica.",
+        ));
+        assert_eq!(call(&mut replica, &["SET", "k", "v"]).await, read_only);
+        call(&mut replica, &["MULTI"]).await;
+        assert_eq!(call(&mut replica, &["DEL", "counter"]).await, read_only);
+        assert!(matches!(
+            call(&mut replica, &["EXEC"]).await,
+            Value::Error(err) if err.starts_with("EXECABORT")
+        ));
+
+        let role = match call(&mut primary, &["ROLE"]).await {
+            Value::Array(role) => role,
+            reply => panic!("unexpected reply to ROLE {reply:?}"),
+        };
+        assert_eq!(role[0], bulk("master"));
+        let offset = match role[1] {
+            Value::Integer(offset) => offset,
+            ref reply => panic!("unexpected offset {reply:?}"),
+        };
+        assert!(offset > 0);
+        match &role[2] {
+            Value::Array(replicas) => assert_eq!(replicas.len(), 1),
+            reply => panic!("unexpected replicas {reply:?}"),
+        }
+        let role = Value::Array(vec![
+            bulk("slave"),
+            bulk("127.0.0.1"),
+            Value::Integer(primary_addr.port() as i64),
+            bulk("connected"),
+            Value::Integer(offset),
+        ]);
+        wait_for_reply(&mut replica, &["ROLE"], |reply| *reply == role).await;
+
+        assert_eq!(call(&mut replica, &["REPLICAOF", "NO", "ONE"]).await, ok());
+        assert_eq!(call(&mut replica, &["SET", "k", "v"]).await, ok());
+        call(&mut primary, &["SET", "counter", "10"]).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(call(&mut replica, &["GET", "counter"]).await, bulk("2"));
+    }
+
+    #[tokio::test]
+    async fn replicas_resume_from_the_backlog() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut replica = TcpStream::connect(addr).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        let reply = psync(&mut replica, &mut decoder, "?", "-1").await;
+        assert_eq!(reply.len(), 3);
+        assert_eq!(reply[0], "FULLRESYNC");
+        let replid = reply[1].clone();
+        let offset: usize = reply[2].parse().unwrap();
+
+        call(&mut client, &["SET", "k", "1"]).await;
+        let (set, raw) = next_frame(&mut replica, &mut decoder).await;
+        assert_eq!(set, Value::Array(vec![bulk("SET"), bulk("k"), bulk("1")]));
+        let offset = offset + raw.len();
+        drop(replica);
+
+        // Writes made while the replica is away are sent when it is back.
+        call(&mut client, &["INCR", "k"]).await;
+        let mut replica = TcpStream::connect(addr).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        let next = (offset + 1).to_string();
+        let reply = psync(&mut replica, &mut decoder, &replid, &next).await;
+        assert_eq!(reply, vec![String::from("CONTINUE"), replid.clone()]);
+        let (incr, raw) = next_frame(&mut replica, &mut decoder).await;
+        assert_eq!(incr, Value::Array(vec![bulk("INCR"), bulk("k")]));
+        let offset = offset + raw.len();
+        drop(replica);
+
+        // Unless the backlog no longer holds them all.
+        call(&mut client, &["CONFIG", "SET", "repl-backlog-size", "1"]).await;
+        let large = "x".repeat(20_000);
+        call(&mut client, &["SET", "large", &large]).await;
+        let mut replica = TcpStream::connect(addr).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        let next = (offset + 1).to_string();
+        let reply = psync(&mut replica, &mut decoder, &replid, &next).await;
+        assert_eq!(reply[0], "FULLRESYNC");
+        let reply = psync(&mut client, &mut FrameDecoder::new(), "unknown", "1").await;
+        assert_eq!(reply[0], "FULLRESYNC");
+    }
+
+    /// Makes the server at `addr` the replica of a primary that is faked
+    /// by the test, returning the link to it once it asks to sync.
+    async fn accept_replica(client: &mut TcpStream) -> (TcpStream, FrameDecoder) {
+        let primary = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = primary.local_addr().unwrap().port().to_string();
+        assert_eq!(call(client, &["REPLICAOF", "127.0.0.1", &port]).await, ok());
+
+        let (mut link, _) = primary.accept().await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        for reply in [&b"+PONG\r\n"[..], b"+OK\r\n", b"+OK\r\n"] {
+            next_frame(&mut link, &mut decoder).await;
+            link.write_all(reply).await.unwrap();
+        }
+        let (psync, _) = next_frame(&mut link, &mut decoder).await;
+        assert!(matches!(psync, Value::Array(args) if args[0] == bulk("PSYNC")));
+        (link, decoder)
+    }
+
+    fn replconf_ack(offset: usize) -> Value {
+        Value::Array(vec![
+            bulk("REPLCONF"),
+            bulk("ACK"),
+            bulk(&offset.to_string()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn replicas_leave_expiry_to_their_primary() {
+        let (addr, store) = start_with_store().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut link, _) = accept_replica(&mut client).await;
+        let entries = vec![
+            (
+                Bytes::from("gone"),
+                DataFrame::with_deadline(Data::String(Bytes::from("v")), 1),
+            ),
+            (
+                Bytes::from("kept"),
+                DataFrame::Plain(Data::String(Bytes::from("v"))),
+            ),
+        ];
+        let payload = rdb::encode(&entries);
+        let replid = "0123456789abcdef0123456789abcdef01234567";
+        let mut sync = format!("+FULLRESYNC {replid} 100\r\n${}\r\n", payload.len()).into_bytes();
+        sync.extend(payload);
+        link.write_all(&sync).await.unwrap();
+
+        wait_for_reply(&mut client, &["GET", "kept"], |reply| *reply == bulk("v")).await;
+        assert_eq!(
+            call(&mut client, &["GET", "gone"]).await,
+            Value::NullBulkString
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(store.contains(Bytes::from("gone")));
+
+        let del = command(&[b"DEL", b"gone"]);
+        link.write_all(&del).await.unwrap();
+        wait_for(|| !store.contains(Bytes::from("gone"))).await;
+        let role = match call(&mut client, &["ROLE"]).await {
+            Value::Array(role) => role,
+            reply => panic!("unexpected reply to ROLE {reply:?}"),
+        };
+        assert_eq!(role[4], Value::Integer(100 + del.len() as i64));
+    }
+    #[tokio::test]
+    async fn replicas_acknowledge_their_offset() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut link, mut decoder) = accept_replica(&mut client).await;
+        let payload = rdb::encode(&[]);
+        let replid = "0123456789abcdef0123456789abcdef01234567";
+        let mut sync = format!("+FULLRESYNC {replid} 100\r\n${}\r\n", payload.len()).into_bytes();
+        sync.extend(payload);
+        link.write_all(&sync).await.unwrap();
+        assert_eq!(
+            next_frame(&mut link, &mut decoder).await.0,
+            replconf_ack(100)
+        );
+
+        let set = command(&[b"SET", b"k", b"v"]);
+        link.write_all(&set).await.unwrap();
+        let getack = command(&[b"REPLCONF", b"GETACK", b"*"]);
+        link.write_all(&getack).await.unwrap();
+        let offset = 100 + set.len();
+        assert_eq!(
+            next_frame(&mut link, &mut decoder).await.0,
+            replconf_ack(offset)
+        );
+        // The request counts towards the offset acknowledged from then on.
+        assert_eq!(
+            next_frame(&mut link, &mut decoder).await.0,
+            replconf_ack(offset + getack.len())
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_for_replicas_to_acknowledge_writes() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            call(&mut client, &["WAIT", "0", "0"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut client, &["WAIT", "1", "50"]).await,
+            Value::Integer(0)
+        );
+        call(&mut client, &["MULTI"]).await;
+        call(&mut client, &["WAIT", "1", "0"]).await;
+        assert_eq!(
+            call(&mut client, &["EXEC"]).await,
+            Value::Array(vec![Value::Integer(0)])
+        );
+
+        let mut replicas = vec![];
+        for _ in 0..2 {
+            let replica_addr = start_server().await;
+            let mut replica = TcpStream::connect(replica_addr).await.unwrap();
+            let port = addr.port().to_string();
+            call(&mut replica, &["REPLICAOF", "127.0.0.1", &port]).await;
+            replicas.push(replica);
+        }
+        call(&mut client, &["SET", "k", "v"]).await;
+        assert_eq!(
+            call(&mut client, &["WAIT", "2", "0"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut client, &["WAIT", "3", "100"]).await,
+            Value::Integer(2)
+        );
+
+        // A replica that only acknowledges when asked still unblocks WAIT.
+        let mut replica = TcpStream::connect(addr).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        let reply = psync(&mut replica, &mut decoder, "?", "-1").await;
+        let offset: usize = reply[2].parse().unwrap();
+        call(&mut client, &["SET", "k", "w"]).await;
+        let (_, raw) = next_frame(&mut replica, &mut decoder).await;
+        let waiting = tokio::spawn(async move { call(&mut client, &["WAIT", "3", "0"]).await });
+        let (getack, _) = next_frame(&mut replica, &mut decoder).await;
+        assert_eq!(
+            getack,
+            Value::Array(vec![bulk("REPLCONF"), bulk("GETACK"), bulk("*")])
+        );
+        let ack = (offset + raw.len()).to_string();
+        let ack = command(&[b"REPLCONF", b"ACK", ack.as_bytes()]);
+        replica.write_all(&ack).await.unwrap();
+        assert_eq!(waiting.await.unwrap(), Value::Integer(3));
+        drop(replicas);
+    }
+}
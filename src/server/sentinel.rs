@@ -0,0 +1,615 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time;
+
+use super::replication::{encode_command, request};
+use super::{Context, Server};
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::frame::FrameDecoder;
+use crate::operation::{Operation, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::sentinel::{Address, Event};
+use crate::store::Store;
+use crate::value::Value;
+
+/// How often a sentinel checks on the instances and sentinels it knows of.
+const SENTINEL_PERIOD: Duration = Duration::from_millis(100);
+/// How long an instance or another sentinel has to answer.
+const SENTINEL_TIMEOUT: Duration = Duration::from_millis(500);
+/// The channel sentinels publish hello messages on.
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+/// The open connections to instances and other sentinels, by address.
+type Links = HashMap<Address, (TcpStream, FrameDecoder)>;
+
+/// What an instance replied to `ROLE`.
+enum Role {
+    /// A primary, with the addresses of its replicas.
+    Primary(Vec<Address>),
+    /// A replica, with how far it got in the replication stream.
+    Replica(u64),
+}
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Turns on sentinel mode, before the server starts.
+    pub fn enable_sentinel(&self) {
+        self.sentinel.enable();
+    }
+
+    /// Starts monitoring a primary, as `SENTINEL MONITOR` does.
+    pub fn sentinel_monitor(
+        &self,
+        name: String,
+        host: String,
+        port: u16,
+        quorum: usize,
+    ) -> Result<(), String> {
+        self.sentinel.monitor(name, host, port, quorum)
+    }
+
+    pub(super) fn handle_sentinel(context: &Context<P, D, S>, op: Operation) -> Value {
+        if !context.sentinel.is_enabled() {
+            return Value::Error(String::from(
+                "ERR This instance is not running in sentinel mode",
+            ));
+        }
+        let done = |result: Result<(), String>| match result {
+            Ok(()) => Value::SimpleString(String::from("OK")),
+            Err(err) => Value::Error(err),
+        };
+        let reply = |result: Result<Value, String>| result.unwrap_or_else(Value::Error);
+        match op {
+            Operation::SentinelMyId => Value::BulkString(Bytes::from(context.sentinel.myid())),
+            Operation::SentinelMasters => context.sentinel.masters(),
+            Operation::SentinelMaster(name) => reply(context.sentinel.master(&name)),
+            Operation::SentinelReplicas(name) => reply(context.sentinel.replicas_info(&name)),
+            Operation::SentinelSentinels(name) => reply(context.sentinel.sentinels_info(&name)),
+            Operation::SentinelGetMasterAddr(name) => match context.sentinel.master_addr(&name) {
+                Some((host, port)) => Value::Array(vec![
+                    Value::BulkString(Bytes::from(host)),
+                    Value::BulkString(Bytes::from(port.to_string())),
+                ]),
+                None => Value::NullArray,
+            },
+            Operation::SentinelMonitor(name, host, port, quorum) => {
+                done(context.sentinel.monitor(name, host, port, quorum))
+            }
+            Operation::SentinelSet(name, options) => done(context.sentinel.set(&name, &options)),
+            Operation::SentinelIsMasterDownByAddr(host, port, epoch, candidate) => {
+                let down = context.sentinel.is_down(&host, port);
+                let leader = match &candidate[..] {
+                    "*" => None,
+                    candidate => context.sentinel.vote(&host, port, epoch, candidate),
+                };
+                let (leader, epoch) = leader.unwrap_or((String::from("*"), 0));
+                Value::Array(vec![
+                    Value::Integer(down as i64),
+                    Value::BulkString(Bytes::from(leader)),
+                    Value::Integer(epoch as i64),
+                ])
+            }
+            _ => unreachable!("not a sentinel command"),
+        }
+    }
+
+    /// Keeps checking on the monitored primaries, their replicas and the
+    /// other sentinels, and fails a primary over once it is down.
+    pub(super) fn spawn_sentinel_task(&self) {
+        if !self.sentinel.is_enabled() {
+            return;
+        }
+        let context = self.context();
+        tokio::task::spawn(async move {
+            let mut links = Links::new();
+            let mut hellos = Links::new();
+            let mut period = time::interval(SENTINEL_PERIOD);
+            loop {
+                period.tick().await;
+                Self::read_hellos(&context, &mut hellos).await;
+                for name in context.sentinel.names() {
+                    Self::check_master(&context, &mut links, &name).await;
+                }
+            }
+        });
+    }
+
+    async fn check_master(context: &Context<P, D, S>, links: &mut Links, name: &str) {
+        let Some(master) = context.sentinel.master_addr(name) else {
+            return;
+        };
+        match Self::ask(context, links, &master, &["ROLE"])
+            .await
+            .as_ref()
+            .and_then(role)
+        {
+            Some(Role::Primary(replicas)) => context.sentinel.master_replied(name, replicas),
+            Some(Role::Replica(_)) => context.sentinel.master_replied(name, vec![]),
+            None => {}
+        }
+        let replicas = context.sentinel.replicas(name);
+        for replica in &replicas {
+            match Self::ask(context, links, replica, &["ROLE"])
+                .await
+                .as_ref()
+                .and_then(role)
+            {
+                Some(Role::Replica(offset)) => context
+                    .sentinel
+                    .replica_replied(name, &replica.0, replica.1, offset),
+                // A replica that was promoted behind the sentinels' back,
+                // or a primary back from the dead, follows the primary.
+                Some(Role::Primary(_))
+                    if context.sentinel.claims_primary(name, &replica.0, replica.1) =>
+                {
+                    let port = master.1.to_string();
+                    let replicaof = ["REPLICAOF", &master.0, &port];
+                    if Self::ask(context, links, replica, &replicaof)
+                        .await
+                        .is_some()
+                    {
+                        let (host, port) = replica;
+                        let message = format!("slave {host}:{port} {host} {port} @ {name}");
+                        Self::event(context, "+convert-to-slave", message);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(hello) = context.sentinel.hello(name) {
+            let publish = ["PUBLISH", HELLO_CHANNEL, &hello];
+            for instance in std::iter::once(&master).chain(&replicas) {
+                Self::ask(context, links, instance, &publish).await;
+            }
+        }
+
+        let (host, port) = (&master.0, master.1);
+        let instance = format!("master {name} {host} {port}");
+        let (sdown, event) = context.sentinel.check_sdown(name);
+        if let Some(Event::SDown(down)) = event {
+            Self::event(
+                context,
+                if down { "+sdown" } else { "-sdown" },
+                instance.clone(),
+            );
+        }
+        if !sdown {
+            return;
+        }
+        let epoch = context.sentinel.current_epoch();
+        Self::ask_sentinels(context, links, name, &master, epoch, "*").await;
+        let (odown, event) = context.sentinel.check_odown(name);
+        if let Some(Event::ODown(down)) = event {
+            Self::event(
+                context,
+                if down { "+odown" } else { "-odown" },
+                instance.clone(),
+            );
+        }
+        if !odown {
+            return;
+        }
+        let Some(epoch) = context.sentinel.start_election(name) else {
+            return;
+        };
+        Self::event(context, "+try-failover", instance.clone());
+        let myid = context.sentinel.myid();
+        Self::ask_sentinels(context, links, name, &master, epoch, &myid).await;
+        if context.sentinel.elected(name, epoch) {
+            Self::event(context, "+elected-leader", instance);
+            Self::failover(context, links, name, epoch).await;
+        }
+    }
+
+    /// Asks the other sentinels whether they see the primary down too, and
+    /// for their vote in `epoch` unless `candidate` is `*`.
+    async fn ask_sentinels(
+        context: &Context<P, D, S>,
+        links: &mut Links,
+        name: &str,
+        master: &Address,
+        epoch: u64,
+        candidate: &str,
+    ) {
+        let port = master.1.to_string();
+        let epoch = epoch.to_string();
+        let args = [
+            "SENTINEL",
+            "is-master-down-by-addr",
+            &master.0,
+            &port,
+            &epoch,
+            candidate,
+        ];
+        for peer in context.sentinel.sentinels(name) {
+            let reply = Self::ask(context, links, &peer, &args).await;
+            if let Some((down, vote)) = reply.as_ref().and_then(down_reply) {
+                context
+                    .sentinel
+                    .peer_replied(name, &peer.0, peer.1, down, vote);
+            }
+        }
+    }
+
+    /// Promotes the best replica, has the other replicas follow it, and
+    /// makes it the primary as of `epoch`, which the other sentinels learn
+    /// from the hello messages.
+    async fn failover(context: &Context<P, D, S>, links: &mut Links, name: &str, epoch: u64) {
+        let Some(promoted) = context.sentinel.best_replica(name) else {
+            Self::event(
+                context,
+                "-failover-abort-no-good-slave",
+                format!("master {name}"),
+            );
+            return;
+        };
+        let promote = ["REPLICAOF", "NO", "ONE"];
+        if Self::ask(context, links, &promoted, &promote)
+            .await
+            .is_none()
+        {
+            return;
+        }
+        let port = promoted.1.to_string();
+        for replica in context.sentinel.replicas(name) {
+            if replica != promoted {
+                Self::ask(context, links, &replica, &["REPLICAOF", &promoted.0, &port]).await;
+            }
+        }
+        let (host, port) = promoted.clone();
+        if let Some((old_host, old_port)) = context.sentinel.switch_master(name, host, port, epoch)
+        {
+            let (host, port) = promoted;
+            let message = format!("{name} {old_host} {old_port} {host} {port}");
+            Self::event(context, "+switch-master", message);
+        }
+    }
+
+    /// Keeps a subscription to the hello messages on every monitored
+    /// instance, and takes in those that arrived since the last time.
+    async fn read_hellos(context: &Context<P, D, S>, hellos: &mut Links) {
+        let instances = context.sentinel.instances();
+        hellos.retain(|address, _| instances.contains(address));
+        for address in instances {
+            if let Entry::Vacant(entry) = hellos.entry(address.clone()) {
+                let subscribe = async {
+                    let mut stream = TcpStream::connect((address.0.as_str(), address.1)).await?;
+                    let command = encode_command(&["SUBSCRIBE", HELLO_CHANNEL]);
+                    stream.write_all(&command).await?;
+                    io::Result::Ok(stream)
+                };
+                match time::timeout(SENTINEL_TIMEOUT, subscribe).await {
+                    Ok(Ok(stream)) => entry.insert((stream, FrameDecoder::new())),
+                    _ => continue,
+                };
+            }
+            let (stream, decoder) = hellos.get_mut(&address).unwrap();
+            // Only what already arrived, without waiting for more.
+            let mut open = loop {
+                match stream.try_read_buf(decoder.buffer_mut()) {
+                    Ok(0) => break false,
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break true,
+                    Err(_) => break false,
+                }
+            };
+            loop {
+                match decoder.decode(context.parser.as_ref()) {
+                    Ok(Some(message)) => {
+                        let Some(hello) = hello_message(&message) else {
+                            continue;
+                        };
+                        if let Some((name, (old_host, old_port), (host, port))) =
+                            context.sentinel.receive_hello(&hello)
+                        {
+                            let message = format!("{name} {old_host} {old_port} {host} {port}");
+                            Self::event(context, "+switch-master", message);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(_) => {
+                        open = false;
+                        break;
+                    }
+                }
+            }
+            if !open {
+                hellos.remove(&address);
+            }
+        }
+    }
+
+    /// Sends `args` to the instance or sentinel at `address`, connecting
+    /// first if need be. A link that fails or takes too long is dropped,
+    /// to connect afresh the next time.
+    async fn ask(
+        context: &Context<P, D, S>,
+        links: &mut Links,
+        address: &Address,
+        args: &[&str],
+    ) -> Option<Value> {
+        let asked = time::timeout(SENTINEL_TIMEOUT, async {
+            let (stream, decoder) = match links.entry(address.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let stream = TcpStream::connect((address.0.as_str(), address.1)).await?;
+                    entry.insert((stream, FrameDecoder::new()))
+                }
+            };
+            request(stream, decoder, context.parser.as_ref(), args).await
+        })
+        .await;
+        match asked {
+            Ok(Ok(reply)) => Some(reply),
+            _ => {
+                links.remove(address);
+                None
+            }
+        }
+    }
+
+    /// Tells clients subscribed to `channel` of a change, as Redis
+    /// sentinels do.
+    fn event(context: &Context<P, D, S>, channel: &'static str, message: String) {
+        let channel = Bytes::from_static(channel.as_bytes());
+        context.pubsub.publish(&channel, &Bytes::from(message));
+    }
+}
+
+/// Whether `op` is served in sentinel mode, where there is no data.
+pub(super) fn allowed_in_sentinel_mode(op: &Operation) -> bool {
+    matches!(
+        op,
+        Operation::Ping
+            | Operation::Hello(_)
+            | Operation::Role
+            | Operation::Subscribe(_)
+            | Operation::Unsubscribe(_)
+            | Operation::PSubscribe(_)
+            | Operation::PUnsubscribe(_)
+            | Operation::Publish(..)
+            | Operation::Invalid(_)
+            | Operation::SentinelMyId
+            | Operation::SentinelMasters
+            | Operation::SentinelMaster(_)
+            | Operation::SentinelReplicas(_)
+            | Operation::SentinelSentinels(_)
+            | Operation::SentinelGetMasterAddr(_)
+            | Operation::SentinelMonitor(..)
+            | Operation::SentinelSet(..)
+            | Operation::SentinelIsMasterDownByAddr(..)
+    )
+}
+
+fn role(reply: &Value) -> Option<Role> {
+    let Value::Array(fields) = reply else {
+        return None;
+    };
+    let text = |value: &Value| match value {
+        Value::BulkString(text) => std::str::from_utf8(text).ok().map(str::to_string),
+        _ => None,
+    };
+    match &fields[..] {
+        [role, _, Value::Array(replicas)] if text(role)? == "master" => {
+            let replicas = replicas.iter().filter_map(|replica| match replica {
+                Value::Array(fields) => match &fields[..] {
+                    [host, port, _] => Some((text(host)?, text(port)?.parse().ok()?)),
+                    _ => None,
+                },
+                _ => None,
+            });
+            Some(Role::Primary(replicas.collect()))
+        }
+        [role, _, _, _, Value::Integer(offset)] if text(role)? == "slave" => {
+            Some(Role::Replica(*offset as u64))
+        }
+        _ => None,
+    }
+}
+
+/// Whether another sentinel said the primary is down, and who it voted for.
+fn down_reply(reply: &Value) -> Option<(bool, Option<(String, u64)>)> {
+    match reply {
+        Value::Array(fields) => match &fields[..] {
+            [Value::Integer(down), Value::BulkString(leader), Value::Integer(epoch)] => {
+                let vote = match &leader[..] {
+                    b"*" => None,
+                    leader => Some((String::from_utf8_lossy(leader).into_owned(), *epoch as u64)),
+                };
+                Some((*down == 1, vote))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The payload of a message published on the hello channel.
+fn hello_message(message: &Value) -> Option<Bytes> {
+    match message {
+        Value::Array(fields) | Value::Push(fields) => match &fields[..] {
+            [Value::BulkString(kind), Value::BulkString(channel), Value::BulkString(hello)]
+                if &kind[..] == b"message" && &channel[..] == HELLO_CHANNEL.as_bytes() =>
+            {
+                Some(hello.clone())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net;
+    use tokio::task::{JoinHandle, JoinSet};
+
+    use super::super::tests::{bulk, call, start_server, start_server_with, wait_for_reply};
+    use super::*;
+
+    async fn start_sentinel() -> SocketAddr {
+        let server = Server::new("0");
+        server.enable_sentinel();
+        start_server_with(server).await
+    }
+
+    /// Forwards connections to `target` until aborted, which cuts them all,
+    /// as if the server behind it went away.
+    async fn start_proxy(target: SocketAddr) -> (SocketAddr, JoinHandle<()>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+            loop {
+                let Ok((mut client, _)) = listener.accept().await else {
+                    continue;
+                };
+                connections.spawn(async move {
+                    if let Ok(mut server) = TcpStream::connect(target).await {
+                        let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                    }
+                });
+            }
+        });
+        (addr, proxy)
+    }
+
+    fn length(reply: &Value) -> usize {
+        match reply {
+            Value::Array(items) => items.len(),
+            _ => 0,
+        }
+    }
+
+    fn address(port: u16) -> Value {
+        Value::Array(vec![
+            Value::BulkString(Bytes::from("127.0.0.1")),
+            Value::BulkString(Bytes::from(port.to_string())),
+        ])
+    }
+
+    #[tokio::test]
+    async fn hello_reports_sentinel_mode() {
+        let mut sentinel = TcpStream::connect(start_sentinel().await).await.unwrap();
+        match call(&mut sentinel, &["HELLO"]).await {
+            Value::Array(fields) => assert!(fields.contains(&bulk("sentinel"))),
+            reply => panic!("unexpected HELLO reply {reply:?}"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn sentinels_promote_a_replica_when_the_primary_goes_away() {
+        let primary = start_server().await;
+        let (proxy, cut) = start_proxy(primary).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        call(&mut client, &["SET", "key", "value"]).await;
+
+        let mut replicas = vec![];
+        for _ in 0..2 {
+            let addr = start_server().await;
+            let mut replica = TcpStream::connect(addr).await.unwrap();
+            let port = proxy.port().to_string();
+            call(&mut replica, &["REPLICAOF", "127.0.0.1", &port]).await;
+            replicas.push((addr, replica));
+        }
+        for (_, replica) in &mut replicas {
+            let value = Value::BulkString(Bytes::from("value"));
+            wait_for_reply(replica, &["GET", "key"], |reply| *reply == value).await;
+        }
+
+        let mut sentinels = vec![];
+        for _ in 0..3 {
+            let mut sentinel = TcpStream::connect(start_sentinel().await).await.unwrap();
+            let port = proxy.port().to_string();
+            let monitor = ["SENTINEL", "MONITOR", "mymaster", "127.0.0.1", &port, "2"];
+            assert_eq!(
+                call(&mut sentinel, &monitor).await,
+                Value::SimpleString(String::from("OK"))
+            );
+            let set = [
+                "SENTINEL",
+                "SET",
+                "mymaster",
+                "down-after-milliseconds",
+                "300",
+                "failover-timeout",
+                "1000",
+            ];
+            assert_eq!(
+                call(&mut sentinel, &set).await,
+                Value::SimpleString(String::from("OK"))
+            );
+            sentinels.push(sentinel);
+        }
+        for sentinel in &mut sentinels {
+            let sentinels = ["SENTINEL", "SENTINELS", "mymaster"];
+            wait_for_reply(sentinel, &sentinels, |reply| length(reply) == 2).await;
+            let replicas = ["SENTINEL", "REPLICAS", "mymaster"];
+            wait_for_reply(sentinel, &replicas, |reply| length(reply) == 2).await;
+            assert_eq!(
+                call(
+                    sentinel,
+                    &["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "mymaster"]
+                )
+                .await,
+                address(proxy.port())
+            );
+        }
+        assert_eq!(
+            call(&mut sentinels[0], &["GET", "key"]).await,
+            Value::Error(String::from("ERR unknown command 'GET'"))
+        );
+
+        cut.abort();
+        let get_master = ["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "mymaster"];
+        let promoted = wait_for_reply(&mut sentinels[0], &get_master, |reply| {
+            *reply != address(proxy.port())
+        })
+        .await;
+        for sentinel in &mut sentinels[1..] {
+            wait_for_reply(sentinel, &get_master, |reply| *reply == promoted).await;
+        }
+        let (promoted, other) = match &promoted {
+            reply if *reply == address(replicas[0].0.port()) => (0, 1),
+            reply if *reply == address(replicas[1].0.port()) => (1, 0),
+            reply => panic!("unexpected primary {reply:?}"),
+        };
+
+        let port = replicas[promoted].0.port().to_string();
+        let mut primary = TcpStream::connect(replicas[promoted].0).await.unwrap();
+        wait_for_reply(&mut primary, &["ROLE"], |reply| match reply {
+            Value::Array(fields) => fields[0] == Value::BulkString(Bytes::from("master")),
+            _ => false,
+        })
+        .await;
+        assert_eq!(
+            call(&mut primary, &["SET", "key", "changed"]).await,
+            Value::SimpleString(String::from("OK"))
+        );
+        let changed = Value::BulkString(Bytes::from("changed"));
+        wait_for_reply(&mut replicas[other].1, &["GET", "key"], |reply| {
+            *reply == changed
+        })
+        .await;
+        match call(&mut replicas[other].1, &["ROLE"]).await {
+            Value::Array(fields) => {
+                assert_eq!(fields[1], Value::BulkString(Bytes::from("127.0.0.1")));
+                assert_eq!(fields[2], Value::Integer(port.parse().unwrap()));
+            }
+            reply => panic!("unexpected reply to ROLE {reply:?}"),
+        }
+    }
+}
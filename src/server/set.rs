@@ -0,0 +1,576 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+
+use bytes::Bytes;
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use super::{locked, remove_if_empty, wrong_type, Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::{OperationDeducer, SetOperator};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    pub(super) async fn handle_sadd(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        members: Vec<Bytes>,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| match set_or_insert(entry) {
+            Err(err) => err,
+            Ok(set) => {
+                let added = members
+                    .into_iter()
+                    .filter(|member| set.insert(member.clone()))
+                    .count();
+                Value::Integer(added as i64)
+            }
+        });
+        if matches!(reply, Value::Integer(added) if added > 0) {
+            Self::notify(context, KeyspaceEvents::SET, "sadd", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_srem(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        members: Vec<Bytes>,
+    ) -> Value {
+        let (reply, removed) = Self::update_key(context, key.clone(), |entry| {
+            let reply = match set_mut(entry) {
+                Err(err) => err,
+                Ok(None) => Value::Integer(0),
+                Ok(Some(set)) => {
+                    let removed = members.iter().filter(|member| set.remove(*member)).count();
+                    Value::Integer(removed as i64)
+                }
+            };
+            (reply, remove_if_empty(entry))
+        });
+        if matches!(reply, Value::Integer(removed) if removed > 0) {
+            Self::notify(context, KeyspaceEvents::SET, "srem", &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_smembers(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Set(vec![]),
+            Some(Data::Set(set)) => {
+                Value::Set(set.iter().cloned().map(Value::BulkString).collect())
+            }
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_sismember(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        member: Bytes,
+    ) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::Set(set)) => Value::Integer(set.contains(&member) as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_smismember(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        members: Vec<Bytes>,
+    ) -> Value {
+        let empty = HashSet::new();
+        Self::read_key(context, key, |data| {
+            let set = match data {
+                None => &empty,
+                Some(Data::Set(set)) => set,
+                Some(_) => return wrong_type(),
+            };
+            Value::Array(
+                members
+                    .iter()
+                    .map(|member| Value::Integer(set.contains(member) as i64))
+                    .collect(),
+            )
+        })
+    }
+
+    pub(super) async fn handle_scard(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::Set(set)) => Value::Integer(set.len() as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    /// `SINTER`, `SUNION` and `SDIFF`. All keys are read under one lock, so
+    /// the result reflects a single point in time.
+    pub(super) async fn handle_scombine(
+        context: &Context<P, D, S>,
+        keys: Vec<Bytes>,
+        operator: SetOperator,
+    ) -> Value {
+        Self::update_keys(context, keys.clone(), |entries| {
+            let sets = match keys
+                .iter()
+                .map(|key| set_ref(entries.get(key)))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(sets) => sets,
+                Err(err) => return err,
+            };
+            let result = combine(&sets, operator);
+            Value::Set(result.into_iter().map(Value::BulkString).collect())
+        })
+    }
+
+    /// `SINTERSTORE`, `SUNIONSTORE` and `SDIFFSTORE`, which replace
+    /// `destination` with the result, whatever it held before.
+    pub(super) async fn handle_scombinestore(
+        context: &Context<P, D, S>,
+        destination: Bytes,
+        keys: Vec<Bytes>,
+        operator: SetOperator,
+    ) -> Value {
+        let mut all_keys = keys.clone();
+        all_keys.push(destination.clone());
+        let stored = Self::update_keys(context, all_keys, |entries| {
+            let sets = keys
+                .iter()
+                .map(|key| set_ref(entries.get(key)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let result = combine(&sets, operator);
+            let len = result.len();
+            let entry = locked(entries, &destination)?;
+            let existed = entry.is_some();
+            *entry = (!result.is_empty()).then(|| DataFrame::Plain(Data::Set(result)));
+            Ok((len, existed))
+        });
+        match stored {
+            Err(err) => err,
+            Ok((len, existed)) => {
+                if len > 0 {
+                    let event = match operator {
+                        SetOperator::Inter => "sinterstore",
+                        SetOperator::Union => "sunionstore",
+                        SetOperator::Diff => "sdiffstore",
+                    };
+                    Self::notify(context, KeyspaceEvents::SET, event, &destination);
+                } else if existed {
+                    Self::notify(context, KeyspaceEvents::GENERIC, "del", &destination);
+                }
+                Value::Integer(len as i64)
+            }
+        }
+    }
+
+    pub(super) async fn handle_smove(
+        context: &Context<P, D, S>,
+        source: Bytes,
+        destination: Bytes,
+        member: Bytes,
+    ) -> Value {
+        let keys = vec![source.clone(), destination.clone()];
+        let moved = Self::update_keys(context, keys, |entries| {
+            let found =
+                set_mut(locked(entries, &source)?)?.is_some_and(|set| set.contains(&member));
+            set_mut(locked(entries, &destination)?)?;
+            if !found {
+                return Ok(None);
+            }
+            if let Some(set) = set_mut(locked(entries, &source)?)? {
+                set.remove(&member);
+            }
+            let removed = remove_if_empty(locked(entries, &source)?);
+            set_or_insert(locked(entries, &destination)?)?.insert(member);
+            Ok(Some(removed))
+        });
+        match moved {
+            Err(err) => err,
+            Ok(None) => Value::Integer(0),
+            // Moving within one set changes nothing, so nothing is published.
+            Ok(Some(_)) if source == destination => Value::Integer(1),
+            Ok(Some(removed)) => {
+                Self::notify(context, KeyspaceEvents::SET, "srem", &source);
+                if removed {
+                    Self::notify(context, KeyspaceEvents::GENERIC, "del", &source);
+                }
+                Self::notify(context, KeyspaceEvents::SET, "sadd", &destination);
+                Value::Integer(1)
+            }
+        }
+    }
+
+    pub(super) async fn handle_srandmember(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        count: Option<i64>,
+    ) -> Value {
+        // Members may repeat for a negative count, so that reply can be far
+        // larger than the set; it is built once the key is no longer locked.
+        let sample = Self::read_key(context, key, |data| {
+            let set = match data {
+                None if count.is_none() => return Sample::Reply(Value::NullBulkString),
+                None => return Sample::Reply(Value::Array(vec![])),
+                Some(Data::Set(set)) => set,
+                Some(_) => return Sample::Reply(wrong_type()),
+            };
+            let mut rng = rand::thread_rng();
+            match count {
+                None => Sample::Reply(match set.iter().choose(&mut rng) {
+                    Some(member) => Value::BulkString(member.clone()),
+                    None => Value::NullBulkString,
+                }),
+                Some(count) if count >= 0 => Sample::Reply(Value::Array(
+                    set.iter()
+                        .choose_multiple(&mut rng, (count as usize).min(set.len()))
+                        .into_iter()
+                        .cloned()
+                        .map(Value::BulkString)
+                        .collect(),
+                )),
+                Some(_) => Sample::Repeated(set.iter().cloned().collect()),
+            }
+        });
+        let members = match sample {
+            Sample::Reply(reply) => return reply,
+            Sample::Repeated(members) => members,
+        };
+        let mut rng = rand::thread_rng();
+        let picks = count.map_or(0, i64::unsigned_abs);
+        Value::Array(
+            (0..picks)
+                .map_while(|_| members.choose(&mut rng).cloned())
+                .map(Value::BulkString)
+                .collect(),
+        )
+    }
+
+    pub(super) async fn handle_spop(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        count: Option<usize>,
+    ) -> Value {
+        let (reply, popped, removed) = Self::update_key(context, key.clone(), |entry| {
+            let set = match set_mut(entry) {
+                Err(err) => return (err, false, false),
+                Ok(None) if count.is_none() => return (Value::NullBulkString, false, false),
+                Ok(None) => return (Value::Set(vec![]), false, false),
+                Ok(Some(set)) => set,
+            };
+            let popped: Vec<Bytes> = set
+                .iter()
+                .choose_multiple(&mut rand::thread_rng(), count.unwrap_or(1).min(set.len()))
+                .into_iter()
+                .cloned()
+                .collect();
+            for member in &popped {
+                set.remove(member);
+            }
+            let removed = remove_if_empty(entry);
+            let any = !popped.is_empty();
+            let reply = match count {
+                None => popped
+                    .into_iter()
+                    .next()
+                    .map_or(Value::NullBulkString, Value::BulkString),
+                Some(_) => Value::Set(popped.into_iter().map(Value::BulkString).collect()),
+            };
+            (reply, any, removed)
+        });
+        if popped {
+            Self::notify(context, KeyspaceEvents::SET, "spop", &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+}
+
+/// What `SRANDMEMBER` takes from the set while it holds the key.
+enum Sample {
+    /// The complete reply.
+    Reply(Value),
+    /// The members to pick repeatedly from for a negative count.
+    Repeated(Vec<Bytes>),
+}
+
+/// The set stored in `entry`, or the WRONGTYPE error if it holds another type.
+fn set_mut(entry: &mut Option<DataFrame<Data>>) -> Result<Option<&mut HashSet<Bytes>>, Value> {
+    match entry.as_mut().and_then(|df| df.data_mut()) {
+        None => Ok(None),
+        Some(Data::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Like [`set_mut`], but stores an empty set first if the key is absent.
+fn set_or_insert(entry: &mut Option<DataFrame<Data>>) -> Result<&mut HashSet<Bytes>, Value> {
+    if entry.is_none() {
+        *entry = Some(DataFrame::Plain(Data::Set(HashSet::new())));
+    }
+    set_mut(entry).map(|set| set.unwrap())
+}
+
+fn set_ref(df: Option<&DataFrame<Data>>) -> Result<Option<&HashSet<Bytes>>, Value> {
+    match df.and_then(|df| df.data()) {
+        None => Ok(None),
+        Some(Data::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Combines `sets` with `operator`, treating missing keys as empty sets.
+fn combine(sets: &[Option<&HashSet<Bytes>>], operator: SetOperator) -> HashSet<Bytes> {
+    let empty = HashSet::new();
+    let mut sets = sets.iter().map(|set| set.unwrap_or(&empty));
+    let mut result = sets.next().cloned().unwrap_or_default();
+    for set in sets {
+        match operator {
+            SetOperator::Inter => result.retain(|member| set.contains(member)),
+            SetOperator::Union => result.extend(set.iter().cloned()),
+            SetOperator::Diff => result.retain(|member| !set.contains(member)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{call, connect, start_server};
+    use super::*;
+    use tokio::net::TcpStream;
+
+    fn members(value: Value) -> Vec<String> {
+        let values = match value {
+            Value::Array(values) | Value::Set(values) => values,
+            value => panic!("not a collection: {value:?}"),
+        };
+        let mut members: Vec<String> = values
+            .into_iter()
+            .map(|value| match value {
+                Value::BulkString(member) => String::from_utf8(member.to_vec()).unwrap(),
+                value => panic!("not a member: {value:?}"),
+            })
+            .collect();
+        members.sort();
+        members
+    }
+
+    #[tokio::test]
+    async fn add_remove_and_membership() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["SADD", "s", "a", "b", "a"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["SADD", "s", "b", "c"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["SCARD", "s"]).await, Value::Integer(3));
+        assert_eq!(
+            call(&mut stream, &["SISMEMBER", "s", "a"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["SMISMEMBER", "s", "a", "x"]).await,
+            Value::Array(vec![Value::Integer(1), Value::Integer(0)])
+        );
+        assert_eq!(
+            members(call(&mut stream, &["SMEMBERS", "s"]).await),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            call(&mut stream, &["SREM", "s", "a", "b", "c", "x"]).await,
+            Value::Integer(3)
+        );
+        assert_eq!(call(&mut stream, &["SCARD", "s"]).await, Value::Integer(0));
+        assert!(members(call(&mut stream, &["SMEMBERS", "s"]).await).is_empty());
+        call(&mut stream, &["RPUSH", "l", "a"]).await;
+        assert_eq!(call(&mut stream, &["SADD", "l", "a"]).await, wrong_type());
+    }
+
+    #[tokio::test]
+    async fn combine_sets() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SADD", "s1", "a", "b", "c", "d"]).await;
+        call(&mut stream, &["SADD", "s2", "c", "d", "e"]).await;
+        call(&mut stream, &["SADD", "s3", "d", "f"]).await;
+        assert_eq!(
+            members(call(&mut stream, &["SINTER", "s1", "s2", "s3"]).await),
+            ["d"]
+        );
+        assert_eq!(
+            members(call(&mut stream, &["SUNION", "s1", "s2", "s3"]).await),
+            ["a", "b", "c", "d", "e", "f"]
+        );
+        assert_eq!(
+            members(call(&mut stream, &["SDIFF", "s1", "s2", "s3"]).await),
+            ["a", "b"]
+        );
+        assert!(members(call(&mut stream, &["SINTER", "s1", "missing"]).await).is_empty());
+        assert_eq!(
+            call(&mut stream, &["SDIFFSTORE", "out", "s1", "s2"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            members(call(&mut stream, &["SMEMBERS", "out"]).await),
+            ["a", "b"]
+        );
+        assert_eq!(
+            call(&mut stream, &["SUNIONSTORE", "s1", "s1", "s3"]).await,
+            Value::Integer(5)
+        );
+        assert_eq!(
+            call(&mut stream, &["SINTERSTORE", "out", "s1", "missing"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["SCARD", "out"]).await,
+            Value::Integer(0)
+        );
+        call(&mut stream, &["RPUSH", "l", "a"]).await;
+        assert_eq!(
+            call(&mut stream, &["SUNION", "s1", "l"]).await,
+            wrong_type()
+        );
+    }
+
+    #[tokio::test]
+    async fn random_members_and_pop() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SADD", "s", "a", "b", "c"]).await;
+        assert_eq!(
+            members(call(&mut stream, &["SRANDMEMBER", "s", "5"]).await),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            members(call(&mut stream, &["SRANDMEMBER", "s", "-5"]).await).len(),
+            5
+        );
+        assert_eq!(
+            call(&mut stream, &["SRANDMEMBER", "missing"]).await,
+            Value::NullBulkString
+        );
+        let popped = members(call(&mut stream, &["SPOP", "s", "2"]).await);
+        assert_eq!(popped.len(), 2);
+        let last = call(&mut stream, &["SPOP", "s"]).await;
+        let mut all = popped;
+        all.extend(members(Value::Array(vec![last])));
+        all.sort();
+        assert_eq!(all, ["a", "b", "c"]);
+        assert_eq!(
+            call(&mut stream, &["SPOP", "s"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["SPOP", "s", "-1"]).await,
+            Value::Error(String::from("ERR value is out of range, must be positive"))
+        );
+    }
+
+    #[tokio::test]
+    async fn extreme_random_member_counts_are_out_of_range() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SADD", "s", "a"]).await;
+        for count in ["-9223372036854775808", "-4611686018427387904", "-1048577"] {
+            assert_eq!(
+                call(&mut stream, &["SRANDMEMBER", "s", count]).await,
+                Value::Error(String::from("ERR value is out of range"))
+            );
+        }
+        assert_eq!(
+            members(call(&mut stream, &["SRANDMEMBER", "s", "-2"]).await),
+            ["a", "a"]
+        );
+        assert_eq!(
+            members(call(&mut stream, &["SRANDMEMBER", "s", "4611686018427387903"]).await),
+            ["a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn spop_with_a_huge_count_empties_the_set() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SADD", "s", "a", "b"]).await;
+        assert_eq!(
+            members(call(&mut stream, &["SPOP", "s", "9223372036854775807"]).await),
+            ["a", "b"]
+        );
+        assert_eq!(call(&mut stream, &["SCARD", "s"]).await, Value::Integer(0));
+        call(&mut stream, &["SADD", "s", "c"]).await;
+        assert_eq!(
+            members(call(&mut stream, &["SMEMBERS", "s"]).await),
+            ["c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn smove_between_sets() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SADD", "src", "a", "b"]).await;
+        assert_eq!(
+            call(&mut stream, &["SMOVE", "src", "dst", "a"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["SMOVE", "src", "dst", "x"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            members(call(&mut stream, &["SMEMBERS", "dst"]).await),
+            ["a"]
+        );
+        assert_eq!(
+            call(&mut stream, &["SMOVE", "src", "dst", "b"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["SCARD", "src"]).await,
+            Value::Integer(0)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn combine_sees_consistent_view_during_moves() {
+        let addr = start_server().await;
+        let mut mover = TcpStream::connect(addr).await.unwrap();
+        let mut reader = TcpStream::connect(addr).await.unwrap();
+        let members: Vec<String> = (0..50).map(|i| i.to_string()).collect();
+        let mut sadd = vec!["SADD", "left"];
+        sadd.extend(members.iter().map(String::as_str));
+        call(&mut mover, &sadd).await;
+        let moves = tokio::spawn(async move {
+            for round in 0..4 {
+                let (from, to) = if round % 2 == 0 {
+                    ("left", "right")
+                } else {
+                    ("right", "left")
+                };
+                for member in &members {
+                    call(&mut mover, &["SMOVE", from, to, member]).await;
+                }
+            }
+        });
+        while !moves.is_finished() {
+            let union = call(&mut reader, &["SUNION", "left", "right"]).await;
+            assert_eq!(super::tests::members(union).len(), 50);
+        }
+        moves.await.unwrap();
+    }
+}
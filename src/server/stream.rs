@@ -0,0 +1,522 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{wrong_type, Context, Server};
+use crate::blocking::BlockedOperation;
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::operation::{OperationDeducer, ReadFrom, XAddOptions, XReadOptions};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::stream::{Entry, NewId, Stream, StreamId, Trim};
+use crate::transaction::SharedGate;
+use crate::value::{Protocol, Value};
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Appends an entry, creating the stream unless NOMKSTREAM is given, and
+    /// trims the stream afterwards. Replies with the ID of the new entry.
+    pub(super) async fn handle_xadd(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        options: Box<XAddOptions>,
+        id: NewId,
+        pairs: Vec<(Bytes, Bytes)>,
+    ) -> Value {
+        let added = Self::update_key(context, key.clone(), |entry| {
+            let now = unix_time_millis() as u64;
+            let id = match stream_mut(entry)? {
+                Some(stream) => stream.new_id(id, now),
+                None if options.nomkstream => return Ok(None),
+                None => Stream::new().new_id(id, now),
+            };
+            let id = id.map_err(|err| Value::Error(String::from(err)))?;
+            let stream = stream_or_insert(entry)?;
+            stream.add(id, &pairs);
+            let trimmed = options.trim.map_or(0, |trim| stream.trim(&trim));
+            Ok(Some((id, trimmed)))
+        });
+        match added {
+            Err(err) => err,
+            Ok(None) => Value::NullBulkString,
+            Ok(Some((id, trimmed))) => {
+                Self::notify(context, KeyspaceEvents::STREAM, "xadd", &key);
+                if trimmed > 0 {
+                    Self::notify(context, KeyspaceEvents::STREAM, "xtrim", &key);
+                }
+                id_reply(id)
+            }
+        }
+    }
+
+    /// `XRANGE`, or `XREVRANGE` if `rev` is set.
+    pub(super) async fn handle_xrange(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Array(vec![]),
+            Some(Data::Stream(_)) if count == Some(0) => Value::NullArray,
+            Some(Data::Stream(stream)) if rev => entries_reply(stream.rev_range(start, end, count)),
+            Some(Data::Stream(stream)) => entries_reply(stream.range(start, end, count)),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_xlen(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::Stream(stream)) => Value::Integer(stream.len() as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_xdel(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        ids: Vec<StreamId>,
+    ) -> Value {
+        let deleted = Self::update_key(context, key.clone(), |entry| match stream_mut(entry)? {
+            None => Ok(0),
+            Some(stream) => Ok(ids.into_iter().filter(|id| stream.remove(*id)).count()),
+        });
+        match deleted {
+            Err(err) => err,
+            Ok(deleted) => {
+                if deleted > 0 {
+                    Self::notify(context, KeyspaceEvents::STREAM, "xdel", &key);
+                }
+                Value::Integer(deleted as i64)
+            }
+        }
+    }
+
+    pub(super) async fn handle_xtrim(context: &Context<P, D, S>, key: Bytes, trim: Trim) -> Value {
+        let trimmed = Self::update_key(context, key.clone(), |entry| match stream_mut(entry)? {
+            None => Ok(0),
+            Some(stream) => Ok(stream.trim(&trim)),
+        });
+        match trimmed {
+            Err(err) => err,
+            Ok(trimmed) => {
+                if trimmed > 0 {
+                    Self::notify(context, KeyspaceEvents::STREAM, "xtrim", &key);
+                }
+                Value::Integer(trimmed as i64)
+            }
+        }
+    }
+
+    /// Replies with the entries after the given IDs of each stream that has
+    /// any. With BLOCK, waits for the first stream to get some if none has.
+    pub(super) async fn handle_xread<'a>(
+        context: &'a Context<P, D, S>,
+        reads: Vec<(Bytes, ReadFrom)>,
+        options: XReadOptions,
+        protocol: Protocol,
+        gate: &mut Option<SharedGate<'a>>,
+    ) -> Value {
+        let mut after = Vec::with_capacity(reads.len());
+        let mut streams = vec![];
+        for (key, from) in reads {
+            let read = Self::read_key(context, key.clone(), |data| {
+                let stream = match data {
+                    None => None,
+                    Some(Data::Stream(stream)) => Some(stream),
+                    Some(_) => return Err(wrong_type()),
+                };
+                // `$` stands for the last ID at the time XREAD runs.
+                let id = match (from, stream) {
+                    (ReadFrom::After(id), _) => id,
+                    (ReadFrom::New, Some(stream)) => stream.last_id(),
+                    (ReadFrom::New, None) => StreamId::MIN,
+                };
+                let entries =
+                    stream.map_or(vec![], |stream| entries_after(stream, id, options.count));
+                Ok((id, entries))
+            });
+            let (id, entries) = match read {
+                Err(err) => return err,
+                Ok(read) => read,
+            };
+            if !entries.is_empty() {
+                streams.push((key.clone(), entries));
+            }
+            after.push((key, id));
+        }
+        if !streams.is_empty() {
+            let streams = streams
+                .into_iter()
+                .map(|(key, entries)| (key, entries_reply(entries)))
+                .collect();
+            return streams_reply(streams, protocol);
+        }
+        if !options.block {
+            return Value::NullArray;
+        }
+        let keys = after.iter().map(|(key, _)| key.clone()).collect();
+        let operation = BlockedOperation::Read {
+            after,
+            count: options.count,
+            protocol,
+        };
+        Self::block_on(
+            context,
+            keys,
+            operation,
+            options.timeout,
+            Value::NullArray,
+            gate,
+        )
+        .await
+    }
+
+    /// The reply for a client blocked in `XREAD` if the stream at `key` has
+    /// entries after `after`, or `None` if it has to keep waiting.
+    pub(super) fn serve_reader(
+        context: &Context<P, D, S>,
+        key: &Bytes,
+        after: StreamId,
+        count: Option<usize>,
+        protocol: Protocol,
+    ) -> Option<Value> {
+        let entries = Self::read_key(context, key.clone(), |data| match data {
+            Some(Data::Stream(stream)) => Ok(entries_after(stream, after, count)),
+            None => Ok(vec![]),
+            Some(_) => Err(wrong_type()),
+        });
+        match entries {
+            Err(err) => Some(err),
+            Ok(entries) if entries.is_empty() => None,
+            Ok(entries) => Some(streams_reply(
+                vec![(key.clone(), entries_reply(entries))],
+                protocol,
+            )),
+        }
+    }
+}
+
+/// The stream stored in `entry`, or the WRONGTYPE error if it holds another type.
+pub(super) fn stream_mut(
+    entry: &mut Option<DataFrame<Data>>,
+) -> Result<Option<&mut Stream>, Value> {
+    match entry.as_mut().and_then(|df| df.data_mut()) {
+        None => Ok(None),
+        Some(Data::Stream(stream)) => Ok(Some(stream)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Like [`stream_mut`], but stores an empty stream first if the key is absent.
+pub(super) fn stream_or_insert(entry: &mut Option<DataFrame<Data>>) -> Result<&mut Stream, Value> {
+    if entry.is_none() {
+        *entry = Some(DataFrame::Plain(Data::Stream(Stream::new())));
+    }
+    stream_mut(entry).map(|stream| stream.unwrap())
+}
+
+fn entries_after(stream: &Stream, after: StreamId, count: Option<usize>) -> Vec<Entry> {
+    match after.next() {
+        Some(start) => stream.range(start, StreamId::MAX, count),
+        None => vec![],
+    }
+}
+
+/// Replies with an entry as a pair of its ID and its fields and values.
+pub(super) fn entry_reply(entry: Entry) -> Value {
+    Value::Array(vec![
+        id_reply(entry.id),
+        Value::Array(entry.fields.into_iter().map(Value::BulkString).collect()),
+    ])
+}
+
+pub(super) fn entries_reply(entries: Vec<Entry>) -> Value {
+    Value::Array(entries.into_iter().map(entry_reply).collect())
+}
+
+pub(super) fn id_reply(id: StreamId) -> Value {
+    Value::BulkString(Bytes::from(id.to_string()))
+}
+
+/// Replies to `XREAD` and `XREADGROUP` with the entries read from each
+/// stream: a map by key in RESP3, and an array of key and entries pairs in RESP2.
+pub(super) fn streams_reply(streams: Vec<(Bytes, Value)>, protocol: Protocol) -> Value {
+    let streams = streams
+        .into_iter()
+        .map(|(key, entries)| (Value::BulkString(key), entries));
+    match protocol {
+        Protocol::Resp2 => Value::Array(
+            streams
+                .map(|(key, entries)| Value::Array(vec![key, entries]))
+                .collect(),
+        ),
+        Protocol::Resp3 => Value::Map(streams.collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpStream;
+    use tokio::time::sleep;
+
+    use super::super::tests::{bulk, call, connect, error, start_server};
+    use super::*;
+
+    fn entry(id: &str, fields: &[&str]) -> Value {
+        Value::Array(vec![
+            bulk(id),
+            Value::Array(fields.iter().map(|field| bulk(field)).collect()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn add_and_read_ranges() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["XADD", "s", "1-1", "a", "1"]).await,
+            bulk("1-1")
+        );
+        assert_eq!(
+            call(&mut stream, &["XADD", "s", "1-*", "b", "2"]).await,
+            bulk("1-2")
+        );
+        assert_eq!(
+            call(&mut stream, &["XADD", "s", "3", "c", "3", "d", "4"]).await,
+            bulk("3-0")
+        );
+        assert_eq!(
+            call(&mut stream, &["XADD", "s", "2-5", "e", "5"]).await,
+            error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+        assert_eq!(
+            call(&mut stream, &["XADD", "fresh", "0-0", "e", "5"]).await,
+            error("ERR The ID specified in XADD must be greater than 0-0")
+        );
+        assert_eq!(
+            call(&mut stream, &["XADD", "s", "1-x", "e", "5"]).await,
+            error("ERR Invalid stream ID specified as stream command argument")
+        );
+        assert_eq!(
+            call(&mut stream, &["XADD", "s", "*", "e"]).await,
+            error("ERR wrong number of arguments for 'xadd' command")
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XADD", "missing", "NOMKSTREAM", "*", "e", "5"]
+            )
+            .await,
+            Value::NullBulkString
+        );
+        let auto = match call(&mut stream, &["XADD", "s", "*", "e", "5"]).await {
+            Value::BulkString(id) => String::from_utf8(id.to_vec()).unwrap(),
+            reply => panic!("no ID: {reply:?}"),
+        };
+        let ms: i64 = auto.strip_suffix("-0").unwrap().parse().unwrap();
+        assert!((unix_time_millis() - ms).abs() < 1000);
+        assert_eq!(call(&mut stream, &["XLEN", "s"]).await, Value::Integer(4));
+        assert_eq!(
+            call(&mut stream, &["XLEN", "missing"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["TYPE", "s"]).await,
+            Value::SimpleString(String::from("stream"))
+        );
+
+        assert_eq!(
+            call(&mut stream, &["XRANGE", "s", "-", "3"]).await,
+            Value::Array(vec![
+                entry("1-1", &["a", "1"]),
+                entry("1-2", &["b", "2"]),
+                entry("3-0", &["c", "3", "d", "4"]),
+            ])
+        );
+        assert_eq!(
+            call(&mut stream, &["XRANGE", "s", "(1-1", "+", "COUNT", "1"]).await,
+            Value::Array(vec![entry("1-2", &["b", "2"])])
+        );
+        assert_eq!(
+            call(&mut stream, &["XREVRANGE", "s", "3", "1", "COUNT", "2"]).await,
+            Value::Array(vec![
+                entry("3-0", &["c", "3", "d", "4"]),
+                entry("1-2", &["b", "2"]),
+            ])
+        );
+        assert_eq!(
+            call(&mut stream, &["XRANGE", "s", "(+", "+"]).await,
+            error("ERR invalid start ID for the interval")
+        );
+        assert_eq!(
+            call(&mut stream, &["XRANGE", "missing", "-", "+"]).await,
+            Value::Array(vec![])
+        );
+
+        assert_eq!(
+            call(&mut stream, &["XDEL", "s", "1-2", "1-2", "9-9"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["XRANGE", "s", "1", "1"]).await,
+            Value::Array(vec![entry("1-1", &["a", "1"])])
+        );
+        call(&mut stream, &["SET", "string", "v"]).await;
+        assert_eq!(
+            call(&mut stream, &["XADD", "string", "*", "a", "1"]).await,
+            wrong_type()
+        );
+    }
+
+    #[tokio::test]
+    async fn trimming() {
+        let mut stream = connect().await;
+        for seq in 1..=5 {
+            let id = format!("1-{seq}");
+            call(&mut stream, &["XADD", "s", &id, "n", &seq.to_string()]).await;
+        }
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XADD", "s", "MAXLEN", "=", "3", "2-0", "n", "6"]
+            )
+            .await,
+            bulk("2-0")
+        );
+        assert_eq!(
+            call(&mut stream, &["XRANGE", "s", "-", "+", "COUNT", "1"]).await,
+            Value::Array(vec![entry("1-4", &["n", "4"])])
+        );
+        // All entries share one block, which approximate trimming keeps whole.
+        assert_eq!(
+            call(&mut stream, &["XTRIM", "s", "MAXLEN", "~", "1"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["XTRIM", "s", "MINID", "1-5"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["XLEN", "s"]).await, Value::Integer(2));
+        assert_eq!(
+            call(&mut stream, &["XTRIM", "s", "MAXLEN", "0", "LIMIT", "10"]).await,
+            error("ERR syntax error, LIMIT cannot be used without the special ~ option")
+        );
+        assert_eq!(
+            call(&mut stream, &["XTRIM", "s", "MAXLEN", "-1"]).await,
+            error("ERR The MAXLEN argument must be >= 0.")
+        );
+        assert_eq!(
+            call(&mut stream, &["XTRIM", "s", "MAXLEN", "0"]).await,
+            Value::Integer(2)
+        );
+        // Streams outlive their last entry.
+        assert_eq!(call(&mut stream, &["XLEN", "s"]).await, Value::Integer(0));
+        assert_eq!(call(&mut stream, &["EXISTS", "s"]).await, Value::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn read_available_entries() {
+        let mut stream = connect().await;
+        call(&mut stream, &["XADD", "a", "1-1", "f", "1"]).await;
+        call(&mut stream, &["XADD", "a", "1-2", "f", "2"]).await;
+        call(&mut stream, &["XADD", "b", "5-0", "f", "3"]).await;
+        assert_eq!(
+            call(
+                &mut stream,
+                &["XREAD", "COUNT", "1", "STREAMS", "a", "b", "c", "0", "0", "0"]
+            )
+            .await,
+            Value::Array(vec![
+                Value::Array(vec![
+                    bulk("a"),
+                    Value::Array(vec![entry("1-1", &["f", "1"])])
+                ]),
+                Value::Array(vec![
+                    bulk("b"),
+                    Value::Array(vec![entry("5-0", &["f", "3"])])
+                ]),
+            ])
+        );
+        assert_eq!(
+            call(&mut stream, &["XREAD", "STREAMS", "a", "b", "1-1", "$"]).await,
+            Value::Array(vec![Value::Array(vec![
+                bulk("a"),
+                Value::Array(vec![entry("1-2", &["f", "2"])])
+            ])])
+        );
+        assert_eq!(
+            call(&mut stream, &["XREAD", "STREAMS", "a", "$"]).await,
+            Value::NullArray
+        );
+        assert_eq!(
+            call(&mut stream, &["XREAD", "STREAMS", "a", "b", "0"]).await,
+            error("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.")
+        );
+
+        call(&mut stream, &["HELLO", "3"]).await;
+        assert_eq!(
+            call(&mut stream, &["XREAD", "STREAMS", "b", "0"]).await,
+            Value::Map(vec![(
+                bulk("b"),
+                Value::Array(vec![entry("5-0", &["f", "3"])])
+            )])
+        );
+        assert_eq!(
+            call(&mut stream, &["XREAD", "BLOCK", "50", "STREAMS", "b", "$"]).await,
+            Value::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_readers_wake_on_new_entries() {
+        let addr = start_server().await;
+        let mut readers = vec![];
+        for from in ["$", "$", "1-0"] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            readers.push(tokio::spawn(async move {
+                call(
+                    &mut stream,
+                    &["XREAD", "BLOCK", "0", "STREAMS", "other", "s", "0", from],
+                )
+                .await
+            }));
+        }
+        // One reader waits for entries past the next one, which must not
+        // keep the others from being served.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut late = TcpStream::connect(addr).await.unwrap();
+        let late = tokio::spawn(async move {
+            call(&mut late, &["XREAD", "BLOCK", "0", "STREAMS", "s", "5-0"]).await
+        });
+        sleep(Duration::from_millis(100)).await;
+        call(&mut stream, &["XADD", "s", "2-0", "f", "v"]).await;
+        let reply = Value::Array(vec![Value::Array(vec![
+            bulk("s"),
+            Value::Array(vec![entry("2-0", &["f", "v"])]),
+        ])]);
+        for reader in readers {
+            assert_eq!(reader.await.unwrap(), reply);
+        }
+        call(&mut stream, &["XADD", "s", "6-0", "f", "w"]).await;
+        assert_eq!(
+            late.await.unwrap(),
+            Value::Array(vec![Value::Array(vec![
+                bulk("s"),
+                Value::Array(vec![entry("6-0", &["f", "w"])]),
+            ])])
+        );
+    }
+}
@@ -0,0 +1,571 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{locked, normalize_range, wrong_type, Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::{unix_time_millis, DataFrame};
+use crate::decimal;
+use crate::operation::{Expiry, GetExOption, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::value::Value;
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Adds `increment` to the integer stored at `key`, treating a missing
+    /// key as 0, and replies with the new value.
+    pub(super) async fn handle_incrby(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        increment: i64,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| {
+            let value = match string_or_insert(entry, "0") {
+                Ok(value) => value,
+                Err(err) => return err,
+            };
+            let current = match std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+            {
+                Some(current) => current,
+                None => {
+                    return Value::Error(String::from(
+                        "ERR value is not an integer or out of range",
+                    ))
+                }
+            };
+            match current.checked_add(increment) {
+                Some(result) => {
+                    *value = Bytes::from(result.to_string());
+                    Value::Integer(result)
+                }
+                None => Value::Error(String::from("ERR increment or decrement would overflow")),
+            }
+        });
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::STRING, "incrby", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_incrbyfloat(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        increment: f64,
+    ) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| {
+            let value = match string_or_insert(entry, "0") {
+                Ok(value) => value,
+                Err(err) => return err,
+            };
+            let current = match std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|current| !current.is_nan())
+            {
+                Some(current) => current,
+                None => return Value::Error(String::from("ERR value is not a valid float")),
+            };
+            let result = current + increment;
+            if !result.is_finite() {
+                return Value::Error(String::from("ERR increment would produce NaN or Infinity"));
+            }
+            *value = Bytes::from(decimal::add(current, increment));
+            Value::BulkString(value.clone())
+        });
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::STRING, "incrbyfloat", &key);
+        }
+        reply
+    }
+
+    /// Appends to the string at `key`, creating it if needed, and replies
+    /// with the new length.
+    pub(super) async fn handle_append(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        suffix: Bytes,
+    ) -> Value {
+        let max_len = context.config.proto_max_bulk_len();
+        let reply = Self::update_key(context, key.clone(), |entry| {
+            let value = match string_or_insert(entry, "") {
+                Ok(value) => value,
+                Err(err) => return err,
+            };
+            if value.len() + suffix.len() > max_len {
+                return too_long();
+            }
+            let mut appended = Vec::with_capacity(value.len() + suffix.len());
+            appended.extend_from_slice(value);
+            appended.extend_from_slice(&suffix);
+            *value = Bytes::from(appended);
+            Value::Integer(value.len() as i64)
+        });
+        if !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::STRING, "append", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_strlen(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::String(value)) => Value::Integer(value.len() as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    /// The substring between two inclusive, possibly negative offsets.
+    pub(super) async fn handle_getrange(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        start: i64,
+        end: i64,
+    ) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::BulkString(Bytes::new()),
+            Some(Data::String(value)) => match normalize_range(start, end, value.len()) {
+                Some((start, end)) => Value::BulkString(value.slice(start..=end)),
+                None => Value::BulkString(Bytes::new()),
+            },
+            Some(_) => wrong_type(),
+        })
+    }
+
+    /// Overwrites part of the string at `key` starting at `offset`, padding
+    /// with zero bytes if the string is shorter, and replies with the new length.
+    pub(super) async fn handle_setrange(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        offset: usize,
+        patch: Bytes,
+    ) -> Value {
+        let max_len = context.config.proto_max_bulk_len();
+        let reply = Self::update_key(context, key.clone(), |entry| {
+            // An empty patch never creates the key or pads the string.
+            if patch.is_empty() {
+                return match entry.as_ref().and_then(|df| df.data()) {
+                    None => Value::Integer(0),
+                    Some(Data::String(value)) => Value::Integer(value.len() as i64),
+                    Some(_) => wrong_type(),
+                };
+            }
+            let value = match string_or_insert(entry, "") {
+                Ok(value) => value,
+                Err(err) => return err,
+            };
+            let end = match offset.checked_add(patch.len()) {
+                Some(end) if end <= max_len => end,
+                _ => return too_long(),
+            };
+            let mut patched = value.to_vec();
+            if patched.len() < end {
+                patched.resize(end, 0);
+            }
+            patched[offset..end].copy_from_slice(&patch);
+            *value = Bytes::from(patched);
+            Value::Integer(value.len() as i64)
+        });
+        if !patch.is_empty() && !matches!(reply, Value::Error(_)) {
+            Self::notify(context, KeyspaceEvents::STRING, "setrange", &key);
+        }
+        reply
+    }
+
+    /// Deletes the key and replies with its string value.
+    pub(super) async fn handle_getdel(context: &Context<P, D, S>, key: Bytes) -> Value {
+        let reply = Self::update_key(context, key.clone(), |entry| {
+            match entry.as_ref().and_then(|df| df.data()) {
+                None => Value::NullBulkString,
+                Some(Data::String(value)) => {
+                    let value = value.clone();
+                    *entry = None;
+                    Value::BulkString(value)
+                }
+                Some(_) => wrong_type(),
+            }
+        });
+        if matches!(reply, Value::BulkString(_)) {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+
+    /// Replies with the string at `key`, changing its deadline as `option` asks.
+    pub(super) async fn handle_getex(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        option: Option<GetExOption>,
+    ) -> Value {
+        let now = unix_time_millis();
+        let (reply, event) = Self::update_key(context, key.clone(), |entry| {
+            let df = match entry {
+                None => return (Value::NullBulkString, None),
+                Some(df) => df,
+            };
+            let value = match df.data() {
+                Some(Data::String(value)) => value.clone(),
+                _ => return (wrong_type(), None),
+            };
+            let event = match option {
+                None => None,
+                Some(GetExOption::Persist) => {
+                    let persisted = df.deadline().is_some();
+                    df.set_deadline(None);
+                    persisted.then_some("persist")
+                }
+                Some(GetExOption::Expire(expiry)) => {
+                    let deadline = match expiry {
+                        Expiry::In(millis) => now.saturating_add(millis),
+                        Expiry::At(deadline) => deadline,
+                    };
+                    if deadline <= now {
+                        *entry = None;
+                        Some("del")
+                    } else {
+                        df.set_deadline(Some(deadline));
+                        Some("expire")
+                    }
+                }
+            };
+            (Value::BulkString(value), event)
+        });
+        if let Some(event) = event {
+            Self::notify(context, KeyspaceEvents::GENERIC, event, &key);
+        }
+        reply
+    }
+
+    /// The string values of all `keys`, read together so that no concurrent
+    /// multi-key write is seen half done. Keys holding other types read as nil.
+    pub(super) async fn handle_mget(context: &Context<P, D, S>, keys: Vec<Bytes>) -> Value {
+        Self::update_keys(context, keys.clone(), |entries| {
+            let values = keys
+                .iter()
+                .map(|key| match entries.get(key).and_then(|df| df.data()) {
+                    Some(Data::String(value)) => Value::BulkString(value.clone()),
+                    _ => Value::NullBulkString,
+                })
+                .collect();
+            Value::Array(values)
+        })
+    }
+
+    /// Writes all pairs at once, clearing any deadlines. With `nx`, nothing is
+    /// written if any of the keys exists.
+    pub(super) async fn handle_mset(
+        context: &Context<P, D, S>,
+        pairs: Vec<(Bytes, Bytes)>,
+        nx: bool,
+    ) -> Value {
+        let keys: Vec<Bytes> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        let reply = Self::update_keys(context, keys.clone(), |entries| {
+            if nx && pairs.iter().any(|(key, _)| entries.get(key).is_some()) {
+                return Value::Integer(0);
+            }
+            // Later pairs win when a key is repeated, as they would in Redis.
+            for (key, value) in pairs {
+                match locked(entries, &key) {
+                    Ok(entry) => *entry = Some(DataFrame::Plain(Data::String(value))),
+                    Err(err) => return err,
+                }
+            }
+            if nx {
+                Value::Integer(1)
+            } else {
+                Value::SimpleString(String::from("OK"))
+            }
+        });
+        if !matches!(reply, Value::Integer(0) | Value::Error(_)) {
+            for key in &keys {
+                Self::notify(context, KeyspaceEvents::STRING, "set", key);
+            }
+        }
+        reply
+    }
+}
+
+/// The string stored in `entry`, storing `initial` first if the key is
+/// absent, or the WRONGTYPE error if it holds another type.
+fn string_or_insert<'a>(
+    entry: &'a mut Option<DataFrame<Data>>,
+    initial: &'static str,
+) -> Result<&'a mut Bytes, Value> {
+    if entry.is_none() {
+        *entry = Some(DataFrame::Plain(Data::String(Bytes::from_static(
+            initial.as_bytes(),
+        ))));
+    }
+    match entry.as_mut().and_then(|df| df.data_mut()) {
+        Some(Data::String(value)) => Ok(value),
+        _ => Err(wrong_type()),
+    }
+}
+
+fn too_long() -> Value {
+    Value::Error(String::from(
+        "ERR string exceeds maximum allowed size (proto-max-bulk-len)",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::super::tests::{bulk, call, connect, error, start_server};
+    use super::*;
+
+    #[tokio::test]
+    async fn counters() {
+        let mut stream = connect().await;
+        assert_eq!(call(&mut stream, &["INCR", "n"]).await, Value::Integer(1));
+        assert_eq!(
+            call(&mut stream, &["INCRBY", "n", "41"]).await,
+            Value::Integer(42)
+        );
+        assert_eq!(call(&mut stream, &["DECR", "n"]).await, Value::Integer(41));
+        assert_eq!(
+            call(&mut stream, &["DECRBY", "n", "50"]).await,
+            Value::Integer(-9)
+        );
+        assert_eq!(call(&mut stream, &["GET", "n"]).await, bulk("-9"));
+        assert_eq!(
+            call(&mut stream, &["INCRBYFLOAT", "n", "10.5"]).await,
+            bulk("1.5")
+        );
+        call(&mut stream, &["SET", "f", "0.2"]).await;
+        assert_eq!(
+            call(&mut stream, &["INCRBYFLOAT", "f", "0.1"]).await,
+            bulk("0.3")
+        );
+        assert_eq!(
+            call(&mut stream, &["INCRBYFLOAT", "f", "5.0e3"]).await,
+            bulk("5000.3")
+        );
+        assert_eq!(
+            call(&mut stream, &["INCR", "n"]).await,
+            error("ERR value is not an integer or out of range")
+        );
+        call(&mut stream, &["SET", "max", &i64::MAX.to_string()]).await;
+        assert_eq!(
+            call(&mut stream, &["INCR", "max"]).await,
+            error("ERR increment or decrement would overflow")
+        );
+        assert_eq!(
+            call(&mut stream, &["DECRBY", "max", &i64::MIN.to_string()]).await,
+            error("ERR decrement would overflow")
+        );
+        call(&mut stream, &["SET", "word", "abc"]).await;
+        assert_eq!(
+            call(&mut stream, &["INCRBYFLOAT", "word", "1"]).await,
+            error("ERR value is not a valid float")
+        );
+        assert_eq!(
+            call(&mut stream, &["INCRBYFLOAT", "n", "inf"]).await,
+            error("ERR increment would produce NaN or Infinity")
+        );
+        call(&mut stream, &["RPUSH", "list", "x"]).await;
+        assert_eq!(call(&mut stream, &["INCR", "list"]).await, wrong_type());
+    }
+
+    #[tokio::test]
+    async fn counters_keep_the_deadline() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "n", "1", "EX", "100"]).await;
+        call(&mut stream, &["INCR", "n"]).await;
+        call(&mut stream, &["APPEND", "n", "0"]).await;
+        assert_eq!(call(&mut stream, &["GET", "n"]).await, bulk("20"));
+        assert_eq!(call(&mut stream, &["TTL", "n"]).await, Value::Integer(100));
+    }
+
+    #[tokio::test]
+    async fn substrings() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["APPEND", "s", "Hello"]).await,
+            Value::Integer(5)
+        );
+        assert_eq!(
+            call(&mut stream, &["APPEND", "s", " World"]).await,
+            Value::Integer(11)
+        );
+        assert_eq!(
+            call(&mut stream, &["STRLEN", "s"]).await,
+            Value::Integer(11)
+        );
+        assert_eq!(
+            call(&mut stream, &["STRLEN", "missing"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["GETRANGE", "s", "0", "4"]).await,
+            bulk("Hello")
+        );
+        assert_eq!(
+            call(&mut stream, &["GETRANGE", "s", "-5", "-1"]).await,
+            bulk("World")
+        );
+        assert_eq!(
+            call(&mut stream, &["GETRANGE", "s", "5", "1"]).await,
+            bulk("")
+        );
+        assert_eq!(
+            call(&mut stream, &["SETRANGE", "s", "6", "Redis"]).await,
+            Value::Integer(11)
+        );
+        assert_eq!(call(&mut stream, &["GET", "s"]).await, bulk("Hello Redis"));
+        assert_eq!(
+            call(&mut stream, &["SETRANGE", "padded", "3", "x"]).await,
+            Value::Integer(4)
+        );
+        assert_eq!(call(&mut stream, &["GET", "padded"]).await, bulk("\0\0\0x"));
+        assert_eq!(
+            call(&mut stream, &["SETRANGE", "none", "3", ""]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["EXISTS", "none"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["SETRANGE", "s", "-1", "x"]).await,
+            error("ERR offset is out of range")
+        );
+        assert_eq!(
+            call(&mut stream, &["SETRANGE", "s", "536870912", "x"]).await,
+            error("ERR string exceeds maximum allowed size (proto-max-bulk-len)")
+        );
+    }
+
+    #[tokio::test]
+    async fn getdel_and_getex() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "k", "v"]).await;
+        assert_eq!(
+            call(&mut stream, &["GETEX", "k", "EX", "100"]).await,
+            bulk("v")
+        );
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(100));
+        assert_eq!(
+            call(&mut stream, &["GETEX", "k", "PERSIST"]).await,
+            bulk("v")
+        );
+        assert_eq!(call(&mut stream, &["TTL", "k"]).await, Value::Integer(-1));
+        assert_eq!(call(&mut stream, &["GETEX", "k"]).await, bulk("v"));
+        assert_eq!(
+            call(&mut stream, &["GETEX", "k", "EX", "0"]).await,
+            error("ERR invalid expire time in 'getex' command")
+        );
+        assert_eq!(
+            call(&mut stream, &["GETEX", "k", "EX", "1", "PERSIST"]).await,
+            error("ERR syntax error")
+        );
+        assert_eq!(call(&mut stream, &["GETDEL", "k"]).await, bulk("v"));
+        assert_eq!(
+            call(&mut stream, &["GETDEL", "k"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["GETEX", "k"]).await,
+            Value::NullBulkString
+        );
+        call(&mut stream, &["SET", "k", "v"]).await;
+        assert_eq!(
+            call(&mut stream, &["GETEX", "k", "PXAT", "1"]).await,
+            bulk("v")
+        );
+        assert_eq!(call(&mut stream, &["EXISTS", "k"]).await, Value::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn multi_key_reads_and_writes() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["MSET", "a", "1", "b", "2", "a", "3"]).await,
+            Value::SimpleString(String::from("OK"))
+        );
+        call(&mut stream, &["RPUSH", "list", "x"]).await;
+        assert_eq!(
+            call(&mut stream, &["MGET", "a", "b", "missing", "list"]).await,
+            Value::Array(vec![
+                bulk("3"),
+                bulk("2"),
+                Value::NullBulkString,
+                Value::NullBulkString
+            ])
+        );
+        assert_eq!(
+            call(&mut stream, &["MSETNX", "c", "1", "a", "9"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(call(&mut stream, &["EXISTS", "c"]).await, Value::Integer(0));
+        assert_eq!(
+            call(&mut stream, &["MSETNX", "c", "1", "d", "2"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["MSET", "a"]).await,
+            error("ERR wrong number of arguments for 'mset' command")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn mget_never_sees_a_partial_mset() {
+        let addr = start_server().await;
+        let mut writer = TcpStream::connect(addr).await.unwrap();
+        let mut reader = TcpStream::connect(addr).await.unwrap();
+        let keys: Vec<String> = (0..20).map(|i| format!("key{i}")).collect();
+        let writes = tokio::spawn(async move {
+            for round in 0..100 {
+                let round = round.to_string();
+                let mut mset = vec!["MSET"];
+                for key in &keys {
+                    mset.extend([key.as_str(), round.as_str()]);
+                }
+                call(&mut writer, &mset).await;
+            }
+        });
+        let mget: Vec<String> = (0..20).map(|i| format!("key{i}")).collect();
+        let mut mget: Vec<&str> = mget.iter().map(String::as_str).collect();
+        mget.insert(0, "MGET");
+        while !writes.is_finished() {
+            match call(&mut reader, &mget).await {
+                Value::Array(values) => {
+                    assert!(
+                        values.windows(2).all(|pair| pair[0] == pair[1]),
+                        "{values:?}"
+                    )
+                }
+                reply => panic!("unexpected MGET reply {reply:?}"),
+            }
+        }
+        writes.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_increments_are_not_lost() {
+        let addr = start_server().await;
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    for _ in 0..250 {
+                        call(&mut stream, &["INCR", "counter"]).await;
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await.unwrap();
+        }
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(call(&mut stream, &["GET", "counter"]).await, bulk("1000"));
+    }
+}
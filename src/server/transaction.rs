@@ -0,0 +1,416 @@
+use std::io::Cursor;
+use std::mem;
+
+use bytes::Bytes;
+
+use super::cluster::command_keys;
+use super::{Client, Context, Server};
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::{Operation, OperationDeducer};
+use crate::parse::RedisParser;
+use crate::store::Store;
+use crate::value::Value;
+
+/// The commands a client queued since `MULTI`.
+#[derive(Debug, Default)]
+pub(super) struct Transaction {
+    /// Each command with the arguments it was deduced from, which are
+    /// logged if it writes.
+    queued: Vec<(Operation, Vec<Bytes>)>,
+    /// Set when a command failed to queue, which makes `EXEC` discard the
+    /// whole transaction.
+    failed: bool,
+}
+
+/// A key passed to `WATCH`, with what `WATCH` saw of it.
+#[derive(Debug)]
+pub(super) struct Watched {
+    key: Bytes,
+    version: u64,
+    /// Whether the key existed. A key that expires before `EXEC` counts as
+    /// changed even though no command wrote to it.
+    existed: bool,
+}
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Handles a command sent while a transaction is open: the transaction
+    /// commands take effect, and everything else is queued for `EXEC`.
+    pub(super) async fn handle_in_transaction(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        op: Operation,
+        command: Vec<Bytes>,
+    ) -> Value {
+        let read_only = Self::read_only(context, client, &op);
+        let appendonly_failed = Self::appendonly_failed(context, &op);
+        let redirect = Self::redirect(context, client, &op);
+        let transaction = client.transaction.as_mut().unwrap();
+        if let Some(redirect) = redirect {
+            transaction.failed = true;
+            return redirect;
+        }
+        match op {
+            Operation::Exec => Self::handle_exec(context, client).await,
+            Operation::Discard => {
+                client.transaction = None;
+                Self::unwatch_all(context, client);
+                Value::SimpleString(String::from("OK"))
+            }
+            Operation::Multi => Value::Error(String::from("ERR MULTI calls can not be nested")),
+            Operation::Watch(_) => {
+                Value::Error(String::from("ERR WATCH inside MULTI is not allowed"))
+            }
+            Operation::Invalid(msg) => {
+                transaction.failed = true;
+                Value::Error(msg)
+            }
+            _ if read_only => {
+                transaction.failed = true;
+                super::read_only()
+            }
+            _ if appendonly_failed => {
+                transaction.failed = true;
+                Self::appendonly_error(context)
+            }
+            op => {
+                transaction.queued.push((op, command));
+                Value::SimpleString(String::from("QUEUED"))
+            }
+        }
+    }
+
+    /// Runs the queued commands as one atomic step, unless queueing failed or
+    /// a watched key changed since `WATCH`.
+    async fn handle_exec(context: &Context<P, D, S>, client: &mut Client) -> Value {
+        let transaction = client.transaction.take().unwrap();
+        let _gate = context.transactions.exclusive().await;
+        let unchanged = client.watched.iter().all(|watched| {
+            context.transactions.version(&watched.key) == watched.version
+                && (!watched.existed
+                    || Self::read_frame(context, watched.key.clone(), |df| df.is_some()))
+        });
+        Self::unwatch_all(context, client);
+        if transaction.failed {
+            return Value::Error(String::from(
+                "EXECABORT Transaction discarded because of previous errors.",
+            ));
+        }
+        // The commands may each be served here but not all together.
+        let keys: Vec<Bytes> = transaction
+            .queued
+            .iter()
+            .flat_map(|(op, _)| command_keys(op))
+            .collect();
+        if let Some(redirect) = Self::redirect_keys(context, client, &keys, client.asking) {
+            return redirect;
+        }
+        if !unchanged {
+            return Value::NullArray;
+        }
+        // Replaying the log runs the transaction's writes as one step too.
+        let logged = Self::propagating(context)
+            && transaction
+                .queued
+                .iter()
+                .any(|(op, _)| !written_keys(op).is_empty());
+        if logged {
+            Self::propagate(context, vec![Bytes::from_static(b"MULTI")]);
+        }
+        let mut replies = Vec::with_capacity(transaction.queued.len());
+        for (op, command) in transaction.queued {
+            replies.push(Self::execute(context, client, op, command, None).await);
+        }
+        if logged {
+            Self::propagate(context, vec![Bytes::from_static(b"EXEC")]);
+        }
+        Value::Array(replies)
+    }
+
+    pub(super) fn handle_multi(client: &mut Client) -> Value {
+        client.transaction = Some(Transaction::default());
+        Value::SimpleString(String::from("OK"))
+    }
+
+    pub(super) fn handle_watch(
+        context: &Context<P, D, S>,
+        client: &mut Client,
+        keys: Vec<Bytes>,
+    ) -> Value {
+        for key in keys {
+            // Reading the version first means a write racing with the
+            // existence check only ever makes EXEC fail, never succeed wrongly.
+            let version = context.transactions.watch(&key);
+            let existed = Self::read_frame(context, key.clone(), |df| df.is_some());
+            client.watched.push(Watched {
+                key,
+                version,
+                existed,
+            });
+        }
+        Value::SimpleString(String::from("OK"))
+    }
+
+    pub(super) fn handle_unwatch(context: &Context<P, D, S>, client: &mut Client) -> Value {
+        Self::unwatch_all(context, client);
+        Value::SimpleString(String::from("OK"))
+    }
+
+    pub(super) fn unwatch_all(context: &Context<P, D, S>, client: &mut Client) {
+        for watched in mem::take(&mut client.watched) {
+            context.transactions.unwatch(&watched.key);
+        }
+    }
+}
+
+/// The keys `op` may write to, whose watchers have to see the write.
+pub(super) fn written_keys(op: &Operation) -> Vec<Bytes> {
+    match op {
+        Operation::Set(key, ..)
+        | Operation::Push(key, ..)
+        | Operation::PushX(key, ..)
+        | Operation::Pop(key, ..)
+        | Operation::LSet(key, ..)
+        | Operation::LTrim(key, ..)
+        | Operation::LRem(key, ..)
+        | Operation::LInsert(key, ..)
+        | Operation::HSet(key, _)
+        | Operation::HMSet(key, _)
+        | Operation::HSetNx(key, ..)
+        | Operation::HDel(key, _)
+        | Operation::HIncrBy(key, ..)
+        | Operation::HIncrByFloat(key, ..)
+        | Operation::SAdd(key, _)
+        | Operation::SRem(key, _)
+        | Operation::SCombineStore(key, ..)
+        | Operation::SPop(key, _)
+        | Operation::ZAdd(key, ..)
+        | Operation::ZIncrBy(key, ..)
+        | Operation::ZRem(key, _)
+        | Operation::ZPop(key, ..)
+        | Operation::Expire(key, ..)
+        | Operation::Persist(key)
+        | Operation::IncrBy(key, _)
+        | Operation::IncrByFloat(key, _)
+        | Operation::Append(key, _)
+        | Operation::SetRange(key, ..)
+        | Operation::GetDel(key)
+        | Operation::GetEx(key, Some(_))
+        | Operation::XAdd(key, ..)
+        | Operation::XDel(key, _)
+        | Operation::XTrim(key, _)
+        | Operation::XGroupCreate(key, ..)
+        | Operation::XGroupSetId(key, ..)
+        | Operation::XGroupDestroy(key, _)
+        | Operation::XGroupCreateConsumer(key, ..)
+        | Operation::XGroupDelConsumer(key, ..)
+        | Operation::XAck(key, ..)
+        | Operation::XClaim(key, ..)
+        | Operation::XAutoClaim(key, ..)
+        | Operation::Restore(key, _) => vec![key.clone()],
+        Operation::LMove(source, destination, ..)
+        | Operation::BLMove(source, destination, ..)
+        | Operation::SMove(source, destination, _)
+        | Operation::Rename(source, destination)
+        | Operation::RenameNx(source, destination) => vec![source.clone(), destination.clone()],
+        Operation::BPop(keys, ..) | Operation::Del(keys) => keys.clone(),
+        Operation::XReadGroup(_, _, reads, _) => reads.iter().map(|(key, _)| key.clone()).collect(),
+        Operation::MSet(pairs) | Operation::MSetNx(pairs) => {
+            pairs.iter().map(|(key, _)| key.clone()).collect()
+        }
+        Operation::Migrate(options) if !options.copy => options.keys.clone(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::super::tests::{bulk, call, connect, ok, start_server};
+    use super::*;
+
+    fn queued() -> Value {
+        Value::SimpleString(String::from("QUEUED"))
+    }
+
+    #[tokio::test]
+    async fn exec_runs_queued_commands() {
+        let mut stream = connect().await;
+        assert_eq!(call(&mut stream, &["MULTI"]).await, ok());
+        assert_eq!(call(&mut stream, &["SET", "k", "1"]).await, queued());
+        assert_eq!(call(&mut stream, &["INCR", "k"]).await, queued());
+        assert_eq!(call(&mut stream, &["RPUSH", "k", "x"]).await, queued());
+        assert_eq!(call(&mut stream, &["GET", "k"]).await, queued());
+        assert_eq!(
+            call(&mut stream, &["EXEC"]).await,
+            Value::Array(vec![
+                ok(),
+                Value::Integer(2),
+                Value::Error(String::from(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value"
+                )),
+                bulk("2"),
+            ])
+        );
+        assert_eq!(
+            call(&mut stream, &["EXEC"]).await,
+            Value::Error(String::from("ERR EXEC without MULTI"))
+        );
+    }
+
+    #[tokio::test]
+    async fn discard_and_queueing_errors() {
+        let mut stream = connect().await;
+        call(&mut stream, &["MULTI"]).await;
+        call(&mut stream, &["SET", "k", "1"]).await;
+        assert_eq!(call(&mut stream, &["DISCARD"]).await, ok());
+        assert_eq!(
+            call(&mut stream, &["GET", "k"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["DISCARD"]).await,
+            Value::Error(String::from("ERR DISCARD without MULTI"))
+        );
+
+        call(&mut stream, &["MULTI"]).await;
+        assert_eq!(
+            call(&mut stream, &["MULTI"]).await,
+            Value::Error(String::from("ERR MULTI calls can not be nested"))
+        );
+        assert_eq!(
+            call(&mut stream, &["WATCH", "k"]).await,
+            Value::Error(String::from("ERR WATCH inside MULTI is not allowed"))
+        );
+        call(&mut stream, &["SET", "k", "1"]).await;
+        assert_eq!(
+            call(&mut stream, &["SET", "k"]).await,
+            Value::Error(String::from(
+                "ERR wrong number of arguments for 'set' command"
+            ))
+        );
+        assert_eq!(
+            call(&mut stream, &["EXEC"]).await,
+            Value::Error(String::from(
+                "EXECABORT Transaction discarded because of previous errors."
+            ))
+        );
+        assert_eq!(
+            call(&mut stream, &["GET", "k"]).await,
+            Value::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn watched_key_changes_abort_exec() {
+        let addr = start_server().await;
+        let mut watcher = TcpStream::connect(addr).await.unwrap();
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        call(&mut watcher, &["SET", "balance", "10"]).await;
+
+        assert_eq!(call(&mut watcher, &["WATCH", "balance"]).await, ok());
+        call(&mut other, &["INCR", "balance"]).await;
+        call(&mut watcher, &["MULTI"]).await;
+        call(&mut watcher, &["SET", "balance", "0"]).await;
+        assert_eq!(call(&mut watcher, &["EXEC"]).await, Value::NullArray);
+        assert_eq!(call(&mut watcher, &["GET", "balance"]).await, bulk("11"));
+
+        // EXEC unwatches, and reads by other clients do not count as changes.
+        call(&mut watcher, &["WATCH", "balance"]).await;
+        call(&mut other, &["GET", "balance"]).await;
+        call(&mut watcher, &["MULTI"]).await;
+        call(&mut watcher, &["SET", "balance", "0"]).await;
+        assert_eq!(
+            call(&mut watcher, &["EXEC"]).await,
+            Value::Array(vec![ok()])
+        );
+
+        call(&mut watcher, &["WATCH", "balance"]).await;
+        assert_eq!(call(&mut watcher, &["UNWATCH"]).await, ok());
+        call(&mut other, &["DEL", "balance"]).await;
+        call(&mut watcher, &["MULTI"]).await;
+        call(&mut watcher, &["GET", "balance"]).await;
+        assert_eq!(
+            call(&mut watcher, &["EXEC"]).await,
+            Value::Array(vec![Value::NullBulkString])
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_watched_key_aborts_exec() {
+        let mut stream = connect().await;
+        call(&mut stream, &["SET", "k", "v", "PX", "200"]).await;
+        call(&mut stream, &["WATCH", "k"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        call(&mut stream, &["MULTI"]).await;
+        call(&mut stream, &["SET", "other", "v"]).await;
+        assert_eq!(call(&mut stream, &["EXEC"]).await, Value::NullArray);
+    }
+
+    #[tokio::test]
+    async fn blocking_commands_do_not_wait_inside_exec() {
+        let mut stream = connect().await;
+        call(&mut stream, &["MULTI"]).await;
+        call(&mut stream, &["BLPOP", "list", "0"]).await;
+        call(&mut stream, &["RPUSH", "list", "a"]).await;
+        call(&mut stream, &["BLPOP", "list", "0"]).await;
+        assert_eq!(
+            call(&mut stream, &["EXEC"]).await,
+            Value::Array(vec![
+                Value::NullArray,
+                Value::Integer(1),
+                Value::Array(vec![bulk("list"), bulk("a")]),
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn exec_is_atomic() {
+        let addr = start_server().await;
+        let mut writer = TcpStream::connect(addr).await.unwrap();
+        let mut reader = TcpStream::connect(addr).await.unwrap();
+        let writes = tokio::spawn(async move {
+            for _ in 0..200 {
+                call(&mut writer, &["MULTI"]).await;
+                call(&mut writer, &["INCR", "a"]).await;
+                call(&mut writer, &["INCR", "b"]).await;
+                call(&mut writer, &["EXEC"]).await;
+            }
+        });
+        while !writes.is_finished() {
+            call(&mut reader, &["MULTI"]).await;
+            call(&mut reader, &["GET", "a"]).await;
+            call(&mut reader, &["GET", "b"]).await;
+            match call(&mut reader, &["EXEC"]).await {
+                Value::Array(values) => assert_eq!(values[0], values[1]),
+                reply => panic!("unexpected EXEC reply {reply:?}"),
+            }
+        }
+        writes.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocked_client_does_not_hold_up_exec() {
+        let addr = start_server().await;
+        let mut blocked = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let pop = tokio::spawn(async move { call(&mut blocked, &["BLPOP", "list", "0"]).await });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        call(&mut stream, &["MULTI"]).await;
+        call(&mut stream, &["RPUSH", "list", "a"]).await;
+        assert_eq!(
+            call(&mut stream, &["EXEC"]).await,
+            Value::Array(vec![Value::Integer(1)])
+        );
+        assert_eq!(
+            pop.await.unwrap(),
+            Value::Array(vec![bulk("list"), bulk("a")])
+        );
+    }
+}
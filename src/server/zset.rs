@@ -0,0 +1,548 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+
+use super::{normalize_range, remove_if_empty, wrong_type, Context, Server};
+use crate::config::KeyspaceEvents;
+use crate::data::Data;
+use crate::dataframe::DataFrame;
+use crate::operation::{OperationDeducer, RangeBy, ZAddOptions, ZRangeOptions};
+use crate::parse::RedisParser;
+use crate::sorted_set::SortedSet;
+use crate::store::Store;
+use crate::value::{Protocol, Value};
+
+impl<P, D, S> Server<P, D, S>
+where
+    P: for<'a> RedisParser<Cursor<&'a [u8]>> + 'static + Sync,
+    D: OperationDeducer + 'static + Sync,
+    S: Store<Bytes, DataFrame<Data>> + 'static + Sync,
+{
+    /// Adds or updates members. Replies with the number of members added
+    /// (or changed, with CH), or with the new score for INCR.
+    pub(super) async fn handle_zadd(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        options: ZAddOptions,
+        pairs: Vec<(f64, Bytes)>,
+    ) -> Value {
+        let (reply, updated) = Self::update_key(context, key.clone(), |entry| {
+            let set = match zset_or_insert(entry) {
+                Err(err) => return (err, false),
+                Ok(set) => set,
+            };
+            let mut added = 0;
+            let mut changed = 0;
+            let mut last_score = None;
+            for (score, member) in pairs {
+                let current = set.score(&member);
+                let score = match (options.incr, current) {
+                    (true, Some(current)) => current + score,
+                    _ => score,
+                };
+                if score.is_nan() {
+                    remove_if_empty(entry);
+                    let err =
+                        Value::Error(String::from("ERR resulting score is not a number (NaN)"));
+                    return (err, false);
+                }
+                match current {
+                    Some(_) if options.nx => continue,
+                    None if options.xx => continue,
+                    Some(current)
+                        if (options.gt && score <= current) || (options.lt && score >= current) =>
+                    {
+                        continue
+                    }
+                    Some(current) => {
+                        if current != score {
+                            set.insert(member, score);
+                            changed += 1;
+                        }
+                    }
+                    None => {
+                        set.insert(member, score);
+                        added += 1;
+                    }
+                }
+                last_score = Some(score);
+            }
+            remove_if_empty(entry);
+            let reply = match (options.incr, options.ch) {
+                (true, _) => last_score.map_or(Value::NullBulkString, Value::Double),
+                (false, true) => Value::Integer(added + changed),
+                (false, false) => Value::Integer(added),
+            };
+            (reply, added + changed > 0)
+        });
+        if updated {
+            let event = if options.incr { "zincr" } else { "zadd" };
+            Self::notify(context, KeyspaceEvents::ZSET, event, &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_zincrby(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        increment: f64,
+        member: Bytes,
+    ) -> Value {
+        let options = ZAddOptions {
+            incr: true,
+            ..ZAddOptions::default()
+        };
+        Self::handle_zadd(context, key, options, vec![(increment, member)]).await
+    }
+
+    pub(super) async fn handle_zrange(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        options: Box<ZRangeOptions>,
+        protocol: Protocol,
+    ) -> Value {
+        Self::read_key(context, key, |data| {
+            let set = match data {
+                None => return Value::Array(vec![]),
+                Some(Data::SortedSet(set)) => set,
+                Some(_) => return wrong_type(),
+            };
+            let len = set.len();
+            let ranks = match &options.by {
+                // Reversed ranks count from the top, so they are mirrored
+                // into ascending ranks.
+                RangeBy::Rank(start, stop) => match normalize_range(*start, *stop, len) {
+                    None => 0..0,
+                    Some((start, stop)) if options.rev => len - 1 - stop..len - start,
+                    Some((start, stop)) => start..stop + 1,
+                },
+                RangeBy::Score(min, max) => set.score_range(*min, *max),
+                RangeBy::Lex(min, max) => set.lex_range(min, max),
+            };
+            let (offset, count) = options.limit.unwrap_or((0, -1));
+            if offset < 0 {
+                return Value::Array(vec![]);
+            }
+            let count = usize::try_from(count).unwrap_or(usize::MAX);
+            let members = set
+                .range(ranks, options.rev)
+                .skip(offset as usize)
+                .take(count)
+                .map(|(member, score)| (member.clone(), score));
+            scored_members(members, options.with_scores, protocol)
+        })
+    }
+
+    pub(super) async fn handle_zrank(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        member: Bytes,
+        rev: bool,
+        with_score: bool,
+    ) -> Value {
+        let missing = match with_score {
+            true => Value::NullArray,
+            false => Value::NullBulkString,
+        };
+        Self::read_key(context, key, |data| {
+            let set = match data {
+                None => return missing,
+                Some(Data::SortedSet(set)) => set,
+                Some(_) => return wrong_type(),
+            };
+            let (rank, score) = match (set.rank(&member), set.score(&member)) {
+                (Some(rank), Some(score)) => (rank, score),
+                _ => return missing,
+            };
+            let rank = match rev {
+                true => set.len() - 1 - rank,
+                false => rank,
+            };
+            match with_score {
+                true => Value::Array(vec![Value::Integer(rank as i64), Value::Double(score)]),
+                false => Value::Integer(rank as i64),
+            }
+        })
+    }
+
+    pub(super) async fn handle_zrem(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        members: Vec<Bytes>,
+    ) -> Value {
+        let (reply, removed) = Self::update_key(context, key.clone(), |entry| {
+            let reply = match zset_mut(entry) {
+                Err(err) => err,
+                Ok(None) => Value::Integer(0),
+                Ok(Some(set)) => {
+                    let removed = members
+                        .iter()
+                        .filter(|member| set.remove(member).is_some())
+                        .count();
+                    Value::Integer(removed as i64)
+                }
+            };
+            (reply, remove_if_empty(entry))
+        });
+        if matches!(reply, Value::Integer(removed) if removed > 0) {
+            Self::notify(context, KeyspaceEvents::ZSET, "zrem", &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+
+    pub(super) async fn handle_zcard(context: &Context<P, D, S>, key: Bytes) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::Integer(0),
+            Some(Data::SortedSet(set)) => Value::Integer(set.len() as i64),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    pub(super) async fn handle_zscore(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        member: Bytes,
+    ) -> Value {
+        Self::read_key(context, key, |data| match data {
+            None => Value::NullBulkString,
+            Some(Data::SortedSet(set)) => set
+                .score(&member)
+                .map_or(Value::NullBulkString, Value::Double),
+            Some(_) => wrong_type(),
+        })
+    }
+
+    /// `ZPOPMIN`, or `ZPOPMAX` if `max` is set. Without a count the reply is
+    /// a flat member and score pair.
+    pub(super) async fn handle_zpop(
+        context: &Context<P, D, S>,
+        key: Bytes,
+        count: Option<usize>,
+        max: bool,
+        protocol: Protocol,
+    ) -> Value {
+        let (reply, popped, removed) = Self::update_key(context, key.clone(), |entry| {
+            let popped = match zset_mut(entry) {
+                Err(err) => return (err, false, false),
+                Ok(None) => vec![],
+                Ok(Some(set)) => set.pop(count.unwrap_or(1), max),
+            };
+            let removed = remove_if_empty(entry);
+            let any = !popped.is_empty();
+            let reply = match count {
+                None => scored_members(popped.into_iter(), true, Protocol::Resp2),
+                Some(_) => scored_members(popped.into_iter(), true, protocol),
+            };
+            (reply, any, removed)
+        });
+        if popped {
+            let event = if max { "zpopmax" } else { "zpopmin" };
+            Self::notify(context, KeyspaceEvents::ZSET, event, &key);
+        }
+        if removed {
+            Self::notify(context, KeyspaceEvents::GENERIC, "del", &key);
+        }
+        reply
+    }
+}
+
+/// The sorted set stored in `entry`, or the WRONGTYPE error if it holds
+/// another type.
+fn zset_mut(entry: &mut Option<DataFrame<Data>>) -> Result<Option<&mut SortedSet>, Value> {
+    match entry.as_mut().and_then(|df| df.data_mut()) {
+        None => Ok(None),
+        Some(Data::SortedSet(set)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Like [`zset_mut`], but stores an empty sorted set first if the key is absent.
+fn zset_or_insert(entry: &mut Option<DataFrame<Data>>) -> Result<&mut SortedSet, Value> {
+    if entry.is_none() {
+        *entry = Some(DataFrame::Plain(Data::SortedSet(SortedSet::new())));
+    }
+    zset_mut(entry).map(|set| set.unwrap())
+}
+
+/// Replies with members, optionally followed by their scores. RESP2 gets a
+/// flat array, RESP3 an array of member and score pairs.
+fn scored_members(
+    members: impl Iterator<Item = (Bytes, f64)>,
+    with_scores: bool,
+    protocol: Protocol,
+) -> Value {
+    let members = members.map(|(member, score)| (Value::BulkString(member), Value::Double(score)));
+    match (with_scores, protocol) {
+        (false, _) => Value::Array(members.map(|(member, _)| member).collect()),
+        (true, Protocol::Resp2) => Value::Array(
+            members
+                .flat_map(|(member, score)| [member, score])
+                .collect(),
+        ),
+        (true, Protocol::Resp3) => Value::Array(
+            members
+                .map(|(member, score)| Value::Array(vec![member, score]))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{bulk, bulks, call, connect, error};
+    use super::*;
+
+    #[tokio::test]
+    async fn add_with_flags() {
+        let mut stream = connect().await;
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "1", "a", "2", "b"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "NX", "5", "a", "3", "c"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(call(&mut stream, &["ZSCORE", "z", "a"]).await, bulk("1"));
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "XX", "CH", "5", "a", "4", "d"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZSCORE", "z", "d"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "GT", "CH", "4", "a", "9", "b"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "LT", "CH", "1", "a", "10", "b"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]).await,
+            bulks(&["a", "1", "c", "3", "b", "9"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "INCR", "2.5", "a"]).await,
+            bulk("3.5")
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "NX", "INCR", "1", "a"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["ZINCRBY", "z", "-1", "b"]).await,
+            bulk("8")
+        );
+        assert_eq!(call(&mut stream, &["ZCARD", "z"]).await, Value::Integer(3));
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "NX", "XX", "1", "a"]).await,
+            error("ERR XX and NX options at the same time are not compatible")
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "GT", "LT", "1", "a"]).await,
+            error("ERR GT, LT, and/or NX options at the same time are not compatible")
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "INCR", "1", "a", "2", "b"]).await,
+            error("ERR INCR option supports a single increment-element pair")
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "x", "a"]).await,
+            error("ERR value is not a valid float")
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "z", "1", "a", "2"]).await,
+            error("ERR syntax error")
+        );
+        call(&mut stream, &["ZADD", "inf", "+inf", "a"]).await;
+        assert_eq!(
+            call(&mut stream, &["ZINCRBY", "inf", "-inf", "a"]).await,
+            error("ERR resulting score is not a number (NaN)")
+        );
+        assert_eq!(
+            call(&mut stream, &["ZADD", "missing", "XX", "1", "a"]).await,
+            Value::Integer(0)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZCARD", "missing"]).await,
+            Value::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn range_by_rank_score_and_lex() {
+        let mut stream = connect().await;
+        call(
+            &mut stream,
+            &[
+                "ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d", "5", "e",
+            ],
+        )
+        .await;
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "1", "-2"]).await,
+            bulks(&["b", "c", "d"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "0", "1", "REV"]).await,
+            bulks(&["e", "d"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZREVRANGE", "z", "-2", "-1"]).await,
+            bulks(&["b", "a"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "(1", "4", "BYSCORE"]).await,
+            bulks(&["b", "c", "d"])
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["ZRANGE", "z", "+inf", "-inf", "BYSCORE", "REV", "LIMIT", "1", "2"]
+            )
+            .await,
+            bulks(&["d", "c"])
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["ZRANGEBYSCORE", "z", "2", "(4", "WITHSCORES"]
+            )
+            .await,
+            bulks(&["b", "2", "c", "3"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZREVRANGEBYSCORE", "z", "3", "-inf"]).await,
+            bulks(&["c", "b", "a"])
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["ZRANGEBYSCORE", "z", "-inf", "+inf", "LIMIT", "3", "-1"]
+            )
+            .await,
+            bulks(&["d", "e"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "5", "1", "BYSCORE"]).await,
+            bulks(&[])
+        );
+
+        call(
+            &mut stream,
+            &["ZADD", "lex", "0", "a", "0", "b", "0", "c", "0", "d"],
+        )
+        .await;
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "lex", "[b", "+", "BYLEX"]).await,
+            bulks(&["b", "c", "d"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGEBYLEX", "lex", "-", "(c"]).await,
+            bulks(&["a", "b"])
+        );
+        assert_eq!(
+            call(
+                &mut stream,
+                &["ZREVRANGEBYLEX", "lex", "[c", "(a", "LIMIT", "0", "1"]
+            )
+            .await,
+            bulks(&["c"])
+        );
+
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]).await,
+            error(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+            )
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "a", "b", "BYSCORE"]).await,
+            error("ERR min or max is not a float")
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "a", "b", "BYLEX"]).await,
+            error("ERR min or max not valid string range item")
+        );
+    }
+
+    #[tokio::test]
+    async fn rank_remove_and_pop() {
+        let mut stream = connect().await;
+        call(
+            &mut stream,
+            &["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"],
+        )
+        .await;
+        assert_eq!(
+            call(&mut stream, &["ZRANK", "z", "c"]).await,
+            Value::Integer(2)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZREVRANK", "z", "c"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANK", "z", "d", "WITHSCORE"]).await,
+            Value::Array(vec![Value::Integer(3), bulk("4")])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANK", "z", "x"]).await,
+            Value::NullBulkString
+        );
+        assert_eq!(
+            call(&mut stream, &["ZREM", "z", "b", "x"]).await,
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZPOPMIN", "z"]).await,
+            bulks(&["a", "1"])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZPOPMAX", "z", "5"]).await,
+            bulks(&["d", "4", "c", "3"])
+        );
+        assert_eq!(call(&mut stream, &["ZCARD", "z"]).await, Value::Integer(0));
+        assert_eq!(call(&mut stream, &["ZPOPMIN", "z"]).await, bulks(&[]));
+        call(&mut stream, &["RPUSH", "l", "a"]).await;
+        assert_eq!(
+            call(&mut stream, &["ZADD", "l", "1", "a"]).await,
+            wrong_type()
+        );
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "l", "0", "-1"]).await,
+            wrong_type()
+        );
+    }
+
+    #[tokio::test]
+    async fn scores_are_paired_in_resp3() {
+        let mut stream = connect().await;
+        call(&mut stream, &["HELLO", "3"]).await;
+        call(&mut stream, &["ZADD", "z", "1.5", "a", "2", "b"]).await;
+        let pair =
+            |member: &str, score: f64| Value::Array(vec![bulk(member), Value::Double(score)]);
+        assert_eq!(
+            call(&mut stream, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]).await,
+            Value::Array(vec![pair("a", 1.5), pair("b", 2.0)])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZSCORE", "z", "a"]).await,
+            Value::Double(1.5)
+        );
+        assert_eq!(
+            call(&mut stream, &["ZPOPMAX", "z", "1"]).await,
+            Value::Array(vec![pair("b", 2.0)])
+        );
+        assert_eq!(
+            call(&mut stream, &["ZPOPMAX", "z"]).await,
+            Value::Array(vec![bulk("a"), Value::Double(1.5)])
+        );
+    }
+}
@@ -0,0 +1,493 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use bytes::Bytes;
+
+const MAX_LEVEL: usize = 32;
+/// The chance of a node reaching each next level, as in Redis.
+const LEVEL_PROBABILITY: f64 = 0.25;
+/// The index of the head node, which holds no member.
+const HEAD: usize = 0;
+
+/// A lower or upper bound of a score range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+/// A lower or upper bound of a lexicographical range. `Min` and `Max` stand
+/// for `-` and `+`, which sort before and after every member.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+/// A set of members ordered by score, and by member for equal scores.
+///
+/// Members are kept both in a map to their score, for constant time lookups,
+/// and in a skip list whose links count the nodes they skip, so ranks and
+/// rank ranges are found in logarithmic time.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member` or updates its score, returning the previous score.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.list.remove(previous, &member);
+        }
+        self.list.insert(score, member);
+        previous
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let (member, score) = self.scores.remove_entry(member)?;
+        self.list.remove(score, &member);
+        Some(score)
+    }
+
+    /// The 0-based position of `member` in ascending order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(
+            self.list
+                .count_while(|s, m| compare(s, m, score, member) == Ordering::Less),
+        )
+    }
+
+    /// The ranks of the members whose score lies between `min` and `max`.
+    pub fn score_range(&self, min: ScoreBound, max: ScoreBound) -> Range<usize> {
+        let start = self.list.count_while(|score, _| match min {
+            ScoreBound::Inclusive(min) => score < min,
+            ScoreBound::Exclusive(min) => score <= min,
+        });
+        let end = self.list.count_while(|score, _| match max {
+            ScoreBound::Inclusive(max) => score <= max,
+            ScoreBound::Exclusive(max) => score < max,
+        });
+        start..end.max(start)
+    }
+
+    /// The ranks of the members between `min` and `max`. Like in Redis, the
+    /// result is only meaningful if all members have the same score.
+    pub fn lex_range(&self, min: &LexBound, max: &LexBound) -> Range<usize> {
+        let start = self.list.count_while(|_, member| match min {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(min) => member < min,
+            LexBound::Exclusive(min) => member <= min,
+        });
+        let end = self.list.count_while(|_, member| match max {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(max) => member <= max,
+            LexBound::Exclusive(max) => member < max,
+        });
+        start..end.max(start)
+    }
+
+    /// The members with ranks in `ranks`, in descending order if `rev` is set.
+    pub fn range(&self, ranks: Range<usize>, rev: bool) -> impl Iterator<Item = (&Bytes, f64)> {
+        let ranks = ranks.start..ranks.end.min(self.len());
+        let count = ranks.len();
+        let first = match (count, rev) {
+            (0, _) => None,
+            (_, false) => self.list.node_at(ranks.start),
+            (_, true) => self.list.node_at(ranks.end - 1),
+        };
+        let list = &self.list;
+        std::iter::successors(first, move |&node| match rev {
+            false => list.nodes[node].levels[0].forward,
+            true => list.nodes[node].backward,
+        })
+        .take(count)
+        .map(move |node| (&list.nodes[node].member, list.nodes[node].score))
+    }
+
+    /// Removes and returns up to `count` members from the low end, or from
+    /// the high end if `rev` is set.
+    pub fn pop(&mut self, count: usize, rev: bool) -> Vec<(Bytes, f64)> {
+        let count = count.min(self.len());
+        let ranks = match rev {
+            false => 0..count,
+            true => self.len() - count..self.len(),
+        };
+        let popped: Vec<(Bytes, f64)> = self
+            .range(ranks, rev)
+            .map(|(member, score)| (member.clone(), score))
+            .collect();
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+}
+
+fn compare(score: f64, member: &[u8], other_score: f64, other_member: &[u8]) -> Ordering {
+    score
+        .partial_cmp(&other_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| member.cmp(other_member))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Level {
+    forward: Option<usize>,
+    /// The number of nodes `forward` moves past, counting the node it lands on.
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+/// A skip list stored in a vector, with indexes for links. Freed slots are
+/// reused by later inserts.
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node {
+                member: Bytes::new(),
+                score: 0.0,
+                backward: None,
+                levels: vec![Level::default(); MAX_LEVEL],
+            }],
+            free: vec![],
+            level: 1,
+        }
+    }
+}
+
+impl SkipList {
+    fn len(&self) -> usize {
+        self.nodes.len() - self.free.len() - 1
+    }
+
+    fn is_before(&self, node: usize, score: f64, member: &[u8]) -> bool {
+        let node = &self.nodes[node];
+        compare(node.score, &node.member, score, member) == Ordering::Less
+    }
+
+    /// For every level, the last node before `(score, member)` and its rank.
+    fn predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[node].levels[i].forward {
+                if !self.is_before(next, score, member) {
+                    break;
+                }
+                rank[i] += self.nodes[node].levels[i].span;
+                node = next;
+            }
+            update[i] = node;
+        }
+        (update, rank)
+    }
+
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+        let level = random_level();
+        if level > self.level {
+            let len = self.len();
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = len;
+            }
+            self.level = level;
+        }
+        let node = Node {
+            member,
+            score,
+            backward: (update[0] != HEAD).then_some(update[0]),
+            levels: vec![Level::default(); level],
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        for i in 0..level {
+            let previous = self.nodes[update[i]].levels[i];
+            self.nodes[index].levels[i] = Level {
+                forward: previous.forward,
+                span: previous.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].levels[i] = Level {
+                forward: Some(index),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &node) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[node].levels[i].span += 1;
+        }
+        if let Some(next) = self.nodes[index].levels[0].forward {
+            self.nodes[next].backward = Some(index);
+        }
+    }
+
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.predecessors(score, member);
+        let index = match self.nodes[update[0]].levels[0].forward {
+            Some(index) if self.nodes[index].member == member => index,
+            _ => return false,
+        };
+        for (i, &node) in update.iter().enumerate().take(self.level) {
+            let level = self.nodes[node].levels[i];
+            if level.forward == Some(index) {
+                let removed = self.nodes[index].levels[i];
+                self.nodes[node].levels[i] = Level {
+                    forward: removed.forward,
+                    span: level.span + removed.span - 1,
+                };
+            } else {
+                self.nodes[node].levels[i].span -= 1;
+            }
+        }
+        if let Some(next) = self.nodes[index].levels[0].forward {
+            self.nodes[next].backward = self.nodes[index].backward;
+        }
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+        self.nodes[index].member = Bytes::new();
+        self.nodes[index].levels = vec![];
+        self.free.push(index);
+        true
+    }
+
+    /// The number of leading nodes for which `before` holds. `before` must
+    /// hold for a prefix of the list and for nothing after it.
+    fn count_while(&self, before: impl Fn(f64, &Bytes) -> bool) -> usize {
+        let mut rank = 0;
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[i].forward {
+                if !before(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+                rank += self.nodes[node].levels[i].span;
+                node = next;
+            }
+        }
+        rank
+    }
+
+    /// The node at the 0-based `rank`.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[i].forward {
+                if traversed + self.nodes[node].levels[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[node].levels[i].span;
+                node = next;
+            }
+            if traversed == target {
+                return Some(node);
+            }
+        }
+        None
+    }
+}
+
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && rand::random::<f64>() < LEVEL_PROBABILITY {
+        level += 1;
+    }
+    level
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// The reference the skip list is checked against: a sorted vector.
+    #[derive(Default)]
+    struct NaiveSortedSet {
+        entries: Vec<(f64, Bytes)>,
+    }
+
+    impl NaiveSortedSet {
+        fn insert(&mut self, member: Bytes, score: f64) {
+            self.remove(&member);
+            self.entries.push((score, member));
+            self.entries.sort_by(|(a, m), (b, n)| compare(*a, m, *b, n));
+        }
+
+        fn remove(&mut self, member: &[u8]) {
+            self.entries.retain(|(_, m)| m != member);
+        }
+
+        fn rank(&self, member: &[u8]) -> Option<usize> {
+            self.entries.iter().position(|(_, m)| m == member)
+        }
+
+        fn ranks_where(&self, keep: impl Fn(f64, &Bytes) -> bool) -> Vec<usize> {
+            (0..self.entries.len())
+                .filter(|&i| keep(self.entries[i].0, &self.entries[i].1))
+                .collect()
+        }
+    }
+
+    fn member(i: u32) -> Bytes {
+        Bytes::from(format!("m{i:03}"))
+    }
+
+    fn ranks(range: Range<usize>) -> Vec<usize> {
+        range.collect()
+    }
+
+    #[test]
+    fn matches_naive_sorted_vec() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut set = SortedSet::new();
+        let mut naive = NaiveSortedSet::default();
+        for _ in 0..3000 {
+            let m = member(rng.gen_range(0..200));
+            if rng.gen_bool(0.7) {
+                // Few distinct scores, so that ties are ordered by member.
+                let score = rng.gen_range(-20..20) as f64 / 2.0;
+                set.insert(m.clone(), score);
+                naive.insert(m, score);
+            } else {
+                set.remove(&m);
+                naive.remove(&m);
+            }
+
+            assert_eq!(set.len(), naive.entries.len());
+            let m = member(rng.gen_range(0..200));
+            assert_eq!(set.rank(&m), naive.rank(&m));
+            let start = rng.gen_range(0..=set.len());
+            let end = rng.gen_range(start..=set.len() + 2);
+            let rev = rng.gen_bool(0.5);
+            let mut expected: Vec<_> = naive.entries[start..end.min(set.len())].to_vec();
+            if rev {
+                expected.reverse();
+            }
+            let actual: Vec<_> = set
+                .range(start..end, rev)
+                .map(|(member, score)| (score, member.clone()))
+                .collect();
+            assert_eq!(actual, expected);
+
+            let min = rng.gen_range(-12..12) as f64 / 2.0;
+            let max = min + rng.gen_range(0..8) as f64 / 2.0;
+            assert_eq!(
+                ranks(set.score_range(ScoreBound::Inclusive(min), ScoreBound::Exclusive(max))),
+                naive.ranks_where(|score, _| min <= score && score < max)
+            );
+            assert_eq!(
+                ranks(set.score_range(ScoreBound::Exclusive(min), ScoreBound::Inclusive(max))),
+                naive.ranks_where(|score, _| min < score && score <= max)
+            );
+        }
+    }
+
+    #[test]
+    fn lex_ranges_match_naive_sorted_vec() {
+        let mut set = SortedSet::new();
+        let mut naive = NaiveSortedSet::default();
+        for i in (0..100).step_by(3) {
+            set.insert(member(i), 0.0);
+            naive.insert(member(i), 0.0);
+        }
+        let bounds = [
+            LexBound::Min,
+            LexBound::Max,
+            LexBound::Inclusive(member(30)),
+            LexBound::Exclusive(member(30)),
+            LexBound::Inclusive(member(31)),
+            LexBound::Exclusive(member(70)),
+        ];
+        let after_min = |bound: &LexBound, m: &Bytes| match bound {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(min) => m >= min,
+            LexBound::Exclusive(min) => m > min,
+        };
+        let before_max = |bound: &LexBound, m: &Bytes| match bound {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(max) => m <= max,
+            LexBound::Exclusive(max) => m < max,
+        };
+        for min in &bounds {
+            for max in &bounds {
+                assert_eq!(
+                    ranks(set.lex_range(min, max)),
+                    naive.ranks_where(|_, m| after_min(min, m) && before_max(max, m)),
+                    "{min:?} {max:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pop_from_both_ends() {
+        let mut set = SortedSet::new();
+        for (i, score) in [3.0, 1.0, 2.0, 5.0, 4.0].into_iter().enumerate() {
+            set.insert(member(i as u32), score);
+        }
+        assert_eq!(set.pop(2, false), vec![(member(1), 1.0), (member(2), 2.0)]);
+        assert_eq!(set.pop(1, true), vec![(member(3), 5.0)]);
+        assert_eq!(set.pop(10, false).len(), 2);
+        assert!(set.is_empty());
+    }
+}
@@ -2,9 +2,8 @@ use std::borrow::Borrow;
 use std::collections::hash_map::RandomState;
 use std::hash::BuildHasher;
 use std::hash::Hash;
-use std::hash::Hasher;
 use std::mem;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 pub trait Store<K, V>: Send {
     fn set(&self, key: K, val: V);
@@ -12,7 +11,47 @@ pub trait Store<K, V>: Send {
     fn remove_if<T: Borrow<K>, F: Fn(&V) -> bool>(&self, key: T, cond: F) -> bool;
     fn get<T: Borrow<K>>(&self, key: T) -> Option<V>;
     fn contains<T: Borrow<K>>(&self, key: T) -> bool;
-    fn for_each<F: FnMut(&K, &V) -> ()>(&self, f: F);
+    fn for_each<F: FnMut(&K, &V)>(&self, f: F);
+    /// Runs `f` on the value stored under `key` while holding its read lock,
+    /// which saves cloning the value just to inspect it.
+    fn read_with<T: Borrow<K>, R, F: FnOnce(Option<&V>) -> R>(&self, key: T, f: F) -> R;
+    /// Runs `f` on the entry for `key` while holding its lock, so whatever `f`
+    /// reads and writes happens atomically. The entry is `None` if the key is
+    /// absent; leaving `None` behind removes the key, leaving `Some` inserts or
+    /// replaces it.
+    fn update_with<R, F: FnOnce(&mut Option<V>) -> R>(&self, key: K, f: F) -> R;
+    /// Runs `f` on the entries for all of `keys` at once. No other operation
+    /// can observe or change any of them until `f` returns, so `f` sees a
+    /// consistent view even when the keys live in different shards. Entries
+    /// left as `None` are removed, like with `update_with`.
+    fn update_many<R, F: FnOnce(&mut Entries<K, V>) -> R>(&self, keys: Vec<K>, f: F) -> R;
+}
+
+/// The entries of the keys locked by [`Store::update_many`].
+#[derive(Debug)]
+pub struct Entries<K, V> {
+    entries: Vec<(K, Option<V>)>,
+}
+
+impl<K: PartialEq, V> Entries<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, val)| val.as_ref())
+    }
+
+    /// The entry for `key`, or `None` if it is not one of the locked keys.
+    pub fn entry(&mut self, key: &K) -> Option<&mut Option<V>> {
+        self.entries
+            .iter_mut()
+            .find(|(k, _)| k == key)
+            .map(|(_, val)| val)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut Option<V>)> {
+        self.entries.iter_mut().map(|(key, val)| (&*key, val))
+    }
 }
 
 type Wrap<K, V> = Arc<RwLock<Node<K, V>>>;
@@ -43,13 +82,14 @@ where
 
 impl<K, V, S> Store<K, V> for ConcurrentHashtable<K, V, S>
 where
-    K: Hash + PartialEq + PartialOrd,
-    V: Clone,
+    K: Hash + PartialEq + PartialOrd + Clone,
+    V: Clone + Default,
     S: BuildHasher,
 {
     fn get<T: Borrow<K>>(&self, key: T) -> Option<V> {
         let hash = self.get_hash(key.borrow());
         let shard = &self.shards[hash];
+        let _gate = shard.gate.read().unwrap_or_else(PoisonError::into_inner);
         shard.get(key)
     }
 
@@ -60,28 +100,109 @@ where
     fn set(&self, key: K, val: V) {
         let hash = self.get_hash(key.borrow());
         let shard = &self.shards[hash];
+        let _gate = shard.gate.read().unwrap_or_else(PoisonError::into_inner);
         shard.set(key, val)
     }
 
     fn remove<T: Borrow<K>>(&self, key: T) -> bool {
         let hash = self.get_hash(key.borrow());
         let shard = &self.shards[hash];
+        let _gate = shard.gate.read().unwrap_or_else(PoisonError::into_inner);
         shard.remove(key)
     }
 
     fn remove_if<T: Borrow<K>, F: Fn(&V) -> bool>(&self, key: T, cond: F) -> bool {
         let hash = self.get_hash(key.borrow());
         let shard = &self.shards[hash];
+        let _gate = shard.gate.read().unwrap_or_else(PoisonError::into_inner);
         shard.remove_if(key, cond)
     }
 
-    fn for_each<F: FnMut(&K, &V) -> ()>(&self, mut f: F) {
+    fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
         for shard in &self.shards {
+            let _gate = shard.gate.read().unwrap_or_else(PoisonError::into_inner);
             shard.for_each(&mut f);
         }
     }
+
+    fn read_with<T: Borrow<K>, R, F: FnOnce(Option<&V>) -> R>(&self, key: T, f: F) -> R {
+        let hash = self.get_hash(key.borrow());
+        let shard = &self.shards[hash];
+        let _gate = shard.gate.read().unwrap_or_else(PoisonError::into_inner);
+        shard.read_with(key, f)
+    }
+
+    fn update_with<R, F: FnOnce(&mut Option<V>) -> R>(&self, key: K, f: F) -> R {
+        let hash = self.get_hash(key.borrow());
+        let shard = &self.shards[hash];
+        let _gate = shard.gate.read().unwrap_or_else(PoisonError::into_inner);
+        shard.update_with(key, f)
+    }
+
+    fn update_many<R, F: FnOnce(&mut Entries<K, V>) -> R>(&self, keys: Vec<K>, f: F) -> R {
+        let mut indexes: Vec<usize> = keys.iter().map(|key| self.get_hash(key)).collect();
+        // Locking shards in index order keeps two multi-key operations from
+        // each waiting on a shard the other holds.
+        indexes.sort_unstable();
+        indexes.dedup();
+        let _gates: Vec<_> = indexes
+            .into_iter()
+            .map(|index| {
+                let gate = self.shards[index].gate.write();
+                gate.unwrap_or_else(PoisonError::into_inner)
+            })
+            .collect();
+        let mut unique: Vec<K> = Vec::with_capacity(keys.len());
+        for key in keys {
+            if !unique.contains(&key) {
+                unique.push(key);
+            }
+        }
+        // Declared after the gates, so the entries are back before the
+        // shards are let go of.
+        let mut taken = Reinsert {
+            table: self,
+            entries: Entries {
+                entries: unique
+                    .into_iter()
+                    .map(|key| {
+                        let shard = &self.shards[self.get_hash(&key)];
+                        let val = shard.update_with(key.clone(), Option::take);
+                        (key, val)
+                    })
+                    .collect(),
+            },
+        };
+        f(&mut taken.entries)
+    }
 }
 
+/// Puts the entries [`Store::update_many`] took out of their shards back
+/// when dropped, so that none are lost even if the update panics.
+struct Reinsert<'a, K, V, S>
+where
+    K: Hash + PartialEq + PartialOrd,
+    V: Clone + Default,
+    S: BuildHasher,
+{
+    table: &'a ConcurrentHashtable<K, V, S>,
+    entries: Entries<K, V>,
+}
+
+impl<K, V, S> Drop for Reinsert<'_, K, V, S>
+where
+    K: Hash + PartialEq + PartialOrd,
+    V: Clone + Default,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        for (key, val) in self.entries.entries.drain(..) {
+            if let Some(val) = val {
+                self.table.shards[self.table.get_hash(&key)].set(key, val);
+            }
+        }
+    }
+}
 
 impl<K, V, S> ConcurrentHashtable<K, V, S>
 where
@@ -89,14 +210,17 @@ where
     S: BuildHasher,
 {
     fn get_hash(&self, key: &K) -> usize {
-        let mut hasher = self.hash_builder.build_hasher();
-        key.borrow().hash(&mut hasher);
-        hasher.finish() as usize % self.shards.len()
+        self.hash_builder.hash_one(key) as usize % self.shards.len()
     }
 }
 
 #[derive(Debug)]
 struct Shard<K, V> {
+    /// Held shared by single-key operations and exclusively by
+    /// `update_many`, which needs the shard to itself. It guards no data,
+    /// and `update_many` puts its entries back even if it panics, so a
+    /// poisoned gate is still safe to take.
+    gate: RwLock<()>,
     head: Wrap<K, V>,
 }
 
@@ -107,6 +231,7 @@ where
 {
     fn default() -> Self {
         Self {
+            gate: RwLock::new(()),
             head: Arc::new(RwLock::new(Node::default())),
         }
     }
@@ -115,32 +240,37 @@ where
 impl<K, V> Shard<K, V>
 where
     K: PartialEq + PartialOrd,
-    V: Clone,
+    V: Clone + Default,
 {
     pub fn get<T: Borrow<K>>(&self, key: T) -> Option<V> {
-        let lock = self.head.read().unwrap();
-        if let None = &lock.next {
-            return None;
+        self.read_with(key, |val| val.cloned())
+    }
+
+    pub fn read_with<T: Borrow<K>, R, F: FnOnce(Option<&V>) -> R>(&self, key: T, f: F) -> R {
+        let lock = self.head.read().unwrap_or_else(PoisonError::into_inner);
+        match &lock.next {
+            None => f(None),
+            Some(next) => {
+                let next = Arc::clone(next);
+                Self::read_util(key, f, next, lock)
+            }
         }
-        let next = Arc::clone(&lock.next.as_ref().unwrap());
-        Self::get_util(key, next, lock)
     }
 
-    pub fn for_each<F: FnMut(&K, &V) -> ()>(&self, mut f: F) {
-        let lock = self.head.read().unwrap();
-        if let None = &lock.next {
-            return;
+    pub fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        let lock = self.head.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(next) = &lock.next {
+            let next = Arc::clone(next);
+            Self::iter_util(&mut f, next, lock)
         }
-        let next = Arc::clone(&lock.next.as_ref().unwrap());
-        Self::iter_util(&mut f, next, lock)
     }
 
-    fn iter_util <F: FnMut(&K, &V) -> ()>(
+    fn iter_util <F: FnMut(&K, &V)>(
         mut f: F,
         node: Wrap<K, V>,
         prev_lock: RwLockReadGuard<'_, Node<K, V>>,
     ) {
-        let lock = node.read().unwrap();
+        let lock = node.read().unwrap_or_else(PoisonError::into_inner);
         mem::drop(prev_lock);
         f(&lock.key, &lock.val);
         match &lock.next {
@@ -152,48 +282,51 @@ where
         }
     }
 
-    fn get_util<T: Borrow<K>>(
+    fn read_util<T: Borrow<K>, R, F: FnOnce(Option<&V>) -> R>(
         key: T,
+        f: F,
         node: Wrap<K, V>,
         prev_lock: RwLockReadGuard<'_, Node<K, V>>,
-    ) -> Option<V> {
-        let lock = node.read().unwrap();
+    ) -> R {
+        let lock = node.read().unwrap_or_else(PoisonError::into_inner);
         mem::drop(prev_lock);
         if &lock.key == key.borrow() {
-            return Some(lock.val.clone());
+            return f(Some(&lock.val));
         }
         if &lock.key > key.borrow() {
-            return None;
+            return f(None);
         }
         match &lock.next {
-            None => None,
+            None => f(None),
             Some(node) => {
                 let next = Arc::clone(node);
-                Self::get_util(key, next, lock)
+                Self::read_util(key, f, next, lock)
             }
         }
     }
 
     pub fn remove<T: Borrow<K>>(&self, key: T) -> bool{
         let always_true: fn(&V) -> bool = |_| true;
-        let lock = self.head.write().unwrap();
-        if let None = &lock.next {
-            false
-        } else {
-            let node = Arc::clone(&lock.next.as_ref().unwrap());
-            let lock_next = node.write().unwrap();
-            Self::remove_util(key, always_true, lock_next, lock)
+        let lock = self.head.write().unwrap_or_else(PoisonError::into_inner);
+        match &lock.next {
+            None => false,
+            Some(next) => {
+                let node = Arc::clone(next);
+                let lock_next = node.write().unwrap_or_else(PoisonError::into_inner);
+                Self::remove_util(key, always_true, lock_next, lock)
+            }
         }
     }
  
     fn remove_if<T: Borrow<K>, F: Fn(&V) -> bool>(&self, key: T, cond: F) -> bool {
-        let lock = self.head.write().unwrap();
-        if let None = &lock.next {
-            return false;
-        } else {
-            let node = Arc::clone(&lock.next.as_ref().unwrap());
-            let lock_next = node.write().unwrap();
-            Self::remove_util(key, cond, lock_next, lock)
+        let lock = self.head.write().unwrap_or_else(PoisonError::into_inner);
+        match &lock.next {
+            None => false,
+            Some(next) => {
+                let node = Arc::clone(next);
+                let lock_next = node.write().unwrap_or_else(PoisonError::into_inner);
+                Self::remove_util(key, cond, lock_next, lock)
+            }
         }
     }
 
@@ -210,7 +343,7 @@ where
             if !cond(&lock.val) {
                 return false;
             }
-            let next = mem::replace(&mut lock.next, None);
+            let next = lock.next.take();
             prev_lock.next = next;
             return true;
         }
@@ -219,7 +352,7 @@ where
             None => false,
             Some(next) => {
                 let next = Arc::clone(next);
-                let next_lock = next.as_ref().write().unwrap();
+                let next_lock = next.as_ref().write().unwrap_or_else(PoisonError::into_inner);
                 mem::drop(prev_lock);
                 Self::remove_util(key, cond, next_lock, lock)
             }
@@ -227,18 +360,20 @@ where
     }
 
     pub fn set(&self, key: K, val: V) {
-        let mut lock = self.head.write().unwrap();
-        if let None = &lock.next {
-            lock.next = Some(Arc::new(RwLock::new(Node {
-                key,
-                val,
-                next: None,
-            })));
-            return;
-        } else {
-            let node = Arc::clone(&lock.next.as_ref().unwrap());
-            let lock_next = node.write().unwrap();
-            Self::set_util(key, val, lock_next, lock);
+        let mut lock = self.head.write().unwrap_or_else(PoisonError::into_inner);
+        match &lock.next {
+            None => {
+                lock.next = Some(Arc::new(RwLock::new(Node {
+                    key,
+                    val,
+                    next: None,
+                })));
+            }
+            Some(next) => {
+                let node = Arc::clone(next);
+                let lock_next = node.write().unwrap_or_else(PoisonError::into_inner);
+                Self::set_util(key, val, lock_next, lock);
+            }
         }
     }
 
@@ -248,13 +383,13 @@ where
         mut lock: RwLockWriteGuard<'_, Node<K, V>>,
         mut prev_lock: RwLockWriteGuard<'_, Node<K, V>>,
     ) {
-        if &lock.key > &key {
-            let next = mem::replace(&mut prev_lock.next, None);
+        if lock.key > key {
+            let next = prev_lock.next.take();
             prev_lock.next = Some(Arc::new(RwLock::new(Node { key, val, next })));
             return;
         }
 
-        if &lock.key == &key {
+        if lock.key == key {
             lock.val = val;
             return;
         }
@@ -269,15 +404,78 @@ where
             }
             Some(next) => {
                 let next = Arc::clone(next);
-                let next_lock = next.as_ref().write().unwrap();
+                let next_lock = next.as_ref().write().unwrap_or_else(PoisonError::into_inner);
                 mem::drop(prev_lock);
                 Self::set_util(key, val, next_lock, lock);
             }
         };
     }
+
+    pub fn update_with<R, F: FnOnce(&mut Option<V>) -> R>(&self, key: K, f: F) -> R {
+        let lock = self.head.write().unwrap_or_else(PoisonError::into_inner);
+        Self::update_util(key, f, lock)
+    }
+
+    fn update_util<R, F: FnOnce(&mut Option<V>) -> R>(
+        key: K,
+        f: F,
+        mut prev_lock: RwLockWriteGuard<'_, Node<K, V>>,
+    ) -> R {
+        let node = match &prev_lock.next {
+            Some(next) => Arc::clone(next),
+            None => return Self::insert_with(key, f, prev_lock),
+        };
+        let mut lock = node.write().unwrap_or_else(PoisonError::into_inner);
+        if lock.key < key {
+            mem::drop(prev_lock);
+            return Self::update_util(key, f, lock);
+        }
+        if lock.key > key {
+            mem::drop(lock);
+            return Self::insert_with(key, f, prev_lock);
+        }
+
+        let mut restore = Restore {
+            entry: Some(mem::take(&mut lock.val)),
+            node: &mut lock,
+        };
+        let result = f(&mut restore.entry);
+        match restore.entry.take() {
+            Some(val) => restore.node.val = val,
+            None => prev_lock.next = restore.node.next.take(),
+        }
+        result
+    }
+
+    fn insert_with<R, F: FnOnce(&mut Option<V>) -> R>(
+        key: K,
+        f: F,
+        mut prev_lock: RwLockWriteGuard<'_, Node<K, V>>,
+    ) -> R {
+        let mut entry = None;
+        let result = f(&mut entry);
+        if let Some(val) = entry {
+            let next = prev_lock.next.take();
+            prev_lock.next = Some(Arc::new(RwLock::new(Node { key, val, next })));
+        }
+        result
+    }
 }
 
+/// Puts the value [`Shard::update_util`] took out of its node back when
+/// dropped, so that it is not lost if the update panics.
+struct Restore<'a, 'b, K, V> {
+    node: &'a mut RwLockWriteGuard<'b, Node<K, V>>,
+    entry: Option<V>,
+}
 
+impl<K, V> Drop for Restore<'_, '_, K, V> {
+    fn drop(&mut self) {
+        if let Some(val) = self.entry.take() {
+            self.node.val = val;
+        }
+    }
+}
 
 #[derive(Default, Debug)]
 struct Node<K, V> {
@@ -367,4 +565,110 @@ mod tests {
             assert_eq!(shard.get(i.to_string()), None);
         }
     }
+
+    #[test]
+    fn update_with_inserts_modifies_and_removes() {
+        let shard: Shard<String, String> = Shard::default();
+        shard.set(own("a"), own("1"));
+        shard.set(own("c"), own("3"));
+        shard.update_with(own("b"), |entry| {
+            assert_eq!(entry, &None);
+            *entry = Some(own("2"));
+        });
+        shard.update_with(own("a"), |entry| entry.as_mut().unwrap().push('!'));
+        let removed = shard.update_with(own("c"), |entry| entry.take());
+        shard.update_with(own("d"), |_| ());
+        assert_eq!(removed, Some(own("3")));
+        assert_eq!(shard.get(own("a")), Some(own("1!")));
+        assert_eq!(shard.get(own("b")), Some(own("2")));
+        assert_eq!(shard.get(own("c")), None);
+        assert_eq!(shard.get(own("d")), None);
+    }
+
+    #[test]
+    fn update_with_keeps_the_key_usable_when_the_update_panics() {
+        let shard: Shard<String, String> = Shard::default();
+        shard.set(own("a"), own("1"));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            shard.update_with(own("a"), |entry| {
+                entry.as_mut().unwrap().push('!');
+                panic!("update failed");
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(shard.get(own("a")), Some(own("1!")));
+        shard.update_with(own("a"), |entry| *entry = Some(own("2")));
+        assert_eq!(shard.get(own("a")), Some(own("2")));
+    }
+
+    #[test]
+    fn update_with_multithreaded() {
+        let shard: Shard<String, String> = Shard::default();
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        shard.update_with((i % 10).to_string(), |entry| {
+                            let count = entry.as_ref().map_or(0, |v| v.parse::<u32>().unwrap());
+                            *entry = Some((count + 1).to_string());
+                        });
+                    }
+                });
+            }
+        });
+        for i in 0..10 {
+            assert_eq!(shard.get(i.to_string()), Some(own("400")));
+        }
+    }
+
+    #[test]
+    fn update_many_moves_between_shards() {
+        let table: ConcurrentHashtable<String, String> = ConcurrentHashtable::with_shards(16);
+        for i in 0..8 {
+            table.set(i.to_string(), own("1"));
+        }
+        scope(|scope| {
+            for t in 0..4 {
+                let table = &table;
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        let from = ((i + t) % 8).to_string();
+                        let to = ((i * 3 + t) % 8).to_string();
+                        table.update_many(vec![from.clone(), to.clone(), from.clone()], |entries| {
+                            let count = |val: &Option<String>| {
+                                val.as_ref().map_or(0, |v| v.parse::<u32>().unwrap())
+                            };
+                            let taken = entries.entry(&from).unwrap().take();
+                            let total = count(entries.entry(&to).unwrap()) + count(&taken);
+                            *entries.entry(&to).unwrap() = Some(total.to_string());
+                        });
+                    }
+                });
+            }
+        });
+        let mut total = 0;
+        table.for_each(|_, val| total += val.parse::<u32>().unwrap());
+        assert_eq!(total, 8);
+    }
+
+    #[test]
+    fn update_many_keeps_entries_when_the_update_panics() {
+        let table: ConcurrentHashtable<String, String> = ConcurrentHashtable::with_shards(16);
+        table.set(own("a"), own("1"));
+        table.set(own("b"), own("2"));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            table.update_many(vec![own("a"), own("b")], |entries| {
+                *entries.entry(&own("a")).unwrap() = Some(own("3"));
+                assert!(entries.entry(&own("c")).is_none());
+                panic!("update failed");
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(table.get(own("a")), Some(own("3")));
+        assert_eq!(table.get(own("b")), Some(own("2")));
+        table.update_many(vec![own("a")], |entries| {
+            *entries.entry(&own("a")).unwrap() = None;
+        });
+        assert_eq!(table.get(own("a")), None);
+    }
 }
@@ -0,0 +1,771 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+use bytes::Bytes;
+
+use crate::consumer_group::ConsumerGroup;
+
+/// A block takes at most this many entries, as Redis's `stream-node-max-entries`.
+const BLOCK_MAX_ENTRIES: usize = 100;
+/// A block takes no more entries once its fields and values add up to this
+/// many bytes, as Redis's `stream-node-max-bytes`.
+const BLOCK_MAX_BYTES: usize = 4096;
+/// How many entries approximate trimming removes at most without a LIMIT.
+const DEFAULT_TRIM_LIMIT: usize = 100 * BLOCK_MAX_ENTRIES;
+
+/// The ID of a stream entry: the Unix time in milliseconds it was added at,
+/// and a sequence number telling apart entries added in the same millisecond.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: Self = Self::new(0, 0);
+    pub const MAX: Self = Self::new(u64::MAX, u64::MAX);
+
+    pub const fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn next(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(Self::new(self.ms, seq)),
+            None => Some(Self::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    /// The greatest ID smaller than this one.
+    pub fn prev(self) -> Option<Self> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(Self::new(self.ms, seq)),
+            None => Some(Self::new(self.ms.checked_sub(1)?, u64::MAX)),
+        }
+    }
+
+    /// Parses `<ms>-<seq>`, or `<ms>` alone, in which case the sequence
+    /// number is left for the caller to pick.
+    pub fn parse(input: &[u8]) -> Option<(u64, Option<u64>)> {
+        let input = std::str::from_utf8(input).ok()?;
+        let number = |part: &str| match part.bytes().all(|b| b.is_ascii_digit()) {
+            true => part.parse::<u64>().ok(),
+            false => None,
+        };
+        match input.split_once('-') {
+            Some((ms, seq)) => Some((number(ms)?, Some(number(seq)?))),
+            None => Some((number(input)?, None)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// How `XADD` picks the ID of a new entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewId {
+    /// `*`: the current time, or right after the last entry if the clock is behind.
+    Auto,
+    /// `<ms>-*`: the next free sequence number within the given millisecond.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+/// How far `XADD` and `XTRIM` trim a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimThreshold {
+    /// Keep at most this many entries.
+    MaxLen(usize),
+    /// Drop entries with a smaller ID.
+    MinId(StreamId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trim {
+    pub threshold: TrimThreshold,
+    /// `~`: only drop whole blocks, which may leave a few more entries than
+    /// asked for but never has to split a block.
+    pub approximate: bool,
+    /// At most how many entries approximate trimming drops; 0 for no limit.
+    pub limit: Option<usize>,
+}
+
+/// A stream entry as read from the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub id: StreamId,
+    /// Field names and values, alternating.
+    pub fields: Vec<Bytes>,
+}
+
+/// An append-only log of entries with increasing IDs.
+///
+/// Entries are packed into blocks of up to [`BLOCK_MAX_ENTRIES`], each
+/// keeping the fields and values of its entries back to back in a single
+/// buffer, and the blocks are kept in a B-tree by the ID they started with.
+/// A stream thus takes a few allocations per block rather than per entry,
+/// and ranges start with a logarithmic lookup of the first block.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    blocks: BTreeMap<StreamId, Block>,
+    len: usize,
+    /// The greatest ID ever added, which new IDs have to exceed even after
+    /// the entry is gone.
+    last_id: StreamId,
+    /// How many entries were ever added, deleted ones included.
+    entries_added: u64,
+    /// The greatest ID removed with `XDEL`.
+    max_deleted_id: StreamId,
+    /// The consumer groups reading the stream, by name.
+    groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    /// How many blocks the entries are packed into.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The ID of the first entry, or `0-0` if there is none.
+    pub fn first_id(&self) -> StreamId {
+        self.blocks
+            .first_key_value()
+            .map_or(StreamId::MIN, |(_, block)| block.ids[0])
+    }
+
+    pub fn first_entry(&self) -> Option<Entry> {
+        let (_, block) = self.blocks.first_key_value()?;
+        Some(block.entry(0))
+    }
+
+    pub fn last_entry(&self) -> Option<Entry> {
+        let (_, block) = self.blocks.last_key_value()?;
+        Some(block.entry(block.len() - 1))
+    }
+
+    /// Picks the ID for a new entry, or fails with the error `XADD` replies with.
+    pub fn new_id(&self, id: NewId, now: u64) -> Result<StreamId, &'static str> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if now > last.ms => Some(StreamId::new(now, 0)),
+            NewId::Auto => match last.next() {
+                Some(id) => Some(id),
+                None => return Err(
+                    "ERR The stream has exhausted the last possible ID, unable to add more items",
+                ),
+            },
+            NewId::AutoSeq(ms) if ms == last.ms => {
+                last.seq.checked_add(1).map(|seq| StreamId::new(ms, seq))
+            }
+            NewId::AutoSeq(ms) if ms > last.ms => Some(StreamId::new(ms, 0)),
+            NewId::AutoSeq(_) => None,
+            NewId::Explicit(StreamId::MIN) => {
+                return Err("ERR The ID specified in XADD must be greater than 0-0")
+            }
+            NewId::Explicit(id) => Some(id).filter(|id| *id > last),
+        };
+        id.ok_or("ERR The ID specified in XADD is equal or smaller than the target stream top item")
+    }
+
+    /// Appends an entry, whose ID has to be greater than any added before.
+    pub fn add(&mut self, id: StreamId, fields: &[(Bytes, Bytes)]) {
+        debug_assert!(id > self.last_id);
+        match self.blocks.last_entry() {
+            Some(mut block) if !block.get().is_full() => block.get_mut().push(id, fields),
+            _ => {
+                let mut block = Block::default();
+                block.push(id, fields);
+                self.blocks.insert(id, block);
+            }
+        }
+        self.len += 1;
+        self.entries_added += 1;
+        self.last_id = id;
+    }
+
+    /// Restores the counters a stream keeps about entries that may be gone,
+    /// for a stream rebuilt from a snapshot by adding its remaining entries.
+    pub fn restore_counters(
+        &mut self,
+        last_id: StreamId,
+        entries_added: u64,
+        max_deleted_id: StreamId,
+    ) {
+        self.last_id = self.last_id.max(last_id);
+        self.entries_added = entries_added;
+        self.max_deleted_id = max_deleted_id;
+    }
+
+    /// The entries block by block, lowest first.
+    pub fn blocks(&self) -> impl Iterator<Item = Vec<Entry>> + '_ {
+        self.blocks
+            .values()
+            .map(|block| (0..block.len()).map(|index| block.entry(index)).collect())
+    }
+
+    /// The entries from `start` to `end`, both included, lowest first, and at
+    /// most `count` of them.
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>) -> Vec<Entry> {
+        let limit = count.unwrap_or(usize::MAX);
+        let mut entries = vec![];
+        if start > end {
+            return entries;
+        }
+        // The block holding `start` began at or before it.
+        let first = match self.blocks.range(..=start).next_back() {
+            Some((first, _)) => *first,
+            None => start,
+        };
+        for block in self.blocks.range(first..=end).map(|(_, block)| block) {
+            let from = block.ids.partition_point(|id| *id < start);
+            for index in from..block.len() {
+                if block.ids[index] > end || entries.len() == limit {
+                    return entries;
+                }
+                entries.push(block.entry(index));
+            }
+        }
+        entries
+    }
+
+    /// Like [`Stream::range`], but highest first.
+    pub fn rev_range(&self, start: StreamId, end: StreamId, count: Option<usize>) -> Vec<Entry> {
+        let limit = count.unwrap_or(usize::MAX);
+        let mut entries = vec![];
+        if start > end {
+            return entries;
+        }
+        for block in self.blocks.range(..=end).rev().map(|(_, block)| block) {
+            let to = block.ids.partition_point(|id| *id <= end);
+            for index in (0..to).rev() {
+                if block.ids[index] < start || entries.len() == limit {
+                    return entries;
+                }
+                entries.push(block.entry(index));
+            }
+        }
+        entries
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<Entry> {
+        self.range(id, id, Some(1)).pop()
+    }
+
+    pub fn contains(&self, id: StreamId) -> bool {
+        match self.blocks.range(..=id).next_back() {
+            Some((_, block)) => block.ids.binary_search(&id).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Deletes the entry with the given ID, returning whether there was one.
+    pub fn remove(&mut self, id: StreamId) -> bool {
+        let Some((&first, block)) = self.blocks.range_mut(..=id).next_back() else {
+            return false;
+        };
+        let Ok(index) = block.ids.binary_search(&id) else {
+            return false;
+        };
+        block.remove(index);
+        if block.len() == 0 {
+            self.blocks.remove(&first);
+        }
+        self.len -= 1;
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /// Drops the oldest entries as `trim` asks, returning how many were dropped.
+    pub fn trim(&mut self, trim: &Trim) -> usize {
+        let limit = match (trim.approximate, trim.limit) {
+            (false, _) | (true, Some(0)) => usize::MAX,
+            (true, Some(limit)) => limit,
+            (true, None) => DEFAULT_TRIM_LIMIT,
+        };
+        let mut removed = 0;
+        while let Some(mut first) = self.blocks.first_entry() {
+            let block = first.get_mut();
+            let excess = match trim.threshold {
+                TrimThreshold::MaxLen(max) => (self.len - removed).saturating_sub(max),
+                TrimThreshold::MinId(min) => block.ids.partition_point(|id| *id < min),
+            };
+            if excess == 0 {
+                break;
+            }
+            if excess >= block.len() {
+                if removed + block.len() > limit {
+                    break;
+                }
+                removed += block.len();
+                first.remove();
+                continue;
+            }
+            if !trim.approximate {
+                block.remove_front(excess);
+                removed += excess;
+            }
+            break;
+        }
+        self.len -= removed;
+        removed
+    }
+
+    pub fn groups(&self) -> &BTreeMap<Bytes, ConsumerGroup> {
+        &self.groups
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    /// Adds a group, returning false if there already is one by that name.
+    pub fn create_group(&mut self, name: Bytes, group: ConsumerGroup) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        self.groups.insert(name, group);
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Hands up to `count` entries the group has not seen yet to `consumer`,
+    /// which keeps them pending until it acknowledges them unless `noack` is
+    /// set. Returns `None` if there is no such group.
+    pub fn read_group(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        count: Option<usize>,
+        noack: bool,
+        now: i64,
+    ) -> Option<Vec<Entry>> {
+        let (last_delivered, mut entries_read) = {
+            let group = self.groups.get(group)?;
+            (group.last_delivered, group.entries_read)
+        };
+        let entries = match last_delivered.next() {
+            Some(start) => self.range(start, StreamId::MAX, count),
+            None => vec![],
+        };
+        for entry in &entries {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones_from(entry.id) => Some(read + 1),
+                _ => self.entries_read_at(entry.id),
+            };
+        }
+        let group = self.groups.get_mut(group)?;
+        let Some(last) = entries.last() else {
+            return Some(entries);
+        };
+        group.last_delivered = last.id;
+        group.entries_read = entries_read;
+        if !noack {
+            for entry in &entries {
+                group.assign(entry.id, consumer, now, 1);
+            }
+        }
+        if let Some(consumer) = group.consumer_mut(consumer) {
+            consumer.active_at = Some(now);
+        }
+        Some(entries)
+    }
+
+    /// The entries pending for `consumer` with an ID greater than `after`, at
+    /// most `count` of them, counting this as another delivery of each.
+    /// Entries deleted from the stream since they were delivered come with
+    /// `None`. Returns `None` if there is no such group.
+    pub fn read_pending(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        after: StreamId,
+        count: Option<usize>,
+        now: i64,
+    ) -> Option<Vec<(StreamId, Option<Entry>)>> {
+        let ids: Vec<StreamId> = {
+            let group = self.groups.get(group)?;
+            let Some(consumer) = group.consumers().get(consumer) else {
+                return Some(vec![]);
+            };
+            let ids = consumer.pending().range(after..).filter(|id| **id > after);
+            ids.take(count.unwrap_or(usize::MAX)).copied().collect()
+        };
+        let entries = ids.iter().map(|id| (*id, self.get(*id))).collect();
+        let group = self.groups.get_mut(group)?;
+        for id in ids {
+            group.redeliver(id, now);
+        }
+        Some(entries)
+    }
+
+    /// How many entries were added that the group has not read yet, if
+    /// deletions do not keep that from being told.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let read = match group.entries_read {
+            Some(read) if !self.has_tombstones_from(group.last_delivered) => Some(read),
+            _ => self.entries_read_at(group.last_delivered),
+        };
+        read.map(|read| self.entries_added.saturating_sub(read))
+    }
+
+    /// Whether an entry at or after `start` was deleted, which makes counting
+    /// the entries up to a given ID impossible without scanning them.
+    fn has_tombstones_from(&self, start: StreamId) -> bool {
+        self.len > 0
+            && self.max_deleted_id != StreamId::MIN
+            && self.first_id() <= self.max_deleted_id
+            && start <= self.max_deleted_id
+    }
+
+    /// How many entries were added up to and including `id`, if that can be
+    /// told without scanning the stream.
+    fn entries_read_at(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if id > self.last_id {
+            // Entries past the last one are yet to be added.
+            return None;
+        }
+        if id == self.last_id || self.len == 0 {
+            return Some(self.entries_added);
+        }
+        // Without deletions, the entries before the first one were all trimmed.
+        let first = self.first_id();
+        if self.max_deleted_id != StreamId::MIN && self.max_deleted_id >= first {
+            return None;
+        }
+        let trimmed = self.entries_added - self.len as u64;
+        match id.cmp(&first) {
+            Ordering::Less => Some(trimmed),
+            Ordering::Equal => Some(trimmed + 1),
+            Ordering::Greater => None,
+        }
+    }
+}
+
+/// A run of consecutive entries.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Block {
+    ids: Vec<StreamId>,
+    /// For each entry, the index in `ends` of its first field.
+    firsts: Vec<usize>,
+    /// Where each field and value ends in `data`.
+    ends: Vec<usize>,
+    /// The fields and values of all entries, back to back.
+    data: Vec<u8>,
+}
+
+impl Block {
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn is_full(&self) -> bool {
+        self.len() >= BLOCK_MAX_ENTRIES || self.data.len() >= BLOCK_MAX_BYTES
+    }
+
+    fn push(&mut self, id: StreamId, fields: &[(Bytes, Bytes)]) {
+        self.ids.push(id);
+        self.firsts.push(self.ends.len());
+        for (field, value) in fields {
+            for bytes in [field, value] {
+                self.data.extend_from_slice(bytes);
+                self.ends.push(self.data.len());
+            }
+        }
+    }
+
+    /// The indexes in `ends` of the fields of the entry at `index`.
+    fn fields_of(&self, index: usize) -> Range<usize> {
+        let end = self.firsts.get(index + 1).copied();
+        self.firsts[index]..end.unwrap_or(self.ends.len())
+    }
+
+    /// Where the field with the given index in `ends` starts in `data`.
+    fn start_of(&self, field: usize) -> usize {
+        match field {
+            0 => 0,
+            field => self.ends[field - 1],
+        }
+    }
+
+    fn entry(&self, index: usize) -> Entry {
+        let fields = self
+            .fields_of(index)
+            .map(|field| Bytes::copy_from_slice(&self.data[self.start_of(field)..self.ends[field]]))
+            .collect();
+        Entry {
+            id: self.ids[index],
+            fields,
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        let fields = self.fields_of(index);
+        let bytes = self.start_of(fields.start)..self.start_of(fields.end);
+        self.data.drain(bytes.clone());
+        self.ends.drain(fields.clone());
+        for end in &mut self.ends[fields.start..] {
+            *end -= bytes.len();
+        }
+        self.firsts.remove(index);
+        for first in &mut self.firsts[index..] {
+            *first -= fields.len();
+        }
+        self.ids.remove(index);
+    }
+
+    /// Removes the first `count` entries, leaving at least one.
+    fn remove_front(&mut self, count: usize) {
+        let fields = self.firsts[count];
+        let bytes = self.start_of(fields);
+        self.data.drain(..bytes);
+        self.ends.drain(..fields);
+        for end in &mut self.ends {
+            *end -= bytes;
+        }
+        self.firsts.drain(..count);
+        for first in &mut self.firsts {
+            *first -= fields;
+        }
+        self.ids.drain(..count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(value: &str) -> Vec<(Bytes, Bytes)> {
+        vec![(Bytes::from_static(b"field"), Bytes::from(value.to_string()))]
+    }
+
+    /// A stream with entries `1-0` up to `<len>-0`, each holding its number.
+    fn stream(len: u64) -> Stream {
+        let mut stream = Stream::new();
+        for ms in 1..=len {
+            stream.add(StreamId::new(ms, 0), &fields(&ms.to_string()));
+        }
+        stream
+    }
+
+    fn ids(entries: &[Entry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.id.ms).collect()
+    }
+
+    #[test]
+    fn ids_parse_and_order() {
+        assert_eq!(StreamId::parse(b"5-3"), Some((5, Some(3))));
+        assert_eq!(StreamId::parse(b"5"), Some((5, None)));
+        assert_eq!(StreamId::parse(b"5-"), None);
+        assert_eq!(StreamId::parse(b"-3"), None);
+        assert_eq!(StreamId::parse(b"+5"), None);
+        assert_eq!(StreamId::new(1, u64::MAX).next(), Some(StreamId::new(2, 0)));
+        assert_eq!(StreamId::new(2, 0).prev(), Some(StreamId::new(1, u64::MAX)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::MIN.prev(), None);
+        assert_eq!(StreamId::new(1, 2).to_string(), "1-2");
+    }
+
+    #[test]
+    fn new_ids_exceed_the_last_one() {
+        let mut stream = Stream::new();
+        assert_eq!(stream.new_id(NewId::Auto, 10), Ok(StreamId::new(10, 0)));
+        assert_eq!(
+            stream.new_id(NewId::AutoSeq(0), 10),
+            Ok(StreamId::new(0, 1))
+        );
+        assert!(stream.new_id(NewId::Explicit(StreamId::MIN), 10).is_err());
+        stream.add(StreamId::new(10, 5), &fields("a"));
+        // A clock running behind still yields increasing IDs.
+        assert_eq!(stream.new_id(NewId::Auto, 9), Ok(StreamId::new(10, 6)));
+        assert_eq!(
+            stream.new_id(NewId::AutoSeq(10), 0),
+            Ok(StreamId::new(10, 6))
+        );
+        assert_eq!(
+            stream.new_id(NewId::AutoSeq(11), 0),
+            Ok(StreamId::new(11, 0))
+        );
+        assert!(stream.new_id(NewId::AutoSeq(9), 0).is_err());
+        assert!(stream
+            .new_id(NewId::Explicit(StreamId::new(10, 5)), 0)
+            .is_err());
+        // Deleting the last entry does not free its ID.
+        stream.remove(StreamId::new(10, 5));
+        assert!(stream
+            .new_id(NewId::Explicit(StreamId::new(10, 5)), 0)
+            .is_err());
+    }
+
+    #[test]
+    fn ranges_span_blocks() {
+        let stream = stream(350);
+        assert_eq!(stream.len(), 350);
+        assert_eq!(stream.blocks.len(), 4);
+        let all = stream.range(StreamId::MIN, StreamId::MAX, None);
+        assert_eq!(ids(&all), (1..=350).collect::<Vec<_>>());
+        assert_eq!(
+            all[41].fields,
+            vec![Bytes::from("field"), Bytes::from("42")]
+        );
+        let some = stream.range(StreamId::new(95, 0), StreamId::new(205, 0), Some(20));
+        assert_eq!(ids(&some), (95..115).collect::<Vec<_>>());
+        let reversed = stream.rev_range(StreamId::new(95, 1), StreamId::new(205, 0), None);
+        assert_eq!(ids(&reversed), (96..=205).rev().collect::<Vec<_>>());
+        assert!(stream
+            .range(StreamId::new(400, 0), StreamId::MAX, None)
+            .is_empty());
+        assert!(stream
+            .rev_range(StreamId::MIN, StreamId::new(0, 9), None)
+            .is_empty());
+    }
+
+    #[test]
+    fn removed_entries_leave_their_neighbours_intact() {
+        let mut stream = stream(150);
+        assert!(stream.remove(StreamId::new(50, 0)));
+        assert!(!stream.remove(StreamId::new(50, 0)));
+        assert!(!stream.remove(StreamId::new(500, 0)));
+        for ms in 101..=150 {
+            assert!(stream.remove(StreamId::new(ms, 0)));
+        }
+        assert_eq!(stream.blocks.len(), 1);
+        assert_eq!(stream.len(), 99);
+        assert_eq!(stream.max_deleted_id(), StreamId::new(150, 0));
+        let around = stream.range(StreamId::new(49, 0), StreamId::new(51, 0), None);
+        assert_eq!(ids(&around), vec![49, 51]);
+        assert_eq!(around[1].fields[1], Bytes::from("51"));
+        assert_eq!(stream.last_entry().unwrap().id, StreamId::new(100, 0));
+    }
+
+    #[test]
+    fn trimming() {
+        let exact = |threshold| Trim {
+            threshold,
+            approximate: false,
+            limit: None,
+        };
+        let approximate = |threshold, limit| Trim {
+            threshold,
+            approximate: true,
+            limit,
+        };
+
+        let mut trimmed = stream(250);
+        assert_eq!(trimmed.trim(&exact(TrimThreshold::MaxLen(120))), 130);
+        assert_eq!(trimmed.first_entry().unwrap().id, StreamId::new(131, 0));
+        assert_eq!(trimmed.first_entry().unwrap().fields[1], Bytes::from("131"));
+
+        // Only whole blocks go, so a few more entries than asked for stay.
+        let mut trimmed = stream(250);
+        assert_eq!(
+            trimmed.trim(&approximate(TrimThreshold::MaxLen(120), None)),
+            100
+        );
+        assert_eq!(trimmed.len(), 150);
+        let mut trimmed = stream(250);
+        let limited = approximate(TrimThreshold::MaxLen(0), Some(150));
+        assert_eq!(trimmed.trim(&limited), 100);
+
+        let mut trimmed = stream(250);
+        let min_id = TrimThreshold::MinId(StreamId::new(205, 0));
+        assert_eq!(trimmed.trim(&approximate(min_id, None)), 200);
+        assert_eq!(trimmed.trim(&exact(min_id)), 4);
+        assert_eq!(trimmed.len(), 46);
+        assert_eq!(trimmed.trim(&exact(min_id)), 0);
+        assert_eq!(trimmed.entries_added(), 250);
+    }
+
+    #[test]
+    fn groups_deliver_each_entry_once() {
+        let mut stream = stream(5);
+        let (alice, bob) = (Bytes::from_static(b"alice"), Bytes::from_static(b"bob"));
+        assert!(stream.create_group(
+            Bytes::from_static(b"g"),
+            ConsumerGroup::new(StreamId::MIN, None)
+        ));
+        assert!(!stream.create_group(Bytes::from_static(b"g"), ConsumerGroup::default()));
+        assert_eq!(stream.lag(stream.group(b"g").unwrap()), Some(5));
+
+        let read = stream
+            .read_group(b"g", &alice, Some(2), false, 100)
+            .unwrap();
+        assert_eq!(ids(&read), vec![1, 2]);
+        let read = stream.read_group(b"g", &bob, None, true, 100).unwrap();
+        assert_eq!(ids(&read), vec![3, 4, 5]);
+        assert!(stream
+            .read_group(b"g", &bob, None, false, 100)
+            .unwrap()
+            .is_empty());
+        let group = stream.group(b"g").unwrap();
+        assert_eq!(group.last_delivered, StreamId::new(5, 0));
+        assert_eq!(group.entries_read, Some(5));
+        assert_eq!(group.pending().len(), 2);
+        assert_eq!(stream.lag(group), Some(0));
+
+        // Deleted entries come back without their fields.
+        stream.remove(StreamId::new(1, 0));
+        let pending = stream
+            .read_pending(b"g", b"alice", StreamId::MIN, None, 200)
+            .unwrap();
+        assert_eq!(pending[0], (StreamId::new(1, 0), None));
+        assert_eq!(pending[1].1.as_ref().unwrap().id, StreamId::new(2, 0));
+        assert_eq!(
+            stream.group(b"g").unwrap().pending()[&StreamId::new(2, 0)].deliveries,
+            2
+        );
+        assert!(stream
+            .read_group(b"missing", &alice, None, false, 0)
+            .is_none());
+
+        // A deletion past the group's position makes its lag unknown.
+        stream.add(StreamId::new(6, 0), &fields("6"));
+        stream.add(StreamId::new(7, 0), &fields("7"));
+        assert_eq!(stream.lag(stream.group(b"g").unwrap()), Some(2));
+        stream.remove(StreamId::new(6, 0));
+        assert_eq!(stream.lag(stream.group(b"g").unwrap()), None);
+    }
+}
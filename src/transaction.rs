@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use tokio::sync::{MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The gate every command holds shared while it runs, along with the turn
+/// of a write that is logged.
+pub struct SharedGate<'a> {
+    _shared: RwLockReadGuard<'a, ()>,
+    _turn: Option<MutexGuard<'a, ()>>,
+}
+
+/// Server-wide state behind `MULTI`/`EXEC` and `WATCH`.
+///
+/// Every command runs holding the gate shared, and `EXEC` holds it
+/// exclusively while it runs the queued commands, so no other client sees or
+/// changes the keyspace halfway through a transaction.
+///
+/// Watched keys carry a version that every write to them bumps. `EXEC`
+/// compares it with the version `WATCH` saw to tell whether a key changed.
+/// Only keys some client watches are tracked, so writes to other keys cost a
+/// single lookup.
+///
+/// While writes are logged, those that are take turns besides, so that they
+/// are logged in the order they took effect. Reads go on meanwhile.
+#[derive(Default)]
+pub struct Transactions {
+    gate: RwLock<()>,
+    turn: tokio::sync::Mutex<()>,
+    versions: Mutex<HashMap<Bytes, WatchedKey>>,
+}
+
+#[derive(Default)]
+struct WatchedKey {
+    version: u64,
+    watchers: usize,
+}
+
+impl Transactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn shared(&self) -> SharedGate<'_> {
+        SharedGate {
+            _shared: self.gate.read().await,
+            _turn: None,
+        }
+    }
+
+    /// The shared gate together with the turn of a logged write, which no
+    /// other logged write has until it is dropped.
+    pub async fn logged(&self) -> SharedGate<'_> {
+        let shared = self.gate.read().await;
+        SharedGate {
+            _shared: shared,
+            _turn: Some(self.turn.lock().await),
+        }
+    }
+
+    /// The turn of a logged write, if no logged write has it. For a command
+    /// holding the gate shared that finds it has something to log after all.
+    pub fn try_turn(&self) -> Option<MutexGuard<'_, ()>> {
+        self.turn.try_lock().ok()
+    }
+
+    pub async fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.gate.write().await
+    }
+
+    /// Starts tracking writes to `key` for one more watcher and returns the
+    /// key's current version.
+    pub fn watch(&self, key: &Bytes) -> u64 {
+        let mut versions = self.versions.lock().unwrap();
+        let watched = versions.entry(key.clone()).or_default();
+        watched.watchers += 1;
+        watched.version
+    }
+
+    /// Drops one watcher of `key`, forgetting the key once nobody watches it.
+    pub fn unwatch(&self, key: &Bytes) {
+        let mut versions = self.versions.lock().unwrap();
+        if let Some(watched) = versions.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                versions.remove(key);
+            }
+        }
+    }
+
+    /// The current version of a watched key.
+    pub fn version(&self, key: &Bytes) -> u64 {
+        let versions = self.versions.lock().unwrap();
+        versions.get(key).map_or(0, |watched| watched.version)
+    }
+
+    /// Records a write to each of `keys`.
+    pub fn touch<'a>(&self, keys: impl IntoIterator<Item = &'a Bytes>) {
+        let mut versions = self.versions.lock().unwrap();
+        if versions.is_empty() {
+            return;
+        }
+        for key in keys {
+            if let Some(watched) = versions.get_mut(key) {
+                watched.version += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_bump_watched_keys_only() {
+        let transactions = Transactions::new();
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let version = transactions.watch(&a);
+        transactions.touch([&b]);
+        assert_eq!(transactions.version(&a), version);
+        transactions.touch([&a, &b]);
+        assert_ne!(transactions.version(&a), version);
+    }
+
+    #[test]
+    fn keys_are_forgotten_after_the_last_unwatch() {
+        let transactions = Transactions::new();
+        let key = Bytes::from_static(b"key");
+        transactions.watch(&key);
+        transactions.watch(&key);
+        transactions.touch([&key]);
+        transactions.unwatch(&key);
+        assert_eq!(transactions.version(&key), 1);
+        transactions.unwatch(&key);
+        assert!(transactions.versions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn logged_writes_take_turns_while_reads_go_on() {
+        let transactions = Transactions::new();
+        let logged = transactions.logged().await;
+        assert!(transactions.try_turn().is_none());
+        let wait = std::time::Duration::from_millis(50);
+        let read = tokio::time::timeout(wait, transactions.shared()).await;
+        assert!(read.is_ok());
+        let write = tokio::time::timeout(wait, transactions.logged()).await;
+        assert!(write.is_err());
+        drop((logged, read));
+        assert!(transactions.try_turn().is_some());
+    }
+}
@@ -1,34 +1,180 @@
+use std::borrow::Cow;
 use std::fmt::Display;
+use std::io::{self, Write};
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+use bytes::Bytes;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Array(Vec<Value>),
     Integer(i64),
     SimpleString(String),
-    BulkString(String),
+    BulkString(Bytes),
     NullBulkString,
     NullArray,
     Error(String),
+    // RESP3 types
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    Verbatim(String, Bytes),
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    Push(Vec<Value>),
+    Attribute(Vec<(Value, Value)>, Box<Value>),
 }
 
-impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+/// A bulk string holding `value`.
+pub fn bulk(value: &str) -> Value {
+    Value::BulkString(Bytes::from(value.to_string()))
+}
+
+/// The RESP version a connection speaks, as negotiated with `HELLO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Value {
+    /// Writes the RESP encoding of the value to `dst`.
+    ///
+    /// Unlike the `Display` implementation, this is binary safe: bulk strings
+    /// are written byte for byte.
+    pub fn encode(&self, dst: &mut impl Write) -> io::Result<()> {
         match self {
-            Self::Integer(integer) => write!(f, ":{integer}\r\n"),
-            Self::SimpleString(string) => write!(f, "+{string}\r\n"),
-            Self::BulkString(string) => write!(f, "${}\r\n{}\r\n", string.len(), string),
-            Self::Error(err) => write!(f, "-{err}\r\n"),
-            Self::NullBulkString => write!(f, "$-1\r\n"),
-            Self::NullArray => write!(f, "*-1\r\n"),
-            Self::Array(tokens) => {
-                write!(f, "*{}\r\n", tokens.len())?;
-                for token in tokens {
-                    write!(f, "{token}")?;
-                }
-                Ok(())
+            Self::Integer(integer) => write!(dst, ":{integer}\r\n"),
+            Self::SimpleString(string) => write!(dst, "+{}\r\n", single_line(string)),
+            Self::BulkString(bytes) => {
+                write!(dst, "${}\r\n", bytes.len())?;
+                dst.write_all(bytes)?;
+                dst.write_all(b"\r\n")
+            }
+            Self::Error(err) => write!(dst, "-{}\r\n", single_line(err)),
+            Self::NullBulkString => write!(dst, "$-1\r\n"),
+            Self::NullArray => write!(dst, "*-1\r\n"),
+            Self::Array(tokens) => Self::encode_aggregate(dst, '*', tokens),
+            Self::Null => write!(dst, "_\r\n"),
+            Self::Boolean(boolean) => write!(dst, "#{}\r\n", if *boolean { 't' } else { 'f' }),
+            Self::Double(double) => write!(dst, ",{}\r\n", format_double(*double)),
+            Self::BigNumber(number) => write!(dst, "({number}\r\n"),
+            Self::Verbatim(format, bytes) => {
+                write!(dst, "={}\r\n{format}:", bytes.len() + format.len() + 1)?;
+                dst.write_all(bytes)?;
+                dst.write_all(b"\r\n")
+            }
+            Self::Map(pairs) => Self::encode_pairs(dst, '%', pairs),
+            Self::Set(tokens) => Self::encode_aggregate(dst, '~', tokens),
+            Self::Push(tokens) => Self::encode_aggregate(dst, '>', tokens),
+            Self::Attribute(pairs, value) => {
+                Self::encode_pairs(dst, '|', pairs)?;
+                value.encode(dst)
+            }
+        }
+    }
+
+    fn encode_aggregate(dst: &mut impl Write, kind: char, tokens: &[Value]) -> io::Result<()> {
+        write!(dst, "{kind}{}\r\n", tokens.len())?;
+        for token in tokens {
+            token.encode(dst)?;
+        }
+        Ok(())
+    }
+
+    fn encode_pairs(dst: &mut impl Write, kind: char, pairs: &[(Value, Value)]) -> io::Result<()> {
+        write!(dst, "{kind}{}\r\n", pairs.len())?;
+        for (key, value) in pairs {
+            key.encode(dst)?;
+            value.encode(dst)?;
+        }
+        Ok(())
+    }
+
+    /// Converts the value to the closest types `protocol` can represent.
+    ///
+    /// RESP2 has no maps, sets, doubles etc., so these are flattened into
+    /// arrays and bulk strings. RESP3 has a single null type.
+    pub fn for_protocol(self, protocol: Protocol) -> Value {
+        match (protocol, self) {
+            (_, Self::Array(tokens)) => Self::Array(Self::all_for_protocol(tokens, protocol)),
+            (Protocol::Resp3, Self::NullBulkString | Self::NullArray) => Self::Null,
+            (Protocol::Resp3, Self::Map(pairs)) => Self::Map(
+                pairs
+                    .into_iter()
+                    .map(|(k, v)| (k.for_protocol(protocol), v.for_protocol(protocol)))
+                    .collect(),
+            ),
+            (Protocol::Resp3, Self::Set(tokens)) => {
+                Self::Set(Self::all_for_protocol(tokens, protocol))
+            }
+            (Protocol::Resp3, Self::Push(tokens)) => {
+                Self::Push(Self::all_for_protocol(tokens, protocol))
+            }
+            (Protocol::Resp3, Self::Attribute(pairs, value)) => Self::Attribute(
+                pairs
+                    .into_iter()
+                    .map(|(k, v)| (k.for_protocol(protocol), v.for_protocol(protocol)))
+                    .collect(),
+                Box::new(value.for_protocol(protocol)),
+            ),
+            (Protocol::Resp3, value) => value,
+            (Protocol::Resp2, Self::Null) => Self::NullBulkString,
+            (Protocol::Resp2, Self::Boolean(boolean)) => Self::Integer(boolean as i64),
+            (Protocol::Resp2, Self::Double(double)) => {
+                Self::BulkString(Bytes::from(format_double(double)))
             }
+            (Protocol::Resp2, Self::BigNumber(number)) => Self::BulkString(Bytes::from(number)),
+            (Protocol::Resp2, Self::Verbatim(_, bytes)) => Self::BulkString(bytes),
+            (Protocol::Resp2, Self::Map(pairs)) => Self::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(k, v)| [k.for_protocol(protocol), v.for_protocol(protocol)])
+                    .collect(),
+            ),
+            (Protocol::Resp2, Self::Set(tokens) | Self::Push(tokens)) => {
+                Self::Array(Self::all_for_protocol(tokens, protocol))
+            }
+            (Protocol::Resp2, Self::Attribute(_, value)) => value.for_protocol(protocol),
+            (Protocol::Resp2, value) => value,
         }
     }
+
+    fn all_for_protocol(tokens: Vec<Value>, protocol: Protocol) -> Vec<Value> {
+        tokens
+            .into_iter()
+            .map(|t| t.for_protocol(protocol))
+            .collect()
+    }
+}
+
+/// `text` with line breaks replaced by spaces, as Redis does for status and
+/// error replies, so that text taken from a client cannot end the line
+/// early and pass for further replies.
+fn single_line(text: &str) -> Cow<'_, str> {
+    match text.contains(['\r', '\n']) {
+        true => Cow::Owned(text.replace(['\r', '\n'], " ")),
+        false => Cow::Borrowed(text),
+    }
+}
+
+fn format_double(double: f64) -> String {
+    if double.is_nan() {
+        String::from("nan")
+    } else if double.is_infinite() {
+        String::from(if double > 0.0 { "inf" } else { "-inf" })
+    } else {
+        double.to_string()
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut buf = vec![];
+        self.encode(&mut buf).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", String::from_utf8_lossy(&buf))
+    }
 }
 
 #[cfg(test)]
@@ -55,9 +201,19 @@ mod tests {
         assert_eq!(disp, "-hello world\r\n");
     }
 
+    #[test]
+    fn line_breaks_in_simple_strings_and_errors_become_spaces() {
+        let token = Value::SimpleString(String::from("a\r\n+OK"));
+        assert_eq!(format!("{token}"), "+a  +OK\r\n");
+        let token = Value::Error(String::from("NOGROUP g\r\n+OK\n"));
+        let mut buf = vec![];
+        token.encode(&mut buf).unwrap();
+        assert_eq!(buf, b"-NOGROUP g  +OK \r\n");
+    }
+
     #[test]
     fn write_bluk_string() {
-        let token = Value::BulkString(String::from("hello"));
+        let token = Value::BulkString(Bytes::from("hello"));
         let disp = format!("{token}");
         assert_eq!(disp, "$5\r\nhello\r\n");
     }
@@ -80,7 +236,7 @@ mod tests {
     fn write_array() {
         let token = Value::Array(vec![
             Value::SimpleString(String::from("hello world")),
-            Value::BulkString(String::from("hello")),
+            Value::BulkString(Bytes::from("hello")),
             Value::Integer(15232),
             Value::NullBulkString,
         ]);
@@ -90,4 +246,121 @@ mod tests {
             "*4\r\n+hello world\r\n$5\r\nhello\r\n:15232\r\n$-1\r\n"
         );
     }
+
+    #[test]
+    fn encode_binary_bulk_string() {
+        let token = Value::BulkString(Bytes::from_static(&[0xff, 0x00, 0xfe]));
+        let mut buf = vec![];
+        token.encode(&mut buf).unwrap();
+        assert_eq!(buf, b"$3\r\n\xff\x00\xfe\r\n");
+    }
+
+    #[test]
+    fn write_resp3_scalars() {
+        assert_eq!(format!("{}", Value::Null), "_\r\n");
+        assert_eq!(format!("{}", Value::Boolean(true)), "#t\r\n");
+        assert_eq!(format!("{}", Value::Boolean(false)), "#f\r\n");
+        assert_eq!(format!("{}", Value::Double(1.5)), ",1.5\r\n");
+        assert_eq!(format!("{}", Value::Double(f64::NEG_INFINITY)), ",-inf\r\n");
+        assert_eq!(
+            format!(
+                "{}",
+                Value::BigNumber(String::from("3492890328409238509324850943850943825024385"))
+            ),
+            "(3492890328409238509324850943850943825024385\r\n"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Value::Verbatim(String::from("txt"), Bytes::from("Some string"))
+            ),
+            "=15\r\ntxt:Some string\r\n"
+        );
+    }
+
+    #[test]
+    fn write_resp3_aggregates() {
+        let map = Value::Map(vec![
+            (
+                Value::SimpleString(String::from("first")),
+                Value::Integer(1),
+            ),
+            (
+                Value::SimpleString(String::from("second")),
+                Value::Integer(2),
+            ),
+        ]);
+        assert_eq!(format!("{map}"), "%2\r\n+first\r\n:1\r\n+second\r\n:2\r\n");
+        let set = Value::Set(vec![Value::Integer(1), Value::Boolean(true)]);
+        assert_eq!(format!("{set}"), "~2\r\n:1\r\n#t\r\n");
+        let push = Value::Push(vec![Value::BulkString(Bytes::from("message"))]);
+        assert_eq!(format!("{push}"), ">1\r\n$7\r\nmessage\r\n");
+        let attribute = Value::Attribute(
+            vec![(
+                Value::SimpleString(String::from("ttl")),
+                Value::Integer(3600),
+            )],
+            Box::new(Value::Integer(1)),
+        );
+        assert_eq!(format!("{attribute}"), "|1\r\n+ttl\r\n:3600\r\n:1\r\n");
+    }
+
+    #[test]
+    fn downgrade_to_resp2() {
+        let value = Value::Array(vec![
+            Value::Null,
+            Value::Boolean(true),
+            Value::Double(2.5),
+            Value::Map(vec![(
+                Value::BulkString(Bytes::from("k")),
+                Value::Set(vec![Value::Integer(1)]),
+            )]),
+        ]);
+        assert_eq!(
+            value.for_protocol(Protocol::Resp2),
+            Value::Array(vec![
+                Value::NullBulkString,
+                Value::Integer(1),
+                Value::BulkString(Bytes::from("2.5")),
+                Value::Array(vec![
+                    Value::BulkString(Bytes::from("k")),
+                    Value::Array(vec![Value::Integer(1)]),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn upgrade_to_resp3() {
+        let value = Value::Array(vec![
+            Value::NullBulkString,
+            Value::NullArray,
+            Value::Integer(1),
+        ]);
+        assert_eq!(
+            value.for_protocol(Protocol::Resp3),
+            Value::Array(vec![Value::Null, Value::Null, Value::Integer(1)])
+        );
+    }
+
+    #[test]
+    fn upgrade_attributes_to_resp3() {
+        let value = Value::Attribute(
+            vec![(
+                Value::BulkString(Bytes::from("k")),
+                Value::Array(vec![Value::NullBulkString]),
+            )],
+            Box::new(Value::NullArray),
+        );
+        assert_eq!(
+            value.for_protocol(Protocol::Resp3),
+            Value::Attribute(
+                vec![(
+                    Value::BulkString(Bytes::from("k")),
+                    Value::Array(vec![Value::Null]),
+                )],
+                Box::new(Value::Null),
+            )
+        );
+    }
 }